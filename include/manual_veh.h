@@ -0,0 +1,62 @@
+/*
+ * C interface to manual-veh, built with the `capi` feature.
+ *
+ * Every function returns MANUALVEH_OK or one of the MANUALVEH_ERROR_* codes, and
+ * manualveh_last_error describes the most recent failure on the calling thread.
+ */
+
+#ifndef MANUAL_VEH_H
+#define MANUAL_VEH_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MANUALVEH_OK 0
+#define MANUALVEH_ERROR_RESOLUTION 1
+#define MANUALVEH_ERROR_REGISTRATION 2
+#define MANUALVEH_ERROR_MODULE_NOT_FOUND 3
+#define MANUALVEH_ERROR_SLOTS_EXHAUSTED 4
+#define MANUALVEH_ERROR_NOT_TOGGLEABLE 5
+#define MANUALVEH_ERROR_ALREADY_REGISTERED 6
+#define MANUALVEH_ERROR_INVALID_ARGUMENT 7
+#define MANUALVEH_ERROR_NOT_REMOVED 8
+#define MANUALVEH_ERROR_NOT_IN_MODULE 9
+
+/* The same as PVECTORED_EXCEPTION_HANDLER, taking a PEXCEPTION_POINTERS. */
+typedef long (__stdcall *ManualVehHandler)(void *exception_pointers);
+
+/* Where the registration functions were found. */
+typedef struct ManualVehInfo {
+    uintptr_t ntdll_base;
+    /* ntdll's internal RtlpAddVectoredHandler */
+    uintptr_t add_handler;
+    /* ntdll's internal RtlpRemoveVectoredHandler */
+    uintptr_t remove_handler;
+} ManualVehInfo;
+
+/* Locates ntdll's registration functions now rather than on first use. */
+int manualveh_init(void);
+
+/* Adds handler to the front of the exception handler list if first is non-zero, or to the
+ * back otherwise, storing its handle in out_handle. */
+int manualveh_add(int first, ManualVehHandler handler, void **out_handle);
+
+/* Removes a handler added with manualveh_add. */
+int manualveh_remove(void *handle);
+
+/* Copies the description of the calling thread's most recent failure into buf, nul-terminated
+ * and cut off to fit in len bytes, returning its full length without the terminator. */
+size_t manualveh_last_error(char *buf, size_t len);
+
+/* Fills in out with where the registration functions were found. */
+int manualveh_resolution_info(ManualVehInfo *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MANUAL_VEH_H */
@@ -0,0 +1,113 @@
+// Imports
+use crate::VectoredHandler;
+use std::ffi::c_void;
+
+/// A handler using the C calling convention rather than the `"system"` one ntdll expects.
+pub type CHandler = unsafe extern "C" fn(*mut c_void) -> i32;
+
+// On x86, "C" is cdecl and "system" is stdcall, so C handlers can't be registered directly.
+// Instead each one is given one of a fixed pool of stdcall thunks that calls through to it.
+#[cfg(target_arch = "x86")]
+mod pool {
+    use super::CHandler;
+    use crate::VectoredHandler;
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const POOL_SIZE: usize = 32;
+
+    static C_HANDLERS: [AtomicUsize; POOL_SIZE] = [const { AtomicUsize::new(0) }; POOL_SIZE];
+
+    unsafe extern "system" fn thunk<const INDEX: usize>(ptrs: *mut c_void) -> i32 {
+        let handler = C_HANDLERS[INDEX].load(Ordering::Acquire);
+        std::mem::transmute::<usize, CHandler>(handler)(ptrs)
+    }
+
+    macro_rules! thunks {
+        ($($index:literal)*) => {
+            static THUNKS: [VectoredHandler; POOL_SIZE] = [$(thunk::<$index>,)*];
+        };
+    }
+
+    thunks! {
+         0  1  2  3  4  5  6  7  8  9 10 11 12 13 14 15
+        16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    }
+
+    pub(super) fn thunk_for(handler: CHandler) -> Option<VectoredHandler> {
+        let handler = handler as usize;
+
+        // Thunks are never released, so reuse the one already dispatching to this handler
+        if let Some(index) = C_HANDLERS
+            .iter()
+            .position(|h| h.load(Ordering::Acquire) == handler)
+        {
+            return Some(THUNKS[index]);
+        }
+
+        C_HANDLERS.iter().enumerate().find_map(|(index, h)| {
+            match h.compare_exchange(0, handler, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => Some(THUNKS[index]),
+                // Someone else claimed the slot for this very handler in the meantime
+                Err(current) if current == handler => Some(THUNKS[index]),
+                Err(_) => None,
+            }
+        })
+    }
+}
+
+/// Turns a handler using the C calling convention into one that can be registered with ntdll.
+///
+/// On x64 and ARM64 the two conventions are the same, and the handler is returned as-is. On x86, the
+/// handler is assigned a stdcall thunk out of a fixed pool of 32. Thunks are never released, but
+/// adapting the same handler again reuses its thunk.
+///
+/// # Panics
+/// Panics on x86 if more than 32 distinct handlers are adapted.
+pub fn adapt_c_handler(f: CHandler) -> VectoredHandler {
+    #[cfg(target_arch = "x86")]
+    return pool::thunk_for(f).expect("all C handler thunks are in use");
+
+    #[cfg(not(target_arch = "x86"))]
+    return unsafe { std::mem::transmute::<CHandler, VectoredHandler>(f) };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{adapt_c_handler, ExceptionInfo, Handling, Order, Veh};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_4301;
+    static SEEN: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn c_handler(ptrs: *mut c_void) -> i32 {
+        match ExceptionInfo::from_raw(ptrs).code().raw() {
+            CODE => {
+                SEEN.fetch_add(1, Ordering::SeqCst);
+                Handling::ContinueExecution.raw()
+            }
+            _ => Handling::ContinueSearch.raw(),
+        }
+    }
+
+    #[test]
+    fn c_handler_called() {
+        unsafe {
+            let _veh = Veh::add_c(Order::First, c_handler);
+            RaiseException(CODE, 0, 0, std::ptr::null());
+            RaiseException(CODE, 0, 0, std::ptr::null());
+        }
+
+        assert_eq!(SEEN.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn adapting_twice_reuses_thunk() {
+        assert_eq!(
+            adapt_c_handler(c_handler) as usize,
+            adapt_c_handler(c_handler) as usize
+        );
+    }
+}
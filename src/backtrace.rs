@@ -0,0 +1,231 @@
+//! Walking the stack of an exception from its context, rather than from the handler.
+//!
+//! On x64 and ARM64, frames are unwound with ntdll's `RtlLookupFunctionEntry` and
+//! `RtlVirtualUnwind`, as the OS's own dispatcher does. x86 has no unwind tables, so the walk
+//! follows the `ebp` chain there, which only works through functions keeping a frame pointer.
+
+// Imports
+use crate::{teb, ContextExt, CONTEXT};
+
+// Architecture-specific imports
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::modules;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use pelite::pe64::{exports::GetProcAddress, PeView};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::ffi::c_void;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::sync::OnceLock;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+type FnRtlLookupFunctionEntry =
+    unsafe extern "system" fn(pc: u64, image_base: *mut u64, history: *mut c_void) -> *const c_void;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+type FnRtlVirtualUnwind = unsafe extern "system" fn(
+    handler_type: u32,
+    image_base: u64,
+    pc: u64,
+    function_entry: *const c_void,
+    context: *mut CONTEXT,
+    handler_data: *mut *mut c_void,
+    establisher_frame: *mut u64,
+    context_pointers: *mut c_void,
+) -> *mut c_void;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+struct Unwinder {
+    lookup: FnRtlLookupFunctionEntry,
+    unwind: FnRtlVirtualUnwind,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn unwinder() -> Option<&'static Unwinder> {
+    static UNWINDER: OnceLock<Option<Unwinder>> = OnceLock::new();
+    UNWINDER
+        .get_or_init(|| {
+            let ntdll = modules::find("ntdll.dll")?;
+            let module = unsafe { PeView::module(ntdll.base() as *const u8) };
+            let lookup = module.get_proc_address("RtlLookupFunctionEntry").ok()?;
+            let unwind = module.get_proc_address("RtlVirtualUnwind").ok()?;
+            unsafe {
+                Some(Unwinder {
+                    lookup: std::mem::transmute::<usize, FnRtlLookupFunctionEntry>(lookup as _),
+                    unwind: std::mem::transmute::<usize, FnRtlVirtualUnwind>(unwind as _),
+                })
+            }
+        })
+        .as_ref()
+}
+
+/// Walks the stack `ctx` was captured on, writing the instruction pointer followed by up to
+/// `frames.len() - 1` return addresses into `frames`, and returns how many were written.
+///
+/// `ctx` must be a context of the current thread, such as the one an exception was raised with,
+/// as the walk stops at the first frame outside of the current thread's stack. Nothing is
+/// allocated, so this can be called from inside a handler.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn capture(ctx: &CONTEXT, frames: &mut [usize]) -> usize {
+    let unwinder = match unwinder() {
+        Some(unwinder) => unwinder,
+        None => return 0,
+    };
+
+    let stack = teb::stack_bounds();
+    let mut context = *ctx;
+    let mut count = 0;
+    while count < frames.len() {
+        let (ip, sp) = (context.ip(), context.sp());
+        if ip == 0 {
+            break;
+        }
+        frames[count] = ip;
+        count += 1;
+        if !stack.contains(&sp) {
+            break;
+        }
+
+        let mut image_base = 0;
+        let entry = unsafe { (unwinder.lookup)(ip as u64, &mut image_base, std::ptr::null_mut()) };
+        if entry.is_null() {
+            // Leaf functions without unwind data return straight to their caller
+            #[cfg(target_arch = "x86_64")]
+            {
+                context.rip = unsafe { *(sp as *const u64) };
+                context.rsp += 8;
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                context.pc = context.x[30];
+            }
+        } else {
+            let mut handler_data = std::ptr::null_mut();
+            let mut establisher_frame = 0;
+            unsafe {
+                (unwinder.unwind)(
+                    0,
+                    image_base,
+                    ip as u64,
+                    entry,
+                    &mut context,
+                    &mut handler_data,
+                    &mut establisher_frame,
+                    std::ptr::null_mut(),
+                )
+            };
+        }
+
+        // Every frame is above the one it called, and one that isn't ends the walk
+        if context.sp() < sp || (context.sp() == sp && context.ip() == ip) {
+            break;
+        }
+    }
+    count
+}
+
+/// Walks the stack `ctx` was captured on, writing the instruction pointer followed by up to
+/// `frames.len() - 1` return addresses into `frames`, and returns how many were written.
+///
+/// `ctx` must be a context of the current thread, such as the one an exception was raised with.
+/// The walk follows the `ebp` chain for as long as each frame pointer is aligned, above the one
+/// before it, and on the current thread's stack. Nothing is allocated, so this can be called
+/// from inside a handler.
+#[cfg(target_arch = "x86")]
+pub fn capture(ctx: &CONTEXT, frames: &mut [usize]) -> usize {
+    if frames.is_empty() {
+        return 0;
+    }
+    frames[0] = ctx.ip();
+
+    let stack = teb::stack_bounds();
+    let mut lowest = ctx.sp();
+    let mut frame = ctx.frame_pointer();
+    let mut count = 1;
+    while count < frames.len() {
+        // The saved frame pointer and the return address after it
+        let valid = frame >= lowest && frame & 3 == 0 && frame >= stack.start;
+        if !valid || frame + 8 > stack.end {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*(frame as *const usize), *((frame + 4) as *const usize)) };
+        if ret == 0 {
+            break;
+        }
+        frames[count] = ret;
+        count += 1;
+
+        if next <= frame {
+            break;
+        }
+        lowest = frame + 8;
+        frame = next;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::capture;
+    use crate::{dispatch, ExceptionCode, ExceptionSnapshot, Filter, Handling};
+    use std::hint::black_box;
+    use std::sync::Mutex;
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_4201;
+
+    // Generous bounds on the size of the functions below
+    const MAX_FUNCTION_LEN: usize = 0x400;
+
+    static SNAPSHOT: Mutex<Option<ExceptionSnapshot>> = Mutex::new(None);
+    static CAPTURED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    #[inline(never)]
+    fn first() {
+        second();
+        black_box(());
+    }
+
+    #[inline(never)]
+    fn second() {
+        third();
+        black_box(());
+    }
+
+    #[inline(never)]
+    fn third() {
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        black_box(());
+    }
+
+    #[test]
+    fn three_calls_deep() {
+        let _serial = dispatch::tests::serial();
+
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let callback = dispatch::register(filter, |info| {
+            let mut frames = [0; 16];
+            let count = capture(info.context(), &mut frames);
+            CAPTURED.lock().unwrap().extend_from_slice(&frames[..count]);
+            *SNAPSHOT.lock().unwrap() = Some(info.snapshot());
+            Handling::ContinueExecution
+        })
+        .unwrap();
+        first();
+        drop(callback);
+
+        let snapshot = SNAPSHOT.lock().unwrap().take().unwrap();
+        let captured = CAPTURED.lock().unwrap().clone();
+        for frames in [snapshot.backtrace(), &captured] {
+            for function in [
+                first as fn() as usize,
+                second as fn() as usize,
+                third as fn() as usize,
+            ] {
+                let range = function..function + MAX_FUNCTION_LEN;
+                assert!(frames.iter().any(|frame| range.contains(frame)));
+            }
+        }
+        assert!(!dispatch::is_installed());
+    }
+}
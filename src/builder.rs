@@ -0,0 +1,350 @@
+// Imports
+use crate::filter::{module_range, Filter};
+use crate::modules::Module;
+use crate::slots::{Callback, Entry, SlotGuard};
+use crate::{
+    modules, raw, ExceptionCode, ExceptionInfo, Handling, Order, VectoredHandler,
+    VectoredHandlerFor, Veh, VehError, VehVoid,
+};
+
+/// Fluent construction of a [`Veh`] with options beyond the handler order.
+///
+/// Unset options keep the behaviour of [`Veh::add`]: the handler is registered into the
+/// exception handler list, receives every exception, and can't be toggled.
+/// Without an explicit order, handlers are registered with [`Order::First`].
+#[derive(Debug, Clone)]
+pub struct VehBuilder {
+    order: Order,
+    handler_list: i32,
+    codes: Vec<ExceptionCode>,
+    modules: Vec<String>,
+    toggleable: bool,
+    pin: Pin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Pin {
+    Never,
+    IfInModule,
+    Required,
+}
+
+impl Default for VehBuilder {
+    fn default() -> Self {
+        VehBuilder::new()
+    }
+}
+
+impl VehBuilder {
+    pub fn new() -> Self {
+        VehBuilder {
+            order: Order::First,
+            handler_list: raw::EXCEPTION_HANDLER_LIST,
+            codes: Vec::new(),
+            modules: Vec::new(),
+            toggleable: false,
+            pin: Pin::Never,
+        }
+    }
+
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn first(self) -> Self {
+        self.order(Order::First)
+    }
+
+    pub fn last(self) -> Self {
+        self.order(Order::Last)
+    }
+
+    /// Registers into the vectored *continue* handler list instead of the exception handler list.
+    pub fn continue_list(mut self) -> Self {
+        self.handler_list = raw::CONTINUE_HANDLER_LIST;
+        self
+    }
+
+    /// Only invoke the handler for exceptions with this code.
+    /// Calling this repeatedly allows any of the given codes.
+    pub fn filter_code(mut self, code: ExceptionCode) -> Self {
+        self.codes.push(code);
+        self
+    }
+
+    /// Only invoke the handler for exceptions raised from inside the named module.
+    /// Calling this repeatedly allows any of the given modules.
+    ///
+    /// The module is looked up once at install time, which fails if it isn't loaded.
+    pub fn filter_module(mut self, name: impl Into<String>) -> Self {
+        self.modules.push(name.into());
+        self
+    }
+
+    /// Allows the handler to be switched off and on again with [`Veh::set_enabled`].
+    pub fn toggleable(mut self) -> Self {
+        self.toggleable = true;
+        self
+    }
+
+    /// Pins the module containing the handler at install time with [`Module::pin`], so that
+    /// freeing it while the handler is still registered can't leave ntdll calling into unmapped
+    /// memory. For closures, the pinned module is the one containing this crate.
+    ///
+    /// Handlers outside any loaded module, such as generated code, are installed unpinned;
+    /// [`Veh::is_pinned`] tells whether pinning worked.
+    ///
+    /// [`Module::pin`]: crate::modules::Module::pin
+    pub fn pin_module(mut self) -> Self {
+        self.pin = self.pin.max(Pin::IfInModule);
+        self
+    }
+
+    /// Like [`pin_module`](Self::pin_module), but fails the install with
+    /// [`VehError::NotInModule`] if the handler isn't inside a loaded module.
+    pub fn require_module(mut self) -> Self {
+        self.pin = Pin::Required;
+        self
+    }
+
+    /// # Safety
+    /// `T` must be a type that matches the Windows API's `EXCEPTION_POINTERS` type.
+    ///
+    /// This function will never directly cause undefined behaviour, but the handlers it registers
+    /// might very well cause UB if they're not written correctly. Calling this function is
+    /// therefore unsafe, as it might affect the program in unexpected ways if the caller doesn't
+    /// properly handle exceptions it catches.
+    pub unsafe fn install_fn<T>(self, handler: VectoredHandlerFor<T>) -> Result<Veh<T>, VehError> {
+        let handler = std::mem::transmute::<VectoredHandlerFor<T>, VectoredHandler>(handler);
+        let filter = self.filter()?;
+
+        // Without anything to check in between, register the handler itself like `Veh::add` does
+        if filter.is_any() && !self.toggleable {
+            let host = self.module_to_pin(handler as usize)?;
+            let handle = raw::try_add_handler(self.handler_list, self.is_first(), handler)?;
+            let mut veh = Veh::from_parts(handle, self.handler_list, handler as usize, None);
+            veh.pinned = pin(host);
+            return Ok(veh);
+        }
+
+        // The trampoline still calls the handler, so both modules have to stay loaded
+        let host = self.module_to_pin(handler as usize)?;
+        let mut veh = self.install(Callback::Raw(handler), filter)?;
+        veh.pinned &= pin(host);
+        Ok(veh)
+    }
+
+    /// # Safety
+    /// This function will never directly cause undefined behaviour, but the handlers it registers
+    /// might very well cause UB if they're not written correctly. Calling this function is
+    /// therefore unsafe, as it might affect the program in unexpected ways if the caller doesn't
+    /// properly handle exceptions it catches.
+    ///
+    /// The closure can be called from any thread, including concurrently from several threads.
+    pub unsafe fn install_closure<F>(self, f: F) -> Result<VehVoid, VehError>
+    where
+        F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+    {
+        let filter = self.filter()?;
+        self.install(Callback::Closure(Box::new(f)), filter)
+    }
+
+    unsafe fn install<T>(self, callback: Callback, filter: Filter) -> Result<Veh<T>, VehError> {
+        let slot = SlotGuard::claim(Entry::new(callback, filter, self.toggleable))?;
+        let host = self.module_to_pin(slot.handler() as usize)?;
+        let handle = raw::try_add_handler(self.handler_list, self.is_first(), slot.handler())?;
+        let mut veh = Veh::from_parts(
+            handle,
+            self.handler_list,
+            slot.handler() as usize,
+            Some(slot),
+        );
+        veh.pinned = pin(host);
+        Ok(veh)
+    }
+
+    // The module containing `address`, if asked to pin it. Only pinned once the handler is
+    // registered, as a pin can't be undone if registering fails
+    fn module_to_pin(&self, address: usize) -> Result<Option<Module>, VehError> {
+        if self.pin == Pin::Never {
+            return Ok(None);
+        }
+        match modules::containing(address) {
+            Some(module) => Ok(Some(module)),
+            None if self.pin == Pin::Required => Err(VehError::NotInModule),
+            None => Ok(None),
+        }
+    }
+
+    fn is_first(&self) -> bool {
+        matches!(self.order, Order::First)
+    }
+
+    fn filter(&self) -> Result<Filter, VehError> {
+        let mut filter = Filter::any();
+
+        if !self.codes.is_empty() {
+            filter = filter.and(Filter::codes(self.codes.iter().copied()));
+        }
+
+        if !self.modules.is_empty() {
+            let ranges = self
+                .modules
+                .iter()
+                .map(|name| module_range(name))
+                .collect::<Result<_, _>>()?;
+            filter = filter.and(Filter::address_ranges(ranges));
+        }
+
+        Ok(filter)
+    }
+}
+
+// Pins `host`, if there's one to pin, returning whether it was pinned
+fn pin(host: Option<Module>) -> bool {
+    host.is_some_and(|module| module.pin())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ExceptionCode, ExceptionInfo, Handling, Order, Veh, VehBuilder, VehError};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const WANTED: u32 = 0xE056_4201;
+    const FILTERED: u32 = 0xE056_4202;
+
+    unsafe extern "system" fn backstop(ptrs: *mut c_void) -> i32 {
+        match ExceptionInfo::from_raw(ptrs).code().raw() {
+            WANTED | FILTERED => Handling::ContinueExecution.raw(),
+            _ => Handling::ContinueSearch.raw(),
+        }
+    }
+
+    #[test]
+    fn filtered_codes_never_reach_closure() {
+        static WANTED_SEEN: AtomicUsize = AtomicUsize::new(0);
+        static FILTERED_SEEN: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe {
+            let _backstop = Veh::<c_void>::add(Order::Last, backstop);
+            let _veh = VehBuilder::new()
+                .first()
+                .filter_code(ExceptionCode::from_raw(WANTED))
+                .install_closure(|info| {
+                    match info.code().raw() {
+                        WANTED => WANTED_SEEN.fetch_add(1, Ordering::SeqCst),
+                        _ => FILTERED_SEEN.fetch_add(1, Ordering::SeqCst),
+                    };
+                    Handling::ContinueExecution
+                })
+                .unwrap();
+
+            RaiseException(WANTED, 0, 0, std::ptr::null());
+            RaiseException(FILTERED, 0, 0, std::ptr::null());
+            RaiseException(WANTED, 0, 0, std::ptr::null());
+        }
+
+        assert_eq!(WANTED_SEEN.load(Ordering::SeqCst), 2);
+        assert_eq!(FILTERED_SEEN.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn toggleable_handler() {
+        const CODE: u32 = 0xE056_4203;
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "system" fn handler(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                CODE => {
+                    SEEN.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueExecution.raw()
+                }
+                _ => Handling::ContinueSearch.raw(),
+            }
+        }
+
+        unsafe extern "system" fn fallback(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                CODE => Handling::ContinueExecution.raw(),
+                _ => Handling::ContinueSearch.raw(),
+            }
+        }
+
+        unsafe {
+            let _fallback = Veh::<c_void>::add(Order::Last, fallback);
+            let veh = VehBuilder::new().toggleable().install_fn(handler).unwrap();
+
+            RaiseException(CODE, 0, 0, std::ptr::null());
+            veh.set_enabled(false).unwrap();
+            RaiseException(CODE, 0, 0, std::ptr::null());
+            veh.set_enabled(true).unwrap();
+            RaiseException(CODE, 0, 0, std::ptr::null());
+
+            let plain = VehBuilder::new().last().install_fn(fallback).unwrap();
+            assert_eq!(plain.set_enabled(false), Err(VehError::NotToggleable));
+        }
+
+        assert_eq!(SEEN.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn unknown_module_filter() {
+        let result = unsafe {
+            VehBuilder::new()
+                .filter_module("definitely-not-loaded.dll")
+                .install_closure(|_| Handling::ContinueSearch)
+        };
+
+        assert!(matches!(result, Err(VehError::ModuleNotFound(_))));
+    }
+
+    #[test]
+    fn pinned_own_module() {
+        unsafe {
+            let veh = VehBuilder::new()
+                .last()
+                .require_module()
+                .install_fn(backstop)
+                .unwrap();
+            assert!(veh.is_pinned());
+
+            let closure = VehBuilder::new()
+                .pin_module()
+                .install_closure(|_| Handling::ContinueSearch)
+                .unwrap();
+            assert!(closure.is_pinned());
+
+            let unpinned = VehBuilder::new().last().install_fn(backstop).unwrap();
+            assert!(!unpinned.is_pinned());
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn generated_handler_not_pinned() {
+        use crate::memory;
+
+        // xor eax, eax (EXCEPTION_CONTINUE_SEARCH), then return
+        #[cfg(target_arch = "x86")]
+        let code = [0x31, 0xC0, 0xC2, 0x04, 0x00];
+        #[cfg(target_arch = "x86_64")]
+        let code = [0x31, 0xC0, 0xC3];
+        let address = memory::alloc_code(&code).unwrap();
+        let handler = unsafe {
+            std::mem::transmute::<usize, unsafe extern "system" fn(*mut c_void) -> i32>(address)
+        };
+
+        unsafe {
+            let required = VehBuilder::new().require_module().install_fn(handler);
+            assert!(matches!(required, Err(VehError::NotInModule)));
+
+            let veh = VehBuilder::new().pin_module().install_fn(handler).unwrap();
+            assert!(!veh.is_pinned());
+            drop(veh);
+            memory::free_code(address);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! `extern "C"` functions for locating and calling ntdll's registration functions from other
+//! languages, declared in `include/manual_veh.h`.
+//!
+//! Build the crate as a `cdylib` or `staticlib` with the `capi` feature, for example with
+//! `cargo rustc --release --features capi --crate-type cdylib`. Every function returns
+//! [`MANUALVEH_OK`] or one of the `MANUALVEH_ERROR_*` codes, and [`manualveh_last_error`] describes
+//! the most recent failure on the calling thread.
+
+// Imports
+use crate::raw::{self, EXCEPTION_HANDLER_LIST};
+use crate::{modules, VectoredHandler, VehError};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void};
+
+pub const MANUALVEH_OK: c_int = 0;
+/// [`VehError::Resolution`]
+pub const MANUALVEH_ERROR_RESOLUTION: c_int = 1;
+/// [`VehError::Registration`]
+pub const MANUALVEH_ERROR_REGISTRATION: c_int = 2;
+/// [`VehError::ModuleNotFound`]
+pub const MANUALVEH_ERROR_MODULE_NOT_FOUND: c_int = 3;
+/// [`VehError::SlotsExhausted`]
+pub const MANUALVEH_ERROR_SLOTS_EXHAUSTED: c_int = 4;
+/// [`VehError::NotToggleable`]
+pub const MANUALVEH_ERROR_NOT_TOGGLEABLE: c_int = 5;
+/// [`VehError::AlreadyRegistered`]
+pub const MANUALVEH_ERROR_ALREADY_REGISTERED: c_int = 6;
+/// A required pointer was null.
+pub const MANUALVEH_ERROR_INVALID_ARGUMENT: c_int = 7;
+/// ntdll didn't find the handle to remove.
+pub const MANUALVEH_ERROR_NOT_REMOVED: c_int = 8;
+/// [`VehError::NotInModule`]
+pub const MANUALVEH_ERROR_NOT_IN_MODULE: c_int = 9;
+
+/// A vectored exception handler, `PVECTORED_EXCEPTION_HANDLER` in C.
+pub type ManualVehHandler = Option<VectoredHandler>;
+
+/// Where the registration functions were found, filled in by [`manualveh_resolution_info`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ManualVehInfo {
+    pub ntdll_base: usize,
+    /// ntdll's internal `RtlpAddVectoredHandler`.
+    pub add_handler: usize,
+    /// ntdll's internal `RtlpRemoveVectoredHandler`.
+    pub remove_handler: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn fail(code: c_int, message: String) -> c_int {
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+fn fail_with(error: VehError) -> c_int {
+    let code = match error {
+        VehError::Resolution => MANUALVEH_ERROR_RESOLUTION,
+        VehError::Registration => MANUALVEH_ERROR_REGISTRATION,
+        VehError::ModuleNotFound(_) => MANUALVEH_ERROR_MODULE_NOT_FOUND,
+        VehError::SlotsExhausted => MANUALVEH_ERROR_SLOTS_EXHAUSTED,
+        VehError::NotToggleable => MANUALVEH_ERROR_NOT_TOGGLEABLE,
+        VehError::AlreadyRegistered => MANUALVEH_ERROR_ALREADY_REGISTERED,
+        VehError::NotInModule => MANUALVEH_ERROR_NOT_IN_MODULE,
+    };
+    fail(code, error.to_string())
+}
+
+/// Locates ntdll's registration functions, see [`init`](crate::init).
+#[no_mangle]
+pub extern "C" fn manualveh_init() -> c_int {
+    match crate::init() {
+        Ok(()) => MANUALVEH_OK,
+        Err(error) => fail_with(error),
+    }
+}
+
+/// Adds `handler` to the front of the exception handler list if `first` is non-zero, or to the
+/// back otherwise, storing its handle in `out_handle`.
+///
+/// # Safety
+/// `out_handle` must be valid for writes, and the handler must be safe to call for any exception
+/// raised in the process until it's removed.
+#[no_mangle]
+pub unsafe extern "C" fn manualveh_add(
+    first: c_int,
+    handler: ManualVehHandler,
+    out_handle: *mut *mut c_void,
+) -> c_int {
+    let handler = match handler {
+        Some(handler) if !out_handle.is_null() => handler,
+        _ => {
+            let message = "the handler and handle pointer must not be null";
+            return fail(MANUALVEH_ERROR_INVALID_ARGUMENT, message.into());
+        }
+    };
+
+    match raw::try_add_handler(EXCEPTION_HANDLER_LIST, first != 0, handler) {
+        Ok(handle) => {
+            *out_handle = handle as *mut c_void;
+            MANUALVEH_OK
+        }
+        Err(error) => fail_with(error),
+    }
+}
+
+/// Removes a handler added with [`manualveh_add`].
+///
+/// # Safety
+/// `handle` must have been returned by [`manualveh_add`], and not been removed since.
+#[no_mangle]
+pub unsafe extern "C" fn manualveh_remove(handle: *mut c_void) -> c_int {
+    if handle.is_null() {
+        return fail(
+            MANUALVEH_ERROR_INVALID_ARGUMENT,
+            "the handle is null".into(),
+        );
+    }
+    match raw::remove_handler(EXCEPTION_HANDLER_LIST, handle) {
+        0 => fail(
+            MANUALVEH_ERROR_NOT_REMOVED,
+            "ntdll didn't remove the handler".into(),
+        ),
+        _ => MANUALVEH_OK,
+    }
+}
+
+/// Copies the description of the calling thread's most recent failure into `buf` as a
+/// nul-terminated string, cut off to fit in `len` bytes, and returns its full length without the
+/// terminator, as `snprintf` does. The description is empty if nothing has failed.
+///
+/// # Safety
+/// `buf` must be valid for `len` bytes of writes, or null if `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn manualveh_last_error(buf: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        if !buf.is_null() && len > 0 {
+            let copied = last.len().min(len - 1);
+            std::ptr::copy_nonoverlapping(last.as_ptr(), buf as *mut u8, copied);
+            *buf.add(copied) = 0;
+        }
+        last.len()
+    })
+}
+
+/// Locates the registration functions if they haven't been yet, and fills in `out` with where
+/// they are.
+///
+/// # Safety
+/// `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn manualveh_resolution_info(out: *mut ManualVehInfo) -> c_int {
+    if out.is_null() {
+        return fail(
+            MANUALVEH_ERROR_INVALID_ARGUMENT,
+            "the output is null".into(),
+        );
+    }
+    let (add_handler, remove_handler) = match raw::handler_functions() {
+        Ok(functions) => functions,
+        Err(error) => return fail_with(error),
+    };
+
+    let ntdll_base = modules::find("ntdll.dll").map_or(0, |ntdll| ntdll.base());
+    out.write(ManualVehInfo {
+        ntdll_base,
+        add_handler,
+        remove_handler,
+    });
+    MANUALVEH_OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ManualVehHandler, ManualVehInfo, MANUALVEH_ERROR_INVALID_ARGUMENT,
+        MANUALVEH_ERROR_NOT_REMOVED, MANUALVEH_OK,
+    };
+    use crate::{modules, ExceptionInfo, EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH};
+    use std::ffi::{c_char, c_int, c_void, CStr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    // Declared as a C consumer would, resolved to the exports at link time
+    extern "C" {
+        fn manualveh_init() -> c_int;
+        fn manualveh_add(first: c_int, handler: ManualVehHandler, out: *mut *mut c_void) -> c_int;
+        fn manualveh_remove(handle: *mut c_void) -> c_int;
+        fn manualveh_last_error(buf: *mut c_char, len: usize) -> usize;
+        fn manualveh_resolution_info(out: *mut ManualVehInfo) -> c_int;
+    }
+
+    const CODE: u32 = 0xE056_5901;
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "system" fn handler(pointers: *mut c_void) -> i32 {
+        let info = ExceptionInfo::from_raw(pointers);
+        match info.code().raw() == CODE {
+            true => {
+                HANDLED.fetch_add(1, Ordering::SeqCst);
+                EXCEPTION_CONTINUE_EXECUTION
+            }
+            false => EXCEPTION_CONTINUE_SEARCH,
+        }
+    }
+
+    fn last_error(len: usize) -> (String, usize) {
+        let mut buf = vec![b'x' as c_char; len];
+        let full = unsafe { manualveh_last_error(buf.as_mut_ptr(), len) };
+        let message = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        (message.to_str().unwrap().to_owned(), full)
+    }
+
+    #[test]
+    fn round_trip() {
+        unsafe {
+            assert_eq!(manualveh_init(), MANUALVEH_OK);
+
+            let mut info = ManualVehInfo::default();
+            assert_eq!(manualveh_resolution_info(&mut info), MANUALVEH_OK);
+            assert_eq!(info.ntdll_base, modules::find("ntdll.dll").unwrap().base());
+            assert_ne!(info.add_handler, 0);
+            assert_ne!(info.remove_handler, 0);
+
+            let mut handle = std::ptr::null_mut();
+            assert_eq!(manualveh_add(1, Some(handler), &mut handle), MANUALVEH_OK);
+            assert!(!handle.is_null());
+            RaiseException(CODE, 0, 0, std::ptr::null());
+            assert_eq!(manualveh_remove(handle), MANUALVEH_OK);
+            assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+            assert_eq!(manualveh_remove(handle), MANUALVEH_ERROR_NOT_REMOVED);
+        }
+    }
+
+    #[test]
+    fn errors_described() {
+        let added = unsafe { manualveh_add(0, None, std::ptr::null_mut()) };
+        assert_eq!(added, MANUALVEH_ERROR_INVALID_ARGUMENT);
+
+        let (message, full) = last_error(64);
+        assert_eq!(message, "the handler and handle pointer must not be null");
+        assert_eq!(full, message.len());
+        let (cut, full) = last_error(8);
+        assert_eq!(cut, "the han");
+        assert_eq!(full, message.len());
+        assert_eq!(
+            unsafe { manualveh_last_error(std::ptr::null_mut(), 0) },
+            full
+        );
+    }
+}
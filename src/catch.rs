@@ -0,0 +1,383 @@
+//! Running a closure and getting the hardware exceptions it raises back as errors.
+//!
+//! [`try_execute`] records where it called the closure from, the stack pointer and the address
+//! it continues at, in a scope linked into a per-thread list. A dispatcher callback catching one
+//! of the exceptions on a thread with a scope resumes the exception's context at the innermost
+//! scope's address and stack pointer instead, which the OS restores with `NtContinue` once the
+//! handlers have returned. Unlike calling `NtContinue` from the handler, this lets the dispatcher
+//! and the OS's dispatch frames return as usual.
+//!
+//! [`panic_on_exception`] turns exceptions into panics instead. A panic can't unwind out of the
+//! handler, so the faulting thread is resumed at a shim that pushes the faulting address as its
+//! return address, making it look called from there, and then panics.
+
+// Imports
+use crate::dispatch::{self, CallbackGuard, Registration};
+use crate::mem;
+use crate::{
+    ContextExt, ExceptionCode, ExceptionInfo, ExceptionSnapshot, Filter, Handling, VehError,
+};
+use std::arch::asm;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const CAUGHT_CODES: [ExceptionCode; 3] = [
+    ExceptionCode::AccessViolation,
+    ExceptionCode::IllegalInstruction,
+    ExceptionCode::IntegerDivideByZero,
+];
+
+// Calls to `try_execute` in progress on any thread, and the callback catching for them
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static CALLBACK: Mutex<Option<CallbackGuard>> = Mutex::new(None);
+
+thread_local! {
+    // The innermost scope on this thread, or null
+    static CURRENT: Cell<*mut Scope> = const { Cell::new(std::ptr::null_mut()) };
+    // The exception the shim is about to panic with
+    static STAGED: Cell<Option<ExceptionSnapshot>> = const { Cell::new(None) };
+}
+
+// The layout the asm below writes `sp` and `landing` with
+#[repr(C)]
+struct Scope {
+    sp: usize,
+    landing: usize,
+    outer: *mut Scope,
+    caught: Option<ExceptionSnapshot>,
+}
+
+/// A hardware exception caught by [`try_execute`].
+#[derive(Debug, Clone)]
+pub struct CaughtException {
+    // Boxed once back from the handler, as it holds a whole context
+    snapshot: Box<ExceptionSnapshot>,
+}
+
+impl CaughtException {
+    pub fn code(&self) -> ExceptionCode {
+        self.snapshot.code()
+    }
+
+    /// The address the exception was raised at.
+    pub fn address(&self) -> usize {
+        self.snapshot.address()
+    }
+
+    /// Everything else about the exception, as it was raised.
+    pub fn snapshot(&self) -> &ExceptionSnapshot {
+        &self.snapshot
+    }
+}
+
+impl fmt::Display for CaughtException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} at {:#x}", self.code(), self.address())
+    }
+}
+
+impl std::error::Error for CaughtException {}
+
+// Keeps the callback registered while it's alive
+struct Active;
+
+impl Active {
+    fn enter() -> Result<Self, VehError> {
+        let mut callback = CALLBACK.lock().unwrap_or_else(|e| e.into_inner());
+        if callback.is_none() {
+            let filter = Filter::codes(CAUGHT_CODES);
+            let registration = Registration::new().priority(i32::MIN).filter(filter);
+            *callback = Some(registration.register(caught)?);
+        }
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        Ok(Active)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        let mut callback = CALLBACK.lock().unwrap_or_else(|e| e.into_inner());
+        if ACTIVE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            callback.take();
+        }
+    }
+}
+
+/// Runs `f` on the current thread, returning the access violation, illegal instruction or
+/// integer division by zero it raised as an error instead.
+///
+/// When `f` raises one, execution jumps straight back here, much like `longjmp`: its frames are
+/// abandoned without dropping anything they own, releasing locks they hold, or running any other
+/// code in them. Calls can be nested, and the innermost one catches. Only exceptions raised on the
+/// calling thread are caught, and panics in `f` keep unwinding as usual.
+///
+/// # Panics
+/// Panics if the callback catching the exceptions couldn't be registered with the dispatcher.
+pub fn try_execute<R>(f: impl FnOnce() -> R) -> Result<R, CaughtException> {
+    let _active = Active::enter().expect("failed to register the try_execute callback");
+
+    let mut result = None;
+    let mut body = Some(|| result = Some(panic::catch_unwind(AssertUnwindSafe(f))));
+    let mut scope = Scope {
+        sp: 0,
+        landing: 0,
+        outer: CURRENT.with(Cell::get),
+        caught: None,
+    };
+    let scope: *mut Scope = &mut scope;
+    CURRENT.with(|current| current.set(scope));
+    unsafe { enter(scope, &mut body) };
+    CURRENT.with(|current| current.set(unsafe { (*scope).outer }));
+    drop(body);
+
+    match unsafe { (*scope).caught.take() } {
+        Some(snapshot) => Err(CaughtException {
+            snapshot: Box::new(snapshot),
+        }),
+        None => match result.unwrap() {
+            Ok(value) => Ok(value),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+extern "C" fn run<G: FnOnce()>(body: *mut c_void) {
+    let body = unsafe { &mut *(body as *mut Option<G>) };
+    body.take().unwrap()()
+}
+
+// Records the stack pointer and resume address in `scope`, and calls `body`. Non-volatile
+// registers are saved on the stack, as resuming leaves the ones the closure had.
+#[cfg(target_arch = "x86_64")]
+unsafe fn enter<G: FnOnce()>(scope: *mut Scope, body: &mut Option<G>) {
+    asm!(
+        "push rbx",
+        "push rbp",
+        "push rsi",
+        "push rdi",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdx], rsp",
+        "lea r8, [rip + 2f]",
+        "mov [rdx + 8], r8",
+        "sub rsp, 0x20",
+        "call rax",
+        "add rsp, 0x20",
+        "2:",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rdi",
+        "pop rsi",
+        "pop rbp",
+        "pop rbx",
+        in("rax") run::<G> as extern "C" fn(*mut c_void),
+        in("rcx") body as *mut Option<G>,
+        in("rdx") scope,
+        out("xmm6") _,
+        out("xmm7") _,
+        out("xmm8") _,
+        out("xmm9") _,
+        out("xmm10") _,
+        out("xmm11") _,
+        out("xmm12") _,
+        out("xmm13") _,
+        out("xmm14") _,
+        out("xmm15") _,
+        clobber_abi("C"),
+    )
+}
+
+// On x86, the head of the thread's SEH chain at `fs:[0]` is saved as well, as the frames resuming
+// abandons leave their records linked into it
+#[cfg(target_arch = "x86")]
+unsafe fn enter<G: FnOnce()>(scope: *mut Scope, body: &mut Option<G>) {
+    asm!(
+        "push ebx",
+        "push ebp",
+        "push esi",
+        "push edi",
+        "push dword ptr fs:[0]",
+        "mov [edx], esp",
+        "lea esi, [2f]",
+        "mov [edx + 4], esi",
+        "push ecx",
+        "call eax",
+        "add esp, 4",
+        "2:",
+        "pop dword ptr fs:[0]",
+        "pop edi",
+        "pop esi",
+        "pop ebp",
+        "pop ebx",
+        in("eax") run::<G> as extern "C" fn(*mut c_void),
+        in("ecx") body as *mut Option<G>,
+        in("edx") scope,
+        clobber_abi("C"),
+    )
+}
+
+// Resumes the innermost scope on this thread, if the exception was raised inside it
+pub(crate) fn caught(info: &mut ExceptionInfo) -> Handling {
+    let scope = match unsafe { CURRENT.with(Cell::get).as_mut() } {
+        Some(scope) => scope,
+        None => return Handling::ContinueSearch,
+    };
+
+    // Only what ran below the scope's frame is caught, and faults `mem` is about to handle aren't
+    if info.context().sp() >= scope.sp || mem::copy_resume(info.context()).is_some() {
+        return Handling::ContinueSearch;
+    }
+    scope.caught = Some(info.snapshot());
+
+    let context = info.context_mut();
+    context.set_ip(scope.landing);
+    context.set_sp(scope.sp);
+    Handling::ContinueExecution
+}
+
+/// Keeps [`panic_on_exception`] turning exceptions into panics until dropped.
+pub struct PanicOnExceptionGuard {
+    _callback: CallbackGuard,
+}
+
+/// Turns the exceptions with these codes, raised on any thread, into panics with a
+/// [`CaughtException`] payload, which `catch_unwind` can catch and downcast.
+///
+/// **This is best-effort, and meant for test harnesses and fuzzers.** The panic unwinds from the
+/// instruction that faulted, which the code around it never expects: destructors run on state
+/// that may be half updated, code compiled as unable to unwind may abort the process instead, and
+/// whatever caused the fault may well have corrupted more than it shows. On x64, faults raised
+/// with a misaligned stack pointer, as in some leaf functions, are passed on rather than turned
+/// into panics. Panics aborting (`panic = "abort"`) also abort here.
+///
+/// # Panics
+/// Panics if the callback couldn't be registered with the dispatcher.
+pub fn panic_on_exception(codes: &[ExceptionCode]) -> PanicOnExceptionGuard {
+    let filter = Filter::codes(codes.iter().copied());
+    let callback = dispatch::register_closure(filter, redirect)
+        .expect("failed to register the panic_on_exception callback");
+    PanicOnExceptionGuard {
+        _callback: callback,
+    }
+}
+
+fn redirect(info: &mut ExceptionInfo) -> Handling {
+    // Faults `mem` is about to handle don't panic either
+    if !info.is_continuable() || mem::copy_resume(info.context()).is_some() {
+        return Handling::ContinueSearch;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if info.context().sp() & 0xF != 0 {
+        return Handling::ContinueSearch;
+    }
+
+    // Allocating is left to the shim, as the fault may have happened inside the allocator
+    STAGED.with(|staged| staged.set(Some(info.snapshot())));
+    let context = info.context_mut();
+    #[cfg(target_arch = "x86_64")]
+    {
+        context.rcx = context.rip;
+    }
+    #[cfg(target_arch = "x86")]
+    {
+        context.ecx = context.eip;
+    }
+    context.set_ip(push_return_address as unsafe extern "C" fn() -> ! as usize);
+    Handling::ContinueExecution
+}
+
+// Entered with the faulting address in the count register and the stack as it was at the fault,
+// which the OS has left by then, so it can be pushed to
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn push_return_address() -> ! {
+    std::arch::naked_asm!("push rcx", "jmp {raise}", raise = sym raise)
+}
+
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+unsafe extern "C" fn push_return_address() -> ! {
+    std::arch::naked_asm!("push ecx", "jmp {raise}", raise = sym raise)
+}
+
+extern "C-unwind" fn raise() -> ! {
+    let snapshot = STAGED.with(|staged| staged.take()).unwrap();
+    panic::panic_any(CaughtException {
+        snapshot: Box::new(snapshot),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{panic_on_exception, try_execute, CaughtException};
+    use crate::{dispatch, ExceptionCode};
+
+    #[test]
+    fn null_pointer() {
+        let _serial = dispatch::tests::serial();
+
+        let caught = try_execute(|| unsafe { std::ptr::read_volatile(std::ptr::null::<u32>()) });
+        let caught = caught.unwrap_err();
+        assert_eq!(caught.code(), ExceptionCode::AccessViolation);
+        assert_eq!(caught.snapshot().parameters()[1], 0);
+
+        assert_eq!(try_execute(|| 42).unwrap(), 42);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn nested() {
+        let _serial = dispatch::tests::serial();
+
+        let outer = try_execute(|| {
+            let inner = try_execute(|| unsafe { std::arch::asm!("ud2") });
+            assert_eq!(inner.unwrap_err().code(), ExceptionCode::IllegalInstruction);
+            7
+        });
+        assert_eq!(outer.unwrap(), 7);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[cfg(target_arch = "x86")]
+    #[test]
+    fn seh_chain_restored() {
+        let _serial = dispatch::tests::serial();
+        let head = || {
+            let head: usize;
+            unsafe { std::arch::asm!("mov {}, fs:[0]", out(reg) head) };
+            head
+        };
+
+        // The closure runs inside `catch_unwind`, whose record is abandoned along with it
+        let before = head();
+        let caught = try_execute(|| unsafe { std::ptr::read_volatile(std::ptr::null::<u32>()) });
+        assert!(caught.is_err());
+        assert_eq!(head(), before);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn panics() {
+        let _serial = dispatch::tests::serial();
+
+        let guard = panic_on_exception(&[ExceptionCode::AccessViolation]);
+        let payload =
+            std::panic::catch_unwind(|| unsafe { std::ptr::read_volatile(0x10 as *const u32) });
+        drop(guard);
+
+        let caught = payload.unwrap_err().downcast::<CaughtException>().unwrap();
+        assert_eq!(caught.code(), ExceptionCode::AccessViolation);
+        assert_eq!(caught.snapshot().parameters()[1], 0x10);
+        assert_ne!(caught.address(), 0);
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,747 @@
+// The crate's own definition of the Windows `CONTEXT` for each architecture, and a common way of
+// accessing the registers handlers most often need.
+
+// Imports
+use std::fmt;
+
+/// Why [`ContextExt::emulate_return`] couldn't resume at the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EmulateError {
+    /// The stack pointer doesn't point to readable memory.
+    UnreadableStack,
+    /// The thread uses a hardware shadow stack (Intel CET), which would treat resuming at the
+    /// return address as a control-flow violation, since it was never actually returned to.
+    ShadowStackActive,
+}
+
+impl fmt::Display for EmulateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulateError::UnreadableStack => f.write_str("the stack pointer is not readable"),
+            EmulateError::ShadowStackActive => f.write_str("a shadow stack is active"),
+        }
+    }
+}
+
+impl std::error::Error for EmulateError {}
+
+/// Why [`ContextExt::skip_instruction`] couldn't skip the current instruction.
+#[cfg(feature = "iced")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SkipError {
+    /// The instruction pointer doesn't point to readable memory.
+    UnreadableInstruction,
+    /// The bytes at the instruction pointer aren't a valid instruction.
+    InvalidInstruction,
+}
+
+#[cfg(feature = "iced")]
+impl fmt::Display for SkipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipError::UnreadableInstruction => {
+                f.write_str("the instruction pointer is not readable")
+            }
+            SkipError::InvalidInstruction => f.write_str("no valid instruction to skip"),
+        }
+    }
+}
+
+#[cfg(feature = "iced")]
+impl std::error::Error for SkipError {}
+
+/// Architecture-independent access to the registers handlers most often need to read or change
+/// before resuming execution.
+pub trait ContextExt {
+    /// The instruction pointer: `Eip`, `Rip` or `Pc`.
+    fn ip(&self) -> usize;
+    fn set_ip(&mut self, ip: usize);
+    /// The stack pointer: `Esp`, `Rsp` or `Sp`.
+    fn sp(&self) -> usize;
+    fn set_sp(&mut self, sp: usize);
+    /// The frame pointer: `Ebp`, `Rbp` or `Fp`.
+    fn frame_pointer(&self) -> usize;
+    /// The register integer return values are passed in: `Eax`, `Rax` or `X0`.
+    fn return_register(&self) -> usize;
+    /// The link register, `Lr`.
+    #[cfg(target_arch = "aarch64")]
+    fn link_register(&self) -> usize;
+    /// The flags register: `EFlags`, or `Cpsr` (the saved PSTATE) on ARM64.
+    fn flags(&self) -> u32;
+    fn set_flags(&mut self, flags: u32);
+    /// A copy as the crate's own [`CONTEXT`], which every supported context type shares its
+    /// layout with, for the registers without an accessor here.
+    fn to_native(&self) -> CONTEXT;
+
+    /// Whether the thread single-steps once resumed.
+    fn trap_flag(&self) -> bool {
+        self.flags() & TRAP_FLAG != 0
+    }
+
+    /// Makes the thread raise a single-step exception after executing one instruction, using
+    /// `EFlags.TF` (bit 8), or `PSTATE.SS` (bit 21) on ARM64.
+    ///
+    /// On ARM64, software stepping also needs `MDSCR_EL1.SS` set, which only the kernel can
+    /// do, so this is best-effort there.
+    fn set_trap_flag(&mut self, enabled: bool) {
+        self.set_flags(with_bit(self.flags(), TRAP_FLAG, enabled));
+    }
+
+    /// Whether the instruction at the instruction pointer ignores hardware breakpoints when
+    /// resumed, see [`ContextExt::set_resume_flag`].
+    fn resume_flag(&self) -> bool {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        return self.flags() & RESUME_FLAG != 0;
+        // ARM64 has no such flag
+        #[cfg(target_arch = "aarch64")]
+        false
+    }
+
+    /// Makes the instruction at the instruction pointer run without triggering a hardware
+    /// execution breakpoint on it again, using `EFlags.RF` (bit 16). The processor clears the
+    /// flag once the instruction completes.
+    ///
+    /// ARM64 has no equivalent, so this does nothing there.
+    fn set_resume_flag(&mut self, enabled: bool) {
+        self.set_flags(with_bit(self.flags(), RESUME_FLAG, enabled));
+    }
+
+    /// Resumes as if the faulting function had returned right away, such as after calling a bad
+    /// function pointer.
+    ///
+    /// On x86 and x64 this pops the return address off the stack into the instruction pointer,
+    /// on ARM64 it resumes at the link register. Nothing is changed if it fails.
+    ///
+    /// The shadow stack isn't adjusted, so this fails with [`EmulateError::ShadowStackActive`]
+    /// in threads that have one, rather than resuming into a control-flow violation.
+    fn emulate_return(&mut self) -> Result<(), EmulateError> {
+        if shadow_stack_active() {
+            return Err(EmulateError::ShadowStackActive);
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            let sp = self.sp();
+            if !crate::memory::is_readable(sp, std::mem::size_of::<usize>()) {
+                return Err(EmulateError::UnreadableStack);
+            }
+
+            self.set_ip(unsafe { *(sp as *const usize) });
+            self.set_sp(sp + std::mem::size_of::<usize>());
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        self.set_ip(self.link_register());
+
+        Ok(())
+    }
+
+    /// Moves the instruction pointer `n` bytes forward, such as past an instruction whose length
+    /// is already known.
+    fn skip_bytes(&mut self, n: usize) {
+        self.set_ip(self.ip().wrapping_add(n));
+    }
+
+    /// Decodes the instruction at the instruction pointer and moves past it, returning its
+    /// length. The instruction is only read if the memory it's in is readable.
+    #[cfg(feature = "iced")]
+    fn skip_instruction(&mut self) -> Result<usize, SkipError> {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let len = {
+            use iced_x86::{Decoder, DecoderOptions};
+
+            // Instructions are at most 15 bytes long, but might sit right before an unreadable page
+            let ip = self.ip();
+            let readable = (1..=15)
+                .rev()
+                .find(|&len| crate::memory::is_readable(ip, len))
+                .ok_or(SkipError::UnreadableInstruction)?;
+
+            let bytes = unsafe { std::slice::from_raw_parts(ip as *const u8, readable) };
+            let bitness = std::mem::size_of::<usize>() as u32 * 8;
+            let instruction =
+                Decoder::with_ip(bitness, bytes, ip as u64, DecoderOptions::NONE).decode();
+            if instruction.is_invalid() {
+                return Err(SkipError::InvalidInstruction);
+            }
+            instruction.len()
+        };
+
+        // Every ARM64 instruction is 4 bytes long
+        #[cfg(target_arch = "aarch64")]
+        let len = 4;
+
+        self.skip_bytes(len);
+        Ok(len)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const TRAP_FLAG: u32 = 1 << 8;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const RESUME_FLAG: u32 = 1 << 16;
+#[cfg(target_arch = "aarch64")]
+const TRAP_FLAG: u32 = 1 << 21;
+#[cfg(target_arch = "aarch64")]
+const RESUME_FLAG: u32 = 0;
+
+fn with_bit(flags: u32, bit: u32, enabled: bool) -> u32 {
+    match enabled {
+        true => flags | bit,
+        false => flags & !bit,
+    }
+}
+
+// `rdssp` is a no-op unless the thread has a shadow stack, leaving the register at zero
+#[cfg(target_arch = "x86")]
+fn shadow_stack_active() -> bool {
+    let mut ssp: usize = 0;
+    // rdsspd eax
+    unsafe {
+        std::arch::asm!(".byte 0xf3, 0x0f, 0x1e, 0xc8", inout("eax") ssp, options(nomem, nostack))
+    };
+    ssp != 0
+}
+
+#[cfg(target_arch = "x86_64")]
+fn shadow_stack_active() -> bool {
+    let mut ssp: usize = 0;
+    // rdsspq rax
+    unsafe {
+        std::arch::asm!(".byte 0xf3, 0x48, 0x0f, 0x1e, 0xc8", inout("rax") ssp, options(nomem, nostack))
+    };
+    ssp != 0
+}
+
+// Windows doesn't support shadow stacks on ARM64
+#[cfg(target_arch = "aarch64")]
+fn shadow_stack_active() -> bool {
+    false
+}
+
+/// The crate's own definition of the x86 `CONTEXT`.
+#[cfg(target_arch = "x86")]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CONTEXT {
+    pub context_flags: u32,
+    pub dr0: u32,
+    pub dr1: u32,
+    pub dr2: u32,
+    pub dr3: u32,
+    pub dr6: u32,
+    pub dr7: u32,
+    /// `FLOATING_SAVE_AREA`
+    pub float_save: [u8; 112],
+    pub seg_gs: u32,
+    pub seg_fs: u32,
+    pub seg_es: u32,
+    pub seg_ds: u32,
+    pub edi: u32,
+    pub esi: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub ebp: u32,
+    pub eip: u32,
+    pub seg_cs: u32,
+    pub eflags: u32,
+    pub esp: u32,
+    pub seg_ss: u32,
+    pub extended_registers: [u8; 512],
+}
+
+/// A 128-bit vector register.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, align(16))]
+pub struct M128A {
+    pub low: u64,
+    pub high: i64,
+}
+
+/// The crate's own definition of the x64 `CONTEXT`.
+#[cfg(target_arch = "x86_64")]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+pub struct CONTEXT {
+    pub p1_home: u64,
+    pub p2_home: u64,
+    pub p3_home: u64,
+    pub p4_home: u64,
+    pub p5_home: u64,
+    pub p6_home: u64,
+    pub context_flags: u32,
+    pub mx_csr: u32,
+    pub seg_cs: u16,
+    pub seg_ds: u16,
+    pub seg_es: u16,
+    pub seg_fs: u16,
+    pub seg_gs: u16,
+    pub seg_ss: u16,
+    pub eflags: u32,
+    pub dr0: u64,
+    pub dr1: u64,
+    pub dr2: u64,
+    pub dr3: u64,
+    pub dr6: u64,
+    pub dr7: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    /// `XMM_SAVE_AREA32`
+    pub flt_save: [u8; 512],
+    pub vector_register: [M128A; 26],
+    pub vector_control: u64,
+    pub debug_control: u64,
+    pub last_branch_to_rip: u64,
+    pub last_branch_from_rip: u64,
+    pub last_exception_to_rip: u64,
+    pub last_exception_from_rip: u64,
+}
+
+/// The crate's own definition of the ARM64 `CONTEXT`.
+#[cfg(target_arch = "aarch64")]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+pub struct CONTEXT {
+    pub context_flags: u32,
+    pub cpsr: u32,
+    /// `X0` to `X28`, followed by `Fp` (`X29`) and `Lr` (`X30`).
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    /// `V0` to `V31`, as `[low, high]` pairs.
+    pub v: [[u64; 2]; 32],
+    pub fpcr: u32,
+    pub fpsr: u32,
+    pub bcr: [u32; 8],
+    pub bvr: [u64; 8],
+    pub wcr: [u32; 2],
+    pub wvr: [u64; 2],
+}
+
+// x86 and x64 contexts only differ in the names of their registers
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+macro_rules! context_ext {
+    ($ty:ty, $ip:ident, $sp:ident, $fp:ident, $ret:ident, $flags:ident) => {
+        impl ContextExt for $ty {
+            fn ip(&self) -> usize {
+                self.$ip as usize
+            }
+
+            fn set_ip(&mut self, ip: usize) {
+                self.$ip = ip as _;
+            }
+
+            fn sp(&self) -> usize {
+                self.$sp as usize
+            }
+
+            fn set_sp(&mut self, sp: usize) {
+                self.$sp = sp as _;
+            }
+
+            fn frame_pointer(&self) -> usize {
+                self.$fp as usize
+            }
+
+            fn return_register(&self) -> usize {
+                self.$ret as usize
+            }
+
+            fn flags(&self) -> u32 {
+                self.$flags
+            }
+
+            fn set_flags(&mut self, flags: u32) {
+                self.$flags = flags;
+            }
+
+            fn to_native(&self) -> CONTEXT {
+                const _: () = assert!(std::mem::size_of::<$ty>() == std::mem::size_of::<CONTEXT>());
+                // The bindings' types can be less aligned than the crate's own
+                unsafe { std::ptr::read_unaligned(self as *const $ty as *const CONTEXT) }
+            }
+        }
+    };
+}
+
+#[cfg(target_arch = "x86")]
+context_ext!(CONTEXT, eip, esp, ebp, eax, eflags);
+#[cfg(all(target_arch = "x86", feature = "impl-winapi"))]
+context_ext!(winapi::um::winnt::CONTEXT, Eip, Esp, Ebp, Eax, EFlags);
+#[cfg(all(target_arch = "x86", feature = "impl-windows"))]
+context_ext!(
+    windows_sys::Win32::System::Diagnostics::Debug::CONTEXT,
+    Eip,
+    Esp,
+    Ebp,
+    Eax,
+    EFlags
+);
+
+#[cfg(target_arch = "x86_64")]
+context_ext!(CONTEXT, rip, rsp, rbp, rax, eflags);
+#[cfg(all(target_arch = "x86_64", feature = "impl-winapi"))]
+context_ext!(winapi::um::winnt::CONTEXT, Rip, Rsp, Rbp, Rax, EFlags);
+#[cfg(all(target_arch = "x86_64", feature = "impl-windows"))]
+context_ext!(
+    windows_sys::Win32::System::Diagnostics::Debug::CONTEXT,
+    Rip,
+    Rsp,
+    Rbp,
+    Rax,
+    EFlags
+);
+
+#[cfg(target_arch = "aarch64")]
+impl ContextExt for CONTEXT {
+    fn ip(&self) -> usize {
+        self.pc as usize
+    }
+
+    fn set_ip(&mut self, ip: usize) {
+        self.pc = ip as u64;
+    }
+
+    fn sp(&self) -> usize {
+        self.sp as usize
+    }
+
+    fn set_sp(&mut self, sp: usize) {
+        self.sp = sp as u64;
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.x[29] as usize
+    }
+
+    fn return_register(&self) -> usize {
+        self.x[0] as usize
+    }
+
+    fn link_register(&self) -> usize {
+        self.x[30] as usize
+    }
+
+    fn flags(&self) -> u32 {
+        self.cpsr
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.cpsr = flags;
+    }
+
+    fn to_native(&self) -> CONTEXT {
+        *self
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", feature = "impl-winapi"))]
+impl ContextExt for winapi::um::winnt::CONTEXT {
+    fn ip(&self) -> usize {
+        self.Pc as usize
+    }
+
+    fn set_ip(&mut self, ip: usize) {
+        self.Pc = ip as u64;
+    }
+
+    fn sp(&self) -> usize {
+        self.Sp as usize
+    }
+
+    fn set_sp(&mut self, sp: usize) {
+        self.Sp = sp as u64;
+    }
+
+    fn frame_pointer(&self) -> usize {
+        unsafe { self.u.s().Fp as usize }
+    }
+
+    fn return_register(&self) -> usize {
+        unsafe { self.u.s().X0 as usize }
+    }
+
+    fn link_register(&self) -> usize {
+        unsafe { self.u.s().Lr as usize }
+    }
+
+    fn flags(&self) -> u32 {
+        self.Cpsr
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.Cpsr = flags;
+    }
+
+    fn to_native(&self) -> CONTEXT {
+        const _: () = assert!(
+            std::mem::size_of::<winapi::um::winnt::CONTEXT>() == std::mem::size_of::<CONTEXT>()
+        );
+        unsafe { std::ptr::read_unaligned(self as *const Self as *const CONTEXT) }
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", feature = "impl-windows"))]
+impl ContextExt for windows_sys::Win32::System::Diagnostics::Debug::CONTEXT {
+    fn ip(&self) -> usize {
+        self.Pc as usize
+    }
+
+    fn set_ip(&mut self, ip: usize) {
+        self.Pc = ip as u64;
+    }
+
+    fn sp(&self) -> usize {
+        self.Sp as usize
+    }
+
+    fn set_sp(&mut self, sp: usize) {
+        self.Sp = sp as u64;
+    }
+
+    fn frame_pointer(&self) -> usize {
+        unsafe { self.Anonymous.Anonymous.Fp as usize }
+    }
+
+    fn return_register(&self) -> usize {
+        unsafe { self.Anonymous.Anonymous.X0 as usize }
+    }
+
+    fn link_register(&self) -> usize {
+        unsafe { self.Anonymous.Anonymous.Lr as usize }
+    }
+
+    fn flags(&self) -> u32 {
+        self.Cpsr
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.Cpsr = flags;
+    }
+
+    fn to_native(&self) -> CONTEXT {
+        const _: () = assert!(
+            std::mem::size_of::<windows_sys::Win32::System::Diagnostics::Debug::CONTEXT>()
+                == std::mem::size_of::<CONTEXT>()
+        );
+        unsafe { std::ptr::read_unaligned(self as *const Self as *const CONTEXT) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContextExt, CONTEXT};
+
+    fn zeroed<T>() -> T {
+        unsafe { std::mem::zeroed() }
+    }
+
+    // Sets the instruction and stack pointers through the trait, returning the values used
+    fn set_all(context: &mut impl ContextExt) -> (usize, usize) {
+        context.set_ip(0x1234);
+        context.set_sp(0x5678);
+        assert_eq!(context.ip(), 0x1234);
+        assert_eq!(context.sp(), 0x5678);
+        (0x1234, 0x5678)
+    }
+
+    #[test]
+    fn layout() {
+        #[cfg(target_arch = "x86")]
+        assert_eq!(std::mem::size_of::<CONTEXT>(), 716);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(std::mem::size_of::<CONTEXT>(), 1232);
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(std::mem::size_of::<CONTEXT>(), 912);
+    }
+
+    #[test]
+    fn native_fields() {
+        let mut context = zeroed::<CONTEXT>();
+        let (ip, sp) = set_all(&mut context);
+
+        #[cfg(target_arch = "x86")]
+        {
+            assert_eq!((context.eip, context.esp), (ip as u32, sp as u32));
+            context.ebp = 1;
+            context.eax = 2;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!((context.rip, context.rsp), (ip as u64, sp as u64));
+            context.rbp = 1;
+            context.rax = 2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!((context.pc, context.sp), (ip as u64, sp as u64));
+            context.x[29] = 1;
+            context.x[0] = 2;
+        }
+
+        assert_eq!(context.frame_pointer(), 1);
+        assert_eq!(context.return_register(), 2);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn emulate_return() {
+        use super::EmulateError;
+
+        let stack = [0x1234usize, 0];
+        let mut context = zeroed::<CONTEXT>();
+
+        context.set_sp(stack.as_ptr() as usize);
+        assert_eq!(context.emulate_return(), Ok(()));
+        assert_eq!(context.ip(), 0x1234);
+        assert_eq!(
+            context.sp(),
+            stack.as_ptr() as usize + std::mem::size_of::<usize>()
+        );
+
+        context.set_sp(0);
+        assert_eq!(context.emulate_return(), Err(EmulateError::UnreadableStack));
+        assert_eq!(context.ip(), 0x1234);
+    }
+
+    #[test]
+    fn trap_and_resume_flags() {
+        let mut context = zeroed::<CONTEXT>();
+
+        context.set_trap_flag(true);
+        assert!(context.trap_flag());
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert_eq!(context.eflags, 1 << 8);
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(context.cpsr, 1 << 21);
+
+        context.set_resume_flag(true);
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            assert!(context.resume_flag());
+            assert_eq!(context.eflags, 1 << 8 | 1 << 16);
+        }
+
+        // Clearing leaves every other bit alone
+        context.set_flags(u32::MAX);
+        context.set_trap_flag(false);
+        context.set_resume_flag(false);
+        assert!(!context.trap_flag());
+        assert!(!context.resume_flag());
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert_eq!(context.eflags, !(1 << 8 | 1 << 16));
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(context.cpsr, !(1 << 21));
+    }
+
+    #[test]
+    fn skip_bytes() {
+        let mut context = zeroed::<CONTEXT>();
+        context.set_ip(0x1000);
+        context.skip_bytes(3);
+        assert_eq!(context.ip(), 0x1003);
+    }
+
+    #[cfg(all(feature = "iced", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[test]
+    fn skip_planted_ud2() {
+        use crate::dispatch::{self, Registration};
+        use crate::{ExceptionCode, Filter, Handling};
+
+        let _serial = dispatch::tests::serial();
+
+        let filter = Filter::code(ExceptionCode::IllegalInstruction).and(Filter::current_thread());
+        let _skip = Registration::new()
+            .filter(filter)
+            .register(|info| match info.context_mut().skip_instruction() {
+                Ok(2) => Handling::ContinueExecution,
+                _ => Handling::ContinueSearch,
+            })
+            .unwrap();
+
+        let mut after_ud2: usize = 0;
+        unsafe { std::arch::asm!("ud2", "mov {0}, 1", inout(reg) after_ud2) };
+        assert_eq!(after_ud2, 1);
+    }
+
+    #[cfg(feature = "impl-winapi")]
+    #[test]
+    fn winapi_fields() {
+        let mut context = zeroed::<winapi::um::winnt::CONTEXT>();
+        let (ip, sp) = set_all(&mut context);
+
+        #[cfg(target_arch = "x86")]
+        {
+            assert_eq!((context.Eip, context.Esp), (ip as u32, sp as u32));
+            context.Ebp = 1;
+            context.Eax = 2;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!((context.Rip, context.Rsp), (ip as u64, sp as u64));
+            context.Rbp = 1;
+            context.Rax = 2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            assert_eq!((context.Pc, context.Sp), (ip as u64, sp as u64));
+            context.u.s_mut().Fp = 1;
+            context.u.s_mut().X0 = 2;
+        }
+
+        assert_eq!(context.frame_pointer(), 1);
+        assert_eq!(context.return_register(), 2);
+    }
+
+    #[cfg(feature = "impl-windows")]
+    #[test]
+    fn windows_sys_fields() {
+        let mut context = zeroed::<windows_sys::Win32::System::Diagnostics::Debug::CONTEXT>();
+        let (ip, sp) = set_all(&mut context);
+
+        #[cfg(target_arch = "x86")]
+        {
+            assert_eq!((context.Eip, context.Esp), (ip as u32, sp as u32));
+            context.Ebp = 1;
+            context.Eax = 2;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!((context.Rip, context.Rsp), (ip as u64, sp as u64));
+            context.Rbp = 1;
+            context.Rax = 2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!((context.Pc, context.Sp), (ip as u64, sp as u64));
+            context.Anonymous.Anonymous.Fp = 1;
+            context.Anonymous.Anonymous.X0 = 2;
+        }
+
+        assert_eq!(context.frame_pointer(), 1);
+        assert_eq!(context.return_register(), 2);
+    }
+}
@@ -0,0 +1,33 @@
+// Imports
+use crate::{ExceptionCode, ExceptionInfo, Handling};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Called when a callback tried to continue execution after an exception that can't be
+/// continued, with that exception's code.
+pub type InvalidContinueHook = fn(code: ExceptionCode);
+
+static ON_INVALID_CONTINUE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets a hook to call whenever the crate's handlers ignore a callback's `ContinueExecution`
+/// because the exception is non-continuable, or removes it.
+///
+/// Continuing such an exception makes the OS raise `STATUS_NONCONTINUABLE_EXCEPTION` in its place,
+/// which is harder to make sense of than the original, so the dispatcher and closure handlers
+/// continue the search instead.
+pub fn set_on_invalid_continue(hook: Option<InvalidContinueHook>) {
+    ON_INVALID_CONTINUE.store(hook.map_or(0, |hook| hook as usize), Ordering::Relaxed);
+}
+
+/// Downgrades `handling` to `ContinueSearch` if `info` can't be continued.
+pub(crate) fn enforce(info: &ExceptionInfo, handling: Handling) -> Handling {
+    if handling != Handling::ContinueExecution || info.is_continuable() {
+        return handling;
+    }
+
+    let hook = ON_INVALID_CONTINUE.load(Ordering::Relaxed);
+    if hook != 0 {
+        let hook = unsafe { std::mem::transmute::<usize, InvalidContinueHook>(hook) };
+        hook(info.code());
+    }
+    Handling::ContinueSearch
+}
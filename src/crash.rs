@@ -0,0 +1,574 @@
+//! Writing a minidump when the process is about to crash.
+//!
+//! [`write_minidump_on_fatal`] registers a handler at the end of the handler list, and starts a
+//! thread waiting for it. When a fatal-looking exception gets that far, the faulting thread hands
+//! its `EXCEPTION_POINTERS` to the waiting thread and blocks until the dump is written, so writing
+//! it never runs on a stack that may have just overflowed. The exception is then passed on, so
+//! WER and any other handlers still see it.
+//!
+//! [`set_fatal_handler`] instead runs a callback on the faulting thread, switched to a stack of
+//! its own, which decides how the process ends.
+
+// Imports
+use crate::{
+    modules, teb, ExceptionCode, ExceptionInfo, ExceptionSnapshot, Handling, VehBuilder, VehError,
+    VehVoid,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::cell::UnsafeCell;
+use std::ffi::c_void;
+use std::fmt;
+use std::io;
+use std::ops::BitOr;
+use std::os::windows::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Architecture-specific imports
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::GetProcAddress, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::GetProcAddress, PeView};
+
+const GENERIC_WRITE: u32 = 0x4000_0000;
+const CREATE_ALWAYS: u32 = 2;
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+const INVALID_HANDLE_VALUE: *mut c_void = -1isize as _;
+
+// How long a crashing thread waits for its dump
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FATAL_CODES: [ExceptionCode; 6] = [
+    ExceptionCode::AccessViolation,
+    ExceptionCode::IllegalInstruction,
+    ExceptionCode::PrivilegedInstruction,
+    ExceptionCode::StackOverflow,
+    ExceptionCode::InPageError,
+    ExceptionCode::IntegerDivideByZero,
+];
+
+type FnMiniDumpWriteDump = unsafe extern "system" fn(
+    process: *mut c_void,
+    process_id: u32,
+    file: *mut c_void,
+    dump_type: u32,
+    exception: *const MinidumpExceptionInformation,
+    user_streams: *const c_void,
+    callback: *const c_void,
+) -> i32;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentProcess() -> *mut c_void;
+    fn GetCurrentProcessId() -> u32;
+    fn CreateFileW(
+        name: *const u16,
+        access: u32,
+        share: u32,
+        security: *const c_void,
+        disposition: u32,
+        flags: u32,
+        template: *mut c_void,
+    ) -> *mut c_void;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+}
+
+// `MINIDUMP_EXCEPTION_INFORMATION`, which dbghelp.h declares with 4-byte packing
+#[repr(C, packed(4))]
+struct MinidumpExceptionInformation {
+    thread_id: u32,
+    exception_pointers: *mut c_void,
+    client_pointers: i32,
+}
+
+/// What a minidump includes, as `MINIDUMP_TYPE` flags combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MinidumpType(u32);
+
+impl MinidumpType {
+    /// Thread stacks and the loaded modules only.
+    pub const NORMAL: Self = MinidumpType(0);
+    pub const WITH_DATA_SEGS: Self = MinidumpType(0x1);
+    pub const WITH_FULL_MEMORY: Self = MinidumpType(0x2);
+    pub const WITH_HANDLE_DATA: Self = MinidumpType(0x4);
+    pub const WITH_UNLOADED_MODULES: Self = MinidumpType(0x20);
+    pub const WITH_INDIRECTLY_REFERENCED_MEMORY: Self = MinidumpType(0x40);
+    pub const WITH_PROCESS_THREAD_DATA: Self = MinidumpType(0x100);
+    pub const WITH_PRIVATE_READ_WRITE_MEMORY: Self = MinidumpType(0x200);
+    pub const WITH_FULL_MEMORY_INFO: Self = MinidumpType(0x800);
+    pub const WITH_THREAD_INFO: Self = MinidumpType(0x1000);
+
+    /// Any other combination of `MINIDUMP_TYPE` flags.
+    pub const fn from_bits(bits: u32) -> Self {
+        MinidumpType(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl BitOr for MinidumpType {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        MinidumpType(self.0 | other.0)
+    }
+}
+
+/// Why [`write_minidump_on_fatal`] or `set_fatal_handler` couldn't set up the crash handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CrashError {
+    /// `MiniDumpWriteDump` couldn't be found in dbghelp, or dbghelp couldn't be loaded.
+    Dbghelp,
+    /// The thread writing dumps couldn't be started.
+    Thread(io::ErrorKind),
+    /// The handler couldn't be registered.
+    Handler(VehError),
+    /// `NtTerminateProcess` couldn't be found in ntdll.
+    Ntdll,
+}
+
+impl fmt::Display for CrashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrashError::Dbghelp => f.write_str("failed to locate MiniDumpWriteDump in dbghelp"),
+            CrashError::Thread(kind) => write!(f, "failed to start the dump thread: {kind}"),
+            CrashError::Handler(error) => write!(f, "failed to register the handler: {error}"),
+            CrashError::Ntdll => f.write_str("failed to locate NtTerminateProcess in ntdll"),
+        }
+    }
+}
+
+impl std::error::Error for CrashError {}
+
+impl From<VehError> for CrashError {
+    fn from(error: VehError) -> Self {
+        CrashError::Handler(error)
+    }
+}
+
+enum Request {
+    Idle,
+    Dump { thread_id: u32, pointers: usize },
+    // A dump was written, or failed to be, and no other will be
+    Done,
+    Stop,
+}
+
+struct Shared {
+    request: Mutex<Request>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Request> {
+        self.request.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps [`write_minidump_on_fatal`] writing a dump for the first fatal exception until dropped.
+pub struct CrashGuard {
+    handler: Option<VehVoid>,
+    shared: Arc<Shared>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Drop for CrashGuard {
+    fn drop(&mut self) {
+        self.handler.take();
+        *self.shared.lock() = Request::Stop;
+        self.shared.changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes a minidump of the process to `path_template` the first time a fatal-looking exception
+/// reaches the end of the handler list.
+///
+/// Fatal-looking are access violations, illegal and privileged instructions, stack overflows,
+/// in-page errors and integer divisions by zero, along with any exception that isn't
+/// continuable. As vectored handlers run before SEH, this includes exceptions an `__except`
+/// block further up the stack would still have caught.
+///
+/// `{pid}` in the template is replaced with the process ID, and `{timestamp}` with the seconds
+/// since the Unix epoch at the time of the crash. `MiniDumpWriteDump` is looked up in dbghelp
+/// now rather than when crashing, loading dbghelp with `LoadLibraryW` if it isn't loaded yet.
+pub fn write_minidump_on_fatal(
+    path_template: &Path,
+    dump_type: MinidumpType,
+) -> Result<CrashGuard, CrashError> {
+    let write = find_write_dump().ok_or(CrashError::Dbghelp)?;
+    let shared = Arc::new(Shared {
+        request: Mutex::new(Request::Idle),
+        changed: Condvar::new(),
+    });
+
+    let template = path_template.to_string_lossy().into_owned();
+    let waiting = shared.clone();
+    let writer = std::thread::Builder::new()
+        .name("manual-veh minidump writer".to_owned())
+        .spawn(move || wait_for_crash(&waiting, write, &template, dump_type))
+        .map_err(|error| CrashError::Thread(error.kind()))?;
+
+    let mut guard = CrashGuard {
+        handler: None,
+        shared: shared.clone(),
+        writer: Some(writer),
+    };
+    let handler = move |info: &mut ExceptionInfo| crashed(&shared, info);
+    guard.handler = Some(unsafe { VehBuilder::new().last().install_closure(handler) }?);
+    Ok(guard)
+}
+
+fn find_write_dump() -> Option<FnMiniDumpWriteDump> {
+    let dbghelp = modules::load("dbghelp.dll")?;
+
+    let module = unsafe { PeView::module(dbghelp.base() as *const u8) };
+    let write = module.get_proc_address("MiniDumpWriteDump").ok()?;
+    Some(unsafe { std::mem::transmute::<usize, FnMiniDumpWriteDump>(write as usize) })
+}
+
+fn crashed(shared: &Shared, info: &mut ExceptionInfo) -> Handling {
+    if info.is_continuable() && !FATAL_CODES.contains(&info.code()) {
+        return Handling::ContinueSearch;
+    }
+
+    let mut request = shared.lock();
+    if !matches!(*request, Request::Idle) {
+        return Handling::ContinueSearch;
+    }
+    *request = Request::Dump {
+        thread_id: teb::current_thread_id(),
+        pointers: info.as_ptr() as usize,
+    };
+    shared.changed.notify_all();
+
+    let writing = |request: &mut Request| matches!(request, Request::Dump { .. });
+    let _ = shared
+        .changed
+        .wait_timeout_while(request, WRITE_TIMEOUT, writing);
+    Handling::ContinueSearch
+}
+
+fn wait_for_crash(
+    shared: &Shared,
+    write: FnMiniDumpWriteDump,
+    template: &str,
+    dump_type: MinidumpType,
+) {
+    let idle = |request: &mut Request| matches!(request, Request::Idle);
+    let mut request = shared
+        .changed
+        .wait_while(shared.lock(), idle)
+        .unwrap_or_else(|e| e.into_inner());
+
+    if let Request::Dump {
+        thread_id,
+        pointers,
+    } = *request
+    {
+        let exception = MinidumpExceptionInformation {
+            thread_id,
+            exception_pointers: pointers as *mut c_void,
+            client_pointers: 0,
+        };
+        let _ = write_dump(write, &expand(template), dump_type, &exception);
+        *request = Request::Done;
+        shared.changed.notify_all();
+    }
+}
+
+fn expand(template: &str) -> PathBuf {
+    let pid = unsafe { GetCurrentProcessId() };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let path = template
+        .replace("{pid}", &pid.to_string())
+        .replace("{timestamp}", &timestamp.to_string());
+    PathBuf::from(path)
+}
+
+fn write_dump(
+    write: FnMiniDumpWriteDump,
+    path: &Path,
+    dump_type: MinidumpType,
+    exception: &MinidumpExceptionInformation,
+) -> bool {
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    unsafe {
+        let file = CreateFileW(
+            name.as_ptr(),
+            GENERIC_WRITE,
+            0,
+            std::ptr::null(),
+            CREATE_ALWAYS,
+            FILE_ATTRIBUTE_NORMAL,
+            std::ptr::null_mut(),
+        );
+        if file == INVALID_HANDLE_VALUE {
+            return false;
+        }
+
+        let written = write(
+            GetCurrentProcess(),
+            GetCurrentProcessId(),
+            file,
+            dump_type.bits(),
+            exception,
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+        CloseHandle(file);
+        written != 0
+    }
+}
+
+/// What [`set_fatal_handler`]'s callback decided to do about a fatal exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatalAction {
+    /// Ends the process right away with this exit code, with `NtTerminateProcess`.
+    TerminateProcess(u32),
+    /// Passes the exception on to the rest of the handlers, and then the OS.
+    ContinueSearch,
+    /// Ends the process with [`std::process::abort`].
+    Abort,
+}
+
+/// Called by [`set_fatal_handler`] with the fatal exception, on its own stack.
+pub type FatalCallback = fn(snapshot: &ExceptionSnapshot) -> FatalAction;
+
+// Enough for a snapshot, and a callback writing a dump or a message
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const FATAL_STACK_SIZE: usize = 256 * 1024;
+
+// What `GetCurrentProcess` returns
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const CURRENT_PROCESS: *mut c_void = -1isize as _;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+type FnNtTerminateProcess = unsafe extern "system" fn(process: *mut c_void, status: i32) -> i32;
+
+// The thread running the fatal callback on the alternate stack, or 0
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+static FATAL_OWNER: AtomicU32 = AtomicU32::new(0);
+
+/// Keeps [`set_fatal_handler`]'s handler registered until dropped.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct FatalGuard {
+    handler: Option<VehVoid>,
+    // Only freed once the handler is gone
+    _stack: Arc<FatalStack>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Drop for FatalGuard {
+    fn drop(&mut self) {
+        self.handler.take();
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+struct FatalStack(UnsafeCell<Box<[u8]>>);
+
+// Only ever used by the thread owning `FATAL_OWNER`
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe impl Sync for FatalStack {}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl FatalStack {
+    // The initial stack pointer, aligned for calls on every architecture
+    fn top(&self) -> usize {
+        let stack = unsafe { &mut *self.0.get() };
+        (stack.as_mut_ptr() as usize + stack.len()) & !0xF
+    }
+}
+
+/// Calls `f` when an exception with one of `codes` reaches the end of the handler list, so the
+/// process can die in a controlled way: flushing what it has queued, writing a dump, or printing
+/// a message, before ending with the [`FatalAction`] `f` returns.
+///
+/// `f` runs on the faulting thread, but on a stack set aside now, so it still has room when the
+/// exception is a stack overflow. It may still find the process in any state, such as with the
+/// heap corrupted or a lock held by the faulting code. Only one thread runs `f` at a time, others
+/// wait for it; an exception raised by `f` itself is passed on, ending the process.
+///
+/// `NtTerminateProcess` is looked up in ntdll now, and called directly, so terminating runs no
+/// other code in the process, not even DLL detach routines.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn set_fatal_handler(
+    codes: &[ExceptionCode],
+    f: FatalCallback,
+) -> Result<FatalGuard, CrashError> {
+    let terminate = find_terminate_process().ok_or(CrashError::Ntdll)?;
+    let stack = Arc::new(FatalStack(UnsafeCell::new(
+        vec![0; FATAL_STACK_SIZE].into_boxed_slice(),
+    )));
+
+    let codes = codes.to_vec();
+    let fatal_stack = stack.clone();
+    let handler = move |info: &mut ExceptionInfo| match codes.contains(&info.code()) {
+        true => fatal(info, f, terminate, &fatal_stack),
+        false => Handling::ContinueSearch,
+    };
+    let handler = unsafe { VehBuilder::new().last().install_closure(handler) }?;
+    Ok(FatalGuard {
+        handler: Some(handler),
+        _stack: stack,
+    })
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn find_terminate_process() -> Option<FnNtTerminateProcess> {
+    let ntdll = modules::find("ntdll.dll")?;
+    let module = unsafe { PeView::module(ntdll.base() as *const u8) };
+    let terminate = module.get_proc_address("NtTerminateProcess").ok()?;
+    Some(unsafe { std::mem::transmute::<usize, FnNtTerminateProcess>(terminate as usize) })
+}
+
+// What the alternate stack's entry point is called with
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+struct Fatal<'a> {
+    info: &'a ExceptionInfo,
+    callback: FatalCallback,
+    terminate: FnNtTerminateProcess,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn fatal(
+    info: &mut ExceptionInfo,
+    callback: FatalCallback,
+    terminate: FnNtTerminateProcess,
+    stack: &FatalStack,
+) -> Handling {
+    let thread = teb::current_thread_id();
+    loop {
+        match FATAL_OWNER.compare_exchange(0, thread, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => break,
+            // The callback itself faulted
+            Err(owner) if owner == thread => return Handling::ContinueSearch,
+            Err(_) => std::thread::yield_now(),
+        }
+    }
+
+    let mut fatal = Fatal {
+        info,
+        callback,
+        terminate,
+    };
+    unsafe {
+        call_on_stack(
+            stack.top(),
+            last_words,
+            &mut fatal as *mut Fatal as *mut c_void,
+        )
+    };
+
+    // Only returns when the callback passed the exception on
+    FATAL_OWNER.store(0, Ordering::Release);
+    Handling::ContinueSearch
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern "C" fn last_words(fatal: *mut c_void) {
+    let fatal = unsafe { &*(fatal as *const Fatal) };
+    let snapshot = fatal.info.snapshot();
+    match (fatal.callback)(&snapshot) {
+        FatalAction::TerminateProcess(code) => unsafe {
+            (fatal.terminate)(CURRENT_PROCESS, code as i32);
+        },
+        FatalAction::ContinueSearch => {}
+        FatalAction::Abort => std::process::abort(),
+    }
+}
+
+// Calls `f(arg)` with the stack pointer at `top`, switching back to the current stack after
+#[cfg(target_arch = "x86_64")]
+unsafe fn call_on_stack(top: usize, f: extern "C" fn(*mut c_void), arg: *mut c_void) {
+    std::arch::asm!(
+        "mov r12, rsp",
+        "mov rsp, rdx",
+        "sub rsp, 0x20",
+        "call rax",
+        "mov rsp, r12",
+        in("rax") f,
+        in("rcx") arg,
+        in("rdx") top,
+        out("r12") _,
+        clobber_abi("C"),
+    )
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn call_on_stack(top: usize, f: extern "C" fn(*mut c_void), arg: *mut c_void) {
+    std::arch::asm!(
+        "mov edi, esp",
+        "mov esp, edx",
+        "sub esp, 12",
+        "push ecx",
+        "call eax",
+        "mov esp, edi",
+        in("eax") f,
+        in("ecx") arg,
+        in("edx") top,
+        out("edi") _,
+        clobber_abi("C"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_minidump_on_fatal, MinidumpType};
+    use crate::testing::run_isolated;
+    use std::path::PathBuf;
+
+    // Only this test writes it, so the child can find it without being told
+    fn dump_path() -> PathBuf {
+        std::env::temp_dir().join("manual-veh-crash-tests-dump_on_crash.dmp")
+    }
+
+    #[test]
+    fn dump_on_crash() {
+        let dump = dump_path();
+        let _ = std::fs::remove_file(&dump);
+
+        run_isolated("crash::tests::dump_on_crash", || {
+            let _guard = write_minidump_on_fatal(&dump_path(), MinidumpType::NORMAL).unwrap();
+            unsafe { std::ptr::read_volatile(std::ptr::null::<u8>()) };
+        })
+        .expect_crash_with_code(0xC000_0005);
+
+        let len = std::fs::metadata(&dump).map(|metadata| metadata.len());
+        let _ = std::fs::remove_file(&dump);
+        assert!(len.unwrap() > 0);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn fatal_handler_exit_code() {
+        use super::{set_fatal_handler, FatalAction};
+        use crate::testing::run_isolated;
+        use crate::{ExceptionCode, ExceptionSnapshot};
+
+        fn last_words(snapshot: &ExceptionSnapshot) -> FatalAction {
+            match snapshot.code() {
+                ExceptionCode::AccessViolation => FatalAction::TerminateProcess(0x5A),
+                _ => FatalAction::TerminateProcess(1),
+            }
+        }
+
+        run_isolated("crash::tests::fatal_handler_exit_code", || {
+            let _guard = set_fatal_handler(&[ExceptionCode::AccessViolation], last_words);
+            unsafe { std::ptr::read_volatile(std::ptr::null::<u8>()) };
+        })
+        .expect_exit_code(0x5A);
+    }
+}
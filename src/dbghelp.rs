@@ -0,0 +1,336 @@
+// Resolving addresses to symbol names with dbghelp, re-exported from `symbols`
+
+// Imports
+use crate::modules;
+use std::ffi::{c_void, OsString};
+use std::fmt;
+use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+// Architecture-specific imports
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::GetProcAddress, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::GetProcAddress, PeView};
+
+const SYMOPT_UNDNAME: u32 = 0x2;
+const SYMOPT_DEFERRED_LOADS: u32 = 0x4;
+const SYMOPT_LOAD_LINES: u32 = 0x10;
+
+const DUPLICATE_SAME_ACCESS: u32 = 0x2;
+const INFINITE: u32 = u32::MAX;
+
+// Longer names are cut off by dbghelp
+const MAX_SYMBOL_NAME: usize = 1024;
+
+// `sizeof(SYMBOL_INFOW)`, which counts the first character of the name
+const SYMBOL_INFO_SIZE: u32 = 88;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentProcess() -> *mut c_void;
+    fn GetCurrentProcessId() -> u32;
+    fn GetLastError() -> u32;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+    fn CreateMutexA(attributes: *mut c_void, owned: i32, name: *const u8) -> *mut c_void;
+    fn WaitForSingleObjectEx(handle: *mut c_void, milliseconds: u32, alertable: i32) -> u32;
+    fn ReleaseMutex(mutex: *mut c_void) -> i32;
+    fn DuplicateHandle(
+        source_process: *mut c_void,
+        source: *mut c_void,
+        target_process: *mut c_void,
+        target: *mut *mut c_void,
+        access: u32,
+        inherit: i32,
+        options: u32,
+    ) -> i32;
+}
+
+// `SYMBOL_INFOW`, with room for the name after it
+#[repr(C)]
+struct SymbolInfoW {
+    size_of_struct: u32,
+    type_index: u32,
+    reserved: [u64; 2],
+    index: u32,
+    size: u32,
+    mod_base: u64,
+    flags: u32,
+    value: u64,
+    address: u64,
+    register: u32,
+    scope: u32,
+    tag: u32,
+    name_len: u32,
+    max_name_len: u32,
+    name: [u16; MAX_SYMBOL_NAME],
+}
+
+// `IMAGEHLP_LINEW64`
+#[repr(C)]
+struct ImagehlpLineW64 {
+    size_of_struct: u32,
+    key: *mut c_void,
+    line_number: u32,
+    file_name: *mut u16,
+    address: u64,
+}
+
+type FnSymSetOptions = unsafe extern "system" fn(options: u32) -> u32;
+type FnSymInitializeW =
+    unsafe extern "system" fn(process: *mut c_void, search_path: *const u16, invade: i32) -> i32;
+type FnSymFromAddrW = unsafe extern "system" fn(
+    process: *mut c_void,
+    address: u64,
+    displacement: *mut u64,
+    symbol: *mut SymbolInfoW,
+) -> i32;
+type FnSymGetLineFromAddrW64 = unsafe extern "system" fn(
+    process: *mut c_void,
+    address: u64,
+    displacement: *mut u32,
+    line: *mut ImagehlpLineW64,
+) -> i32;
+
+/// Why a [`Symbolizer`] couldn't be set up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SymbolError {
+    /// dbghelp couldn't be loaded, or is missing one of the functions used.
+    Dbghelp,
+    /// dbghelp's session couldn't be initialized, with this `GetLastError` code.
+    Initialize(u32),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Dbghelp => f.write_str("failed to locate the symbol functions in dbghelp"),
+            SymbolError::Initialize(code) => {
+                write!(f, "failed to initialize dbghelp (error {code})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+/// What an address resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolInfo {
+    /// The undecorated name of the symbol containing the address.
+    pub name: String,
+    /// How far the address is past the start of the symbol.
+    pub displacement: u64,
+    /// The base name of the module containing the address.
+    pub module: Option<String>,
+    /// The source file and line the address was compiled from, if line information was found.
+    pub file_line: Option<(PathBuf, u32)>,
+}
+
+struct Functions {
+    from_addr: FnSymFromAddrW,
+    line_from_addr: FnSymGetLineFromAddrW64,
+}
+
+/// The process's dbghelp symbol session, for resolving addresses with the PDBs of developer
+/// builds.
+///
+/// dbghelp isn't thread-safe, so every call into it goes through the symbolizer's lock, and the
+/// named mutex the standard library's backtraces take around their own calls. Those locks, and
+/// dbghelp itself, may be held by any thread at the time an exception is raised, and
+/// loading symbols allocates and reads files, so a symbolizer must never be used from inside an
+/// exception handler. Capture frames with [`ExceptionSnapshot::backtrace`] in the handler
+/// instead, and symbolize them afterwards on a normal thread.
+///
+/// [`ExceptionSnapshot::backtrace`]: crate::ExceptionSnapshot::backtrace
+pub struct Symbolizer {
+    // The handle dbghelp knows the session by, and the functions using it, only called locked
+    session: Mutex<(usize, Functions)>,
+    // The process-wide dbghelp mutex, never closed
+    named: usize,
+}
+
+impl Symbolizer {
+    /// The process's symbolizer, initializing dbghelp the first time it's called.
+    ///
+    /// dbghelp is loaded if it isn't loaded yet, and initialized with deferred symbol loading, so
+    /// a module's symbols are only read once one of its addresses is symbolized. dbghelp keeps
+    /// its options and loaded modules for the whole process, so they're shared with other users
+    /// of dbghelp, such as the standard library's backtraces. Failing to set it up is
+    /// remembered, and returned again.
+    pub fn get() -> Result<&'static Symbolizer, SymbolError> {
+        static SYMBOLIZER: OnceLock<Result<Symbolizer, SymbolError>> = OnceLock::new();
+        SYMBOLIZER
+            .get_or_init(Symbolizer::initialize)
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+
+    fn initialize() -> Result<Symbolizer, SymbolError> {
+        let dbghelp = modules::load("dbghelp.dll").ok_or(SymbolError::Dbghelp)?;
+        let module = unsafe { PeView::module(dbghelp.base() as *const u8) };
+        let find = |name| {
+            module
+                .get_proc_address(name)
+                .map(|address| address as usize)
+                .map_err(|_| SymbolError::Dbghelp)
+        };
+
+        let (set_options, initialize_w, from_addr, line_from_addr) = unsafe {
+            (
+                std::mem::transmute::<usize, FnSymSetOptions>(find("SymSetOptions")?),
+                std::mem::transmute::<usize, FnSymInitializeW>(find("SymInitializeW")?),
+                std::mem::transmute::<usize, FnSymFromAddrW>(find("SymFromAddrW")?),
+                std::mem::transmute::<usize, FnSymGetLineFromAddrW64>(find(
+                    "SymGetLineFromAddrW64",
+                )?),
+            )
+        };
+
+        unsafe {
+            let named = CreateMutexA(std::ptr::null_mut(), 0, mutex_name().as_ptr());
+            if named.is_null() {
+                return Err(SymbolError::Initialize(GetLastError()));
+            }
+            let lock = NamedLock::acquire(named);
+
+            let mut process = std::ptr::null_mut();
+            let current = GetCurrentProcess();
+            let duplicated = DuplicateHandle(
+                current,
+                current,
+                current,
+                &mut process,
+                0,
+                0,
+                DUPLICATE_SAME_ACCESS,
+            );
+            if duplicated == 0 {
+                let error = GetLastError();
+                drop(lock);
+                CloseHandle(named);
+                return Err(SymbolError::Initialize(error));
+            }
+
+            set_options(SYMOPT_UNDNAME | SYMOPT_DEFERRED_LOADS | SYMOPT_LOAD_LINES);
+            if initialize_w(process, std::ptr::null(), 1) == 0 {
+                let error = GetLastError();
+                CloseHandle(process);
+                drop(lock);
+                CloseHandle(named);
+                return Err(SymbolError::Initialize(error));
+            }
+
+            let functions = Functions {
+                from_addr,
+                line_from_addr,
+            };
+            Ok(Symbolizer {
+                session: Mutex::new((process as usize, functions)),
+                named: named as usize,
+            })
+        }
+    }
+
+    /// Resolves `address` to the symbol containing it, or `None` if dbghelp doesn't know one.
+    ///
+    /// This must not be called from inside an exception handler.
+    pub fn symbolize(&self, address: usize) -> Option<SymbolInfo> {
+        let session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let (process, functions) = (session.0 as *mut c_void, &session.1);
+        let named = unsafe { NamedLock::acquire(self.named as *mut c_void) };
+
+        let mut symbol: Box<SymbolInfoW> = Box::new(unsafe { std::mem::zeroed() });
+        symbol.size_of_struct = SYMBOL_INFO_SIZE;
+        symbol.max_name_len = MAX_SYMBOL_NAME as u32;
+        let mut displacement = 0;
+        let found = unsafe {
+            (functions.from_addr)(process, address as u64, &mut displacement, &mut *symbol)
+        };
+        if found == 0 {
+            return None;
+        }
+        let name_len = (symbol.name_len as usize).min(MAX_SYMBOL_NAME);
+        let name = String::from_utf16_lossy(&symbol.name[..name_len]);
+
+        let mut line: ImagehlpLineW64 = unsafe { std::mem::zeroed() };
+        line.size_of_struct = std::mem::size_of::<ImagehlpLineW64>() as u32;
+        let mut line_displacement = 0;
+        let found_line = unsafe {
+            (functions.line_from_addr)(process, address as u64, &mut line_displacement, &mut line)
+        };
+        let file_line = match found_line != 0 && !line.file_name.is_null() {
+            true => Some((wide_path(line.file_name), line.line_number)),
+            false => None,
+        };
+        drop(named);
+        drop(session);
+
+        Some(SymbolInfo {
+            name,
+            displacement,
+            module: modules::containing(address).map(|module| module.name()),
+            file_line,
+        })
+    }
+}
+
+// The mutex the standard library's backtraces, and the backtrace crate, take while calling into
+// dbghelp: `Local\RustBacktraceMutex` followed by the process ID as 8 hex digits
+fn mutex_name() -> [u8; 33] {
+    let mut name = *b"Local\\RustBacktraceMutex00000000\0";
+    let id = unsafe { GetCurrentProcessId() };
+    for (i, digit) in name[24..32].iter_mut().enumerate() {
+        *digit = b"0123456789ABCDEF"[(id >> (28 - 4 * i)) as usize & 0xF];
+    }
+    name
+}
+
+// Owns the named mutex until dropped
+struct NamedLock(*mut c_void);
+
+impl NamedLock {
+    // Waits for the mutex, which a thread can take again while it already owns it
+    unsafe fn acquire(mutex: *mut c_void) -> NamedLock {
+        WaitForSingleObjectEx(mutex, INFINITE, 0);
+        NamedLock(mutex)
+    }
+}
+
+impl Drop for NamedLock {
+    fn drop(&mut self) {
+        unsafe { ReleaseMutex(self.0) };
+    }
+}
+
+// Copies a null-terminated path owned by dbghelp
+fn wide_path(path: *const u16) -> PathBuf {
+    let len = (0..).take_while(|&i| unsafe { *path.add(i) } != 0).count();
+    let wide = unsafe { std::slice::from_raw_parts(path, len) };
+    PathBuf::from(OsString::from_wide(wide))
+}
+
+// Only MSVC builds come with the PDBs dbghelp reads
+#[cfg(all(test, target_env = "msvc"))]
+mod tests {
+    use super::Symbolizer;
+
+    #[inline(never)]
+    pub fn symbolized_function() -> usize {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn function_name() {
+        assert_eq!(symbolized_function(), 42);
+
+        let symbolizer = Symbolizer::get().unwrap();
+        let address = symbolized_function as fn() -> usize as usize;
+        let symbol = symbolizer.symbolize(address).unwrap();
+        assert!(symbol.name.contains("symbolized_function"));
+        assert_eq!(symbol.displacement, 0);
+        assert!(symbol.module.is_some());
+    }
+}
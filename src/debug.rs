@@ -0,0 +1,103 @@
+//! Breaking into an attached debugger on chosen exceptions.
+//!
+//! [`break_on`] registers an observer ahead of every other dispatcher callback which, for the
+//! given codes, executes an `int3` while a debugger is attached, so it stops with the exception's
+//! record and context still on the stack, before any handler has had a chance to change them.
+//! Whether a debugger is attached is read from `PEB.BeingDebugged`, so nothing is resolved or
+//! called while handling the exception.
+//!
+//! ```ignore
+//! let _break = manual_veh::debug::break_on(&[ExceptionCode::Other(0xC0000374)])?;
+//! ```
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{teb, ExceptionCode, Filter, Handling, VehError};
+use std::time::{Duration, Instant};
+
+/// Keeps the observer registered until dropped.
+pub struct BreakOnGuard {
+    _callback: CallbackGuard,
+}
+
+/// Breaks into the attached debugger whenever the process sees an exception with one of `codes`,
+/// until the returned guard is dropped. Without a debugger, the exceptions pass by untouched.
+///
+/// The exception is passed on to the remaining callbacks and handlers once the debugger
+/// continues.
+pub fn break_on(codes: &[ExceptionCode]) -> Result<BreakOnGuard, VehError> {
+    register(codes, Duration::ZERO)
+}
+
+/// Like [`break_on`], but when no debugger is attached, spins for up to `wait` for one to attach
+/// before passing the exception on.
+///
+/// The faulting thread spins inside the handler, so the rest of the process keeps running while
+/// it waits.
+pub fn break_on_waiting(codes: &[ExceptionCode], wait: Duration) -> Result<BreakOnGuard, VehError> {
+    register(codes, wait)
+}
+
+fn register(codes: &[ExceptionCode], wait: Duration) -> Result<BreakOnGuard, VehError> {
+    let callback = Registration::new()
+        .priority(i32::MIN)
+        .filter(Filter::codes(codes.iter().copied()))
+        .observe()
+        .register_closure(move |_| {
+            if wait_for_debugger(wait) {
+                unsafe { std::arch::asm!("int3") };
+            }
+            Handling::ContinueSearch
+        })?;
+    Ok(BreakOnGuard {
+        _callback: callback,
+    })
+}
+
+// Reading the clock is just `QueryPerformanceCounter`, and only needed if nothing's attached yet
+fn wait_for_debugger(wait: Duration) -> bool {
+    if teb::being_debugged() {
+        return true;
+    }
+    if wait.is_zero() {
+        return false;
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < wait {
+        if teb::being_debugged() {
+            return true;
+        }
+        std::hint::spin_loop();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{break_on, break_on_waiting};
+    use crate::{dispatch, teb, testing, ExceptionCode};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn passed_through_without_debugger() {
+        const CODE: u32 = 0xE056_6501;
+        if teb::being_debugged() {
+            return;
+        }
+
+        let _serial = dispatch::tests::serial();
+        let code = ExceptionCode::from_raw(CODE);
+        let guard = break_on(&[code]).unwrap();
+        assert_eq!(testing::exceptions_in(|| testing::raise(CODE, &[])), [code]);
+        drop(guard);
+
+        let wait = Duration::from_millis(20);
+        let guard = break_on_waiting(&[code], wait).unwrap();
+        let start = Instant::now();
+        assert_eq!(testing::exceptions_in(|| testing::raise(CODE, &[])), [code]);
+        assert!(start.elapsed() >= wait);
+        drop(guard);
+        assert!(!dispatch::is_installed());
+    }
+}
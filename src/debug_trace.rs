@@ -0,0 +1,181 @@
+//! Printing every exception with `OutputDebugStringA`, for watching them in DebugView.
+//!
+//! [`enable`] registers a dispatcher observer after every other callback, which writes a line
+//! such as `VEH code=0xC0000005 ip=0x7FF6A1B21234 addr=0x10 tid=1234` for each exception. The
+//! line is formatted into a buffer on the stack and `OutputDebugStringA` is looked up beforehand,
+//! so nothing is allocated or resolved while handling the exception.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{modules, teb, ExceptionInfo, Handling, VehError};
+
+type FnOutputDebugStringA = unsafe extern "system" fn(output: *const u8);
+
+// Raised by `OutputDebugStringA` and `OutputDebugStringW` themselves, and not traced, as that
+// would print again for every line printed
+const DBG_PRINTEXCEPTION_C: u32 = 0x4001_0006;
+const DBG_PRINTEXCEPTION_WIDE_C: u32 = 0x4001_000A;
+
+// Long enough for every field at their widest, and the terminating newline and nul
+const LINE_CAPACITY: usize = 96;
+
+/// Keeps the tracer registered until dropped.
+pub struct TraceGuard {
+    _callback: CallbackGuard,
+}
+
+/// Prints a line with `OutputDebugStringA` for every exception the dispatcher sees, until the
+/// returned guard is dropped.
+///
+/// The tracer runs after every other dispatcher callback and never handles the exception. Like
+/// any other callback, it's skipped when the crate's handlers are nested too deeply, see
+/// [`set_max_handler_depth`](crate::set_max_handler_depth).
+pub fn enable() -> Result<TraceGuard, VehError> {
+    let output = find_output_debug_string().ok_or(VehError::Resolution)?;
+
+    let callback = Registration::new()
+        .priority(i32::MAX)
+        .observe()
+        .register_closure(move |info| trace(output, info))?;
+    Ok(TraceGuard {
+        _callback: callback,
+    })
+}
+
+fn find_output_debug_string() -> Option<FnOutputDebugStringA> {
+    let output = modules::kernel_export("OutputDebugStringA")?;
+    Some(unsafe { std::mem::transmute::<usize, FnOutputDebugStringA>(output) })
+}
+
+fn trace(output: FnOutputDebugStringA, info: &mut ExceptionInfo) -> Handling {
+    let code = info.code().raw();
+    if code == DBG_PRINTEXCEPTION_C || code == DBG_PRINTEXCEPTION_WIDE_C {
+        return Handling::ContinueSearch;
+    }
+
+    let mut line = Line::new();
+    let tid = teb::current_thread_id();
+    format_line(
+        &mut line,
+        code,
+        info.address(),
+        info.accessed_address(),
+        tid,
+    );
+    line.push(b"\n\0");
+    unsafe { output(line.bytes().as_ptr()) };
+    Handling::ContinueSearch
+}
+
+// A fixed-size buffer, silently cutting off whatever doesn't fit
+struct Line {
+    bytes: [u8; LINE_CAPACITY],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            bytes: [0; LINE_CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let count = bytes.len().min(LINE_CAPACITY - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&bytes[..count]);
+        self.len += count;
+    }
+
+    // As `0x` followed by uppercase digits, without leading zeroes
+    fn push_hex(&mut self, value: u64) {
+        let mut digits = [0; 16];
+        let mut start = digits.len();
+        let mut value = value;
+        loop {
+            start -= 1;
+            digits[start] = b"0123456789ABCDEF"[(value & 0xF) as usize];
+            value >>= 4;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(b"0x");
+        self.push(&digits[start..]);
+    }
+
+    fn push_decimal(&mut self, value: u32) {
+        let mut digits = [0; 10];
+        let mut start = digits.len();
+        let mut value = value;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[start..]);
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+// The line for one exception, with `addr` only for exceptions that accessed an address
+fn format_line(line: &mut Line, code: u32, ip: usize, accessed: Option<usize>, tid: u32) {
+    line.push(b"VEH code=");
+    line.push_hex(code as u64);
+    line.push(b" ip=");
+    line.push_hex(ip as u64);
+    if let Some(accessed) = accessed {
+        line.push(b" addr=");
+        line.push_hex(accessed as u64);
+    }
+    line.push(b" tid=");
+    line.push_decimal(tid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enable, format_line, Line, LINE_CAPACITY};
+    use crate::{dispatch, ExceptionCode, Filter, Handling};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    #[test]
+    fn formatted_lines() {
+        let mut line = Line::new();
+        format_line(&mut line, 0xC000_0005, 0x7FF6_1234, Some(0x10), 1234);
+        assert_eq!(
+            line.bytes(),
+            b"VEH code=0xC0000005 ip=0x7FF61234 addr=0x10 tid=1234"
+        );
+
+        let mut line = Line::new();
+        format_line(&mut line, 0x8000_0003, 0, None, 0);
+        assert_eq!(line.bytes(), b"VEH code=0x80000003 ip=0x0 tid=0");
+
+        let mut line = Line::new();
+        let widest = usize::MAX;
+        format_line(&mut line, u32::MAX, widest, Some(widest), u32::MAX);
+        line.push(b"\n\0");
+        assert!(line.len < LINE_CAPACITY);
+        assert_eq!(line.bytes().last(), Some(&0));
+    }
+
+    #[test]
+    fn traced_exception_passed_on() {
+        let _serial = dispatch::tests::serial();
+        const CODE: u32 = 0xE056_5601;
+
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let fallback = dispatch::register(filter, |_| Handling::ContinueExecution).unwrap();
+        let trace = enable().unwrap();
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        drop(trace);
+        drop(fallback);
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,398 @@
+//! Suppressing repeats of the same exception, so a tight loop faulting on one page doesn't flood
+//! whatever the exceptions are reported to.
+//!
+//! A [`Deduper`] keys exceptions, by default by their code, faulting instruction and the page of
+//! the address they accessed, and remembers each key in a small fixed-size table, updated with
+//! atomics and without allocating, so it can run inside handlers. The first exception of a key
+//! passes, and repeats are counted instead until the [`DedupWindow`] is over, when the next one
+//! passes along with a [`Suppressed`] summary of how many were dropped in between.
+//!
+//! Once a key's [`DedupWindow::Time`] is over, its slot may be taken by another key, dropping any
+//! repeats not yet reported through [`Deduper::flush`]. Exceptions of a key that doesn't fit in
+//! the table pass through undeduplicated, and races between threads hitting the same key at once
+//! can let an extra repeat through or miscount one, which is fine for a rate limiter.
+
+// Imports
+use crate::memory::PAGE_SIZE;
+use crate::{ExceptionCode, ExceptionInfo, Handling};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The table is small and fixed, as floods of repeats come from a handful of places at a time
+const TABLE_SIZE: usize = 64;
+// How many slots after a key's own one are tried before giving up on deduplicating it
+const MAX_PROBES: usize = 8;
+
+/// What a [`Deduper`] considers the same exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DedupKey {
+    pub code: ExceptionCode,
+    /// The faulting instruction.
+    pub ip: usize,
+    /// The page of the accessed address, or 0 for exceptions that don't access one.
+    pub page: usize,
+}
+
+impl DedupKey {
+    /// The default key: the exception's code, address and accessed page.
+    pub fn of(info: &ExceptionInfo) -> Self {
+        let accessed = info.accessed_address().unwrap_or(0);
+        DedupKey {
+            code: info.code(),
+            ip: info.address(),
+            page: accessed & !(PAGE_SIZE - 1),
+        }
+    }
+
+    // splitmix64 over the fields, never 0 as that marks free slots
+    fn hash(&self) -> u64 {
+        let mut hash = self.code.raw() as u64;
+        for value in [self.ip as u64, self.page as u64] {
+            hash = (hash ^ value).wrapping_add(0x9E37_79B9_7F4A_7C15);
+            hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            hash ^= hash >> 31;
+        }
+        hash.max(1)
+    }
+}
+
+/// How long a [`Deduper`] keeps suppressing repeats of a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DedupWindow {
+    /// Suppress repeats for this long after one passed.
+    Time(Duration),
+    /// Suppress this many repeats after one passed.
+    Count(u64),
+}
+
+/// How many repeats of a key were suppressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Suppressed {
+    pub key: DedupKey,
+    pub count: u64,
+}
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "suppressed {} duplicates of {:?} at {:#x}",
+            self.count, self.key.code, self.key.ip
+        )?;
+        if self.key.page != 0 {
+            write!(f, " accessing page {:#x}", self.key.page)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a [`Deduper`] decided about an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    /// The exception is the first of its key, or of a new window.
+    Pass,
+    /// The exception repeats one that passed within the window.
+    Suppress,
+    /// The exception starts a new window, after repeats were suppressed in the last one.
+    Summarize(Suppressed),
+}
+
+struct Slot {
+    // The key's hash, or 0 while the slot is free
+    hash: AtomicU64,
+    code: AtomicU32,
+    ip: AtomicUsize,
+    page: AtomicUsize,
+    // When the current window started, in microseconds since the deduper was created
+    window_start: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl Slot {
+    fn key(&self) -> DedupKey {
+        DedupKey {
+            code: ExceptionCode::from_raw(self.code.load(Ordering::Relaxed)),
+            ip: self.ip.load(Ordering::Relaxed),
+            page: self.page.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Deduplicates exceptions by key, see the [module documentation](self).
+pub struct Deduper {
+    window: DedupWindow,
+    key: fn(&ExceptionInfo) -> DedupKey,
+    epoch: Instant,
+    table: [Slot; TABLE_SIZE],
+}
+
+impl Deduper {
+    /// A deduper keying exceptions with [`DedupKey::of`].
+    pub fn new(window: DedupWindow) -> Self {
+        Deduper::with_key(window, DedupKey::of)
+    }
+
+    /// A deduper keying exceptions with `key`.
+    pub fn with_key(window: DedupWindow, key: fn(&ExceptionInfo) -> DedupKey) -> Self {
+        Deduper {
+            window,
+            key,
+            epoch: Instant::now(),
+            table: std::array::from_fn(|_| Slot {
+                hash: AtomicU64::new(0),
+                code: AtomicU32::new(0),
+                ip: AtomicUsize::new(0),
+                page: AtomicUsize::new(0),
+                window_start: AtomicU64::new(0),
+                suppressed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn window(&self) -> DedupWindow {
+        self.window
+    }
+
+    /// Decides on an exception raised now. Doesn't allocate, so it can be called from handlers.
+    pub fn check(&self, info: &ExceptionInfo) -> Verdict {
+        self.check_key((self.key)(info), Instant::now())
+    }
+
+    /// Decides on an exception with `key`, as if it was raised at `now`.
+    pub fn check_key(&self, key: DedupKey, now: Instant) -> Verdict {
+        let now = now.saturating_duration_since(self.epoch).as_micros() as u64;
+        let hash = key.hash();
+        for probe in 0..MAX_PROBES {
+            let slot = &self.table[(hash as usize).wrapping_add(probe) % TABLE_SIZE];
+            let current = slot.hash.load(Ordering::Acquire);
+            if current == hash {
+                return self.repeat(slot, key, now);
+            }
+
+            // Free, or left by a key whose window is over
+            if current == 0 || self.expired(slot, now) {
+                match slot
+                    .hash
+                    .compare_exchange(current, hash, Ordering::AcqRel, Ordering::Acquire)
+                {
+                    Ok(_) => {
+                        slot.code.store(key.code.raw(), Ordering::Relaxed);
+                        slot.ip.store(key.ip, Ordering::Relaxed);
+                        slot.page.store(key.page, Ordering::Relaxed);
+                        slot.window_start.store(now, Ordering::Relaxed);
+                        slot.suppressed.store(0, Ordering::Relaxed);
+                        return Verdict::Pass;
+                    }
+                    Err(current) if current == hash => return self.repeat(slot, key, now),
+                    Err(_) => {}
+                }
+            }
+        }
+
+        // No room to remember it
+        Verdict::Pass
+    }
+
+    // Whether the slot's window is over, so it can be taken by another key. Count windows only
+    // move on with repeats of their own key, so they never are
+    fn expired(&self, slot: &Slot, now: u64) -> bool {
+        match self.window {
+            DedupWindow::Time(window) => {
+                let start = slot.window_start.load(Ordering::Relaxed);
+                now.saturating_sub(start) >= window.as_micros() as u64
+            }
+            DedupWindow::Count(_) => false,
+        }
+    }
+
+    fn repeat(&self, slot: &Slot, key: DedupKey, now: u64) -> Verdict {
+        let suppressed = match self.window {
+            DedupWindow::Time(window) => {
+                let start = slot.window_start.load(Ordering::Relaxed);
+                if now.saturating_sub(start) < window.as_micros() as u64 {
+                    slot.suppressed.fetch_add(1, Ordering::Relaxed);
+                    return Verdict::Suppress;
+                }
+                slot.window_start.store(now, Ordering::Relaxed);
+                slot.suppressed.swap(0, Ordering::Relaxed)
+            }
+            DedupWindow::Count(window) => {
+                let suppressed = slot.suppressed.fetch_add(1, Ordering::Relaxed);
+                if suppressed < window {
+                    return Verdict::Suppress;
+                }
+                slot.suppressed.store(0, Ordering::Relaxed);
+                suppressed
+            }
+        };
+
+        match suppressed {
+            0 => Verdict::Pass,
+            count => Verdict::Summarize(Suppressed { key, count }),
+        }
+    }
+
+    /// Takes the counts of repeats suppressed since the last exception of their key passed, for
+    /// reporting them without waiting for the key to come up again.
+    ///
+    /// Meant to be called periodically from a normal thread.
+    pub fn flush(&self) -> impl Iterator<Item = Suppressed> + '_ {
+        self.table
+            .iter()
+            .filter(|slot| slot.hash.load(Ordering::Acquire) != 0)
+            .filter_map(|slot| match slot.suppressed.swap(0, Ordering::Relaxed) {
+                0 => None,
+                count => Some(Suppressed {
+                    key: slot.key(),
+                    count,
+                }),
+            })
+    }
+
+    /// Wraps `callback` for registering as a dispatcher observer, only calling it for exceptions
+    /// that pass, with the summary of the repeats suppressed before them, if any.
+    pub fn wrap<F>(
+        self: &Arc<Self>,
+        callback: F,
+    ) -> impl Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static
+    where
+        F: Fn(&mut ExceptionInfo, Option<Suppressed>) -> Handling + Send + Sync + 'static,
+    {
+        let deduper = self.clone();
+        move |info| match deduper.check(info) {
+            Verdict::Pass => callback(info, None),
+            Verdict::Suppress => Handling::ContinueSearch,
+            Verdict::Summarize(suppressed) => callback(info, Some(suppressed)),
+        }
+    }
+}
+
+impl fmt::Debug for Deduper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deduper")
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DedupKey, DedupWindow, Deduper, Suppressed, Verdict};
+    use crate::dispatch::{self, Registration};
+    use crate::{ExceptionCode, Filter, Handling};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const KEY: DedupKey = DedupKey {
+        code: ExceptionCode::GuardPageViolation,
+        ip: 0x1400_1234,
+        page: 0x7FF0_0000,
+    };
+
+    #[test]
+    fn time_window() {
+        let deduper = Deduper::new(DedupWindow::Time(Duration::from_secs(1)));
+        let start = Instant::now();
+
+        let passed: Vec<_> = (0..10_000)
+            .map(|i| deduper.check_key(KEY, start + Duration::from_micros(i)))
+            .filter(|verdict| *verdict != Verdict::Suppress)
+            .collect();
+        assert_eq!(passed, [Verdict::Pass]);
+
+        let later = start + Duration::from_secs(2);
+        let summary = Suppressed {
+            key: KEY,
+            count: 9_999,
+        };
+        assert_eq!(deduper.check_key(KEY, later), Verdict::Summarize(summary));
+        assert!(summary
+            .to_string()
+            .starts_with("suppressed 9999 duplicates"));
+
+        let other = DedupKey {
+            ip: 0x1400_5678,
+            ..KEY
+        };
+        assert_eq!(deduper.check_key(other, later), Verdict::Pass);
+        assert_eq!(deduper.check_key(KEY, later), Verdict::Suppress);
+        assert_eq!(
+            deduper.flush().collect::<Vec<_>>(),
+            [Suppressed { key: KEY, count: 1 }]
+        );
+    }
+
+    #[test]
+    fn expired_slots_reused() {
+        let deduper = Deduper::new(DedupWindow::Time(Duration::from_secs(1)));
+        let start = Instant::now();
+        let key = |ip| DedupKey { ip, ..KEY };
+
+        // Far more keys than slots, so every one is taken
+        for ip in 0..1_000 {
+            deduper.check_key(key(ip), start);
+        }
+        assert_eq!(deduper.check_key(key(0x5EED), start), Verdict::Pass);
+        assert_eq!(deduper.check_key(key(0x5EED), start), Verdict::Pass);
+
+        let later = start + Duration::from_secs(2);
+        assert_eq!(deduper.check_key(key(0x5EED), later), Verdict::Pass);
+        assert_eq!(deduper.check_key(key(0x5EED), later), Verdict::Suppress);
+    }
+
+    #[test]
+    fn count_window() {
+        let deduper = Deduper::new(DedupWindow::Count(3));
+        let now = Instant::now();
+
+        let verdicts: Vec<_> = (0..6).map(|_| deduper.check_key(KEY, now)).collect();
+        let summary = Verdict::Summarize(Suppressed { key: KEY, count: 3 });
+        let suppress = Verdict::Suppress;
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Pass,
+                suppress,
+                suppress,
+                suppress,
+                summary,
+                suppress
+            ]
+        );
+    }
+
+    #[test]
+    fn wrapped_observer() {
+        const CODE: u32 = 0xE056_5201;
+        static PASSED: AtomicUsize = AtomicUsize::new(0);
+        let _serial = dispatch::tests::serial();
+
+        let deduper = Arc::new(Deduper::new(DedupWindow::Time(Duration::from_secs(60))));
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let fixup = dispatch::register(filter.clone(), |_| Handling::ContinueExecution).unwrap();
+        let observer = Registration::new()
+            .observe()
+            .filter(filter)
+            .register_closure(deduper.wrap(|_, _| {
+                PASSED.fetch_add(1, Ordering::SeqCst);
+                Handling::ContinueSearch
+            }))
+            .unwrap();
+
+        // The same call site every time
+        for _ in 0..100 {
+            unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        }
+        drop(observer);
+        drop(fixup);
+
+        assert_eq!(PASSED.load(Ordering::SeqCst), 1);
+        assert_eq!(deduper.flush().map(|s| s.count).sum::<u64>(), 99);
+        assert!(!dispatch::is_installed());
+    }
+}
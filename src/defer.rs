@@ -0,0 +1,265 @@
+//! Handing work from handlers to an ordinary thread, to run once the faulting thread has moved
+//! on.
+//!
+//! A [`Deferred`] holds a fixed ring of [`WorkItem`]s allocated up front. Handlers push items
+//! without taking locks or allocating, the same way an [`ExceptionQueue`](crate::queue) is pushed
+//! to, and the items are run either on a worker thread of the crate's own, or as user APCs on a
+//! thread of the caller's choosing, whenever that thread waits alertably.
+
+// Imports
+use crate::queue::{self, Ring};
+use crate::{memory, modules, VehError};
+use std::ffi::c_void;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+type FnApc = unsafe extern "system" fn(data: usize);
+type FnQueueUserApc =
+    unsafe extern "system" fn(apc: FnApc, thread: *mut c_void, data: usize) -> u32;
+
+/// Something for a [`Deferred`] to do later.
+#[derive(Debug, Clone, Copy)]
+pub enum WorkItem {
+    /// Changes the protection of `size` bytes at `address`, such as to put a guard back once the
+    /// thread that tripped it is done with the page.
+    Protect {
+        address: usize,
+        size: usize,
+        protection: u32,
+    },
+    /// Releases the allocation at `address` with `VirtualFree`, once no thread can still be
+    /// running code in it.
+    Release { address: usize },
+    /// Calls the function with the argument.
+    UserFn(fn(usize), usize),
+}
+
+impl WorkItem {
+    fn run(self) {
+        match self {
+            WorkItem::Protect {
+                address,
+                size,
+                protection,
+            } => {
+                let _ = unsafe { memory::set_protection(address, size, protection) };
+            }
+            WorkItem::Release { address } => unsafe { memory::free_code(address) },
+            WorkItem::UserFn(f, argument) => {
+                // A panicking item only ends itself, not the thread running the others
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| f(argument)));
+            }
+        }
+    }
+}
+
+// The ring, and whether the worker should stop waiting on it
+struct Shared {
+    ring: Ring<WorkItem>,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn with_capacity(capacity: usize) -> Self {
+        Shared {
+            ring: Ring::with_capacity(capacity),
+            stop: AtomicBool::new(false),
+        }
+    }
+
+    fn run_all(&self) {
+        while let Some(item) = self.ring.try_recv() {
+            item.run();
+        }
+    }
+}
+
+enum Consumer {
+    Worker(Option<JoinHandle<()>>),
+    Apc {
+        queue: FnQueueUserApc,
+        thread: *mut c_void,
+    },
+}
+
+/// A ring of [`WorkItem`]s pushed to from handlers, and run later somewhere safer. See the
+/// [module documentation](self).
+pub struct Deferred {
+    shared: Arc<Shared>,
+    consumer: Consumer,
+}
+
+// The APC thread's handle is only ever passed to `QueueUserAPC`
+unsafe impl Send for Deferred {}
+unsafe impl Sync for Deferred {}
+
+impl Deferred {
+    /// Starts a worker thread running up to `capacity` pending items, oldest first, until the
+    /// `Deferred` is dropped.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0.
+    pub fn spawn(capacity: usize) -> io::Result<Deferred> {
+        let shared = Arc::new(Shared::with_capacity(capacity));
+        let worker = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("manual-veh deferred".into())
+                .spawn(move || {
+                    while !shared.stop.load(Ordering::SeqCst) {
+                        // Waits a slice at a time, to notice being stopped
+                        if let Some(item) = shared.ring.recv_timeout(queue::MAX_WAIT_SLICE) {
+                            item.run();
+                        }
+                    }
+                    // Nothing pushes anymore, so this is everything left
+                    shared.run_all();
+                })?
+        };
+        Ok(Deferred {
+            shared,
+            consumer: Consumer::Worker(Some(worker)),
+        })
+    }
+
+    /// Runs the items pushed as user APCs on `thread`, each time it enters an alertable wait,
+    /// such as with `SleepEx(.., TRUE)`. `QueueUserAPC` is located through kernelbase's or
+    /// kernel32's exports.
+    ///
+    /// Items still pending when the `Deferred` is dropped are run whenever the thread next gets
+    /// to the APCs queued for them.
+    ///
+    /// # Safety
+    /// `thread` must be a handle to a thread in this process with `THREAD_SET_CONTEXT` access,
+    /// valid until the `Deferred` is dropped.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0.
+    pub unsafe fn apc(capacity: usize, thread: *mut c_void) -> Result<Deferred, VehError> {
+        let queue = find_queue_user_apc().ok_or(VehError::Resolution)?;
+        Ok(Deferred {
+            shared: Arc::new(Shared::with_capacity(capacity)),
+            consumer: Consumer::Apc { queue, thread },
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.ring.capacity()
+    }
+
+    /// Queues `item`, returning whether there was room for it.
+    ///
+    /// This takes no locks and doesn't allocate, and gives up after a bounded number of
+    /// attempts, so it's safe to call from inside a handler. Items that didn't fit are
+    /// counted in [`dropped`](Self::dropped).
+    pub fn push(&self, item: WorkItem) -> bool {
+        // Pushing wakes the worker, if it's waiting
+        let pushed = self.shared.ring.push_with(false, |slot| {
+            slot.write(item);
+        });
+        if !pushed {
+            return false;
+        }
+
+        if let Consumer::Apc { queue, thread } = &self.consumer {
+            // The APC keeps the ring alive until it's run
+            let shared = Arc::into_raw(self.shared.clone());
+            if unsafe { queue(run_apc, *thread, shared as usize) } == 0 {
+                drop(unsafe { Arc::from_raw(shared) });
+            }
+        }
+        true
+    }
+
+    /// How many items were dropped because the ring was full, or too contended.
+    pub fn dropped(&self) -> usize {
+        self.shared.ring.dropped()
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        if let Consumer::Worker(worker) = &mut self.consumer {
+            self.shared.stop.store(true, Ordering::SeqCst);
+            self.shared.ring.wake();
+            if let Some(worker) = worker.take() {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+// Runs everything pending, so an APC whose item an earlier one already ran finds nothing to do
+unsafe extern "system" fn run_apc(data: usize) {
+    let shared = Arc::from_raw(data as *const Shared);
+    shared.run_all();
+}
+
+fn find_queue_user_apc() -> Option<FnQueueUserApc> {
+    let address = modules::kernel_export("QueueUserAPC")?;
+    Some(unsafe { std::mem::transmute::<usize, FnQueueUserApc>(address) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deferred, WorkItem};
+    use crate::{dispatch, testing, ExceptionCode, Filter, Handling};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SleepEx(milliseconds: u32, alertable: i32) -> u32;
+    }
+
+    #[test]
+    fn pushed_from_handler() {
+        const CODE: u32 = 0xE056_6301;
+        static RAN_WITH: AtomicUsize = AtomicUsize::new(0);
+        fn work(argument: usize) {
+            RAN_WITH.store(argument, Ordering::SeqCst);
+        }
+
+        let _serial = dispatch::tests::serial();
+        let deferred = Arc::new(Deferred::spawn(8).unwrap());
+        let callback = {
+            let deferred = deferred.clone();
+            let filter = Filter::code(ExceptionCode::from_raw(CODE));
+            dispatch::register_closure(filter, move |info| {
+                deferred.push(WorkItem::UserFn(work, info.parameters()[0]));
+                Handling::ContinueExecution
+            })
+            .unwrap()
+        };
+        testing::raise(CODE, &[0x5EED]);
+        drop(callback);
+
+        // Dropping waits for the worker to run what's left
+        drop(Arc::into_inner(deferred).unwrap());
+        assert_eq!(RAN_WITH.load(Ordering::SeqCst), 0x5EED);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn run_as_apc() {
+        static RAN: AtomicUsize = AtomicUsize::new(0);
+        fn work(argument: usize) {
+            RAN.fetch_add(argument, Ordering::SeqCst);
+        }
+
+        let deferred = unsafe { Deferred::apc(2, GetCurrentThread()).unwrap() };
+        assert!(deferred.push(WorkItem::UserFn(work, 1)));
+        assert!(deferred.push(WorkItem::UserFn(work, 2)));
+        assert!(!deferred.push(WorkItem::UserFn(work, 4)));
+        assert_eq!(deferred.dropped(), 1);
+        assert_eq!(RAN.load(Ordering::SeqCst), 0);
+
+        unsafe { SleepEx(0, 1) };
+        assert_eq!(RAN.load(Ordering::SeqCst), 3);
+    }
+}
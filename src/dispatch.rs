@@ -0,0 +1,1227 @@
+//! A single native vectored handler dispatching to any number of Rust callbacks.
+//!
+//! Every `Veh` is a real entry in ntdll's handler list, and independent users of this crate in the
+//! same process have no way of ordering their handlers relative to each other. The dispatcher
+//! instead registers exactly one first-position handler, installed when the first callback is
+//! registered and removed again when the last [`CallbackGuard`] is dropped, and runs its own list
+//! of callbacks in priority order.
+//!
+//! The list is published as an immutable, sorted snapshot. Dispatch only ever loads the current
+//! snapshot and iterates it; registering or unregistering builds a new snapshot and swaps it in
+//! with a compare-and-swap, never taking a lock dispatch could be waiting on. Callbacks can
+//! therefore register and unregister callbacks themselves, with the change taking effect for the
+//! next exception.
+//!
+//! Each callback comes with a [`Filter`], checked before the callback is called, so callbacks only
+//! ever see the exceptions they asked for. By default dispatch stops at the first callback that
+//! handles the exception, apart from observers, which see every exception; see [`DispatchPolicy`].
+
+// Imports
+use crate::defer::{Deferred, WorkItem};
+use crate::exception::EXCEPTION_CONTINUE_SEARCH;
+use crate::queue::ExceptionQueue;
+use crate::sync::{InFlight, InFlightGuard};
+use crate::{
+    continuable, module_guard, panics, raw, reentry, teb, ExceptionInfo, Filter, Handling, VehError,
+};
+use once_cell::race::OnceBox;
+use std::ffi::c_void;
+use std::ops::Range;
+use std::ptr::null_mut;
+#[cfg(feature = "tracing")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(feature = "timing")]
+pub use crate::timing::CallbackTiming;
+
+/// A dispatcher callback taking a plain function pointer.
+pub type CallbackFn = fn(&mut ExceptionInfo) -> Handling;
+
+type Closure = Box<dyn Fn(&mut ExceptionInfo) -> Handling + Send + Sync>;
+
+enum Callback {
+    Fn(CallbackFn),
+    Closure(Closure),
+}
+
+/// How the dispatcher decides which callbacks to run, see [`set_policy`].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchPolicy {
+    /// Stop at the first callback that handles the exception. Observers still run.
+    #[default]
+    FirstHandledWins = 0,
+    /// Run every matching callback, then go with the verdict of the first one to handle the
+    /// exception, if any.
+    NotifyAllThenDecide = 1,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(DispatchPolicy::FirstHandledWins as u8);
+
+/// Sets the process-wide dispatch policy.
+///
+/// This should be done once, before any exception can occur. Changing it while exceptions are
+/// being dispatched is racy: each dispatch reads the policy once when it starts, so a dispatch
+/// already running keeps following the old one.
+pub fn set_policy(policy: DispatchPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// The current dispatch policy.
+pub fn policy() -> DispatchPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => DispatchPolicy::NotifyAllThenDecide,
+        _ => DispatchPolicy::FirstHandledWins,
+    }
+}
+
+/// Identifies a closure for duplicate detection, see [`Registration::key`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallbackKey {
+    Id(u64),
+    Name(&'static str),
+}
+
+impl From<u64> for CallbackKey {
+    fn from(id: u64) -> Self {
+        CallbackKey::Id(id)
+    }
+}
+
+impl From<&'static str> for CallbackKey {
+    fn from(name: &'static str) -> Self {
+        CallbackKey::Name(name)
+    }
+}
+
+/// What registering an already registered callback does, see [`Registration::on_duplicate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnDuplicate {
+    /// Fail with [`VehError::AlreadyRegistered`].
+    #[default]
+    Error,
+    /// Return another guard for the existing registration, which stays registered until all of
+    /// its guards are dropped.
+    Reuse,
+}
+
+// What duplicates are detected by. Fn pointers are keyed automatically, closures only if asked to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Fn(usize),
+    Caller(CallbackKey),
+}
+
+struct Entry {
+    id: u64,
+    key: Option<Key>,
+    // Number of guards for this entry, zero once the last one is being dropped
+    guards: AtomicUsize,
+    // Dispatch never reads this, it only decides where the entry goes in the list
+    priority: AtomicI32,
+    filter: Filter,
+    // Always invoked, but never decides how the exception is handled
+    observe: bool,
+    callback: Callback,
+    #[cfg(feature = "timing")]
+    timing: crate::timing::Timing,
+}
+
+impl Entry {
+    // Adds a guard, unless the last one is already gone
+    fn add_guard(&self) -> bool {
+        self.guards
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |guards| {
+                (guards > 0).then_some(guards + 1)
+            })
+            .is_ok()
+    }
+
+    fn sort_key(&self) -> (i32, u64) {
+        (self.priority.load(Ordering::Relaxed), self.id)
+    }
+
+    // Panics are caught here, so one misbehaving callback doesn't take the others down with it
+    fn invoke(&self, info: &mut ExceptionInfo) -> Handling {
+        #[cfg(feature = "context-diff")]
+        let before = context_before(info);
+
+        let call = |info: &mut ExceptionInfo| match &self.callback {
+            Callback::Fn(f) => panics::guarded(f, info),
+            Callback::Closure(f) => panics::guarded(f, info),
+        };
+        #[cfg(feature = "timing")]
+        let handling = self.timing.time(|| call(info));
+        #[cfg(not(feature = "timing"))]
+        let handling = call(info);
+
+        #[cfg(feature = "context-diff")]
+        if let Some((hook, before)) = before {
+            let changes = crate::context_diff(&before, info.context());
+            if !changes.is_empty() {
+                hook(info, &changes);
+            }
+        }
+
+        handling
+    }
+}
+
+/// Called after a dispatcher callback changed the context it was given, with every register it
+/// changed, see [`set_on_context_change`].
+#[cfg(feature = "context-diff")]
+pub type ContextChangeHook = fn(info: &ExceptionInfo, changes: &[crate::RegisterChange]);
+
+#[cfg(feature = "context-diff")]
+static ON_CONTEXT_CHANGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets a hook to call whenever a callback changes the context of the exception it was called
+/// for, or removes it. Meant for debugging handlers that change the wrong register by mistake.
+///
+/// While a hook is set, the context is copied before every callback, and compared afterwards.
+#[cfg(feature = "context-diff")]
+pub fn set_on_context_change(hook: Option<ContextChangeHook>) {
+    ON_CONTEXT_CHANGE.store(hook.map_or(0, |hook| hook as usize), Ordering::Relaxed);
+}
+
+// The hook and a copy of the context to compare against, if there's a hook to call
+#[cfg(feature = "context-diff")]
+fn context_before(info: &ExceptionInfo) -> Option<(ContextChangeHook, crate::CONTEXT)> {
+    let hook = ON_CONTEXT_CHANGE.load(Ordering::Relaxed);
+    if hook == 0 || info.context_ptr().is_null() {
+        return None;
+    }
+
+    let hook = unsafe { std::mem::transmute::<usize, ContextChangeHook>(hook) };
+    Some((hook, *info.context()))
+}
+
+#[cfg(feature = "tracing")]
+static TRACE_DISPATCH: AtomicBool = AtomicBool::new(false);
+
+/// Turns the `manual_veh::dispatch` trace event for every exception the dispatcher decides on on
+/// or off. It's off by default.
+///
+/// The event only has integer fields, `code`, `ip`, `tid` and `handled`, but it's still emitted
+/// from inside the exception handler, so the subscriber must be able to cope with being called
+/// there, even for exceptions raised inside the subscriber itself.
+#[cfg(feature = "tracing")]
+pub fn set_trace_dispatch(enabled: bool) {
+    TRACE_DISPATCH.store(enabled, Ordering::Relaxed);
+}
+
+// The list dispatch iterates, sorted by priority and then registration order
+struct Snapshot {
+    entries: Vec<Arc<Entry>>,
+    // Links retired snapshots waiting to be freed
+    next: *mut Snapshot,
+}
+
+static SNAPSHOT: AtomicPtr<Snapshot> = AtomicPtr::new(null_mut());
+// Snapshots that have been replaced, but might still be read by another thread
+static RETIRED: AtomicPtr<Snapshot> = AtomicPtr::new(null_mut());
+// Every thread reading a snapshot, dispatching or updating the list, is counted in here
+static READERS: InFlight = InFlight::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// The native handler's ntdll handle. This is the only lock, it is never taken by dispatch, and
+// only held while installing or removing the native handler.
+static NATIVE: Mutex<Option<usize>> = Mutex::new(None);
+
+fn native() -> MutexGuard<'static, Option<usize>> {
+    NATIVE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+unsafe extern "system" fn dispatch(ptrs: *mut c_void) -> i32 {
+    let info = ExceptionInfo::from_raw(ptrs);
+    let _depth = match reentry::enter(info) {
+        Some(depth) => depth,
+        None => return EXCEPTION_CONTINUE_SEARCH,
+    };
+    let _errors = teb::SavedErrors::save();
+
+    // Enter before loading the snapshot, so it isn't freed while we're iterating it
+    let _reading = READERS.enter();
+    let snapshot = match SNAPSHOT.load(Ordering::SeqCst).as_ref() {
+        Some(snapshot) => snapshot,
+        None => return EXCEPTION_CONTINUE_SEARCH,
+    };
+
+    let notify_all = policy() == DispatchPolicy::NotifyAllThenDecide;
+    let mut verdict = Handling::ContinueSearch;
+
+    for entry in &snapshot.entries {
+        if !entry.filter.matches(info) {
+            continue;
+        }
+
+        if entry.observe {
+            entry.invoke(info);
+            continue;
+        }
+
+        // Once handled, the remaining callbacks only run if the policy asks for it
+        let handled = verdict != Handling::ContinueSearch;
+        if handled && !notify_all {
+            continue;
+        }
+
+        // A callback wrongly continuing leaves the decision to the ones after it
+        let result = entry.invoke(info);
+        let result = continuable::enforce(info, result);
+        if !handled {
+            verdict = result;
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    crate::stats::record(info.code(), verdict != Handling::ContinueSearch);
+    #[cfg(feature = "etw")]
+    crate::etw::write(info, verdict != Handling::ContinueSearch);
+    #[cfg(feature = "tracing")]
+    if TRACE_DISPATCH.load(Ordering::Relaxed) {
+        let handled = verdict != Handling::ContinueSearch;
+        let tid = teb::current_thread_id();
+        crate::events::dispatched(info.code().raw(), info.address(), tid, handled);
+    }
+    verdict.raw()
+}
+
+/// Reads the current list. The reference is only valid while `_reading` is held.
+fn entries<'a>(_reading: &'a InFlightGuard) -> &'a [Arc<Entry>] {
+    match unsafe { SNAPSHOT.load(Ordering::SeqCst).as_ref() } {
+        Some(snapshot) => &snapshot.entries,
+        None => &[],
+    }
+}
+
+/// Replaces the list with the one `change` builds from the current list, or leaves it alone if
+/// `change` returns `None`. If another thread replaces the list first, `change` is called again
+/// with the new one, so it must not have side effects that can't be repeated.
+///
+/// This never blocks on other registrations or on dispatching threads, so it can be called from
+/// inside a callback. Dispatch that's already running keeps iterating the old list; the change
+/// takes effect with the next exception.
+fn update(
+    mut change: impl FnMut(&[Arc<Entry>]) -> Option<Vec<Arc<Entry>>>,
+) -> Result<(), VehError> {
+    loop {
+        let reading = READERS.enter();
+        let old = SNAPSHOT.load(Ordering::SeqCst);
+
+        let entries = match change(entries(&reading)) {
+            Some(entries) => entries,
+            None => return Ok(()),
+        };
+        let new = match entries.is_empty() {
+            true => null_mut(),
+            false => Box::into_raw(Box::new(Snapshot {
+                entries,
+                next: null_mut(),
+            })),
+        };
+
+        if SNAPSHOT
+            .compare_exchange(old, new, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            if !old.is_null() {
+                unsafe { retire(old) };
+            }
+            break;
+        }
+
+        // Someone else got there first, try again on top of their list
+        if !new.is_null() {
+            drop(unsafe { Box::from_raw(new) });
+        }
+    }
+
+    reclaim();
+    sync_native()
+}
+
+// Installs the native handler if the list is non-empty, or removes it if it's empty. Done after
+// every change, under the lock, so concurrent changes always leave it matching the final list.
+fn sync_native() -> Result<(), VehError> {
+    let mut native = native();
+    let empty = SNAPSHOT.load(Ordering::SeqCst).is_null();
+
+    match (*native, empty) {
+        (None, false) if module_guard::is_detached(dispatch as *const () as usize) => {}
+        (None, false) => {
+            let handle =
+                unsafe { raw::try_add_handler(raw::EXCEPTION_HANDLER_LIST, true, dispatch)? };
+            *native = Some(handle as usize);
+        }
+        (Some(handle), true) => {
+            unsafe { raw::remove_handler(raw::EXCEPTION_HANDLER_LIST, handle as _) };
+            *native = None;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Unregisters the native handler if it's inside `module`, for `module_guard::detach`. The
+// callbacks stay in the list, but nothing calls them until the module guard is initialized again.
+pub(crate) fn detach_native(module: &Range<usize>) -> bool {
+    let mut native = native();
+    match *native {
+        Some(handle) if module.contains(&(dispatch as *const () as usize)) => {
+            unsafe { raw::remove_handler(raw::EXCEPTION_HANDLER_LIST, handle as _) };
+            *native = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+// Pushes a replaced snapshot onto the retired list. `first..=last` must be a chain nobody else
+// can reach.
+unsafe fn retire_chain(first: *mut Snapshot, last: *mut Snapshot) {
+    let mut head = RETIRED.load(Ordering::SeqCst);
+    loop {
+        (*last).next = head;
+        match RETIRED.compare_exchange(head, first, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(current) => head = current,
+        }
+    }
+}
+
+unsafe fn retire(snapshot: *mut Snapshot) {
+    retire_chain(snapshot, snapshot)
+}
+
+// Memory reclamation is leak-until-quiescent: replaced snapshots are kept on the retired list,
+// and freed by whichever update next finds no thread reading any snapshot. Readers are never
+// waited for (a callback might be the one updating), so while any are still running, the
+// snapshots are left for a later update to free.
+//
+// The retired list is taken before checking for readers. Anything on it was unpublished before
+// that, so a thread that could still be reading it must have entered before the check too.
+fn reclaim() {
+    let first = RETIRED.swap(null_mut(), Ordering::SeqCst);
+    if first.is_null() {
+        return;
+    }
+
+    if !READERS.is_idle() {
+        let mut last = first;
+        unsafe {
+            while !(*last).next.is_null() {
+                last = (*last).next;
+            }
+            retire_chain(first, last);
+        }
+        return;
+    }
+
+    let mut current = first;
+    while !current.is_null() {
+        let snapshot = unsafe { Box::from_raw(current) };
+        current = snapshot.next;
+    }
+}
+
+/// Options for a dispatcher registration.
+#[derive(Debug, Clone, Default)]
+pub struct Registration {
+    priority: i32,
+    filter: Filter,
+    observe: bool,
+    key: Option<CallbackKey>,
+    on_duplicate: OnDuplicate,
+}
+
+impl Registration {
+    pub fn new() -> Self {
+        Registration::default()
+    }
+
+    /// Callbacks with a lower priority run first, ties are broken by registration order.
+    /// Defaults to 0.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Only invoke the callback for exceptions matching `filter`. Defaults to [`Filter::any`].
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Make the callback an observer: it sees every matching exception, even ones an earlier
+    /// callback already handled, and its return value is ignored.
+    pub fn observe(mut self) -> Self {
+        self.observe = true;
+        self
+    }
+
+    /// Identify the callback by `key` rather than by its function pointer, so registering a
+    /// second callback with the same key is detected as a duplicate. Closures without a key are
+    /// never considered duplicates.
+    pub fn key(mut self, key: impl Into<CallbackKey>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// What to do if the callback is already registered. Defaults to [`OnDuplicate::Error`].
+    ///
+    /// When reusing, the new guard refers to the existing registration, and this registration's
+    /// own options are ignored.
+    pub fn on_duplicate(mut self, on_duplicate: OnDuplicate) -> Self {
+        self.on_duplicate = on_duplicate;
+        self
+    }
+
+    pub fn register(self, callback: CallbackFn) -> Result<CallbackGuard, VehError> {
+        let key = match self.key {
+            Some(key) => Key::Caller(key),
+            None => Key::Fn(callback as usize),
+        };
+        self.insert(Some(key), Callback::Fn(callback))
+    }
+
+    pub fn register_closure<F>(self, callback: F) -> Result<CallbackGuard, VehError>
+    where
+        F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+    {
+        let key = self.key.map(Key::Caller);
+        self.insert(key, Callback::Closure(Box::new(callback)))
+    }
+
+    fn insert(self, key: Option<Key>, callback: Callback) -> Result<CallbackGuard, VehError> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            id,
+            key,
+            guards: AtomicUsize::new(1),
+            priority: AtomicI32::new(self.priority),
+            filter: self.filter,
+            observe: self.observe,
+            callback,
+            #[cfg(feature = "timing")]
+            timing: Default::default(),
+        });
+
+        let mut result = Ok(id);
+        update(|entries| {
+            // Entries whose last guard is being dropped are on their way out, and don't count
+            let existing = entries
+                .iter()
+                .find(|e| key.is_some() && e.key == key && e.guards.load(Ordering::Relaxed) > 0);
+
+            if let Some(existing) = existing {
+                match self.on_duplicate {
+                    OnDuplicate::Error => {
+                        result = Err(VehError::AlreadyRegistered);
+                        return None;
+                    }
+                    // Unless it just lost its last guard after all
+                    OnDuplicate::Reuse if existing.add_guard() => {
+                        result = Ok(existing.id);
+                        return None;
+                    }
+                    OnDuplicate::Reuse => {}
+                }
+            }
+
+            let mut entries = entries.to_vec();
+            let index = entries.partition_point(|e| e.sort_key() < entry.sort_key());
+            entries.insert(index, entry.clone());
+            Some(entries)
+        })
+        .inspect_err(|_| {
+            // Installing the native handler failed, so take the entry back out
+            let _ = update(|entries| remove(entries, id));
+        })?;
+
+        #[cfg(feature = "tracing")]
+        if result == Ok(id) {
+            let priority = entry.priority.load(Ordering::Relaxed);
+            crate::events::callback_registered(id, priority, entry.observe);
+        }
+        result.map(|id| CallbackGuard { id })
+    }
+}
+
+/// Registers a callback for exceptions matching `filter`, with the default priority.
+///
+/// Callbacks can be called from any thread, and from several threads at once. Registering a
+/// function that is already registered fails with [`VehError::AlreadyRegistered`].
+pub fn register(filter: Filter, callback: CallbackFn) -> Result<CallbackGuard, VehError> {
+    Registration::new().filter(filter).register(callback)
+}
+
+/// Registers a closure for exceptions matching `filter`, with the default priority.
+pub fn register_closure<F>(filter: Filter, callback: F) -> Result<CallbackGuard, VehError>
+where
+    F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+{
+    Registration::new()
+        .filter(filter)
+        .register_closure(callback)
+}
+
+/// Registers a closure identified by `key`, failing with [`VehError::AlreadyRegistered`] if a
+/// callback with the same key is already registered.
+pub fn register_keyed<F>(
+    key: impl Into<CallbackKey>,
+    filter: Filter,
+    callback: F,
+) -> Result<CallbackGuard, VehError>
+where
+    F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+{
+    Registration::new()
+        .key(key)
+        .filter(filter)
+        .register_closure(callback)
+}
+
+/// Registers an observer pushing a snapshot of every exception into `queue`, see
+/// [`ExceptionQueue::push_snapshot`].
+///
+/// The observer runs at the default priority. For only some exceptions, or another priority,
+/// register a closure calling `push_snapshot` instead.
+pub fn observe_into(queue: &Arc<ExceptionQueue>) -> Result<CallbackGuard, VehError> {
+    let queue = queue.clone();
+    Registration::new().observe().register_closure(move |info| {
+        queue.push_snapshot(info);
+        Handling::ContinueSearch
+    })
+}
+
+/// Registers a closure for exceptions matching `filter` that's consumed the first time it returns
+/// [`Handling::ContinueExecution`], and never called again after that.
+///
+/// While the callback is running on one thread, exceptions on other threads pass it by as if it
+/// weren't registered. Once it's been consumed, the registration is removed on a
+/// [`Deferred`](crate::defer::Deferred) worker rather than inside the handler, or when the guard
+/// is dropped, whichever comes first.
+pub fn register_once<F>(filter: Filter, callback: F) -> Result<OnceGuard, VehError>
+where
+    F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+{
+    // Started here, as the handler can't start a thread
+    let reaper = REAPER.get_or_init(|| Box::new(Deferred::spawn(REAPER_CAPACITY).ok()));
+
+    let state = Arc::new(OnceState {
+        state: AtomicU8::new(ONCE_ARMED),
+        guard: Mutex::new(None),
+    });
+    let guard = {
+        let state = state.clone();
+        register_closure(filter, move |info| {
+            // Exactly one thread gets to decide at a time
+            let claimed = state.state.compare_exchange(
+                ONCE_ARMED,
+                ONCE_RUNNING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            );
+            if claimed.is_err() {
+                return Handling::ContinueSearch;
+            }
+
+            let handling = callback(info);
+            if handling != Handling::ContinueExecution {
+                state.state.store(ONCE_ARMED, Ordering::Release);
+                return handling;
+            }
+
+            state.state.store(ONCE_TRIGGERED, Ordering::Release);
+            if let Some(reaper) = reaper {
+                // Not the last reference, so dropping it here if the push fails frees nothing
+                let reap = Arc::into_raw(state.clone()) as usize;
+                if !reaper.push(WorkItem::UserFn(reap_once, reap)) {
+                    drop(unsafe { Arc::from_raw(reap as *const OnceState) });
+                }
+            }
+            handling
+        })?
+    };
+
+    *state.guard() = Some(guard);
+    Ok(OnceGuard { state })
+}
+
+fn reap_once(state: usize) {
+    let state = unsafe { Arc::from_raw(state as *const OnceState) };
+    let guard = state.guard().take();
+    drop(guard);
+}
+
+/// Whether the dispatcher's native handler is currently registered with ntdll.
+pub fn is_installed() -> bool {
+    native().is_some()
+}
+
+/// How long each registered callback has taken so far, in dispatch order.
+///
+/// Each call is timed with two reads of the performance counter around it, including the
+/// panic guard but not the filter.
+#[cfg(feature = "timing")]
+pub fn timing_report() -> Vec<CallbackTiming> {
+    let reading = READERS.enter();
+    let report = entries(&reading).iter().map(|entry| {
+        let key = match entry.key {
+            Some(Key::Caller(key)) => Some(key),
+            _ => None,
+        };
+        let priority = entry.priority.load(Ordering::Relaxed);
+        entry.timing.report(entry.id, key, priority)
+    });
+    report.collect()
+}
+
+/// Sets the [`timing_report`] totals of every registered callback back to zero.
+#[cfg(feature = "timing")]
+pub fn reset_timing() {
+    let reading = READERS.enter();
+    entries(&reading)
+        .iter()
+        .for_each(|entry| entry.timing.reset());
+}
+
+/// Unregisters its callback from the dispatcher on drop.
+///
+/// A callback might still be running on another thread right after the guard is dropped, but
+/// its storage is only freed once no thread is dispatching through it anymore.
+pub struct CallbackGuard {
+    id: u64,
+}
+
+impl CallbackGuard {
+    /// Identifies the registration in [`timing_report`].
+    #[cfg(feature = "timing")]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The callback's current priority.
+    pub fn priority(&self) -> i32 {
+        let reading = READERS.enter();
+        let entry = entries(&reading).iter().find(|entry| entry.id == self.id);
+        entry.map_or(0, |entry| entry.priority.load(Ordering::Relaxed))
+    }
+
+    /// Moves the callback to a new position in the list, as if it had been registered with
+    /// `priority` but keeping its original registration order for ties.
+    ///
+    /// Exceptions being dispatched on other threads at the same time see either the old or the
+    /// new order, never a mix of both.
+    pub fn set_priority(&self, priority: i32) {
+        {
+            let reading = READERS.enter();
+            if let Some(entry) = entries(&reading).iter().find(|entry| entry.id == self.id) {
+                entry.priority.store(priority, Ordering::Relaxed);
+            }
+        }
+
+        // The list doesn't become empty, so this never needs to install the native handler
+        let _ = update(|entries| {
+            let mut entries = entries.to_vec();
+            entries.sort_by_key(|entry| entry.sort_key());
+            Some(entries)
+        });
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        {
+            let reading = READERS.enter();
+            if let Some(entry) = entries(&reading).iter().find(|entry| entry.id == self.id) {
+                // Other guards still share this registration
+                if entry.guards.fetch_sub(1, Ordering::Relaxed) > 1 {
+                    return;
+                }
+            }
+        }
+
+        // Removing can't fail, as it never needs to install the native handler
+        #[cfg(feature = "tracing")]
+        crate::events::callback_removed(self.id);
+        let _ = update(|entries| remove(entries, self.id));
+    }
+}
+
+const ONCE_ARMED: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_TRIGGERED: u8 = 2;
+
+// How many consumed callbacks can wait to be unregistered at once. Any more are left to their
+// guards, and never called again either way
+const REAPER_CAPACITY: usize = 64;
+
+static REAPER: OnceBox<Option<Deferred>> = OnceBox::new();
+
+struct OnceState {
+    state: AtomicU8,
+    guard: Mutex<Option<CallbackGuard>>,
+}
+
+impl OnceState {
+    fn guard(&self) -> MutexGuard<'_, Option<CallbackGuard>> {
+        self.guard
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Unregisters a [`register_once`] callback on drop, unless it's been unregistered already.
+pub struct OnceGuard {
+    state: Arc<OnceState>,
+}
+
+impl OnceGuard {
+    /// Whether the callback has handled an exception, and so won't be called again.
+    pub fn was_triggered(&self) -> bool {
+        self.state.state.load(Ordering::Acquire) == ONCE_TRIGGERED
+    }
+}
+
+impl Drop for OnceGuard {
+    fn drop(&mut self) {
+        let guard = self.state.guard().take();
+        drop(guard);
+    }
+}
+
+fn remove(entries: &[Arc<Entry>], id: u64) -> Option<Vec<Arc<Entry>>> {
+    Some(entries.iter().filter(|e| e.id != id).cloned().collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        dispatch, is_installed, policy, register, register_keyed, register_once, set_policy,
+        CallbackGuard, DispatchPolicy, OnDuplicate, Registration,
+    };
+    use crate::exception::{EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH};
+    use crate::EXCEPTION_RECORD;
+    use crate::{set_on_invalid_continue, VehError, EXCEPTION_NONCONTINUABLE};
+    use crate::{ExceptionCode, ExceptionInfo, Filter, Handling, EXCEPTION_POINTERS};
+    use crate::{Order, Veh};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, MutexGuard};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    // The dispatcher is process-wide, so tests asserting on its state mustn't run in parallel
+    pub(crate) fn serial() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    const CODE: u32 = 0xE056_4701;
+    static CALLS: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+    fn record(info: &ExceptionInfo, name: &'static str) -> bool {
+        let ours = info.code().raw() == CODE;
+        if ours {
+            CALLS.lock().unwrap().push(name);
+        }
+        ours
+    }
+
+    fn observe(info: &mut ExceptionInfo) -> Handling {
+        record(info, "observe");
+        Handling::ContinueSearch
+    }
+
+    fn handle(info: &mut ExceptionInfo) -> Handling {
+        match record(info, "handle") {
+            true => Handling::ContinueExecution,
+            false => Handling::ContinueSearch,
+        }
+    }
+
+    #[test]
+    fn priority_order_and_removal() {
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        {
+            // Registered in reverse, but the lower priority must still run first
+            let _handle = Registration::new().priority(10).register(handle).unwrap();
+            let _observe = Registration::new().priority(0).register(observe).unwrap();
+            assert!(is_installed());
+
+            unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+            assert_eq!(*CALLS.lock().unwrap(), ["observe", "handle"]);
+        }
+
+        assert!(!is_installed());
+    }
+
+    #[test]
+    fn handled_stops_dispatch() {
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        {
+            let _handle = Registration::new().priority(-1).register(handle).unwrap();
+            let _observe = register(Filter::any(), observe).unwrap();
+
+            unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+            assert_eq!(*CALLS.lock().unwrap(), ["handle"]);
+        }
+
+        assert!(!is_installed());
+    }
+
+    // Marks exceptions raised by the tests, so real faults from elsewhere are never "handled"
+    const MARKER: usize = 0x5645_4821;
+
+    fn raise_marked(code: ExceptionCode) {
+        let params = [0, MARKER];
+        unsafe { RaiseException(code.raw(), 0, 2, params.as_ptr() as _) };
+    }
+
+    fn is_marked(info: &ExceptionInfo) -> bool {
+        info.parameters() == [0, MARKER]
+    }
+
+    #[test]
+    fn code_filters() {
+        static AV_CALLS: AtomicUsize = AtomicUsize::new(0);
+        static BP_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        fn counted(info: &ExceptionInfo, calls: &AtomicUsize) -> Handling {
+            match is_marked(info) {
+                true => {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueExecution
+                }
+                false => Handling::ContinueSearch,
+            }
+        }
+
+        let _serial = serial();
+
+        let _av = register(Filter::code(ExceptionCode::AccessViolation), |info| {
+            counted(info, &AV_CALLS)
+        })
+        .unwrap();
+        let _bp = register(Filter::code(ExceptionCode::Breakpoint), |info| {
+            counted(info, &BP_CALLS)
+        })
+        .unwrap();
+
+        raise_marked(ExceptionCode::AccessViolation);
+        assert_eq!(AV_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(BP_CALLS.load(Ordering::SeqCst), 0);
+
+        raise_marked(ExceptionCode::Breakpoint);
+        assert_eq!(AV_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(BP_CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn code_set_filter() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let _serial = serial();
+
+        let filter = Filter::codes([ExceptionCode::AccessViolation, ExceptionCode::Breakpoint]);
+        let _both = register(filter, |info| match is_marked(info) {
+            true => {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                Handling::ContinueExecution
+            }
+            false => Handling::ContinueSearch,
+        })
+        .unwrap();
+
+        raise_marked(ExceptionCode::AccessViolation);
+        raise_marked(ExceptionCode::Breakpoint);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn thread_filter() {
+        const THREAD_CODE: u32 = 0xE056_4702;
+        static OWN_THREAD: AtomicUsize = AtomicUsize::new(0);
+        static FALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+        fn counted(info: &ExceptionInfo, calls: &AtomicUsize) -> Handling {
+            match info.code().raw() {
+                THREAD_CODE => {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueExecution
+                }
+                _ => Handling::ContinueSearch,
+            }
+        }
+
+        let _serial = serial();
+
+        let _own_thread =
+            register(Filter::current_thread(), |info| counted(info, &OWN_THREAD)).unwrap();
+        let _fallback = Registration::new()
+            .priority(10)
+            .register(|info| counted(info, &FALLBACK))
+            .unwrap();
+
+        std::thread::spawn(|| unsafe { RaiseException(THREAD_CODE, 0, 0, std::ptr::null()) })
+            .join()
+            .unwrap();
+        assert_eq!(OWN_THREAD.load(Ordering::SeqCst), 0);
+        assert_eq!(FALLBACK.load(Ordering::SeqCst), 1);
+
+        unsafe { RaiseException(THREAD_CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(OWN_THREAD.load(Ordering::SeqCst), 1);
+        assert_eq!(FALLBACK.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn runtime_priority_change() {
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        fn ten(info: &mut ExceptionInfo) -> Handling {
+            record(info, "10");
+            Handling::ContinueSearch
+        }
+
+        fn zero(info: &mut ExceptionInfo) -> Handling {
+            record(info, "0");
+            Handling::ContinueSearch
+        }
+
+        fn five(info: &mut ExceptionInfo) -> Handling {
+            record(info, "5");
+            Handling::ContinueSearch
+        }
+
+        let _ten = Registration::new().priority(10).register(ten).unwrap();
+        let zero = Registration::new().priority(0).register(zero).unwrap();
+        let _five = Registration::new().priority(5).register(five).unwrap();
+        let _handle = Registration::new().priority(100).register(handle).unwrap();
+
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["0", "5", "10", "handle"]);
+        CALLS.lock().unwrap().clear();
+
+        // Ties with the callback registered first, so it now runs right after it
+        zero.set_priority(10);
+        assert_eq!(zero.priority(), 10);
+
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["5", "10", "0", "handle"]);
+    }
+
+    #[test]
+    fn observer_sees_handled_exception() {
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        // Its verdict is ignored, so this doesn't stop the handler from running
+        fn watch(info: &mut ExceptionInfo) -> Handling {
+            record(info, "watch");
+            Handling::ContinueExecution
+        }
+
+        let _watch = Registration::new()
+            .priority(-10)
+            .observe()
+            .register(watch)
+            .unwrap();
+        let _handle = Registration::new().priority(0).register(handle).unwrap();
+        let _late = Registration::new()
+            .priority(10)
+            .observe()
+            .register(observe)
+            .unwrap();
+        let _skipped = Registration::new()
+            .priority(20)
+            .register_closure(handle)
+            .unwrap();
+
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["watch", "handle", "observe"]);
+    }
+
+    #[test]
+    fn notify_all_policy() {
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        struct Restore;
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                set_policy(DispatchPolicy::FirstHandledWins);
+            }
+        }
+
+        let _restore = Restore;
+        set_policy(DispatchPolicy::NotifyAllThenDecide);
+        assert_eq!(policy(), DispatchPolicy::NotifyAllThenDecide);
+
+        let _first = Registration::new().priority(0).register(observe).unwrap();
+        let _handle = Registration::new().priority(5).register(handle).unwrap();
+        let _last = Registration::new()
+            .priority(10)
+            .register_closure(observe)
+            .unwrap();
+
+        // Everything runs, and the exception still ends up handled rather than crashing the test
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["observe", "handle", "observe"]);
+    }
+
+    #[test]
+    fn noncontinuable_not_continued() {
+        static INVALID: AtomicUsize = AtomicUsize::new(0);
+
+        fn on_invalid_continue(code: ExceptionCode) {
+            if code.raw() == CODE {
+                INVALID.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let _serial = serial();
+        set_on_invalid_continue(Some(on_invalid_continue));
+        let _handle = register(Filter::any(), handle).unwrap();
+
+        let mut record: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        record.exception_code = CODE;
+        let mut ptrs = EXCEPTION_POINTERS {
+            exception_record: &mut record,
+            context_record: std::ptr::null_mut(),
+        };
+
+        unsafe {
+            assert_eq!(
+                dispatch(&mut ptrs as *mut _ as _),
+                EXCEPTION_CONTINUE_EXECUTION
+            );
+            assert_eq!(INVALID.load(Ordering::SeqCst), 0);
+
+            (*ptrs.exception_record).exception_flags = EXCEPTION_NONCONTINUABLE;
+            assert_eq!(
+                dispatch(&mut ptrs as *mut _ as _),
+                EXCEPTION_CONTINUE_SEARCH
+            );
+        }
+        assert_eq!(INVALID.load(Ordering::SeqCst), 1);
+
+        set_on_invalid_continue(None);
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let _serial = serial();
+
+        let first = register_keyed("duplicate_keys", Filter::any(), observe).unwrap();
+        assert_eq!(
+            register_keyed("duplicate_keys", Filter::any(), observe).err(),
+            Some(VehError::AlreadyRegistered)
+        );
+
+        drop(first);
+        let _again = register_keyed("duplicate_keys", Filter::any(), observe).unwrap();
+    }
+
+    #[test]
+    fn duplicate_fn_reused() {
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        let first = register(Filter::any(), handle).unwrap();
+        assert_eq!(
+            register(Filter::any(), handle).err(),
+            Some(VehError::AlreadyRegistered)
+        );
+
+        let second = Registration::new()
+            .on_duplicate(OnDuplicate::Reuse)
+            .register(handle)
+            .unwrap();
+
+        // Still registered, and only once, until the last guard is gone
+        drop(first);
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["handle"]);
+
+        drop(second);
+        assert!(!is_installed());
+    }
+
+    #[test]
+    fn register_from_callback() {
+        static ONE_SHOT: Mutex<Option<CallbackGuard>> = Mutex::new(None);
+
+        fn arm(info: &mut ExceptionInfo) -> Handling {
+            if !record(info, "arm") {
+                return Handling::ContinueSearch;
+            }
+
+            let guard = Registration::new().priority(-1).register(handle).unwrap();
+            *ONE_SHOT.lock().unwrap() = Some(guard);
+            Handling::ContinueExecution
+        }
+
+        let _serial = serial();
+        CALLS.lock().unwrap().clear();
+
+        let arm = register(Filter::any(), arm).unwrap();
+
+        // The new callback only takes effect for the next exception
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["arm"]);
+
+        drop(arm);
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        assert_eq!(*CALLS.lock().unwrap(), ["arm", "handle"]);
+
+        ONE_SHOT.lock().unwrap().take();
+        assert!(!is_installed());
+    }
+
+    #[test]
+    fn once_consumed() {
+        const ONCE: u32 = 0xE056_4702;
+        static ONCE_CALLS: AtomicUsize = AtomicUsize::new(0);
+        static BACKSTOP_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "system" fn backstop(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                ONCE => {
+                    BACKSTOP_CALLS.fetch_add(1, Ordering::SeqCst);
+                    EXCEPTION_CONTINUE_EXECUTION
+                }
+                _ => EXCEPTION_CONTINUE_SEARCH,
+            }
+        }
+
+        let _serial = serial();
+        let _backstop = unsafe { Veh::<c_void>::add(Order::Last, backstop) };
+        let filter = Filter::code(ExceptionCode::from_raw(ONCE));
+        let guard = register_once(filter, |_| {
+            ONCE_CALLS.fetch_add(1, Ordering::SeqCst);
+            Handling::ContinueExecution
+        })
+        .unwrap();
+        assert!(!guard.was_triggered());
+
+        unsafe { RaiseException(ONCE, 0, 0, std::ptr::null()) };
+        assert!(guard.was_triggered());
+        unsafe { RaiseException(ONCE, 0, 0, std::ptr::null()) };
+        assert_eq!(ONCE_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(BACKSTOP_CALLS.load(Ordering::SeqCst), 1);
+
+        drop(guard);
+        assert!(!is_installed());
+    }
+}
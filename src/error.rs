@@ -0,0 +1,42 @@
+// Imports
+use std::fmt;
+
+/// Errors returned by the crate's fallible registration APIs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VehError {
+    /// The ntdll, kernelbase or kernel32 functions the operation needs, such as the internal ones
+    /// used to (un)register handlers, couldn't be located.
+    Resolution,
+    /// ntdll refused to register the handler.
+    Registration,
+    /// A module filter named a module that isn't loaded in this process.
+    ModuleNotFound(String),
+    /// Every trampoline in the crate's fixed pool is already in use.
+    SlotsExhausted,
+    /// The operation requires a registration built with `VehBuilder::toggleable`.
+    NotToggleable,
+    /// A dispatcher callback with the same function or key is already registered.
+    AlreadyRegistered,
+    /// The handler isn't inside any loaded module, so its module couldn't be pinned, as required
+    /// by `VehBuilder::require_module`.
+    NotInModule,
+}
+
+impl fmt::Display for VehError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VehError::Resolution => {
+                f.write_str("failed to locate the system functions the operation needs")
+            }
+            VehError::Registration => f.write_str("ntdll failed to register the handler"),
+            VehError::ModuleNotFound(name) => write!(f, "module `{name}` is not loaded"),
+            VehError::SlotsExhausted => f.write_str("no free handler trampolines are left"),
+            VehError::NotToggleable => f.write_str("the handler was not registered as toggleable"),
+            VehError::AlreadyRegistered => f.write_str("the callback is already registered"),
+            VehError::NotInModule => f.write_str("the handler is not inside a loaded module"),
+        }
+    }
+}
+
+impl std::error::Error for VehError {}
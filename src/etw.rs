@@ -0,0 +1,404 @@
+//! Writing an ETW event for every exception, for ETW-based telemetry.
+//!
+//! [`enable`] registers a TraceLogging provider with the given GUID, named `manual_veh`, and from
+//! then on the dispatcher writes an `Exception` event for every exception it has decided on, with
+//! these fields:
+//!
+//! - `Code`, the exception code
+//! - `Address`, where the exception happened
+//! - `Ip`, the instruction pointer in the exception's context
+//! - `ThreadId`
+//! - `Location`, `Address` as `module+offset`, or the bare address outside any module
+//! - `Handled`, whether a dispatcher callback handled the exception
+//!
+//! The provider is registered before any event is written, and writing an event only fills in a
+//! fixed-size buffer on the stack, so nothing is allocated while handling the exception. While no
+//! session is listening to the provider, nothing but that check is done.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{modules, teb, ContextExt, ExceptionInfo, Handling, VehError};
+use std::ffi::c_void;
+use std::fmt::{self, Write};
+use std::ptr::null;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn EventRegister(
+        provider: *const Guid,
+        callback: *const c_void,
+        context: *mut c_void,
+        handle: *mut u64,
+    ) -> u32;
+    fn EventUnregister(handle: u64) -> u32;
+    fn EventSetInformation(handle: u64, class: u32, information: *const c_void, length: u32)
+        -> u32;
+    fn EventEnabled(handle: u64, descriptor: *const EventDescriptor) -> u8;
+    fn EventWriteTransfer(
+        handle: u64,
+        descriptor: *const EventDescriptor,
+        activity: *const Guid,
+        related_activity: *const Guid,
+        count: u32,
+        data: *const EventDataDescriptor,
+    ) -> u32;
+}
+
+/// A GUID, identifying an ETW provider.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl Guid {
+    /// The GUID written as `value`, so `{12345678-9ABC-DEF0-1234-56789ABCDEF0}` is
+    /// `Guid::from_u128(0x12345678_9ABC_DEF0_1234_56789ABCDEF0)`.
+    pub const fn from_u128(value: u128) -> Self {
+        Guid {
+            data1: (value >> 96) as u32,
+            data2: (value >> 80) as u16,
+            data3: (value >> 64) as u16,
+            data4: (value as u64).to_be_bytes(),
+        }
+    }
+}
+
+/// Errors returned by [`enable`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EtwError {
+    /// A provider is already enabled, see [`disable`].
+    AlreadyEnabled,
+    /// `EventRegister` failed with this error code.
+    Register(u32),
+    /// The dispatcher callback couldn't be registered.
+    Handler(VehError),
+}
+
+impl fmt::Display for EtwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EtwError::AlreadyEnabled => f.write_str("an ETW provider is already enabled"),
+            EtwError::Register(code) => write!(f, "EventRegister failed with error {code}"),
+            EtwError::Handler(error) => write!(f, "failed to register the handler: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for EtwError {}
+
+impl From<VehError> for EtwError {
+    fn from(error: VehError) -> Self {
+        EtwError::Handler(error)
+    }
+}
+
+#[repr(C)]
+struct EventDescriptor {
+    id: u16,
+    version: u8,
+    channel: u8,
+    level: u8,
+    opcode: u8,
+    task: u16,
+    keyword: u64,
+}
+
+#[repr(C)]
+struct EventDataDescriptor {
+    ptr: u64,
+    size: u32,
+    // 0 for field data, or one of the metadata types below
+    kind: u32,
+}
+
+const KIND_DATA: u32 = 0;
+const KIND_EVENT_METADATA: u32 = 1;
+const KIND_PROVIDER_METADATA: u32 = 2;
+
+// `EventSetInformation`'s information class for setting provider traits
+const EVENT_PROVIDER_SET_TRAITS: u32 = 2;
+
+// The field types that are used, as encoded in TraceLogging metadata
+const IN_ANSI_STRING: u8 = 2;
+const IN_UINT32: u8 = 8;
+const IN_BOOL32: u8 = 13;
+const IN_HEX_INT32: u8 = 20;
+#[cfg(target_pointer_width = "32")]
+const IN_POINTER: u8 = 20;
+#[cfg(target_pointer_width = "64")]
+const IN_POINTER: u8 = 21;
+
+// Metadata blobs start with their own size, followed by `parts`
+const fn metadata<const N: usize>(parts: &[&[u8]]) -> [u8; N] {
+    let mut blob = [0; N];
+    let size = (N as u16).to_le_bytes();
+    blob[0] = size[0];
+    blob[1] = size[1];
+
+    let mut at = 2;
+    let mut i = 0;
+    while i < parts.len() {
+        let mut j = 0;
+        while j < parts[i].len() {
+            blob[at] = parts[i][j];
+            at += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    assert!(at == N, "metadata size doesn't match its contents");
+    blob
+}
+
+// Doubles as the provider traits, which have the same layout when there are no other traits
+static PROVIDER_METADATA: [u8; 13] = metadata(&[b"manual_veh\0"]);
+
+#[rustfmt::skip]
+static EVENT_METADATA: [u8; 61] = metadata(&[
+    // No event tags
+    &[0],
+    b"Exception\0",
+    b"Code\0", &[IN_HEX_INT32],
+    b"Address\0", &[IN_POINTER],
+    b"Ip\0", &[IN_POINTER],
+    b"ThreadId\0", &[IN_UINT32],
+    b"Location\0", &[IN_ANSI_STRING],
+    b"Handled\0", &[IN_BOOL32],
+]);
+
+static DESCRIPTOR: EventDescriptor = EventDescriptor {
+    id: 0,
+    version: 0,
+    // The channel TraceLogging events are written to
+    channel: 11,
+    // Informational
+    level: 4,
+    opcode: 0,
+    task: 0,
+    keyword: 0,
+};
+
+// Long enough for a module name of 100 characters and its offset
+const LOCATION_CAPACITY: usize = 128;
+
+struct Provider {
+    handle: u64,
+    _callback: CallbackGuard,
+}
+
+static PROVIDER: Mutex<Option<Provider>> = Mutex::new(None);
+// The registered provider's handle, or 0, for the dispatcher to read without locking
+static HANDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the provider `provider` and starts writing an event for every exception, until
+/// [`disable`] is called.
+///
+/// A dispatcher observer is registered alongside the provider, keeping the dispatcher's handler
+/// installed even when no other callbacks are. The events are written once the dispatcher has
+/// decided on the exception, after every callback.
+pub fn enable(provider: Guid) -> Result<(), EtwError> {
+    let mut registered = PROVIDER.lock().unwrap_or_else(|e| e.into_inner());
+    if registered.is_some() {
+        return Err(EtwError::AlreadyEnabled);
+    }
+
+    let mut handle = 0;
+    let status = unsafe { EventRegister(&provider, null(), std::ptr::null_mut(), &mut handle) };
+    if status != 0 {
+        return Err(EtwError::Register(status));
+    }
+    // TraceLogging consumers can also read the name from every event, so failing here is fine
+    let traits = PROVIDER_METADATA.as_ptr() as *const c_void;
+    let length = PROVIDER_METADATA.len() as u32;
+    unsafe { EventSetInformation(handle, EVENT_PROVIDER_SET_TRAITS, traits, length) };
+
+    let callback = Registration::new()
+        .observe()
+        .register_closure(|_| Handling::ContinueSearch);
+    let callback = match callback {
+        Ok(callback) => callback,
+        Err(error) => {
+            unsafe { EventUnregister(handle) };
+            return Err(error.into());
+        }
+    };
+
+    HANDLE.store(handle, Ordering::SeqCst);
+    *registered = Some(Provider {
+        handle,
+        _callback: callback,
+    });
+    Ok(())
+}
+
+/// Stops writing events and unregisters the provider, if one is enabled.
+///
+/// An exception being dispatched on another thread meanwhile might still try to write its event
+/// to the unregistered provider, which ETW turns away.
+pub fn disable() {
+    let provider = PROVIDER.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(provider) = provider {
+        HANDLE.store(0, Ordering::SeqCst);
+        let handle = provider.handle;
+        drop(provider);
+        unsafe { EventUnregister(handle) };
+    }
+}
+
+/// Writes the event for an exception the dispatcher has decided on, if anyone is listening.
+pub(crate) fn write(info: &ExceptionInfo, handled: bool) {
+    let handle = HANDLE.load(Ordering::Relaxed);
+    if handle == 0 || unsafe { EventEnabled(handle, &DESCRIPTOR) } == 0 {
+        return;
+    }
+
+    let ip = match info.context_ptr().is_null() {
+        true => 0,
+        false => info.context().ip(),
+    };
+    let tid = teb::current_thread_id();
+    let event = ExceptionEvent::new(info.code().raw(), info.address(), ip, tid, handled);
+    let data = event.descriptors();
+    let count = data.len() as u32;
+    unsafe { EventWriteTransfer(handle, &DESCRIPTOR, null(), null(), count, data.as_ptr()) };
+}
+
+// An event's fields, laid out for `EventWriteTransfer`
+struct ExceptionEvent {
+    code: u32,
+    address: usize,
+    ip: usize,
+    tid: u32,
+    handled: u32,
+    // Nul-terminated
+    location: [u8; LOCATION_CAPACITY],
+    location_len: usize,
+}
+
+impl ExceptionEvent {
+    fn new(code: u32, address: usize, ip: usize, tid: u32, handled: bool) -> Self {
+        let mut location = Location {
+            bytes: [0; LOCATION_CAPACITY],
+            len: 0,
+        };
+        // Names that don't fit are cut off
+        let _ = write_location(&mut location, address);
+
+        ExceptionEvent {
+            code,
+            address,
+            ip,
+            tid,
+            handled: handled as u32,
+            location: location.bytes,
+            location_len: location.len + 1,
+        }
+    }
+
+    fn descriptors(&self) -> [EventDataDescriptor; 8] {
+        fn data<T: ?Sized>(value: &T, kind: u32) -> EventDataDescriptor {
+            EventDataDescriptor {
+                ptr: value as *const T as *const u8 as usize as u64,
+                size: std::mem::size_of_val(value) as u32,
+                kind,
+            }
+        }
+
+        [
+            data(&PROVIDER_METADATA, KIND_PROVIDER_METADATA),
+            data(&EVENT_METADATA, KIND_EVENT_METADATA),
+            data(&self.code, KIND_DATA),
+            data(&self.address, KIND_DATA),
+            data(&self.ip, KIND_DATA),
+            data(&self.tid, KIND_DATA),
+            data(&self.location[..self.location_len], KIND_DATA),
+            data(&self.handled, KIND_DATA),
+        ]
+    }
+}
+
+fn write_location(location: &mut Location, address: usize) -> fmt::Result {
+    let module = match modules::containing(address) {
+        Some(module) => module,
+        None => return write!(location, "{address:#x}"),
+    };
+    for c in char::decode_utf16(module.name_wide().iter().copied()) {
+        location.write_char(c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+    }
+    write!(location, "+{:#x}", address - module.base())
+}
+
+// Keeps the last byte for the nul terminator, and replaces anything that isn't ASCII
+struct Location {
+    bytes: [u8; LOCATION_CAPACITY],
+    len: usize,
+}
+
+impl Write for Location {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.len == LOCATION_CAPACITY - 1 {
+                return Err(fmt::Error);
+            }
+            self.bytes[self.len] = if c.is_ascii() { c as u8 } else { b'?' };
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disable, enable, EtwError, ExceptionEvent, Guid, KIND_DATA};
+    use crate::{dispatch, modules, ExceptionCode, Filter, Handling};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    // Only used by these tests
+    const PROVIDER: Guid = Guid::from_u128(0x6F0C_1D7E_3B2A_4C59_9E84_15A7_D3C6_0B21);
+
+    #[test]
+    fn event_fields() {
+        let ntdll = modules::find("ntdll.dll").unwrap();
+        let address = ntdll.base() + 0x1234;
+        let event = ExceptionEvent::new(0xC000_0005, address, address, 42, true);
+        assert_eq!(&event.location[..event.location_len], b"ntdll.dll+0x1234\0");
+
+        let data = event.descriptors();
+        assert!(data[2..].iter().all(|data| data.kind == KIND_DATA));
+        assert_eq!(data[2].size, 4);
+        assert_eq!(data[3].size as usize, std::mem::size_of::<usize>());
+        assert_eq!(data[6].size as usize, b"ntdll.dll+0x1234\0".len());
+        assert_eq!(unsafe { *(data[7].ptr as usize as *const u32) }, 1);
+
+        let event = ExceptionEvent::new(0x8000_0003, 0x10, 0, 0, false);
+        assert_eq!(&event.location[..event.location_len], b"0x10\0");
+    }
+
+    #[test]
+    fn enable_and_disable() {
+        let _serial = dispatch::tests::serial();
+        const CODE: u32 = 0xE056_5701;
+
+        enable(PROVIDER).unwrap();
+        assert_eq!(enable(PROVIDER), Err(EtwError::AlreadyEnabled));
+
+        // Nobody is listening, so this only checks that the provider isn't enabled
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let fixup = dispatch::register(filter, |_| Handling::ContinueExecution).unwrap();
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+        drop(fixup);
+
+        disable();
+        assert!(!dispatch::is_installed());
+        enable(PROVIDER).unwrap();
+        disable();
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,181 @@
+//! The `tracing` events emitted with the `tracing` feature.
+//!
+//! Locating ntdll's functions is reported under the `manual_veh::resolution` target, adding and
+//! removing native handlers and dispatcher callbacks under `manual_veh::handlers`, and, only
+//! once [`set_trace_dispatch`](crate::dispatch::set_trace_dispatch) turns them on, every
+//! exception dispatched under `manual_veh::dispatch`.
+
+// Imports
+use crate::raw::{CONTINUE_HANDLER_LIST, EXCEPTION_HANDLER_LIST};
+use std::ffi::c_void;
+
+const RESOLUTION: &str = "manual_veh::resolution";
+const HANDLERS: &str = "manual_veh::handlers";
+const DISPATCH: &str = "manual_veh::dispatch";
+
+// How the functions were located
+pub(crate) const WRAPPER_SCAN: &str = "continue handler wrappers";
+pub(crate) const EXPORTS: &str = "exports";
+
+fn list_name(handler_type: i32) -> &'static str {
+    match handler_type {
+        EXCEPTION_HANDLER_LIST => "exception",
+        CONTINUE_HANDLER_LIST => "continue",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn resolving(functions: &'static str, method: &'static str) {
+    tracing::debug!(target: RESOLUTION, functions, method, "resolving");
+}
+
+pub(crate) fn resolved(functions: &'static str, method: &'static str, found: bool) {
+    match found {
+        true => tracing::debug!(target: RESOLUTION, functions, method, "resolved"),
+        false => tracing::warn!(target: RESOLUTION, functions, method, "resolution failed"),
+    }
+}
+
+pub(crate) fn handler_added(handler_type: i32, first: bool, handler: usize, handle: *const c_void) {
+    let list = list_name(handler_type);
+    let order = if first { "first" } else { "last" };
+    match handle.is_null() {
+        false => tracing::debug!(
+            target: HANDLERS,
+            list,
+            order,
+            handler,
+            handle = handle as usize,
+            "handler added"
+        ),
+        true => tracing::warn!(target: HANDLERS, list, order, handler, "handler not added"),
+    }
+}
+
+pub(crate) fn handler_removed(handler_type: i32, handle: *const c_void, removed: bool) {
+    let list = list_name(handler_type);
+    let handle = handle as usize;
+    match removed {
+        true => tracing::debug!(target: HANDLERS, list, handle, "handler removed"),
+        false => tracing::warn!(target: HANDLERS, list, handle, "handler not removed"),
+    }
+}
+
+pub(crate) fn callback_registered(id: u64, priority: i32, observe: bool) {
+    tracing::debug!(target: HANDLERS, id, priority, observe, "callback registered");
+}
+
+pub(crate) fn callback_removed(id: u64) {
+    tracing::debug!(target: HANDLERS, id, "callback removed");
+}
+
+// Only integer fields, so that nothing but the subscriber itself formats or allocates
+pub(crate) fn dispatched(code: u32, ip: usize, tid: u32, handled: bool) {
+    tracing::trace!(target: DISPATCH, code, ip, tid, handled, "exception dispatched");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatch::{self, set_trace_dispatch};
+    use crate::{teb, ExceptionCode, Filter, Handling};
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    // Every event's message and fields, formatted
+    #[derive(Default)]
+    struct Collector {
+        events: Mutex<Vec<Vec<(&'static str, String)>>>,
+    }
+
+    struct Fields(Vec<(&'static str, String)>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name(), format!("{value:?}")));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(Vec::new());
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    fn field<'a>(fields: &'a [(&'static str, String)], name: &str) -> &'a str {
+        let value = fields.iter().find(|(field, _)| *field == name);
+        value.map_or("", |(_, value)| value)
+    }
+
+    #[test]
+    fn register_exception_drop() {
+        let _serial = dispatch::tests::serial();
+        const CODE: u32 = 0xE056_5501;
+
+        let collector = Arc::new(Collector::default());
+        tracing::subscriber::with_default(collector.clone(), || {
+            set_trace_dispatch(true);
+            let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+            let guard = dispatch::Registration::new()
+                .priority(7)
+                .filter(filter)
+                .register_closure(|_| Handling::ContinueExecution)
+                .unwrap();
+            unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+            drop(guard);
+            set_trace_dispatch(false);
+        });
+
+        // Resolution only happens once per process, so it may have been done by another test
+        let events = collector.events.lock().unwrap();
+        let events: Vec<_> = events
+            .iter()
+            .filter(|fields| !matches!(field(fields, "message"), "resolving" | "resolved"))
+            .collect();
+        let messages: Vec<_> = events.iter().map(|e| field(e, "message")).collect();
+        assert_eq!(
+            messages,
+            [
+                "handler added",
+                "callback registered",
+                "exception dispatched",
+                "callback removed",
+                "handler removed",
+            ]
+        );
+
+        assert_eq!(field(events[0], "list"), "exception");
+        assert_eq!(field(events[0], "order"), "first");
+        assert_ne!(field(events[0], "handler"), "");
+        assert_eq!(field(events[1], "priority"), "7");
+        assert_eq!(field(events[2], "code"), CODE.to_string());
+        assert_eq!(
+            field(events[2], "tid"),
+            teb::current_thread_id().to_string()
+        );
+        assert_eq!(field(events[2], "handled"), "true");
+        assert_eq!(field(events[0], "handle"), field(events[4], "handle"));
+        assert!(!dispatch::is_installed());
+    }
+}
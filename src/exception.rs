@@ -0,0 +1,410 @@
+// Imports
+use crate::CONTEXT;
+use std::ffi::c_void;
+use std::hash::{Hash, Hasher};
+
+/// `EXCEPTION_CONTINUE_EXECUTION`, as returned from a vectored handler.
+pub const EXCEPTION_CONTINUE_EXECUTION: i32 = -1;
+/// `EXCEPTION_CONTINUE_SEARCH`, as returned from a vectored handler.
+pub const EXCEPTION_CONTINUE_SEARCH: i32 = 0;
+/// The `ExceptionFlags` bit set on exceptions that execution can't continue after.
+pub const EXCEPTION_NONCONTINUABLE: u32 = 0x1;
+
+// Structs
+/// The crate's own definition of the Windows `EXCEPTION_RECORD`, so that the higher-level APIs
+/// work regardless of which (if any) of the `impl-*` features are enabled.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct EXCEPTION_RECORD {
+    pub exception_code: u32,
+    pub exception_flags: u32,
+    pub exception_record: *mut EXCEPTION_RECORD,
+    pub exception_address: *mut c_void,
+    pub number_parameters: u32,
+    pub exception_information: [usize; 15],
+}
+
+/// The crate's own definition of the Windows `EXCEPTION_POINTERS`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct EXCEPTION_POINTERS {
+    pub exception_record: *mut EXCEPTION_RECORD,
+    pub context_record: *mut CONTEXT,
+}
+
+macro_rules! exception_codes {
+    ($($(#[$meta:meta])* $name:ident = $value:literal,)*) => {
+        /// A typed view of an exception's `ExceptionCode`.
+        ///
+        /// Codes without a named variant are kept as [`ExceptionCode::Other`]. Comparisons are
+        /// done on the raw value, so `Other(0xC0000005)` equals `AccessViolation`.
+        #[derive(Debug, Clone, Copy)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum ExceptionCode {
+            $($(#[$meta])* $name,)*
+            /// Any code that doesn't have a dedicated variant.
+            Other(u32),
+        }
+
+        impl ExceptionCode {
+            pub const fn from_raw(code: u32) -> Self {
+                match code {
+                    $($value => ExceptionCode::$name,)*
+                    other => ExceptionCode::Other(other),
+                }
+            }
+
+            pub const fn raw(self) -> u32 {
+                match self {
+                    $(ExceptionCode::$name => $value,)*
+                    ExceptionCode::Other(code) => code,
+                }
+            }
+        }
+    };
+}
+
+exception_codes! {
+    /// `STATUS_GUARD_PAGE_VIOLATION`
+    GuardPageViolation = 0x8000_0001,
+    /// `STATUS_DATATYPE_MISALIGNMENT`
+    DatatypeMisalignment = 0x8000_0002,
+    /// `STATUS_BREAKPOINT`
+    Breakpoint = 0x8000_0003,
+    /// `STATUS_SINGLE_STEP`
+    SingleStep = 0x8000_0004,
+    /// `STATUS_ACCESS_VIOLATION`
+    AccessViolation = 0xC000_0005,
+    /// `STATUS_IN_PAGE_ERROR`
+    InPageError = 0xC000_0006,
+    /// `STATUS_INVALID_HANDLE`
+    InvalidHandle = 0xC000_0008,
+    /// `STATUS_ILLEGAL_INSTRUCTION`
+    IllegalInstruction = 0xC000_001D,
+    /// `STATUS_NONCONTINUABLE_EXCEPTION`
+    NoncontinuableException = 0xC000_0025,
+    /// `STATUS_INVALID_DISPOSITION`
+    InvalidDisposition = 0xC000_0026,
+    /// `STATUS_ARRAY_BOUNDS_EXCEEDED`
+    ArrayBoundsExceeded = 0xC000_008C,
+    /// `STATUS_FLOAT_DENORMAL_OPERAND`
+    FloatDenormalOperand = 0xC000_008D,
+    /// `STATUS_FLOAT_DIVIDE_BY_ZERO`
+    FloatDivideByZero = 0xC000_008E,
+    /// `STATUS_FLOAT_INEXACT_RESULT`
+    FloatInexactResult = 0xC000_008F,
+    /// `STATUS_FLOAT_INVALID_OPERATION`
+    FloatInvalidOperation = 0xC000_0090,
+    /// `STATUS_FLOAT_OVERFLOW`
+    FloatOverflow = 0xC000_0091,
+    /// `STATUS_FLOAT_STACK_CHECK`
+    FloatStackCheck = 0xC000_0092,
+    /// `STATUS_FLOAT_UNDERFLOW`
+    FloatUnderflow = 0xC000_0093,
+    /// `STATUS_INTEGER_DIVIDE_BY_ZERO`
+    IntegerDivideByZero = 0xC000_0094,
+    /// `STATUS_INTEGER_OVERFLOW`
+    IntegerOverflow = 0xC000_0095,
+    /// `STATUS_PRIVILEGED_INSTRUCTION`
+    PrivilegedInstruction = 0xC000_0096,
+    /// `STATUS_STACK_OVERFLOW`
+    StackOverflow = 0xC000_00FD,
+    /// `STATUS_FLOAT_MULTIPLE_FAULTS`, raised for SSE exceptions on x64
+    FloatMultipleFaults = 0xC000_02B4,
+    /// `STATUS_FLOAT_MULTIPLE_TRAPS`, raised for SSE exceptions on x64
+    FloatMultipleTraps = 0xC000_02B5,
+    /// `STATUS_HEAP_CORRUPTION`
+    HeapCorruption = 0xC000_0374,
+    /// `STATUS_STACK_BUFFER_OVERRUN`
+    StackBufferOverrun = 0xC000_0409,
+}
+
+impl PartialEq for ExceptionCode {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw() == other.raw()
+    }
+}
+
+impl Eq for ExceptionCode {}
+
+impl Hash for ExceptionCode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.raw().hash(state)
+    }
+}
+
+impl From<u32> for ExceptionCode {
+    fn from(code: u32) -> Self {
+        ExceptionCode::from_raw(code)
+    }
+}
+
+impl From<ExceptionCode> for u32 {
+    fn from(code: ExceptionCode) -> Self {
+        code.raw()
+    }
+}
+
+/// What a handler wants the OS to do after it returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handling {
+    /// Let the next handler in the chain look at the exception.
+    ContinueSearch,
+    /// Resume execution using the (possibly modified) context.
+    ContinueExecution,
+}
+
+impl Handling {
+    pub const fn raw(self) -> i32 {
+        match self {
+            Handling::ContinueSearch => EXCEPTION_CONTINUE_SEARCH,
+            Handling::ContinueExecution => EXCEPTION_CONTINUE_EXECUTION,
+        }
+    }
+
+    pub const fn from_raw(value: i32) -> Self {
+        match value {
+            EXCEPTION_CONTINUE_EXECUTION => Handling::ContinueExecution,
+            _ => Handling::ContinueSearch,
+        }
+    }
+}
+
+/// The exception a handler was called for.
+///
+/// This is a transparent wrapper around `EXCEPTION_POINTERS`, so the pointer a vectored handler
+/// receives can be reinterpreted as `&mut ExceptionInfo` directly.
+#[repr(transparent)]
+pub struct ExceptionInfo(EXCEPTION_POINTERS);
+
+impl ExceptionInfo {
+    /// # Safety
+    /// `ptrs` must point to a valid `EXCEPTION_POINTERS` whose exception record stays valid (and
+    /// isn't accessed through other means) for the lifetime `'a`.
+    pub unsafe fn from_raw<'a>(ptrs: *mut c_void) -> &'a mut ExceptionInfo {
+        &mut *(ptrs as *mut ExceptionInfo)
+    }
+
+    /// The underlying `EXCEPTION_POINTERS`, for passing on to raw handlers.
+    pub fn as_ptr(&mut self) -> *mut c_void {
+        self as *mut ExceptionInfo as _
+    }
+
+    pub fn record(&self) -> &EXCEPTION_RECORD {
+        unsafe { &*self.0.exception_record }
+    }
+
+    pub fn record_mut(&mut self) -> &mut EXCEPTION_RECORD {
+        unsafe { &mut *self.0.exception_record }
+    }
+
+    pub fn code(&self) -> ExceptionCode {
+        ExceptionCode::from_raw(self.record().exception_code)
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.record().exception_flags
+    }
+
+    /// Whether execution may continue after this exception, that is whether
+    /// `EXCEPTION_NONCONTINUABLE` is clear.
+    pub fn is_continuable(&self) -> bool {
+        self.flags() & EXCEPTION_NONCONTINUABLE == 0
+    }
+
+    /// The address the exception was raised at.
+    pub fn address(&self) -> usize {
+        self.record().exception_address as usize
+    }
+
+    /// The exception's parameters, `ExceptionInformation` cut down to the `NumberParameters`
+    /// entries that are actually set. A count beyond the array's 15 entries is clamped.
+    pub fn parameters(&self) -> &[usize] {
+        ExceptionRecordView(self.record()).parameters()
+    }
+
+    /// Parameter `i`, or `None` if the exception doesn't have that many, see
+    /// [`ExceptionInfo::parameters`].
+    pub fn parameter(&self, i: usize) -> Option<usize> {
+        self.parameters().get(i).copied()
+    }
+
+    /// For access violations and in-page errors, the address that was being read or written.
+    pub fn accessed_address(&self) -> Option<usize> {
+        match self.code() {
+            ExceptionCode::AccessViolation | ExceptionCode::InPageError => self.parameter(1),
+            _ => None,
+        }
+    }
+
+    /// The exception's record followed by the ones nested in it, where each is the exception
+    /// that was being dispatched when the one before it was raised.
+    ///
+    /// At most [`MAX_RECORD_CHAIN`] records are returned, so a corrupted chain linking back to
+    /// itself can't loop forever.
+    pub fn record_chain(&self) -> impl Iterator<Item = ExceptionRecordView<'_>> {
+        let mut next = self.0.exception_record as *const EXCEPTION_RECORD;
+        (0..MAX_RECORD_CHAIN).map_while(move |_| {
+            // The OS keeps the nested records alive for as long as the outer one
+            let record = unsafe { next.as_ref()? };
+            next = record.exception_record;
+            Some(ExceptionRecordView(record))
+        })
+    }
+
+    /// The raw `CONTEXT` pointer of the thread that raised the exception.
+    pub fn context_ptr(&self) -> *mut c_void {
+        self.0.context_record as _
+    }
+
+    /// The register state of the thread that raised the exception, see [`ContextExt`].
+    ///
+    /// [`ContextExt`]: crate::ContextExt
+    pub fn context(&self) -> &CONTEXT {
+        unsafe { &*self.0.context_record }
+    }
+
+    /// The register state execution resumes with if the exception is handled.
+    pub fn context_mut(&mut self) -> &mut CONTEXT {
+        unsafe { &mut *self.0.context_record }
+    }
+
+    /// Resumes the thread with `context` right away instead of returning from the handler, see
+    /// [`raw::nt_continue`](crate::raw::nt_continue).
+    ///
+    /// # Safety
+    /// The handler's caller, and the OS's dispatch frame above it, never get control back. That
+    /// includes the crate's own handlers: resuming from a [`dispatch`](crate::dispatch) or
+    /// [`VehBuilder`](crate::VehBuilder) callback leaves it counted as still running, which
+    /// keeps the dispatcher from freeing replaced registrations and counts against
+    /// [`set_max_handler_depth`](crate::set_max_handler_depth) for good. It's meant for raw
+    /// handlers added with [`Veh::add`](crate::Veh::add).
+    pub unsafe fn resume_with(&mut self, context: &CONTEXT) -> ! {
+        crate::raw::nt_continue(context, false)
+    }
+}
+
+/// The most records [`ExceptionInfo::record_chain`] walks.
+pub const MAX_RECORD_CHAIN: usize = 16;
+
+/// One of the records in [`ExceptionInfo::record_chain`].
+#[derive(Clone, Copy)]
+pub struct ExceptionRecordView<'a>(&'a EXCEPTION_RECORD);
+
+impl<'a> ExceptionRecordView<'a> {
+    pub fn record(&self) -> &'a EXCEPTION_RECORD {
+        self.0
+    }
+
+    pub fn code(&self) -> ExceptionCode {
+        ExceptionCode::from_raw(self.0.exception_code)
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.0.exception_flags
+    }
+
+    /// The address the exception was raised at.
+    pub fn address(&self) -> usize {
+        self.0.exception_address as usize
+    }
+
+    /// The exception's parameters, see [`ExceptionInfo::parameters`].
+    pub fn parameters(&self) -> &'a [usize] {
+        let len = (self.0.number_parameters as usize).min(self.0.exception_information.len());
+        &self.0.exception_information[..len]
+    }
+}
+
+impl std::fmt::Debug for ExceptionRecordView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExceptionRecordView")
+            .field("code", &self.code())
+            .field("flags", &self.flags())
+            .field("address", &self.address())
+            .field("parameters", &self.parameters())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_RECORD_CHAIN;
+    use super::{ExceptionCode, ExceptionInfo, EXCEPTION_POINTERS, EXCEPTION_RECORD};
+    use std::ptr::null_mut;
+
+    fn with_count<R>(number_parameters: u32, f: impl FnOnce(&ExceptionInfo) -> R) -> R {
+        let mut record: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        record.number_parameters = number_parameters;
+        for (i, parameter) in record.exception_information.iter_mut().enumerate() {
+            *parameter = 100 + i;
+        }
+        info_for(&mut record, f)
+    }
+
+    #[test]
+    fn parameters() {
+        with_count(0, |info| {
+            assert!(info.parameters().is_empty());
+            assert_eq!(info.parameter(0), None);
+        });
+
+        with_count(2, |info| {
+            assert_eq!(info.parameters(), &[100, 101]);
+            assert_eq!(info.parameter(1), Some(101));
+            // Set in the array, but not part of the exception
+            assert_eq!(info.parameter(2), None);
+        });
+
+        with_count(15, |info| {
+            assert_eq!(info.parameters().len(), 15);
+            assert_eq!(info.parameter(14), Some(114));
+        });
+
+        with_count(200, |info| {
+            assert_eq!(info.parameters().len(), 15);
+            assert_eq!(info.parameter(15), None);
+        });
+    }
+
+    fn info_for<R>(record: &mut EXCEPTION_RECORD, f: impl FnOnce(&ExceptionInfo) -> R) -> R {
+        let mut ptrs = EXCEPTION_POINTERS {
+            exception_record: record,
+            context_record: null_mut(),
+        };
+        f(unsafe { ExceptionInfo::from_raw(&mut ptrs as *mut _ as _) })
+    }
+
+    #[test]
+    fn nested_records() {
+        let mut inner: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        inner.exception_code = ExceptionCode::Breakpoint.raw();
+        inner.exception_address = 0x1000 as _;
+
+        let mut outer: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        outer.exception_code = ExceptionCode::AccessViolation.raw();
+        outer.exception_record = &mut inner;
+        outer.number_parameters = 2;
+        outer.exception_information[1] = 0x2000;
+
+        info_for(&mut outer, |info| {
+            let chain: Vec<_> = info.record_chain().collect();
+            assert_eq!(chain.len(), 2);
+            assert_eq!(chain[0].code(), ExceptionCode::AccessViolation);
+            assert_eq!(chain[0].parameters(), &[0, 0x2000]);
+            assert_eq!(chain[1].code(), ExceptionCode::Breakpoint);
+            assert_eq!(chain[1].address(), 0x1000);
+            assert!(chain[1].parameters().is_empty());
+        });
+    }
+
+    #[test]
+    fn cyclic_records() {
+        let mut record: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        record.exception_record = &mut record;
+
+        info_for(&mut record, |info| {
+            assert_eq!(info.record_chain().count(), MAX_RECORD_CHAIN);
+        });
+    }
+}
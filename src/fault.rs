@@ -0,0 +1,215 @@
+// Decoders for the parameters of memory access faults.
+
+// Imports
+use crate::{ExceptionCode, ExceptionInfo};
+
+/// What the faulting instruction was trying to do, from `ExceptionInformation[0]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AvOperation {
+    Read,
+    Write,
+    /// Executing non-executable memory (a DEP violation).
+    Execute,
+    /// A value outside of the documented ones.
+    Other(usize),
+}
+
+impl AvOperation {
+    pub const fn from_raw(value: usize) -> Self {
+        match value {
+            0 => AvOperation::Read,
+            1 => AvOperation::Write,
+            8 => AvOperation::Execute,
+            other => AvOperation::Other(other),
+        }
+    }
+}
+
+/// The details of an access violation or guard page violation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccessViolationInfo {
+    pub operation: AvOperation,
+    /// The address that was being accessed.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::hex"))]
+    pub address: usize,
+}
+
+/// The details of an in-page error, raised when paging memory in failed, such as for a memory
+/// mapped file on a network share that's gone away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InPageErrorInfo {
+    pub operation: AvOperation,
+    /// The address that was being accessed.
+    pub address: usize,
+    /// The `NTSTATUS` the paging I/O failed with.
+    pub nt_status: i32,
+}
+
+impl InPageErrorInfo {
+    /// The name of [`InPageErrorInfo::nt_status`], see [`nt_status_name`].
+    pub fn nt_status_name(&self) -> Option<&'static str> {
+        nt_status_name(self.nt_status)
+    }
+}
+
+macro_rules! nt_statuses {
+    ($($name:ident = $value:literal,)*) => {
+        /// The name of an `NTSTATUS` commonly behind in-page errors, such as
+        /// `"STATUS_DEVICE_DATA_ERROR"`, or `None` if it isn't one of them.
+        pub fn nt_status_name(status: i32) -> Option<&'static str> {
+            match status as u32 {
+                $($value => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+nt_statuses! {
+    STATUS_NO_SUCH_DEVICE = 0xC000_000E,
+    STATUS_NO_MEDIA_IN_DEVICE = 0xC000_0013,
+    STATUS_NO_MEMORY = 0xC000_0017,
+    STATUS_ACCESS_DENIED = 0xC000_0022,
+    STATUS_CRC_ERROR = 0xC000_003F,
+    STATUS_FILE_LOCK_CONFLICT = 0xC000_0054,
+    STATUS_DISK_FULL = 0xC000_007F,
+    STATUS_FILE_INVALID = 0xC000_0098,
+    STATUS_INSUFFICIENT_RESOURCES = 0xC000_009A,
+    STATUS_DEVICE_DATA_ERROR = 0xC000_009C,
+    STATUS_DEVICE_NOT_CONNECTED = 0xC000_009D,
+    STATUS_IO_TIMEOUT = 0xC000_00B5,
+    STATUS_BAD_NETWORK_PATH = 0xC000_00BE,
+    STATUS_UNEXPECTED_NETWORK_ERROR = 0xC000_00C4,
+    STATUS_NETWORK_NAME_DELETED = 0xC000_00C9,
+    STATUS_BAD_NETWORK_NAME = 0xC000_00CC,
+    STATUS_IO_DEVICE_ERROR = 0xC000_0185,
+    STATUS_CONNECTION_DISCONNECTED = 0xC000_020C,
+    STATUS_NETWORK_UNREACHABLE = 0xC000_023C,
+    STATUS_VOLUME_DISMOUNTED = 0xC000_026E,
+    STATUS_DEVICE_HARDWARE_ERROR = 0xC000_0483,
+}
+
+impl ExceptionInfo {
+    /// The details of an `EXCEPTION_ACCESS_VIOLATION`, or `None` for other exceptions.
+    pub fn access_violation(&self) -> Option<AccessViolationInfo> {
+        self.access_info(ExceptionCode::AccessViolation)
+    }
+
+    /// The details of a `STATUS_GUARD_PAGE_VIOLATION`, or `None` for other exceptions.
+    pub fn guard_page(&self) -> Option<AccessViolationInfo> {
+        self.access_info(ExceptionCode::GuardPageViolation)
+    }
+
+    /// The details of an `EXCEPTION_IN_PAGE_ERROR`, or `None` for other exceptions.
+    pub fn in_page_error(&self) -> Option<InPageErrorInfo> {
+        match (self.code(), self.parameters()) {
+            (ExceptionCode::InPageError, &[operation, address, nt_status, ..]) => {
+                Some(InPageErrorInfo {
+                    operation: AvOperation::from_raw(operation),
+                    address,
+                    nt_status: nt_status as i32,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn access_info(&self, code: ExceptionCode) -> Option<AccessViolationInfo> {
+        match self.parameters() {
+            &[operation, address, ..] if self.code() == code => Some(AccessViolationInfo {
+                operation: AvOperation::from_raw(operation),
+                address,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nt_status_name, AccessViolationInfo, AvOperation, InPageErrorInfo};
+    use crate::{ExceptionCode, ExceptionInfo, EXCEPTION_POINTERS, EXCEPTION_RECORD};
+    use std::ptr::null_mut;
+
+    fn with_record<R>(
+        code: ExceptionCode,
+        parameters: &[usize],
+        f: impl FnOnce(&ExceptionInfo) -> R,
+    ) -> R {
+        let mut record: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        record.exception_code = code.raw();
+        record.number_parameters = parameters.len() as u32;
+        record.exception_information[..parameters.len()].copy_from_slice(parameters);
+
+        let mut ptrs = EXCEPTION_POINTERS {
+            exception_record: &mut record,
+            context_record: null_mut(),
+        };
+        f(unsafe { ExceptionInfo::from_raw(&mut ptrs as *mut _ as _) })
+    }
+
+    #[test]
+    fn access_violation_operations() {
+        let av = ExceptionCode::AccessViolation;
+        for (raw, operation) in [
+            (0, AvOperation::Read),
+            (1, AvOperation::Write),
+            (8, AvOperation::Execute),
+            (3, AvOperation::Other(3)),
+        ] {
+            with_record(av, &[raw, 0x1000], |info| {
+                let expected = AccessViolationInfo {
+                    operation,
+                    address: 0x1000,
+                };
+                assert_eq!(info.access_violation(), Some(expected));
+                assert_eq!(info.guard_page(), None);
+            });
+        }
+    }
+
+    #[test]
+    fn guard_page_violation() {
+        with_record(ExceptionCode::GuardPageViolation, &[1, 0x2000], |info| {
+            let expected = AccessViolationInfo {
+                operation: AvOperation::Write,
+                address: 0x2000,
+            };
+            assert_eq!(info.guard_page(), Some(expected));
+            assert_eq!(info.access_violation(), None);
+        });
+    }
+
+    #[test]
+    fn missing_parameters() {
+        with_record(ExceptionCode::AccessViolation, &[0], |info| {
+            assert_eq!(info.access_violation(), None);
+        });
+        with_record(ExceptionCode::Breakpoint, &[0, 0x1000], |info| {
+            assert_eq!(info.access_violation(), None);
+        });
+    }
+
+    #[test]
+    fn in_page_error() {
+        let status = 0xC000_009Cu32 as usize;
+        with_record(ExceptionCode::InPageError, &[0, 0x3000, status], |info| {
+            let expected = InPageErrorInfo {
+                operation: AvOperation::Read,
+                address: 0x3000,
+                nt_status: 0xC000_009Cu32 as i32,
+            };
+            assert_eq!(info.in_page_error(), Some(expected));
+            assert_eq!(expected.nt_status_name(), Some("STATUS_DEVICE_DATA_ERROR"));
+        });
+
+        // The status is missing, and the rest of the array must not be read in its place
+        with_record(ExceptionCode::InPageError, &[0, 0x3000], |info| {
+            assert_eq!(info.in_page_error(), None);
+        });
+
+        assert_eq!(nt_status_name(0), None);
+    }
+}
@@ -0,0 +1,225 @@
+// Imports
+use crate::{modules, teb, ExceptionCode, ExceptionInfo, VehError};
+use std::ops::Range;
+
+/// Conditions an exception has to satisfy before a callback is invoked.
+///
+/// Everything is resolved when the filter is built, so matching never allocates, and callbacks
+/// whose filter doesn't match are skipped without being called at all. Filters combined with
+/// [`Filter::and`] only match if all of their conditions do.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    conditions: Vec<Condition>,
+}
+
+/// Which address [`Filter::address_in_with`] checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressSource {
+    /// The address of the instruction that raised the exception.
+    #[default]
+    Exception,
+    /// The address being read or written, for access violations and in-page errors. All other
+    /// exceptions never match.
+    Accessed,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    /// The exception code is one of these.
+    Codes(Vec<ExceptionCode>),
+    /// The chosen address falls inside one of these ranges.
+    Address {
+        ranges: Vec<Range<usize>>,
+        source: AddressSource,
+    },
+    /// The exception was raised on the thread with this ID.
+    Thread(u32),
+}
+
+impl Filter {
+    /// Matches every exception.
+    pub fn any() -> Self {
+        Filter::default()
+    }
+
+    /// Matches exceptions with the given code.
+    pub fn code(code: ExceptionCode) -> Self {
+        Filter::codes([code])
+    }
+
+    /// Matches exceptions with any of the given codes.
+    pub fn codes(codes: impl IntoIterator<Item = ExceptionCode>) -> Self {
+        Filter::with(Condition::Codes(codes.into_iter().collect()))
+    }
+
+    /// Matches exceptions raised at an address inside `range`.
+    pub fn address_in(range: Range<usize>) -> Self {
+        Filter::address_in_with(range, AddressSource::Exception)
+    }
+
+    /// Matches exceptions where the address picked by `source` falls inside `range`.
+    pub fn address_in_with(range: Range<usize>, source: AddressSource) -> Self {
+        Filter::with(Condition::Address {
+            ranges: vec![range],
+            source,
+        })
+    }
+
+    /// Matches exceptions raised from inside the loaded module `name`, such as `"ntdll.dll"`.
+    ///
+    /// The module is looked up once, here, so it must already be loaded, and the filter keeps
+    /// matching its old address range if it's unloaded later.
+    pub fn module(name: &str) -> Result<Self, VehError> {
+        Ok(Filter::address_ranges(vec![module_range(name)?]))
+    }
+
+    /// Matches exceptions raised on the thread with ID `tid`.
+    pub fn thread(tid: u32) -> Self {
+        Filter::with(Condition::Thread(tid))
+    }
+
+    /// Matches exceptions raised on the calling thread, see [`Filter::thread`].
+    pub fn current_thread() -> Self {
+        Filter::thread(teb::current_thread_id())
+    }
+
+    /// Combines two filters, matching only exceptions both of them match.
+    pub fn and(mut self, other: Filter) -> Self {
+        self.conditions.extend(other.conditions);
+        self
+    }
+
+    /// Whether this filter matches every exception.
+    pub fn is_any(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    // Matches exceptions raised at an address inside any of the ranges
+    pub(crate) fn address_ranges(ranges: Vec<Range<usize>>) -> Self {
+        Filter::with(Condition::Address {
+            ranges,
+            source: AddressSource::Exception,
+        })
+    }
+
+    fn with(condition: Condition) -> Self {
+        Filter {
+            conditions: vec![condition],
+        }
+    }
+
+    /// Whether `info` satisfies every condition of this filter.
+    pub fn matches(&self, info: &ExceptionInfo) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Codes(codes) => codes.contains(&info.code()),
+            Condition::Address { ranges, source } => {
+                let address = match source {
+                    AddressSource::Exception => Some(info.address()),
+                    AddressSource::Accessed => info.accessed_address(),
+                };
+                address.is_some_and(|address| ranges.iter().any(|r| r.contains(&address)))
+            }
+            // Handlers run on the faulting thread, so its ID is simply the current one
+            Condition::Thread(tid) => teb::current_thread_id() == *tid,
+        })
+    }
+}
+
+// Resolves a loaded module to the address range it occupies
+pub(crate) fn module_range(name: &str) -> Result<Range<usize>, VehError> {
+    match modules::find(name) {
+        Some(module) => Ok(module.range()),
+        None => Err(VehError::ModuleNotFound(name.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressSource, Filter};
+    use crate::{modules, ExceptionCode, ExceptionInfo, VehError};
+    use crate::{EXCEPTION_POINTERS, EXCEPTION_RECORD};
+    use std::ffi::c_void;
+    use std::ptr::null_mut;
+
+    fn record(code: ExceptionCode, address: usize, accessed: usize) -> EXCEPTION_RECORD {
+        let mut exception_information = [0; 15];
+        exception_information[1] = accessed;
+
+        EXCEPTION_RECORD {
+            exception_code: code.raw(),
+            exception_flags: 0,
+            exception_record: null_mut(),
+            exception_address: address as *mut c_void,
+            number_parameters: 2,
+            exception_information,
+        }
+    }
+
+    fn matches(filter: &Filter, mut record: EXCEPTION_RECORD) -> bool {
+        let mut ptrs = EXCEPTION_POINTERS {
+            exception_record: &mut record,
+            context_record: null_mut(),
+        };
+        filter.matches(unsafe { ExceptionInfo::from_raw(&mut ptrs as *mut _ as _) })
+    }
+
+    #[test]
+    fn address_range() {
+        let av = ExceptionCode::AccessViolation;
+        let filter = Filter::address_in(0x1000..0x2000);
+        assert!(matches(&filter, record(av, 0x1800, 0)));
+        assert!(!matches(&filter, record(av, 0x2000, 0x1800)));
+
+        let filter = Filter::address_in_with(0x1000..0x2000, AddressSource::Accessed);
+        assert!(matches(&filter, record(av, 0, 0x1800)));
+        assert!(!matches(&filter, record(av, 0x1800, 0x2000)));
+
+        // Only access violations and in-page errors carry an accessed address
+        let breakpoint = ExceptionCode::Breakpoint;
+        assert!(!matches(&filter, record(breakpoint, 0, 0x1800)));
+    }
+
+    #[test]
+    fn module_filter() {
+        let ntdll = modules::find("ntdll.dll").unwrap();
+        let inside = ntdll.base() + ntdll.size() / 2;
+        let outside = ntdll.base() + ntdll.size();
+
+        let filter = Filter::module("NTDLL.DLL").unwrap();
+        assert!(matches(
+            &filter,
+            record(ExceptionCode::AccessViolation, inside, 0)
+        ));
+        assert!(!matches(
+            &filter,
+            record(ExceptionCode::AccessViolation, outside, 0)
+        ));
+
+        assert!(matches!(
+            Filter::module("not-loaded.dll"),
+            Err(VehError::ModuleNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn combined_filters() {
+        let ntdll = modules::find("ntdll.dll").unwrap();
+        let inside = ntdll.base() + ntdll.size() / 2;
+
+        let filter = Filter::module("ntdll.dll")
+            .unwrap()
+            .and(Filter::code(ExceptionCode::AccessViolation));
+        assert!(matches(
+            &filter,
+            record(ExceptionCode::AccessViolation, inside, 0)
+        ));
+        assert!(!matches(
+            &filter,
+            record(ExceptionCode::Breakpoint, inside, 0)
+        ));
+        assert!(!matches(
+            &filter,
+            record(ExceptionCode::AccessViolation, 0, 0)
+        ));
+    }
+}
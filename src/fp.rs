@@ -0,0 +1,317 @@
+//! Trapping floating-point exceptions instead of getting NaNs and infinities.
+//!
+//! Floating-point exceptions are masked by default, so invalid operations quietly produce NaNs.
+//! An [`ExceptionTrap`] unmasks the requested ones in `MXCSR` (and on x86, the x87 control word)
+//! of the current thread, so they raise exceptions, and reports those to a callback deciding how
+//! to carry on.
+
+// Imports
+use crate::dispatch::{self, CallbackGuard};
+use crate::{ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling, VehError, CONTEXT};
+use std::arch::asm;
+use std::marker::PhantomData;
+use std::ops::BitOr;
+
+// The exception flags in `MXCSR` and the x87 status word, with the masks 7 bits above them in
+// `MXCSR`, and at the same bits of the x87 control word
+const FLAGS: u32 = 0x3F;
+const MXCSR_MASK_SHIFT: u32 = 7;
+
+// Offsets into the `FXSAVE` area of a context
+const FXSAVE_FSW: usize = 2;
+const FXSAVE_MXCSR: usize = 24;
+const FXSAVE_XMM: usize = 160;
+
+const FLOAT_CODES: [ExceptionCode; 9] = [
+    ExceptionCode::FloatDenormalOperand,
+    ExceptionCode::FloatDivideByZero,
+    ExceptionCode::FloatInexactResult,
+    ExceptionCode::FloatInvalidOperation,
+    ExceptionCode::FloatOverflow,
+    ExceptionCode::FloatStackCheck,
+    ExceptionCode::FloatUnderflow,
+    ExceptionCode::FloatMultipleFaults,
+    ExceptionCode::FloatMultipleTraps,
+];
+
+/// A set of floating-point exceptions, combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FpExceptionKinds(u32);
+
+impl FpExceptionKinds {
+    pub const INVALID: Self = FpExceptionKinds(1 << 0);
+    pub const DENORMAL: Self = FpExceptionKinds(1 << 1);
+    pub const DIVIDE_BY_ZERO: Self = FpExceptionKinds(1 << 2);
+    pub const OVERFLOW: Self = FpExceptionKinds(1 << 3);
+    pub const UNDERFLOW: Self = FpExceptionKinds(1 << 4);
+    pub const INEXACT: Self = FpExceptionKinds(1 << 5);
+
+    pub const fn empty() -> Self {
+        FpExceptionKinds(0)
+    }
+
+    pub const fn all() -> Self {
+        FpExceptionKinds(FLAGS)
+    }
+
+    /// The set as `MXCSR` exception flags.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for FpExceptionKinds {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        FpExceptionKinds(self.0 | other.0)
+    }
+}
+
+/// Which floating-point exception was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FpFault {
+    InvalidOperation,
+    DenormalOperand,
+    DivideByZero,
+    Overflow,
+    Underflow,
+    Inexact,
+    /// The x87 register stack over- or underflowed.
+    StackCheck,
+}
+
+impl FpFault {
+    // SSE exceptions can be reported as "multiple", in which case the flags say which
+    pub(crate) fn from_status(code: ExceptionCode, mxcsr: u32) -> Option<Self> {
+        Some(match code {
+            ExceptionCode::FloatInvalidOperation => FpFault::InvalidOperation,
+            ExceptionCode::FloatDenormalOperand => FpFault::DenormalOperand,
+            ExceptionCode::FloatDivideByZero => FpFault::DivideByZero,
+            ExceptionCode::FloatOverflow => FpFault::Overflow,
+            ExceptionCode::FloatUnderflow => FpFault::Underflow,
+            ExceptionCode::FloatInexactResult => FpFault::Inexact,
+            ExceptionCode::FloatStackCheck => FpFault::StackCheck,
+            ExceptionCode::FloatMultipleFaults | ExceptionCode::FloatMultipleTraps => {
+                let unmasked = mxcsr & FLAGS & !(mxcsr >> MXCSR_MASK_SHIFT);
+                match unmasked.trailing_zeros() {
+                    0 => FpFault::InvalidOperation,
+                    1 => FpFault::DenormalOperand,
+                    2 => FpFault::DivideByZero,
+                    3 => FpFault::Overflow,
+                    4 => FpFault::Underflow,
+                    5 => FpFault::Inexact,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// A floating-point exception caught by an [`ExceptionTrap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FpEvent {
+    /// The instruction that raised it.
+    pub ip: usize,
+    pub fault: FpFault,
+}
+
+/// How to carry on after an [`FpEvent`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FpAction {
+    /// Abort the process.
+    Abort,
+    /// Leave the exception to the handlers after this one.
+    Pass,
+    /// Skip the faulting instruction, decoding it to find its length.
+    #[cfg(feature = "iced")]
+    Skip,
+    /// Skip the faulting instruction, which is this many bytes long.
+    SkipBytes(usize),
+    /// Skip the faulting instruction (this many bytes long) as if it had stored `value` in the
+    /// low lane of the `xmm` register with this index.
+    Substitute { xmm: usize, value: f64, len: usize },
+}
+
+/// Unmasks floating-point exceptions on the current thread, restoring the previous masks when
+/// dropped. It must be dropped on the thread it was created on.
+pub struct ExceptionTrap {
+    mxcsr: u32,
+    #[cfg(target_arch = "x86")]
+    control_word: u16,
+    _callback: CallbackGuard,
+    // The masks belong to this thread
+    _thread: PhantomData<*const ()>,
+}
+
+impl ExceptionTrap {
+    /// Unmasks `kinds` on the current thread, calling `callback` whenever one of them is raised
+    /// there.
+    pub fn enable<F>(kinds: FpExceptionKinds, callback: F) -> Result<Self, VehError>
+    where
+        F: Fn(FpEvent) -> FpAction + Send + Sync + 'static,
+    {
+        let filter = Filter::codes(FLOAT_CODES).and(Filter::current_thread());
+        let guard = dispatch::register_closure(filter, move |info| trapped(info, &callback))?;
+
+        // Flags already set would raise as soon as they're unmasked
+        let mxcsr = unsafe { get_mxcsr() };
+        unsafe { set_mxcsr(mxcsr & !FLAGS & !(kinds.bits() << MXCSR_MASK_SHIFT)) };
+
+        #[cfg(target_arch = "x86")]
+        let control_word = unsafe {
+            let control_word = get_control_word();
+            asm!("fnclex", options(nomem, nostack));
+            set_control_word(control_word & !(kinds.bits() as u16));
+            control_word
+        };
+
+        Ok(ExceptionTrap {
+            mxcsr,
+            #[cfg(target_arch = "x86")]
+            control_word,
+            _callback: guard,
+            _thread: PhantomData,
+        })
+    }
+}
+
+impl Drop for ExceptionTrap {
+    fn drop(&mut self) {
+        unsafe {
+            set_mxcsr(self.mxcsr & !FLAGS);
+            #[cfg(target_arch = "x86")]
+            {
+                asm!("fnclex", options(nomem, nostack));
+                set_control_word(self.control_word);
+            }
+        }
+    }
+}
+
+fn trapped(info: &mut ExceptionInfo, callback: &impl Fn(FpEvent) -> FpAction) -> Handling {
+    let code = info.code();
+    let context = unsafe { &mut *(info.context_ptr() as *mut CONTEXT) };
+    let fault = match FpFault::from_status(code, mxcsr(context)) {
+        Some(fault) => fault,
+        None => return Handling::ContinueSearch,
+    };
+
+    let event = FpEvent {
+        ip: context.ip(),
+        fault,
+    };
+    match callback(event) {
+        FpAction::Abort => std::process::abort(),
+        FpAction::Pass => return Handling::ContinueSearch,
+        #[cfg(feature = "iced")]
+        FpAction::Skip => {
+            if context.skip_instruction().is_err() {
+                return Handling::ContinueSearch;
+            }
+        }
+        FpAction::SkipBytes(len) => context.skip_bytes(len),
+        FpAction::Substitute { xmm, value, len } => {
+            let offset = FXSAVE_XMM + xmm * 16;
+            fxsave(context)[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            context.skip_bytes(len);
+        }
+    }
+
+    // The flags that raised it would otherwise stay set
+    clear_flags(context);
+    Handling::ContinueExecution
+}
+
+fn fxsave(context: &mut CONTEXT) -> &mut [u8; 512] {
+    #[cfg(target_arch = "x86")]
+    return &mut context.extended_registers;
+    #[cfg(target_arch = "x86_64")]
+    return &mut context.flt_save;
+}
+
+pub(crate) fn mxcsr(context: &mut CONTEXT) -> u32 {
+    let bytes = &fxsave(context)[FXSAVE_MXCSR..FXSAVE_MXCSR + 4];
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn clear_flags(context: &mut CONTEXT) {
+    let mxcsr = mxcsr(context) & !FLAGS;
+    fxsave(context)[FXSAVE_MXCSR..FXSAVE_MXCSR + 4].copy_from_slice(&mxcsr.to_le_bytes());
+    #[cfg(target_arch = "x86_64")]
+    {
+        context.mx_csr = mxcsr;
+    }
+
+    // The x87 status word, along with its summary and busy bits
+    let status = &mut fxsave(context)[FXSAVE_FSW];
+    *status &= !(FLAGS as u8 | 0x80);
+    #[cfg(target_arch = "x86")]
+    {
+        context.float_save[4] &= !(FLAGS as u8 | 0x80);
+    }
+}
+
+unsafe fn get_mxcsr() -> u32 {
+    let mut mxcsr = 0u32;
+    asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack));
+    mxcsr
+}
+
+unsafe fn set_mxcsr(mxcsr: u32) {
+    asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack));
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn get_control_word() -> u16 {
+    let mut control_word = 0u16;
+    asm!("fnstcw [{}]", in(reg) &mut control_word, options(nostack));
+    control_word
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn set_control_word(control_word: u16) {
+    asm!("fldcw [{}]", in(reg) &control_word, options(nostack));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExceptionTrap, FpAction, FpExceptionKinds, FpFault};
+    use crate::dispatch;
+    use std::arch::asm;
+    use std::sync::Mutex;
+
+    fn divide(a: f64, b: f64) -> f64 {
+        let result;
+        unsafe { asm!("divsd xmm0, xmm1", inout("xmm0") a => result, in("xmm1") b) };
+        result
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        static FAULTS: Mutex<Vec<FpFault>> = Mutex::new(Vec::new());
+        let _serial = dispatch::tests::serial();
+
+        let trap = ExceptionTrap::enable(FpExceptionKinds::DIVIDE_BY_ZERO, |event| {
+            FAULTS.lock().unwrap().push(event.fault);
+            FpAction::Substitute {
+                xmm: 0,
+                value: 42.0,
+                len: 4,
+            }
+        })
+        .unwrap();
+        assert_eq!(divide(1.0, 0.0), 42.0);
+        assert_eq!(divide(1.0, 2.0), 0.5);
+        drop(trap);
+
+        assert_eq!(*FAULTS.lock().unwrap(), [FpFault::DivideByZero]);
+        assert_eq!(divide(1.0, 0.0), f64::INFINITY);
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,293 @@
+//! Watching memory accesses with guard pages.
+//!
+//! A [`GuardedRegion`] adds `PAGE_GUARD` to the pages covering a range, so the first access to
+//! each raises `STATUS_GUARD_PAGE_VIOLATION`, after which the system removes the guard again. The
+//! crate handles that exception by reporting the access, letting the thread
+//! [single-step](crate::step) over the instruction, and putting the guard back afterwards.
+//!
+//! While the guard is off for one thread's step, accesses by other threads to the same page go
+//! unnoticed. The guard is only put back once every thread that tripped it has finished stepping.
+
+// Imports
+use crate::dispatch::{self, CallbackGuard};
+use crate::memory::{self, PAGE_GUARD, PAGE_SIZE};
+use crate::{
+    step, teb, AvOperation, ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling, VehError,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// An access to memory watched by a [`GuardedRegion`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessEvent {
+    pub operation: AvOperation,
+    /// The address that was accessed.
+    pub address: usize,
+    /// The instruction that accessed it.
+    pub ip: usize,
+    pub thread_id: u32,
+}
+
+/// Why a [`GuardedRegion`] couldn't be set up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GuardError {
+    /// The range is empty.
+    Empty,
+    /// Part of the range isn't committed memory.
+    NotCommitted,
+    /// Part of the range is a guard page already, such as from another region or a thread stack.
+    AlreadyGuarded,
+    /// The guard couldn't be applied, with this `GetLastError` code.
+    Protect(u32),
+    /// The region's callback couldn't be registered with the dispatcher.
+    Dispatch(VehError),
+}
+
+impl fmt::Display for GuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardError::Empty => f.write_str("the range is empty"),
+            GuardError::NotCommitted => f.write_str("the range is not committed memory"),
+            GuardError::AlreadyGuarded => f.write_str("the range holds guard pages already"),
+            GuardError::Protect(code) => write!(f, "failed to apply the guard (error {code})"),
+            GuardError::Dispatch(error) => write!(f, "failed to register the callback: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for GuardError {}
+
+impl From<VehError> for GuardError {
+    fn from(error: VehError) -> Self {
+        GuardError::Dispatch(error)
+    }
+}
+
+thread_local! {
+    // The region the current thread is stepping over an access to, by address, or 0
+    static STEPPING: Cell<usize> = const { Cell::new(0) };
+}
+
+struct State {
+    range: Range<usize>,
+    // The first guarded page, and the original protection of each one
+    base: usize,
+    protections: Box<[u32]>,
+    guard: Mutex<Guard>,
+}
+
+struct Guard {
+    // Threads stepping over an access, and the pages whose guard is off until they're done
+    stepping: usize,
+    tripped: Box<[bool]>,
+    removed: bool,
+}
+
+impl State {
+    fn pages(&self) -> Range<usize> {
+        self.base..self.base + self.protections.len() * PAGE_SIZE
+    }
+
+    // Puts the guard back on the pages that tripped it
+    fn rearm(&self, guard: &mut Guard) {
+        for (page, tripped) in guard.tripped.iter_mut().enumerate() {
+            if std::mem::take(tripped) {
+                let address = self.base + page * PAGE_SIZE;
+                let protect = self.protections[page] | PAGE_GUARD;
+                let _ = unsafe { memory::set_protection(address, PAGE_SIZE, protect) };
+            }
+        }
+    }
+}
+
+/// A range of memory whose accesses are reported, restoring its original protection when dropped.
+pub struct GuardedRegion {
+    state: Arc<State>,
+    // Taken on drop, once no thread is stepping anymore
+    callback: Option<CallbackGuard>,
+}
+
+impl GuardedRegion {
+    /// Guards the pages covering `range`, calling `callback` for every access to it from any
+    /// thread.
+    ///
+    /// Accesses to the rest of those pages are resumed without calling the callback, and accesses
+    /// made while another thread is stepping over one aren't reported.
+    pub fn watch(range: Range<usize>, callback: fn(AccessEvent)) -> Result<Self, GuardError> {
+        if range.is_empty() {
+            return Err(GuardError::Empty);
+        }
+
+        let base = range.start & !(PAGE_SIZE - 1);
+        let protections = (base..range.end)
+            .step_by(PAGE_SIZE)
+            .map(|page| match memory::protection(page) {
+                Some(protect) if protect & PAGE_GUARD != 0 => Err(GuardError::AlreadyGuarded),
+                Some(protect) => Ok(protect),
+                None => Err(GuardError::NotCommitted),
+            })
+            .collect::<Result<Box<[u32]>, _>>()?;
+
+        let state = Arc::new(State {
+            range,
+            base,
+            guard: Mutex::new(Guard {
+                stepping: 0,
+                tripped: vec![false; protections.len()].into(),
+                removed: false,
+            }),
+            protections,
+        });
+
+        let filter = Filter::code(ExceptionCode::GuardPageViolation);
+        let shared = state.clone();
+        let guard =
+            dispatch::register_closure(filter, move |info| tripped(info, &shared, callback))?;
+
+        let region = GuardedRegion {
+            state,
+            callback: Some(guard),
+        };
+        for (page, &protect) in region.state.protections.iter().enumerate() {
+            let address = base + page * PAGE_SIZE;
+            unsafe { memory::set_protection(address, PAGE_SIZE, protect | PAGE_GUARD) }
+                .map_err(GuardError::Protect)?;
+        }
+        Ok(region)
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.state.range.clone()
+    }
+}
+
+impl Drop for GuardedRegion {
+    fn drop(&mut self) {
+        let state = &self.state;
+        state
+            .guard
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .removed = true;
+
+        // Threads still stepping would otherwise have their single-step go unhandled
+        loop {
+            let guard = state.guard.lock().unwrap_or_else(|e| e.into_inner());
+            if guard.stepping == 0 {
+                break;
+            }
+            drop(guard);
+            std::thread::yield_now();
+        }
+
+        for (page, &protect) in state.protections.iter().enumerate() {
+            let address = state.base + page * PAGE_SIZE;
+            let _ = unsafe { memory::set_protection(address, PAGE_SIZE, protect) };
+        }
+        self.callback.take();
+    }
+}
+
+fn tripped(info: &mut ExceptionInfo, state: &Arc<State>, callback: fn(AccessEvent)) -> Handling {
+    let access = match info.guard_page() {
+        Some(access) if state.pages().contains(&access.address) => access,
+        _ => return Handling::ContinueSearch,
+    };
+
+    let mut guard = state.guard.lock().unwrap_or_else(|e| e.into_inner());
+    if guard.removed {
+        return Handling::ContinueExecution;
+    }
+    guard.tripped[(access.address - state.base) / PAGE_SIZE] = true;
+
+    // An instruction touching two guarded pages trips them one after the other, while it's
+    // already stepping
+    let id = Arc::as_ptr(state) as usize;
+    let stepping = STEPPING.with(|stepping| stepping.replace(id)) == id;
+    if !stepping {
+        guard.stepping += 1;
+        let shared = state.clone();
+        step::single_step(info.context_mut(), move |_| stepped(&shared));
+    }
+    drop(guard);
+
+    if state.range.contains(&access.address) {
+        callback(AccessEvent {
+            operation: access.operation,
+            address: access.address,
+            ip: info.context().ip(),
+            thread_id: teb::current_thread_id(),
+        });
+    }
+    Handling::ContinueExecution
+}
+
+fn stepped(state: &State) {
+    STEPPING.with(|stepping| stepping.set(0));
+
+    let mut guard = state.guard.lock().unwrap_or_else(|e| e.into_inner());
+    guard.stepping -= 1;
+    if guard.stepping == 0 && !guard.removed {
+        state.rearm(&mut guard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessEvent, GuardError, GuardedRegion};
+    use crate::memory::PAGE_SIZE;
+    use crate::{dispatch, AvOperation};
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<AccessEvent>> = Mutex::new(Vec::new());
+
+    fn record(event: AccessEvent) {
+        // Reserved up front, so this doesn't touch the heap
+        EVENTS.lock().unwrap().push(event);
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let _serial = dispatch::tests::serial();
+        EVENTS.lock().unwrap().reserve(16);
+
+        // A page of its own in the middle of the buffer, so nothing else shares it
+        let mut buffer = vec![0u8; PAGE_SIZE * 3];
+        let page = (buffer.as_mut_ptr() as usize + PAGE_SIZE) & !(PAGE_SIZE - 1);
+        let watched = page + 0x10..page + 0x20;
+        let region = GuardedRegion::watch(watched.clone(), record).unwrap();
+        assert_eq!(
+            GuardedRegion::watch(watched.clone(), record).err(),
+            Some(GuardError::AlreadyGuarded)
+        );
+
+        unsafe {
+            // Outside of the range, but on the same page
+            std::ptr::write_volatile(page as *mut u8, 1);
+            std::ptr::write_volatile((page + 0x18) as *mut u8, 2);
+            assert_eq!(std::ptr::read_volatile((page + 0x14) as *const u8), 0);
+            assert_eq!(std::ptr::read_volatile((page + 0x18) as *const u8), 2);
+        }
+        drop(region);
+
+        let events: Vec<_> = EVENTS
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|event| (event.operation, event.address))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (AvOperation::Write, page + 0x18),
+                (AvOperation::Read, page + 0x14),
+                (AvOperation::Read, page + 0x18),
+            ]
+        );
+        assert_eq!(buffer[page - buffer.as_ptr() as usize], 1);
+        assert!(!dispatch::is_installed());
+    }
+}
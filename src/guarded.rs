@@ -0,0 +1,176 @@
+//! Integer division returning faults as errors instead of raising them.
+//!
+//! Each division runs as a short asm sequence that loads the address of its `div`/`idiv` and of
+//! the instruction after it into `r11` and `r10`. A vectored handler, registered once on first
+//! use, recognizes a divide fault at the address in `r11`, records the fault in `rcx`, and resumes
+//! at `r10`. Dividing therefore costs nothing beyond the instruction itself unless it faults.
+//!
+//! Only x64 has 64-bit division instructions, so this is only available there.
+
+// Imports
+use crate::{ExceptionCode, ExceptionInfo, Handling, Order, Veh, CONTEXT};
+use std::arch::asm;
+use std::ffi::c_void;
+use std::fmt;
+use std::sync::Once;
+
+const NO_FAULT: usize = 0;
+const DIVIDE_BY_ZERO: usize = 1;
+const OVERFLOW: usize = 2;
+
+// The most bytes a division instruction takes, bounding the distance between the two addresses
+const MAX_DIVIDE_LEN: u64 = 4;
+
+/// Why a guarded division failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArithmeticFault {
+    /// The divisor was zero.
+    DivideByZero,
+    /// The quotient doesn't fit, as for `i64::MIN / -1`.
+    Overflow,
+}
+
+impl fmt::Display for ArithmeticFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArithmeticFault::DivideByZero => f.write_str("attempted to divide by zero"),
+            ArithmeticFault::Overflow => f.write_str("the quotient overflowed"),
+        }
+    }
+}
+
+impl std::error::Error for ArithmeticFault {}
+
+/// `a / b`, or the fault dividing raised.
+pub fn div(a: i64, b: i64) -> Result<i64, ArithmeticFault> {
+    signed(a, b).map(|(quotient, _)| quotient)
+}
+
+/// `a % b`, or the fault dividing raised.
+pub fn rem(a: i64, b: i64) -> Result<i64, ArithmeticFault> {
+    signed(a, b).map(|(_, remainder)| remainder)
+}
+
+/// `a / b`, or [`ArithmeticFault::DivideByZero`].
+pub fn div_u64(a: u64, b: u64) -> Result<u64, ArithmeticFault> {
+    unsigned(a, b).map(|(quotient, _)| quotient)
+}
+
+/// `a % b`, or [`ArithmeticFault::DivideByZero`].
+pub fn rem_u64(a: u64, b: u64) -> Result<u64, ArithmeticFault> {
+    unsigned(a, b).map(|(_, remainder)| remainder)
+}
+
+fn signed(a: i64, b: i64) -> Result<(i64, i64), ArithmeticFault> {
+    install();
+
+    let (quotient, remainder, fault);
+    unsafe {
+        asm!(
+            "lea r11, [rip + 2f]",
+            "lea r10, [rip + 3f]",
+            "cqo",
+            "2: idiv {b}",
+            "3:",
+            b = in(reg) b,
+            inout("rax") a => quotient,
+            out("rdx") remainder,
+            inout("rcx") NO_FAULT => fault,
+            out("r10") _,
+            out("r11") _,
+            options(nostack),
+        )
+    };
+    result(fault).map(|()| (quotient, remainder))
+}
+
+fn unsigned(a: u64, b: u64) -> Result<(u64, u64), ArithmeticFault> {
+    install();
+
+    let (quotient, remainder, fault);
+    unsafe {
+        asm!(
+            "lea r11, [rip + 2f]",
+            "lea r10, [rip + 3f]",
+            "xor edx, edx",
+            "2: div {b}",
+            "3:",
+            b = in(reg) b,
+            inout("rax") a => quotient,
+            out("rdx") remainder,
+            inout("rcx") NO_FAULT => fault,
+            out("r10") _,
+            out("r11") _,
+            options(nostack),
+        )
+    };
+    result(fault).map(|()| (quotient, remainder))
+}
+
+fn result(fault: usize) -> Result<(), ArithmeticFault> {
+    match fault {
+        DIVIDE_BY_ZERO => Err(ArithmeticFault::DivideByZero),
+        OVERFLOW => Err(ArithmeticFault::Overflow),
+        _ => Ok(()),
+    }
+}
+
+// Registers the handler the first time, keeping it registered for good
+fn install() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| std::mem::forget(unsafe { Veh::<c_void>::add(Order::First, handler) }));
+}
+
+unsafe extern "system" fn handler(ptrs: *mut c_void) -> i32 {
+    let info = ExceptionInfo::from_raw(ptrs);
+    let fault = match info.code() {
+        ExceptionCode::IntegerDivideByZero => DIVIDE_BY_ZERO,
+        ExceptionCode::IntegerOverflow => OVERFLOW,
+        _ => return Handling::ContinueSearch.raw(),
+    };
+
+    let context = &mut *(info.context_ptr() as *mut CONTEXT);
+    let guarded = context.rip == context.r11
+        && context.r10 > context.r11
+        && context.r10 - context.r11 <= MAX_DIVIDE_LEN;
+    if !guarded {
+        return Handling::ContinueSearch.raw();
+    }
+
+    context.rcx = fault as u64;
+    context.rip = context.r10;
+    Handling::ContinueExecution.raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{div, div_u64, rem, rem_u64, ArithmeticFault};
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    #[test]
+    fn faults() {
+        assert_eq!(div(7, -2), Ok(-3));
+        assert_eq!(rem(7, -2), Ok(1));
+        assert_eq!(div_u64(u64::MAX, 2), Ok(u64::MAX / 2));
+        assert_eq!(rem_u64(7, 4), Ok(3));
+
+        assert_eq!(div(black_box(1), 0), Err(ArithmeticFault::DivideByZero));
+        assert_eq!(rem_u64(black_box(1), 0), Err(ArithmeticFault::DivideByZero));
+        assert_eq!(div(i64::MIN, -1), Err(ArithmeticFault::Overflow));
+        assert_eq!(div(17, 5), Ok(3));
+    }
+
+    #[test]
+    fn fast_path() {
+        let start = Instant::now();
+        let mut sum = 0i64;
+        for i in 1..1_000_000 {
+            sum = sum.wrapping_add(div(black_box(i * 7), black_box(i)).unwrap());
+        }
+        assert_eq!(sum, 7 * 999_999);
+
+        // Nowhere near what this should take, only catching per-call registration or faults
+        assert!(start.elapsed().as_secs() < 5);
+    }
+}
@@ -0,0 +1,311 @@
+//! Function hooks that never modify code, by making the target's page inaccessible.
+//!
+//! A [`VehHook`] sets the page its target lives on to `PAGE_NOACCESS`. Calling the target then
+//! raises an access violation at its address, and the crate resumes the thread at the detour
+//! instead. Any other access to the page (other code on it, or data read from it) gets the
+//! original protection back for a single step of the thread, after which the page is made
+//! inaccessible again.
+//!
+//! Hooks on the same page share that bookkeeping. While a thread is stepping through a page,
+//! calls to targets on it from other threads aren't redirected.
+
+// Imports
+use crate::dispatch::{self, CallbackGuard};
+use crate::memory::{self, PAGE_SIZE};
+use crate::threads::{self, ThreadError};
+use crate::{
+    step, AvOperation, ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling, VehError,
+};
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::fmt;
+use std::sync::Mutex;
+
+const PAGE_NOACCESS: u32 = 0x01;
+
+// The most pages one instruction may need back at once, such as code on one reading from another
+const MAX_RESTORED: usize = 4;
+
+/// Why a [`VehHook`] couldn't be installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HookError {
+    /// The target is hooked already.
+    AlreadyHooked,
+    /// The target isn't in committed memory.
+    NotCommitted,
+    /// The target's page couldn't be protected, with this `GetLastError` code.
+    Protect(u32),
+    /// The hooks' callback couldn't be registered with the dispatcher.
+    Dispatch(VehError),
+    /// The other threads couldn't be suspended around installing the hook.
+    Suspend(ThreadError),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookError::AlreadyHooked => f.write_str("the target is already hooked"),
+            HookError::NotCommitted => f.write_str("the target is not in committed memory"),
+            HookError::Protect(code) => write!(f, "failed to protect the target (error {code})"),
+            HookError::Dispatch(error) => write!(f, "failed to register the callback: {error}"),
+            HookError::Suspend(error) => write!(f, "failed to suspend other threads: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+impl From<VehError> for HookError {
+    fn from(error: VehError) -> Self {
+        HookError::Dispatch(error)
+    }
+}
+
+impl From<ThreadError> for HookError {
+    fn from(error: ThreadError) -> Self {
+        HookError::Suspend(error)
+    }
+}
+
+struct Page {
+    base: usize,
+    protect: u32,
+    // Targets on the page and their detours
+    hooks: Vec<(usize, usize)>,
+    // Threads stepping with the original protection back
+    stepping: usize,
+}
+
+struct Hooks {
+    pages: Vec<Page>,
+    // The dispatcher callback, registered while there are any hooks
+    callback: Option<CallbackGuard>,
+}
+
+static HOOKS: Mutex<Hooks> = Mutex::new(Hooks {
+    pages: Vec::new(),
+    callback: None,
+});
+
+thread_local! {
+    // The pages the current thread is stepping through with their protection restored
+    static RESTORED: Cell<[usize; MAX_RESTORED]> = const { Cell::new([0; MAX_RESTORED]) };
+}
+
+fn hooks() -> std::sync::MutexGuard<'static, Hooks> {
+    HOOKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A hook redirecting calls to a function, removed again when dropped.
+pub struct VehHook {
+    target: usize,
+}
+
+impl VehHook {
+    /// Redirects every call to `target` from any thread to `detour`.
+    ///
+    /// # Safety
+    /// `detour` must have the same signature and calling convention as `target`. Nothing run
+    /// while handling exceptions, such as the crate itself or other handlers, may be on the same
+    /// page as `target`, as each of its instructions then raises an exception of its own.
+    pub unsafe fn install(target: *const c_void, detour: *const c_void) -> Result<Self, HookError> {
+        Self::install_with(target, detour, false)
+    }
+
+    /// As [`install`](Self::install), but with every other thread
+    /// [suspended](threads::suspend_others) while the target's page is protected, so none of them
+    /// is part way through it at the time.
+    ///
+    /// # Safety
+    /// As [`install`](Self::install).
+    pub unsafe fn install_suspended(
+        target: *const c_void,
+        detour: *const c_void,
+    ) -> Result<Self, HookError> {
+        Self::install_with(target, detour, true)
+    }
+
+    unsafe fn install_with(
+        target: *const c_void,
+        detour: *const c_void,
+        suspend: bool,
+    ) -> Result<Self, HookError> {
+        let (target, detour) = (target as usize, detour as usize);
+        let base = target & !(PAGE_SIZE - 1);
+
+        let mut hooks = hooks();
+        if hooks
+            .pages
+            .iter()
+            .any(|page| page.hooks.iter().any(|h| h.0 == target))
+        {
+            return Err(HookError::AlreadyHooked);
+        }
+
+        if !hooks.pages.iter().any(|page| page.base == base) {
+            let protect = memory::protection(base).ok_or(HookError::NotCommitted)?;
+            if hooks.callback.is_none() {
+                let filter = Filter::code(ExceptionCode::AccessViolation);
+                hooks.callback = Some(dispatch::register(filter, faulted)?);
+            }
+
+            // Allocated before suspending, as a suspended thread may hold the heap's lock
+            hooks.pages.reserve(1);
+            let protected = match suspend.then(threads::suspend_others).transpose() {
+                Ok(_others) => memory::set_protection(base, PAGE_SIZE, PAGE_NOACCESS)
+                    .map_err(HookError::Protect),
+                Err(error) => Err(HookError::Suspend(error)),
+            };
+            if let Err(error) = protected {
+                if hooks.pages.is_empty() {
+                    hooks.callback.take();
+                }
+                return Err(error);
+            }
+            hooks.pages.push(Page {
+                base,
+                protect,
+                hooks: Vec::new(),
+                stepping: 0,
+            });
+        }
+
+        let page = hooks.pages.iter_mut().find(|page| page.base == base);
+        page.unwrap().hooks.push((target, detour));
+        Ok(VehHook { target })
+    }
+
+    pub fn target(&self) -> *const c_void {
+        self.target as _
+    }
+}
+
+impl Drop for VehHook {
+    fn drop(&mut self) {
+        let mut hooks = hooks();
+        let index = hooks
+            .pages
+            .iter()
+            .position(|page| page.hooks.iter().any(|h| h.0 == self.target));
+        let index = match index {
+            Some(index) => index,
+            None => return,
+        };
+
+        let page = &mut hooks.pages[index];
+        page.hooks.retain(|h| h.0 != self.target);
+        if page.hooks.is_empty() {
+            // Threads still stepping find the page gone, and leave its protection alone
+            let page = hooks.pages.swap_remove(index);
+            let _ = unsafe { memory::set_protection(page.base, PAGE_SIZE, page.protect) };
+        }
+        if hooks.pages.is_empty() {
+            hooks.callback.take();
+        }
+    }
+}
+
+fn faulted(info: &mut ExceptionInfo) -> Handling {
+    let access = match info.access_violation() {
+        Some(access) => access,
+        None => return Handling::ContinueSearch,
+    };
+
+    let mut hooks = hooks();
+    let base = access.address & !(PAGE_SIZE - 1);
+    let page = match hooks.pages.iter_mut().find(|page| page.base == base) {
+        Some(page) => page,
+        None => return Handling::ContinueSearch,
+    };
+
+    // Without NX, fetching from the page is reported as a read, so a fault at the faulting
+    // instruction itself is taken as executing it too
+    let ip = info.context().ip();
+    if ip == access.address || access.operation == AvOperation::Execute {
+        if let Some(&(_, detour)) = page.hooks.iter().find(|h| h.0 == ip) {
+            info.context_mut().set_ip(detour);
+            return Handling::ContinueExecution;
+        }
+    }
+
+    // Anything else gets to run with the original protection for one instruction
+    let mut restored = RESTORED.with(Cell::get);
+    let free = match restored.iter_mut().find(|slot| **slot == 0) {
+        Some(free) => free,
+        None => return Handling::ContinueSearch,
+    };
+    if unsafe { memory::set_protection(base, PAGE_SIZE, page.protect) }.is_err() {
+        return Handling::ContinueSearch;
+    }
+
+    *free = base;
+    RESTORED.with(|slots| slots.set(restored));
+    page.stepping += 1;
+    drop(hooks);
+
+    // A previous step of this thread that faulted on another page is replaced, but this one
+    // re-protects every page restored for it
+    step::single_step(info.context_mut(), |_| stepped());
+    Handling::ContinueExecution
+}
+
+fn stepped() {
+    let restored = RESTORED.with(|slots| slots.replace([0; MAX_RESTORED]));
+
+    let mut hooks = hooks();
+    for base in restored.into_iter().filter(|&base| base != 0) {
+        if let Some(page) = hooks.pages.iter_mut().find(|page| page.base == base) {
+            // A page hooked again since would start out with none
+            page.stepping = page.stepping.saturating_sub(1);
+            if page.stepping == 0 {
+                let _ = unsafe { memory::set_protection(base, PAGE_SIZE, PAGE_NOACCESS) };
+            }
+        }
+    }
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    use super::{HookError, VehHook};
+    use crate::{dispatch, memory};
+    use std::ffi::c_void;
+
+    extern "C" fn detour() -> u32 {
+        42
+    }
+
+    #[test]
+    fn redirects_target_only() {
+        let _serial = dispatch::tests::serial();
+
+        // The page mustn't hold any code run while handling the exceptions, so the functions are
+        // put on a page of their own: `mov eax, 1; ret` as the target, and `mov eax, 2; ret` as
+        // an unrelated function after it
+        let mut code = [0xCC; 0x16];
+        code[..6].copy_from_slice(&[0xB8, 1, 0, 0, 0, 0xC3]);
+        code[0x10..].copy_from_slice(&[0xB8, 2, 0, 0, 0, 0xC3]);
+        let page = memory::alloc_code(&code).unwrap();
+        let target: extern "C" fn() -> u32 = unsafe { std::mem::transmute(page) };
+        let unrelated: extern "C" fn() -> u32 = unsafe { std::mem::transmute(page + 0x10) };
+
+        let hook = unsafe { VehHook::install(page as _, detour as *const c_void) }.unwrap();
+        assert_eq!(
+            unsafe { VehHook::install(page as _, detour as *const c_void) }.err(),
+            Some(HookError::AlreadyHooked)
+        );
+
+        assert_eq!(target(), 42);
+        assert_eq!(unrelated(), 2);
+        assert_eq!(
+            unsafe { std::ptr::read_volatile((page + 0x11) as *const u8) },
+            2
+        );
+        assert_eq!(target(), 42);
+
+        drop(hook);
+        assert_eq!(target(), 1);
+        assert!(!dispatch::is_installed());
+        unsafe { memory::free_code(page) };
+    }
+}
@@ -0,0 +1,912 @@
+//! Hardware breakpoints, using the x86 debug registers.
+//!
+//! The processor has four debug address registers, `Dr0` to `Dr3`, each watching one address for
+//! execution or data access as configured in `Dr7`, and raising `STATUS_SINGLE_STEP` when it's
+//! hit. A [`HwBreakpoint`] claims one of them, programs it, and calls its callback through the
+//! [dispatcher](crate::dispatch) whenever it's hit.
+//!
+//! Debug registers belong to a thread, so a breakpoint only watches the thread it was set on,
+//! unless it's applied to the others with [`HwBreakpoint::apply_all_threads`], or to threads
+//! created later with [`HwBreakpoint::follow_new_threads`].
+
+// Imports
+use crate::dispatch::{self, CallbackGuard, Registration};
+use crate::raw::{self, ThreadContextError};
+use crate::sync::InFlight;
+use crate::threads::{self, ThreadError};
+use crate::{reentry, teb, ExceptionCode, ExceptionInfo, Filter, Handling, VehError, CONTEXT};
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[cfg(target_arch = "x86")]
+const CONTEXT_DEBUG_REGISTERS: u32 = 0x0001_0010;
+#[cfg(target_arch = "x86_64")]
+const CONTEXT_DEBUG_REGISTERS: u32 = 0x0010_0010;
+
+const SLOT_COUNT: usize = 4;
+
+const THREAD_SUSPEND_RESUME: u32 = 0x0002;
+const THREAD_GET_CONTEXT: u32 = 0x0008;
+const THREAD_SET_CONTEXT: u32 = 0x0010;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentThread() -> *mut c_void;
+    fn OpenThread(access: u32, inherit: i32, thread_id: u32) -> *mut c_void;
+    fn SuspendThread(thread: *mut c_void) -> u32;
+    fn ResumeThread(thread: *mut c_void) -> u32;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+    fn GetThreadContext(thread: *mut c_void, context: *mut CONTEXT) -> i32;
+    fn SetThreadContext(thread: *mut c_void, context: *const CONTEXT) -> i32;
+    fn GetLastError() -> u32;
+}
+
+// The slots claimed by breakpoints, one bit each
+static CLAIMED: AtomicU8 = AtomicU8::new(0);
+
+// Breakpoints following new threads, by slot: their address, and their Dr7 control bits with
+// `FOLLOWED` set, or 0
+const FOLLOWED: usize = 1 << 8;
+static FOLLOW_ADDRESS: [AtomicUsize; SLOT_COUNT] = [const { AtomicUsize::new(0) }; SLOT_COUNT];
+static FOLLOW_CONTROL: [AtomicUsize; SLOT_COUNT] = [const { AtomicUsize::new(0) }; SLOT_COUNT];
+// Bumped every time a slot starts being followed, so threads armed with an earlier breakpoint in
+// the same slot are armed again
+static FOLLOW_GENERATION: [AtomicU32; SLOT_COUNT] = [const { AtomicU32::new(0) }; SLOT_COUNT];
+// Threads currently arming themselves, waited for before a followed breakpoint is cleared
+static REARMING: InFlight = InFlight::new();
+// The dispatcher callback arming threads, registered while any breakpoint is followed
+static REARM_HOOK: Mutex<Option<CallbackGuard>> = Mutex::new(None);
+
+thread_local! {
+    // The generation of each followed slot the current thread was armed with, or 0. Kept per
+    // thread rather than by thread ID, as IDs are reused as soon as a thread exits
+    static ARMED: Cell<[u32; SLOT_COUNT]> = const { Cell::new([0; SLOT_COUNT]) };
+}
+
+/// What a [`HwBreakpoint`] watches for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// Executing the instruction at the address. Must be used with [`Size::One`].
+    Execute,
+    /// Writing to the watched bytes.
+    Write,
+    /// Reading or writing the watched bytes.
+    ReadWrite,
+}
+
+/// How many bytes a [`HwBreakpoint`] watches. The address must be aligned to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Size {
+    One,
+    Two,
+    Four,
+    /// Only available on x64.
+    Eight,
+}
+
+impl Size {
+    pub const fn bytes(self) -> usize {
+        match self {
+            Size::One => 1,
+            Size::Two => 2,
+            Size::Four => 4,
+            Size::Eight => 8,
+        }
+    }
+}
+
+/// Why a [`HwBreakpoint`] couldn't be set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HwbpError {
+    /// All four debug registers are already in use.
+    NoFreeSlot,
+    /// The size isn't available for the kind of breakpoint or on this architecture.
+    InvalidSize,
+    /// The address of a data breakpoint isn't aligned to its size.
+    Misaligned,
+    /// Reading or writing the thread's debug registers failed, with this `GetLastError` code.
+    ThreadContext(u32),
+    /// Reading or writing the thread's debug registers through ntdll failed, with this
+    /// `NTSTATUS`.
+    NtStatus(i32),
+    /// The breakpoint's callback couldn't be registered with the dispatcher.
+    Dispatch(VehError),
+}
+
+impl fmt::Display for HwbpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HwbpError::NoFreeSlot => f.write_str("every debug register is already in use"),
+            HwbpError::InvalidSize => f.write_str("the breakpoint size is invalid"),
+            HwbpError::Misaligned => f.write_str("the address is not aligned to the size"),
+            HwbpError::ThreadContext(code) => {
+                write!(
+                    f,
+                    "failed to access the thread's debug registers (error {code})"
+                )
+            }
+            HwbpError::NtStatus(status) => write!(
+                f,
+                "failed to access the thread's debug registers (status {:#010x})",
+                *status as u32
+            ),
+            HwbpError::Dispatch(error) => write!(f, "failed to register the callback: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for HwbpError {}
+
+impl From<VehError> for HwbpError {
+    fn from(error: VehError) -> Self {
+        HwbpError::Dispatch(error)
+    }
+}
+
+/// The outcome of [`HwBreakpoint::apply_all_threads`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AppliedCount {
+    /// Threads the breakpoint was newly applied to.
+    pub applied: usize,
+    /// Threads that exited, couldn't be accessed, or were running one of the crate's handlers.
+    pub skipped: usize,
+}
+
+// Everything needed to program a debug register
+#[derive(Debug, Clone, Copy)]
+struct Watch {
+    slot: usize,
+    address: usize,
+    kind: Kind,
+    size: Size,
+}
+
+impl Watch {
+    // The slot's 4-bit R/W and LEN field in Dr7
+    fn control(&self) -> usize {
+        let rw = match self.kind {
+            Kind::Execute => 0b00,
+            Kind::Write => 0b01,
+            Kind::ReadWrite => 0b11,
+        };
+        let len = match self.size {
+            Size::One => 0b00,
+            Size::Two => 0b01,
+            Size::Four => 0b11,
+            Size::Eight => 0b10,
+        };
+        len << 2 | rw
+    }
+}
+
+/// A hardware breakpoint, removed again when dropped.
+pub struct HwBreakpoint {
+    watch: Watch,
+    // Every thread the breakpoint was applied to, so it can be cleared from all of them
+    threads: Mutex<Vec<u32>>,
+    following: AtomicBool,
+    // Taken on drop, so the callback is gone before the slot can be claimed again
+    callback: Option<CallbackGuard>,
+}
+
+impl HwBreakpoint {
+    /// Sets a breakpoint on the current thread, calling `callback` every time it's hit.
+    ///
+    /// Execution always continues after the callback returns: after the instruction that
+    /// accessed the data for data breakpoints, or with the instruction at `address` for execute
+    /// breakpoints, which doesn't trigger the breakpoint again right away.
+    pub fn set<F>(address: usize, kind: Kind, size: Size, callback: F) -> Result<Self, HwbpError>
+    where
+        F: Fn(&mut ExceptionInfo) + Send + Sync + 'static,
+    {
+        let valid = match kind {
+            Kind::Execute => size == Size::One,
+            _ => size.bytes() <= std::mem::size_of::<usize>(),
+        };
+        if !valid {
+            return Err(HwbpError::InvalidSize);
+        }
+        if !address.is_multiple_of(size.bytes()) {
+            return Err(HwbpError::Misaligned);
+        }
+
+        // Registers enabled by someone else, like a debugger, aren't free either
+        let current = unsafe { get_context(GetCurrentThread())? };
+        let slot = claim(current.dr7 as usize)?;
+        let watch = Watch {
+            slot,
+            address,
+            kind,
+            size,
+        };
+
+        let filter = Filter::code(ExceptionCode::SingleStep);
+        let registered =
+            dispatch::register_closure(filter, move |info| hit(info, &watch, &callback));
+        let breakpoint = HwBreakpoint {
+            watch,
+            threads: Mutex::new(vec![teb::current_thread_id()]),
+            following: AtomicBool::new(false),
+            callback: match registered {
+                Ok(guard) => Some(guard),
+                Err(error) => {
+                    release(slot);
+                    return Err(error.into());
+                }
+            },
+        };
+
+        // Dropping the half-set breakpoint releases everything again
+        unsafe { program(GetCurrentThread(), &watch, true)? };
+        Ok(breakpoint)
+    }
+
+    pub fn address(&self) -> usize {
+        self.watch.address
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.watch.kind
+    }
+
+    pub fn size(&self) -> Size {
+        self.watch.size
+    }
+
+    /// Applies the breakpoint to every other thread in the process as well, using the same debug
+    /// register. Threads created afterwards aren't covered.
+    ///
+    /// Each thread is suspended while its debug registers are programmed. If the register is in
+    /// use by something else on one of them, it's overwritten. Threads running one of the crate's
+    /// handlers are skipped, as they resume with the context of their exception, which would undo
+    /// the change.
+    pub fn apply_all_threads(&self) -> Result<AppliedCount, HwbpError> {
+        let mut threads = self.threads.lock().unwrap_or_else(|e| e.into_inner());
+        let mut count = AppliedCount::default();
+        let own = teb::current_thread_id();
+
+        for thread_id in process_threads()? {
+            if threads.contains(&thread_id) {
+                continue;
+            }
+
+            // Threads can exit at any point, which just leaves nothing to apply to. Checked once
+            // suspended, so it can't enter a handler afterwards
+            let applied = with_thread(thread_id, |thread| unsafe {
+                if thread_id != own && reentry::is_dispatching(thread_id) {
+                    return Ok(false);
+                }
+                program(thread, &self.watch, true).map(|()| true)
+            });
+            match applied {
+                Ok(true) => {
+                    threads.push(thread_id);
+                    count.applied += 1;
+                }
+                Ok(false) | Err(_) => count.skipped += 1,
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Keeps applying the breakpoint to threads that didn't have it yet, the first time each of
+    /// them raises an exception, or calls [`rearm_current_thread`].
+    ///
+    /// A new thread isn't covered until then, so code watching for accesses right from the start
+    /// of a thread should call [`rearm_current_thread`] when it's attached, such as from
+    /// `DllMain` with `DLL_THREAD_ATTACH`.
+    pub fn follow_new_threads(&self) -> Result<(), HwbpError> {
+        let mut hook = REARM_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+        if hook.is_none() {
+            // Arm threads before any other callback can see their exception
+            let registered = Registration::new()
+                .priority(i32::MIN)
+                .observe()
+                .register_closure(|info| {
+                    let _ = rearm(Some(info), false);
+                    Handling::ContinueSearch
+                })?;
+            *hook = Some(registered);
+        }
+
+        let slot = self.watch.slot;
+        FOLLOW_ADDRESS[slot].store(self.watch.address, Ordering::SeqCst);
+        FOLLOW_GENERATION[slot].fetch_add(1, Ordering::SeqCst);
+        FOLLOW_CONTROL[slot].store(self.watch.control() | FOLLOWED, Ordering::SeqCst);
+        self.following.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The debug register used, from 0 for `Dr0` to 3 for `Dr3`.
+    pub fn slot(&self) -> usize {
+        self.watch.slot
+    }
+}
+
+impl Drop for HwBreakpoint {
+    fn drop(&mut self) {
+        if *self.following.get_mut() {
+            self.unfollow();
+        }
+
+        // Threads that exited already have nothing left to clear
+        let threads = self.threads.get_mut().unwrap_or_else(|e| e.into_inner());
+        for &thread_id in threads.iter() {
+            let _ = with_thread(thread_id, |thread| unsafe {
+                program(thread, &self.watch, false)
+            });
+        }
+        self.callback.take();
+        release(self.watch.slot);
+    }
+}
+
+/// A data breakpoint reporting the value written, along with the one it replaced.
+pub struct Watchpoint {
+    breakpoint: HwBreakpoint,
+}
+
+impl Watchpoint {
+    /// Watches the `T` at `target` for writes on the current thread, calling `callback` with the
+    /// old and new value for each one.
+    ///
+    /// Data breakpoints trigger once the write has completed, so the old value is the one seen
+    /// when the watchpoint was set or last hit, kept in a copy. Writes through other means than
+    /// watched threads, and writes of the same value, are reported as part of the next hit.
+    ///
+    /// `T` must be 1, 2, 4 or (on x64) 8 bytes large, and `target` aligned to its size.
+    ///
+    /// # Safety
+    /// `target` must stay valid for reads for as long as the watchpoint exists.
+    pub unsafe fn on_write<T: Copy + 'static>(
+        target: *const T,
+        callback: fn(old: T, new: T, info: &ExceptionInfo),
+    ) -> Result<Self, HwbpError> {
+        let size = match std::mem::size_of::<T>() {
+            1 => Size::One,
+            2 => Size::Two,
+            4 => Size::Four,
+            8 => Size::Eight,
+            _ => return Err(HwbpError::InvalidSize),
+        };
+
+        // Raw pointers aren't `Send`, hence passing the address around instead
+        let address = target as usize;
+        let shadow = AtomicU64::new(to_bits(std::ptr::read_volatile(target)));
+        let breakpoint = HwBreakpoint::set(address, Kind::Write, size, move |info| {
+            let new = unsafe { std::ptr::read_volatile(address as *const T) };
+            let old = shadow.swap(to_bits(new), Ordering::SeqCst);
+            callback(from_bits(old), new, info);
+        })?;
+
+        Ok(Watchpoint { breakpoint })
+    }
+
+    /// The underlying breakpoint, for applying it to other threads.
+    pub fn breakpoint(&self) -> &HwBreakpoint {
+        &self.breakpoint
+    }
+}
+
+// Values of at most 8 bytes, stored in the low bytes of a `u64`
+fn to_bits<T: Copy>(value: T) -> u64 {
+    let mut bits = 0u64;
+    unsafe {
+        let size = std::mem::size_of::<T>();
+        std::ptr::copy_nonoverlapping(
+            &value as *const T as *const u8,
+            &mut bits as *mut _ as _,
+            size,
+        )
+    };
+    bits
+}
+
+fn from_bits<T: Copy>(bits: u64) -> T {
+    unsafe { std::ptr::read_unaligned(&bits as *const u64 as *const T) }
+}
+
+thread_local! {
+    // The target whose hook the current thread is calling through to, or 0
+    static BYPASSED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A hook redirecting calls to a function using an execute breakpoint, leaving its code and page
+/// protection alone. The hook is removed when dropped.
+pub struct HwbpHook {
+    breakpoint: HwBreakpoint,
+}
+
+impl HwbpHook {
+    /// Redirects calls to `target` on the current thread to `detour`.
+    ///
+    /// Like any [`HwBreakpoint`], the hook applies only to the current thread until it's applied
+    /// to others through [`HwbpHook::breakpoint`].
+    ///
+    /// # Safety
+    /// `detour` must have the same signature and calling convention as `target`.
+    pub unsafe fn install(target: *const c_void, detour: *const c_void) -> Result<Self, HwbpError> {
+        let (target, detour) = (target as usize, detour as usize);
+        let breakpoint = HwBreakpoint::set(target, Kind::Execute, Size::One, move |info| {
+            if BYPASSED.with(Cell::get) != target {
+                crate::ContextExt::set_ip(info.context_mut(), detour);
+            }
+        })?;
+
+        Ok(HwbpHook { breakpoint })
+    }
+
+    /// Runs `f` with calls to the target on the current thread going to the target itself, so a
+    /// detour can forward to the original function.
+    ///
+    /// Only this hook is bypassed; calls `f` makes to other hooked functions are still redirected.
+    pub fn call_original<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Restore(usize);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                BYPASSED.with(|bypassed| bypassed.set(self.0));
+            }
+        }
+
+        let target = self.breakpoint.address();
+        let _restore = Restore(BYPASSED.with(|bypassed| bypassed.replace(target)));
+        f()
+    }
+
+    /// The underlying breakpoint, for applying it to other threads.
+    pub fn breakpoint(&self) -> &HwBreakpoint {
+        &self.breakpoint
+    }
+}
+
+impl HwBreakpoint {
+    // Stops arming new threads, and clears the breakpoint from the ones that were
+    fn unfollow(&self) {
+        let slot = self.watch.slot;
+        FOLLOW_CONTROL[slot].store(0, Ordering::SeqCst);
+        REARMING.wait_idle();
+
+        // Any thread may have armed itself, so each one still holding the breakpoint is cleared
+        let threads = process_threads().unwrap_or_default();
+        for thread_id in threads {
+            let _ = with_thread(thread_id, |thread| unsafe {
+                clear_followed(thread, &self.watch)
+            });
+        }
+
+        let mut hook = REARM_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+        if FOLLOW_CONTROL
+            .iter()
+            .all(|control| control.load(Ordering::SeqCst) == 0)
+        {
+            hook.take();
+        }
+    }
+}
+
+/// Applies every breakpoint set to [follow new threads](HwBreakpoint::follow_new_threads) to the
+/// current thread, even if it was already armed before.
+pub fn rearm_current_thread() -> Result<(), HwbpError> {
+    rearm(None, true)
+}
+
+// Arms the current thread with the followed breakpoints it's missing, or all of them if `force`
+fn rearm(info: Option<&mut ExceptionInfo>, force: bool) -> Result<(), HwbpError> {
+    let _rearming = REARMING.enter();
+    let mut armed = match force {
+        true => [0; SLOT_COUNT],
+        false => ARMED.with(Cell::get),
+    };
+
+    let mut watches = [None; SLOT_COUNT];
+    let mut missing = false;
+    for (slot, watch) in watches.iter_mut().enumerate() {
+        let control = FOLLOW_CONTROL[slot].load(Ordering::SeqCst);
+        let generation = FOLLOW_GENERATION[slot].load(Ordering::SeqCst);
+        if control & FOLLOWED != 0 && armed[slot] != generation {
+            let address = FOLLOW_ADDRESS[slot].load(Ordering::SeqCst);
+            *watch = Some((address, control & !FOLLOWED));
+            armed[slot] = generation;
+            missing = true;
+        }
+    }
+    if !missing {
+        return Ok(());
+    }
+
+    let apply_all = |context: &mut CONTEXT| {
+        for (slot, watch) in watches.iter().enumerate() {
+            if let Some(watch) = watch {
+                apply(context, slot, Some(*watch));
+            }
+        }
+    };
+
+    unsafe {
+        let thread = GetCurrentThread();
+        let mut context = get_context(thread)?;
+        apply_all(&mut context);
+        set_context(thread, &context)?;
+    }
+
+    // Continuing after the exception restores its context, debug registers included if it has them
+    if let Some(info) = info.filter(|info| !info.context_ptr().is_null()) {
+        let context = info.context_mut();
+        if context.context_flags & CONTEXT_DEBUG_REGISTERS == CONTEXT_DEBUG_REGISTERS {
+            apply_all(context);
+        }
+    }
+
+    ARMED.with(|stored| stored.set(armed));
+    Ok(())
+}
+
+fn hit(
+    info: &mut ExceptionInfo,
+    watch: &Watch,
+    callback: &impl Fn(&mut ExceptionInfo),
+) -> Handling {
+    let status = 1 << watch.slot;
+    let context = info.context();
+    let ours = context.dr6 as usize & status != 0
+        || (watch.kind == Kind::Execute && info.address() == watch.address);
+    if !ours {
+        return Handling::ContinueSearch;
+    }
+
+    callback(info);
+
+    let context = info.context_mut();
+    context.dr6 = (context.dr6 as usize & !status) as _;
+    // Execute breakpoints fault before the instruction runs, which would just hit them again
+    if watch.kind == Kind::Execute {
+        crate::ContextExt::set_resume_flag(context, true);
+    }
+    Handling::ContinueExecution
+}
+
+// Claims the lowest slot that's neither claimed by another breakpoint nor enabled in `dr7`
+fn claim(dr7: usize) -> Result<usize, HwbpError> {
+    let mut slot = 0;
+    CLAIMED
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |claimed| {
+            slot =
+                (0..SLOT_COUNT).find(|&i| claimed & 1 << i == 0 && dr7 & 0b11 << (i * 2) == 0)?;
+            Some(claimed | 1 << slot)
+        })
+        .map(|_| slot)
+        .map_err(|_| HwbpError::NoFreeSlot)
+}
+
+fn release(slot: usize) {
+    CLAIMED.fetch_and(!(1 << slot), Ordering::SeqCst);
+}
+
+fn debug_register(context: &mut CONTEXT, slot: usize) -> &mut usize {
+    let register = match slot {
+        0 => &mut context.dr0,
+        1 => &mut context.dr1,
+        2 => &mut context.dr2,
+        _ => &mut context.dr3,
+    };
+    // The registers are pointer-sized on both architectures
+    unsafe { &mut *(register as *mut _ as *mut usize) }
+}
+
+// Both go through ntdll directly, falling back to kernel32 if its functions can't be found
+unsafe fn get_context(thread: *mut c_void) -> Result<CONTEXT, HwbpError> {
+    let mut context: CONTEXT = std::mem::zeroed();
+    context.context_flags = CONTEXT_DEBUG_REGISTERS;
+    match raw::nt_get_context_thread(thread, &mut context) {
+        Ok(()) => Ok(context),
+        Err(ThreadContextError::Status(status)) => Err(HwbpError::NtStatus(status)),
+        Err(_) => match GetThreadContext(thread, &mut context) {
+            0 => Err(HwbpError::ThreadContext(GetLastError())),
+            _ => Ok(context),
+        },
+    }
+}
+
+// Only writes the debug registers if `context` comes from `get_context`, as its `context_flags`
+// are still limited to them
+unsafe fn set_context(thread: *mut c_void, context: &CONTEXT) -> Result<(), HwbpError> {
+    match raw::nt_set_context_thread(thread, context) {
+        Ok(()) => Ok(()),
+        Err(ThreadContextError::Status(status)) => Err(HwbpError::NtStatus(status)),
+        Err(_) => match SetThreadContext(thread, context) {
+            0 => Err(HwbpError::ThreadContext(GetLastError())),
+            _ => Ok(()),
+        },
+    }
+}
+
+// Enables debug register `slot` with an address and control bits, or clears it with `None`
+fn apply(context: &mut CONTEXT, slot: usize, watch: Option<(usize, usize)>) {
+    let mut dr7 = context.dr7 as usize & !(0b11 << (slot * 2) | 0b1111 << (16 + slot * 4));
+    let address = match watch {
+        Some((address, control)) => {
+            dr7 |= 1 << (slot * 2) | control << (16 + slot * 4);
+            address
+        }
+        None => 0,
+    };
+    *debug_register(context, slot) = address;
+    context.dr7 = dr7 as _;
+}
+
+// Enables or clears `watch` in the debug registers of `thread`
+unsafe fn program(thread: *mut c_void, watch: &Watch, enable: bool) -> Result<(), HwbpError> {
+    let mut context = get_context(thread)?;
+    let enabled = enable.then(|| (watch.address, watch.control()));
+    apply(&mut context, watch.slot, enabled);
+    set_context(thread, &context)
+}
+
+// Clears `watch` from the debug registers of `thread` if it's still programmed there, leaving
+// whatever else uses the register alone
+unsafe fn clear_followed(thread: *mut c_void, watch: &Watch) -> Result<(), HwbpError> {
+    let mut context = get_context(thread)?;
+    let enabled = context.dr7 as usize & 0b11 << (watch.slot * 2) != 0;
+    if enabled && *debug_register(&mut context, watch.slot) == watch.address {
+        apply(&mut context, watch.slot, None);
+        set_context(thread, &context)?;
+    }
+    Ok(())
+}
+
+// The process's threads, with a failure to list them reported like other thread access errors
+fn process_threads() -> Result<Vec<u32>, HwbpError> {
+    threads::process_threads().map_err(|error| match error {
+        ThreadError::Snapshot(code) => HwbpError::ThreadContext(code),
+    })
+}
+
+// Runs `f` with a handle to the thread `thread_id`, suspended unless it's the current one
+fn with_thread<R>(
+    thread_id: u32,
+    f: impl FnOnce(*mut c_void) -> Result<R, HwbpError>,
+) -> Result<R, HwbpError> {
+    if thread_id == teb::current_thread_id() {
+        return f(unsafe { GetCurrentThread() });
+    }
+
+    unsafe {
+        let access = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_SET_CONTEXT;
+        let thread = OpenThread(access, 0, thread_id);
+        if thread.is_null() {
+            return Err(HwbpError::ThreadContext(GetLastError()));
+        }
+
+        let result = match SuspendThread(thread) {
+            u32::MAX => Err(HwbpError::ThreadContext(GetLastError())),
+            _ => {
+                let result = f(thread);
+                ResumeThread(thread);
+                result
+            }
+        };
+        CloseHandle(thread);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HwBreakpoint, HwbpError, HwbpHook, Kind, Size, Watchpoint};
+    use crate::{dispatch, teb, ExceptionCode, Filter, Handling};
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Mutex};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    #[inline(never)]
+    fn watched(x: usize) -> usize {
+        std::hint::black_box(x) + 1
+    }
+
+    #[test]
+    fn execute_breakpoint() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        let _serial = dispatch::tests::serial();
+
+        let call = std::hint::black_box(watched as fn(usize) -> usize);
+        let breakpoint = HwBreakpoint::set(call as usize, Kind::Execute, Size::One, |_| {
+            HITS.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        assert_eq!(call(1), 2);
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+
+        drop(breakpoint);
+        assert_eq!(call(2), 3);
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn slots_exhausted() {
+        static WATCHED: [AtomicUsize; 5] = [const { AtomicUsize::new(0) }; 5];
+        let _serial = dispatch::tests::serial();
+
+        let set = |i: usize| {
+            let address = &WATCHED[i] as *const AtomicUsize as usize;
+            HwBreakpoint::set(address, Kind::Write, Size::Four, |_| {})
+        };
+        let breakpoints: Vec<_> = (0..4).map(|i| set(i).unwrap()).collect();
+        assert_eq!(set(4).err(), Some(HwbpError::NoFreeSlot));
+
+        drop(breakpoints);
+        let _again = set(4).unwrap();
+
+        assert_eq!(
+            HwBreakpoint::set(1, Kind::Execute, Size::Four, |_| {}).err(),
+            Some(HwbpError::InvalidSize)
+        );
+        assert_eq!(
+            HwBreakpoint::set(2, Kind::Write, Size::Four, |_| {}).err(),
+            Some(HwbpError::Misaligned)
+        );
+    }
+
+    #[test]
+    fn other_threads() {
+        static WATCHED: AtomicU32 = AtomicU32::new(0);
+        static HIT_ON: AtomicU32 = AtomicU32::new(0);
+        let _serial = dispatch::tests::serial();
+
+        // The worker has to exist before the breakpoint is applied, and write only afterwards
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (go_tx, go_rx) = mpsc::channel::<()>();
+        let worker = std::thread::spawn(move || {
+            ready_tx.send(teb::current_thread_id()).unwrap();
+            go_rx.recv().unwrap();
+            WATCHED.store(1, Ordering::SeqCst);
+        });
+        let worker_id = ready_rx.recv().unwrap();
+
+        let address = &WATCHED as *const AtomicU32 as usize;
+        let breakpoint = HwBreakpoint::set(address, Kind::Write, Size::Four, |_| {
+            HIT_ON.store(teb::current_thread_id(), Ordering::SeqCst);
+        })
+        .unwrap();
+        let count = breakpoint.apply_all_threads().unwrap();
+        assert!(count.applied >= 1);
+
+        go_tx.send(()).unwrap();
+        worker.join().unwrap();
+        assert_eq!(HIT_ON.load(Ordering::SeqCst), worker_id);
+    }
+
+    #[test]
+    fn new_threads() {
+        const CODE: u32 = 0xE056_4801;
+        static WATCHED: AtomicU32 = AtomicU32::new(0);
+        static HIT_ON: AtomicU32 = AtomicU32::new(0);
+        let _serial = dispatch::tests::serial();
+
+        let address = &WATCHED as *const AtomicU32 as usize;
+        let breakpoint = HwBreakpoint::set(address, Kind::Write, Size::Four, |_| {
+            HIT_ON.store(teb::current_thread_id(), Ordering::SeqCst);
+        })
+        .unwrap();
+        breakpoint.follow_new_threads().unwrap();
+
+        // Any exception arms the thread, even one that's handled right away
+        let worker = std::thread::spawn(|| {
+            let filter = Filter::code(ExceptionCode::from_raw(CODE)).and(Filter::current_thread());
+            let _handle =
+                dispatch::register_closure(filter, |_| Handling::ContinueExecution).unwrap();
+            unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+
+            WATCHED.store(1, Ordering::SeqCst);
+            teb::current_thread_id()
+        });
+
+        let worker_id = worker.join().unwrap();
+        assert_eq!(HIT_ON.load(Ordering::SeqCst), worker_id);
+    }
+
+    #[test]
+    fn reused_thread_ids() {
+        const CODE: u32 = 0xE056_4802;
+        static WATCHED: AtomicU32 = AtomicU32::new(0);
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        let _serial = dispatch::tests::serial();
+
+        // One after the other, so the IDs of those that exited are handed out again
+        let run_workers = || {
+            for _ in 0..32 {
+                std::thread::spawn(|| {
+                    let filter =
+                        Filter::code(ExceptionCode::from_raw(CODE)).and(Filter::current_thread());
+                    let _handle =
+                        dispatch::register_closure(filter, |_| Handling::ContinueExecution)
+                            .unwrap();
+                    unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+                    WATCHED.store(1, Ordering::SeqCst);
+                })
+                .join()
+                .unwrap();
+            }
+        };
+
+        // A second breakpoint in the same slot arms the threads again as well
+        let address = &WATCHED as *const AtomicU32 as usize;
+        for round in 1..=2 {
+            let breakpoint = HwBreakpoint::set(address, Kind::Write, Size::Four, |_| {
+                HITS.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+            breakpoint.follow_new_threads().unwrap();
+            run_workers();
+            drop(breakpoint);
+            assert_eq!(HITS.load(Ordering::SeqCst), round * 32);
+        }
+    }
+
+    #[test]
+    fn watchpoint_values() {
+        static WATCHED: AtomicU32 = AtomicU32::new(5);
+        static WRITES: Mutex<Vec<(u32, u32)>> = Mutex::new(Vec::new());
+        let _serial = dispatch::tests::serial();
+
+        #[inline(never)]
+        fn increment(value: &AtomicU32) {
+            value.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn record(old: u32, new: u32, _: &crate::ExceptionInfo) {
+            WRITES.lock().unwrap().push((old, new));
+        }
+
+        let target = WATCHED.as_ptr() as *const u32;
+        let watchpoint = unsafe { Watchpoint::on_write(target, record) }.unwrap();
+        increment(&WATCHED);
+        increment(&WATCHED);
+        drop(watchpoint);
+        increment(&WATCHED);
+
+        assert_eq!(*WRITES.lock().unwrap(), [(5, 6), (6, 7)]);
+        let odd = unsafe { Watchpoint::on_write(&[0u8; 3], |_, _, _| {}) };
+        assert_eq!(odd.err(), Some(HwbpError::InvalidSize));
+    }
+
+    #[test]
+    fn hook_calls_original() {
+        static HOOK: Mutex<Option<HwbpHook>> = Mutex::new(None);
+        static DETOURED: AtomicUsize = AtomicUsize::new(0);
+        static ORIGINAL: AtomicUsize = AtomicUsize::new(0);
+        let _serial = dispatch::tests::serial();
+
+        #[inline(never)]
+        extern "C" fn original(x: usize) -> usize {
+            ORIGINAL.fetch_add(1, Ordering::SeqCst);
+            x * 2
+        }
+
+        extern "C" fn detour(x: usize) -> usize {
+            DETOURED.fetch_add(1, Ordering::SeqCst);
+            let hook = HOOK.lock().unwrap();
+            hook.as_ref().unwrap().call_original(|| {
+                std::hint::black_box(original as extern "C" fn(usize) -> usize)(x)
+            }) + 1
+        }
+
+        let call = std::hint::black_box(original as extern "C" fn(usize) -> usize);
+        let hook = unsafe { HwbpHook::install(call as _, detour as _) }.unwrap();
+        *HOOK.lock().unwrap() = Some(hook);
+
+        assert_eq!(call(3), 7);
+        assert_eq!(call(4), 9);
+        assert_eq!(DETOURED.load(Ordering::SeqCst), 2);
+        assert_eq!(ORIGINAL.load(Ordering::SeqCst), 2);
+
+        HOOK.lock().unwrap().take();
+        assert_eq!(call(5), 10);
+        assert_eq!(DETOURED.load(Ordering::SeqCst), 2);
+    }
+}
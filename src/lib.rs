@@ -2,30 +2,167 @@
 #![cfg(windows)]
 
 // Modules
+mod adapter;
+mod builder;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod catch;
+mod context;
+mod continuable;
+#[cfg(feature = "dbghelp")]
+mod dbghelp;
+mod error;
+#[cfg(feature = "tracing")]
+mod events;
+mod exception;
+mod fault;
+mod filter;
+mod logger;
+mod memory;
+mod panics;
 mod raw_offset;
+mod recorder;
+mod reentry;
+mod registers;
+mod scoped;
+mod segfault;
+#[cfg(feature = "serde")]
+mod serialize;
+mod slots;
+mod snapshot;
+mod sync;
+mod teb;
+mod thread_veh;
+#[cfg(feature = "timing")]
+mod timing;
+mod vch;
+#[cfg(all(feature = "xstate", any(target_arch = "x86", target_arch = "x86_64")))]
+mod xstate;
 
 // Public modules
+pub mod backtrace;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod crash;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod debug;
+pub mod debug_trace;
+pub mod dedup;
+pub mod defer;
+pub mod dispatch;
+#[cfg(feature = "etw")]
+pub mod etw;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod fp;
+pub mod guard;
+#[cfg(target_arch = "x86_64")]
+pub mod guarded;
+pub mod hook;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod hwbp;
+pub mod mem;
+pub mod module_guard;
+pub mod modules;
+pub mod posix;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod probe;
+pub mod queue;
 pub mod raw;
+#[cfg(all(feature = "remote", any(target_arch = "x86", target_arch = "x86_64")))]
+pub mod remote;
+pub mod scan;
+pub mod stack;
+#[cfg(feature = "static-init")]
+pub mod static_init;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod step;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod swbp;
+pub mod symbols;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod threads;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod trace;
+pub mod uef;
+
+// Re-exports
+pub use crate::adapter::{adapt_c_handler, CHandler};
+pub use crate::builder::VehBuilder;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use crate::catch::{panic_on_exception, try_execute, CaughtException, PanicOnExceptionGuard};
+pub use crate::context::*;
+pub use crate::continuable::{set_on_invalid_continue, InvalidContinueHook};
+pub use crate::error::VehError;
+pub use crate::exception::{
+    ExceptionCode, ExceptionInfo, ExceptionRecordView, Handling, EXCEPTION_CONTINUE_EXECUTION,
+    EXCEPTION_CONTINUE_SEARCH, EXCEPTION_NONCONTINUABLE, EXCEPTION_POINTERS, EXCEPTION_RECORD,
+    MAX_RECORD_CHAIN,
+};
+pub use crate::fault::{nt_status_name, AccessViolationInfo, AvOperation, InPageErrorInfo};
+pub use crate::filter::{AddressSource, Filter};
+pub use crate::logger::{log_all_exceptions, ExceptionLogger, LoggerError, LoggerGuard};
+pub use crate::panics::{
+    abort_on_handler_panic, set_handler_panic_disposition, take_last_handler_panic,
+};
+pub use crate::raw::init;
+pub use crate::recorder::{
+    install_panic_hook_enrichment, last_exception, record_last_exception, RecorderGuard,
+};
+pub use crate::reentry::{set_max_handler_depth, set_on_reentry, ReentryHook};
+pub use crate::registers::{context_diff, fmt_registers, RegisterChange, RegisterDump};
+pub use crate::scoped::{with_closure_handler, with_handler};
+pub use crate::segfault::{on_segfault, SegfaultAction, SegfaultGuard, SegfaultInfo};
+pub use crate::snapshot::{ExceptionSnapshot, SnapshotModule, MAX_SNAPSHOT_FRAMES};
+pub use crate::thread_veh::ThreadVeh;
+pub use crate::vch::*;
 
 // Imports
-use crate::raw::{add_vectored_exception_handler, remove_vectored_exception_handler};
+use crate::raw::{
+    add_vectored_continue_handler, add_vectored_exception_handler, remove_handler,
+    CONTINUE_HANDLER_LIST, EXCEPTION_HANDLER_LIST,
+};
+use crate::slots::SlotGuard;
+use std::sync::atomic::Ordering;
 use std::{ffi::c_void, marker::PhantomData};
 
-type RawExceptionHandler = unsafe extern "system" fn(*mut c_void) -> i32;
+/// A vectored exception handler, as registered with ntdll.
+pub type VectoredHandler = unsafe extern "system" fn(*mut c_void) -> i32;
+
+/// A vectored exception handler taking a typed `EXCEPTION_POINTERS`, such as
+/// `VectoredHandlerFor<winapi::um::winnt::EXCEPTION_POINTERS>`.
+pub type VectoredHandlerFor<T> = unsafe extern "system" fn(*mut T) -> i32;
 
 // This is essentially just a boolean with different names
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Order {
     First = 1,
     Last = 0,
 }
 
 // Wrap the pointer/handle returned by `add_vectored_exception_handler`
-pub struct Veh<T>(*const c_void, PhantomData<T>);
+pub struct Veh<T> {
+    handle: *const c_void,
+    handler_list: i32,
+    // State for registrations going through a trampoline, freed once unregistered
+    slot: Option<SlotGuard>,
+    pinned: bool,
+    // Set while `module_guard` is tracking the registration
+    tracking: Option<u64>,
+    _marker: PhantomData<T>,
+}
 
 impl<T> Drop for Veh<T> {
     fn drop(&mut self) {
-        unsafe { remove_vectored_exception_handler(self.0) };
+        // Unregister first so no new calls reach the trampoline, then let the slot wait out the
+        // ones still running before freeing its state. `module_guard::detach` may have removed it
+        // already, and ntdll mustn't see the handle twice
+        let registered = self.tracking.is_none_or(module_guard::untrack);
+        if registered {
+            unsafe { remove_handler(self.handler_list, self.handle) };
+        }
+        self.slot.take();
     }
 }
 
@@ -51,7 +188,63 @@ impl<T> Veh<T> {
     /// Be sure that you know what you're doing, and know that a crash in an exception handler
     /// will trigger a new exception, calling the exception handler chain all over again.
     pub unsafe fn add_raw(order: Order, handler: usize) -> Self {
-        Veh(raw_add(order, handler), PhantomData)
+        Veh::from_parts(
+            raw_add(order, handler),
+            EXCEPTION_HANDLER_LIST,
+            handler,
+            None,
+        )
+    }
+
+    // `handler` is the function registered with ntdll, whether the handler itself or a trampoline
+    pub(crate) fn from_parts(
+        handle: *const c_void,
+        handler_list: i32,
+        handler: usize,
+        slot: Option<SlotGuard>,
+    ) -> Self {
+        Veh {
+            handle,
+            handler_list,
+            slot,
+            pinned: false,
+            tracking: module_guard::track(handler, handle, handler_list),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Unregisters the handler, the same as dropping it.
+    ///
+    /// For handlers with state of their own (closures, filters, ...), this waits for invocations
+    /// still running on other threads before freeing that state, so it must not be called from
+    /// inside the handler itself.
+    pub fn remove(self) {
+        drop(self)
+    }
+
+    /// Switches a handler built with [`VehBuilder::toggleable`] off or on without unregistering it.
+    /// While disabled, the handler passes every exception on as if it wasn't registered.
+    pub fn set_enabled(&self, enabled: bool) -> Result<(), VehError> {
+        match &self.slot {
+            Some(slot) if slot.entry().toggleable => {
+                slot.entry().enabled.store(enabled, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(VehError::NotToggleable),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match &self.slot {
+            Some(slot) => slot.entry().enabled.load(Ordering::Relaxed),
+            None => true,
+        }
+    }
+
+    /// Whether the module containing the handler was pinned at registration, as asked for with
+    /// [`VehBuilder::pin_module`].
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
     }
 }
 
@@ -65,11 +258,21 @@ impl Veh<c_void> {
     /// This registers a function to be called when an exception occurs.
     /// Be sure that you know what you're doing, and know that a crash in an exception handler
     /// will trigger a new exception, calling the exception handler chain all over again.
-    pub unsafe fn add(
-        order: Order,
-        handler: unsafe extern "system" fn(*mut c_void) -> i32,
-    ) -> Self {
-        Veh(raw_add(order, handler as _), PhantomData)
+    pub unsafe fn add(order: Order, handler: VectoredHandler) -> Self {
+        Veh::from_parts(
+            raw_add(order, handler as _),
+            EXCEPTION_HANDLER_LIST,
+            handler as usize,
+            None,
+        )
+    }
+
+    /// Registers a handler using the C calling convention, see [`adapt_c_handler`].
+    ///
+    /// # Safety
+    /// See [`Veh::add`].
+    pub unsafe fn add_c(order: Order, handler: CHandler) -> Self {
+        Veh::<c_void>::add(order, adapt_c_handler(handler))
     }
 }
 
@@ -86,9 +289,14 @@ impl VehWinapi {
     /// will trigger a new exception, calling the exception handler chain all over again.
     pub unsafe fn add(
         order: Order,
-        handler: unsafe extern "system" fn(*mut winapi::um::winnt::EXCEPTION_POINTERS) -> i32,
+        handler: VectoredHandlerFor<winapi::um::winnt::EXCEPTION_POINTERS>,
     ) -> Self {
-        Veh(raw_add(order, handler as _), PhantomData)
+        Veh::from_parts(
+            raw_add(order, handler as _),
+            EXCEPTION_HANDLER_LIST,
+            handler as usize,
+            None,
+        )
     }
 }
 
@@ -105,59 +313,99 @@ impl VehWindows {
     /// will trigger a new exception, calling the exception handler chain all over again.
     pub unsafe fn add(
         order: Order,
-        handler: unsafe extern "system" fn(
-            *mut windows_sys::Win32::System::Diagnostics::Debug::EXCEPTION_POINTERS,
-        ) -> windows_sys::Win32::System::Kernel::EXCEPTION_DISPOSITION,
+        handler: VectoredHandlerFor<
+            windows_sys::Win32::System::Diagnostics::Debug::EXCEPTION_POINTERS,
+        >,
     ) -> Self {
-        Veh(raw_add(order, handler as _), PhantomData)
+        Veh::from_parts(
+            raw_add(order, handler as _),
+            EXCEPTION_HANDLER_LIST,
+            handler as usize,
+            None,
+        )
     }
 }
 
+/// Registers every handler in `handlers`, so that they're called in the same order as in the
+/// slice, relative to each other, with their guards returned in that order too.
+///
+/// Adding several handlers with [`Order::First`] one at a time leaves them in reverse, as each
+/// goes ahead of the ones before it, so those are added from the end of the slice instead. The
+/// handlers are added back to back, leaving other threads as little time as possible to add
+/// theirs in between. If adding one fails, the ones added before it are removed again.
+///
+/// # Safety
+/// See [`Veh::add`].
+pub unsafe fn add_batch(
+    order: Order,
+    handlers: &[VectoredHandler],
+) -> Result<Vec<Veh<c_void>>, VehError> {
+    let first = order == Order::First;
+    let mut added: Vec<Option<Veh<c_void>>> = handlers.iter().map(|_| None).collect();
+
+    let mut indices: Vec<_> = (0..handlers.len()).collect();
+    if first {
+        indices.reverse();
+    }
+    for index in indices {
+        let handler = handlers[index];
+        // Dropping `added` on failure removes the others
+        let handle = raw::try_add_handler(EXCEPTION_HANDLER_LIST, first, handler)?;
+        let veh = Veh::from_parts(handle, EXCEPTION_HANDLER_LIST, handler as usize, None);
+        added[index] = Some(veh);
+    }
+
+    Ok(added.into_iter().flatten().collect())
+}
+
 unsafe fn raw_add(order: Order, handler: usize) -> *const c_void {
-    let handler = std::mem::transmute(handler);
+    let handler = std::mem::transmute::<usize, VectoredHandler>(handler);
     match order {
         Order::First => add_vectored_exception_handler(true, handler),
         Order::Last => add_vectored_exception_handler(false, handler),
     }
 }
 
+unsafe fn raw_add_continue(order: Order, handler: usize) -> *const c_void {
+    let handler = std::mem::transmute::<usize, VectoredHandler>(handler);
+    match order {
+        Order::First => add_vectored_continue_handler(true, handler),
+        Order::Last => add_vectored_continue_handler(false, handler),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{Order, Veh};
+    use crate::{
+        add_batch, modules, testing, ContextExt, ExceptionCode, ExceptionInfo, Order, Veh,
+    };
+    use crate::{ExceptionSnapshot, VectoredHandler};
+    use std::ffi::c_void;
+    use std::sync::Mutex;
     use winapi::{
-        um::{
-            minwinbase::EXCEPTION_ACCESS_VIOLATION,
-            winnt::{EXCEPTION_POINTERS, LONG, PEXCEPTION_POINTERS},
-        },
+        um::winnt::{EXCEPTION_POINTERS, LONG, PEXCEPTION_POINTERS},
         vc::excpt::{EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH},
     };
 
     #[test]
     fn handler_executed() {
+        const CODE: u32 = 0xE056_6102;
         static mut FLAG: bool = false;
+        static SNAPSHOT: Mutex<Option<ExceptionSnapshot>> = Mutex::new(None);
 
         unsafe extern "system" fn handler(ptrs: PEXCEPTION_POINTERS) -> LONG {
-            let cr = &mut *(*ptrs).ContextRecord;
-            let er = &mut *(*ptrs).ExceptionRecord;
+            let er = &*(*ptrs).ExceptionRecord;
 
             // Avoid catching exceptions that aren't caused by us
-            if er.ExceptionCode == EXCEPTION_ACCESS_VIOLATION && er.ExceptionAddress == 1 as _ {
-                // Emulate a `ret` instruction to let the program continue
-                #[cfg(target_pointer_width = "32")]
-                {
-                    cr.Eip = *(cr.Esp as *const u32) as _;
-                    cr.Esp += 4;
-                }
-                #[cfg(target_pointer_width = "64")]
-                {
-                    cr.Rip = *(cr.Rsp as *const u64) as _;
-                    cr.Rsp += 8;
-                }
+            if er.ExceptionCode == CODE {
+                // Keep a copy to check once we're out of the handler
+                let info = ExceptionInfo::from_raw(ptrs as _);
+                *SNAPSHOT.lock().unwrap() = Some(info.snapshot());
 
                 // Set the flag
                 FLAG = true;
 
-                // Continue execution
+                // A raised exception continues right after the raise
                 EXCEPTION_CONTINUE_EXECUTION
             } else {
                 // Continue executing handlers
@@ -169,8 +417,70 @@ mod tests {
             let _veh = Veh::<EXCEPTION_POINTERS>::add(Order::First, handler);
 
             assert!(!FLAG);
-            (std::mem::transmute::<_, fn()>(1 as *const usize))();
+            testing::raise(CODE, &[]);
             assert!(FLAG);
         }
+
+        let snapshot = SNAPSHOT.lock().unwrap().take().unwrap();
+        assert_eq!(snapshot.code(), ExceptionCode::Other(CODE));
+        assert_eq!(snapshot.context().unwrap().ip(), snapshot.address());
+        assert_eq!(snapshot.thread_id(), crate::teb::current_thread_id());
+        // The test executable, which the loader lists first
+        let executable = modules::iter().next().unwrap();
+        assert_eq!(snapshot.module().unwrap().base(), executable.base());
+    }
+
+    #[test]
+    fn batch_order_kept() {
+        const FIRST: u32 = 0xE056_6701;
+        const LAST: u32 = 0xE056_6702;
+        static CALLED: Mutex<Vec<(u32, usize)>> = Mutex::new(Vec::new());
+
+        unsafe fn record(ptrs: *mut c_void, index: usize) -> i32 {
+            let code = ExceptionInfo::from_raw(ptrs).code().raw();
+            if code == FIRST || code == LAST {
+                CALLED.lock().unwrap().push((code, index));
+            }
+            EXCEPTION_CONTINUE_SEARCH
+        }
+        unsafe extern "system" fn zero(ptrs: *mut c_void) -> i32 {
+            record(ptrs, 0)
+        }
+        unsafe extern "system" fn one(ptrs: *mut c_void) -> i32 {
+            record(ptrs, 1)
+        }
+        unsafe extern "system" fn two(ptrs: *mut c_void) -> i32 {
+            record(ptrs, 2)
+        }
+        unsafe extern "system" fn backstop(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                FIRST | LAST => {
+                    record(ptrs, usize::MAX);
+                    EXCEPTION_CONTINUE_EXECUTION
+                }
+                _ => EXCEPTION_CONTINUE_SEARCH,
+            }
+        }
+
+        let handlers: [VectoredHandler; 3] = [zero, one, two];
+        let expected = |code| vec![(code, 0), (code, 1), (code, 2), (code, usize::MAX)];
+        unsafe {
+            // Ahead of a backstop added before them
+            let first_backstop = Veh::<c_void>::add(Order::First, backstop);
+            let batch = add_batch(Order::First, &handlers).unwrap();
+            assert_eq!(batch.len(), 3);
+            testing::raise(FIRST, &[]);
+            drop(batch);
+            drop(first_backstop);
+
+            // Behind everything, with a backstop added after them
+            let batch = add_batch(Order::Last, &handlers).unwrap();
+            let last_backstop = Veh::<c_void>::add(Order::Last, backstop);
+            testing::raise(LAST, &[]);
+            drop(last_backstop);
+            drop(batch);
+        }
+        let called = std::mem::take(&mut *CALLED.lock().unwrap());
+        assert_eq!(called, [expected(FIRST), expected(LAST)].concat());
     }
 }
@@ -0,0 +1,209 @@
+//! Logging every first-chance exception from an ordinary thread, in one call.
+//!
+//! [`log_all_exceptions`] puts together what's otherwise wired up by hand: a dispatcher observer
+//! snapshotting each exception into an [`ExceptionQueue`], and a consumer thread draining the
+//! queue into a sink, where it's safe to format, allocate and write to files.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::queue::ExceptionQueue;
+use crate::{ExceptionSnapshot, Handling, VehError};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How long the consumer waits for a snapshot before checking whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Options for the logger started by [`ExceptionLogger::start`].
+#[derive(Debug, Clone)]
+pub struct ExceptionLogger {
+    capacity: usize,
+    backtraces: bool,
+}
+
+impl Default for ExceptionLogger {
+    fn default() -> Self {
+        ExceptionLogger {
+            capacity: 64,
+            backtraces: true,
+        }
+    }
+}
+
+impl ExceptionLogger {
+    /// A logger queueing up to 64 snapshots, with backtraces.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many snapshots can wait for the sink before the oldest are dropped.
+    ///
+    /// # Panics
+    /// [`start`](Self::start) panics if this is 0.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Whether snapshots include a backtrace. Walking the stack is most of what snapshotting
+    /// costs the faulting thread, so leaving it out helps processes raising many exceptions.
+    pub fn backtraces(mut self, backtraces: bool) -> Self {
+        self.backtraces = backtraces;
+        self
+    }
+
+    /// Starts calling `sink` on a thread of its own with a snapshot of every exception the
+    /// dispatcher sees, oldest first, until the returned guard is dropped.
+    pub fn start<F>(self, sink: F) -> Result<LoggerGuard, LoggerError>
+    where
+        F: Fn(&ExceptionSnapshot) + Send + Sync + 'static,
+    {
+        let queue = Arc::new(ExceptionQueue::with_capacity(self.capacity));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let consumer = {
+            let queue = queue.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("manual-veh logger".into())
+                .spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        if let Some(snapshot) = queue.recv_timeout(POLL_INTERVAL) {
+                            sink(&snapshot);
+                        }
+                    }
+                    // The observer is already gone, so this is everything left
+                    queue.drain().for_each(|snapshot| sink(&snapshot));
+                })
+                .map_err(|e| LoggerError::Spawn(e.kind()))?
+        };
+
+        let backtraces = self.backtraces;
+        let pushing = queue.clone();
+        let observer = Registration::new().observe().register_closure(move |info| {
+            pushing.push_snapshot_with(info, backtraces);
+            Handling::ContinueSearch
+        });
+
+        // Built first, so that a failed registration stops the consumer on the way out
+        let mut guard = LoggerGuard {
+            observer: None,
+            queue,
+            stop,
+            consumer: Some(consumer),
+        };
+        guard.observer = Some(observer.map_err(LoggerError::Veh)?);
+        Ok(guard)
+    }
+}
+
+/// Calls `sink` with a snapshot of every first-chance exception, with the default
+/// [`ExceptionLogger`] options. See [`ExceptionLogger::start`].
+pub fn log_all_exceptions<F>(sink: F) -> Result<LoggerGuard, LoggerError>
+where
+    F: Fn(&ExceptionSnapshot) + Send + Sync + 'static,
+{
+    ExceptionLogger::new().start(sink)
+}
+
+/// Errors returned by [`ExceptionLogger::start`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LoggerError {
+    /// The consumer thread couldn't be spawned.
+    Spawn(io::ErrorKind),
+    /// The observer couldn't be registered.
+    Veh(VehError),
+}
+
+impl std::fmt::Display for LoggerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoggerError::Spawn(kind) => write!(f, "failed to spawn the logger thread: {kind}"),
+            LoggerError::Veh(e) => write!(f, "failed to register the logger: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LoggerError {}
+
+impl From<VehError> for LoggerError {
+    fn from(e: VehError) -> Self {
+        LoggerError::Veh(e)
+    }
+}
+
+/// Keeps [`log_all_exceptions`] logging until dropped.
+///
+/// Dropping it unregisters the observer, and then waits for the sink to be called with every
+/// snapshot still queued.
+pub struct LoggerGuard {
+    observer: Option<CallbackGuard>,
+    queue: Arc<ExceptionQueue>,
+    stop: Arc<AtomicBool>,
+    consumer: Option<JoinHandle<()>>,
+}
+
+impl LoggerGuard {
+    /// How many snapshots were dropped because the sink fell behind.
+    pub fn dropped(&self) -> usize {
+        self.queue.dropped()
+    }
+}
+
+impl Drop for LoggerGuard {
+    fn drop(&mut self) {
+        self.observer.take();
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(consumer) = self.consumer.take() {
+            // A panicking sink already ended the thread, which is all there is to do here
+            let _ = consumer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExceptionLogger;
+    use crate::{dispatch, teb, ExceptionCode, Filter, Handling};
+    use std::sync::{Arc, Mutex};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    #[test]
+    fn logged_in_order() {
+        let _serial = dispatch::tests::serial();
+        const FIRST: u32 = 0xE056_5801;
+        const SECOND: u32 = 0xE056_5802;
+
+        let tid = teb::current_thread_id();
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let logger = {
+            let logged = logged.clone();
+            ExceptionLogger::new()
+                .capacity(16)
+                .backtraces(false)
+                .start(move |snapshot| {
+                    // Other tests' exceptions are logged too
+                    if snapshot.thread_id() == tid {
+                        let mut logged = logged.lock().unwrap();
+                        logged.push((snapshot.code().raw(), snapshot.backtrace().len()));
+                    }
+                })
+                .unwrap()
+        };
+
+        let codes = [ExceptionCode::Other(FIRST), ExceptionCode::Other(SECOND)];
+        let filter = Filter::codes(codes).and(Filter::current_thread());
+        let fixup = dispatch::register(filter, |_| Handling::ContinueExecution).unwrap();
+        unsafe { RaiseException(FIRST, 0, 0, std::ptr::null()) };
+        unsafe { RaiseException(SECOND, 0, 0, std::ptr::null()) };
+        drop(fixup);
+        drop(logger);
+
+        assert_eq!(*logged.lock().unwrap(), [(FIRST, 0), (SECOND, 0)]);
+        assert!(!dispatch::is_installed());
+    }
+}
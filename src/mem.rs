@@ -0,0 +1,386 @@
+//! Writing to memory regardless of its protection, and accessing memory that might not be there.
+//!
+//! [`try_read`] and friends copy through a routine of the crate's own, and a vectored handler
+//! registered for good on first use recognizes faults raised by it and ends the copy early
+//! instead. Nothing is allocated after that first use, and as the handler is a native one rather
+//! than a dispatcher callback, they can be used from inside other handlers. Handlers ahead of it
+//! in the chain still see those faults first.
+
+// Imports
+use crate::dispatch::{self, CallbackGuard};
+use crate::memory::{self, PAGE_SIZE};
+use crate::{step, AvOperation, ExceptionCode, ExceptionInfo, Filter, Handling, VehError};
+use std::cell::Cell;
+use std::fmt;
+use std::sync::Mutex;
+
+// Architecture-specific imports
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::raw::{self, EXCEPTION_HANDLER_LIST};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::{ContextExt, CONTEXT};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::ffi::c_void;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::ops::Range;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::sync::OnceLock;
+
+// Protections without write access, and their writable counterparts
+const PAGE_NOACCESS: u32 = 0x01;
+const PAGE_READONLY: u32 = 0x02;
+const PAGE_READWRITE: u32 = 0x04;
+const PAGE_EXECUTE: u32 = 0x10;
+const PAGE_EXECUTE_READ: u32 = 0x20;
+const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+
+/// Why [`write_through`] couldn't write, or [`try_read_bytes`] couldn't read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MemError {
+    /// Part of the destination isn't committed memory, or for reads, not even the first byte
+    /// could be read.
+    Unmapped,
+    /// The callback making the destination writable couldn't be registered with the dispatcher.
+    Dispatch(VehError),
+    /// The handler catching faults while copying couldn't be registered.
+    Handler(VehError),
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemError::Unmapped => f.write_str("the destination is not committed memory"),
+            MemError::Dispatch(error) => write!(f, "failed to register the callback: {error}"),
+            MemError::Handler(error) => write!(f, "failed to register the handler: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for MemError {}
+
+impl From<VehError> for MemError {
+    fn from(error: VehError) -> Self {
+        MemError::Dispatch(error)
+    }
+}
+
+// Threads inside `write_through`, and the callback handling their writes while there are any
+static WRITERS: Mutex<(usize, Option<CallbackGuard>)> = Mutex::new((0, None));
+
+thread_local! {
+    // The range the current thread is writing through, or an empty one
+    static WRITING: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+// Keeps the callback registered while it's alive
+struct Writer;
+
+impl Writer {
+    fn enter() -> Result<Self, VehError> {
+        let mut writers = WRITERS.lock().unwrap_or_else(|e| e.into_inner());
+        if writers.1.is_none() {
+            let filter = Filter::code(ExceptionCode::AccessViolation);
+            writers.1 = Some(dispatch::register(filter, faulted)?);
+        }
+        writers.0 += 1;
+        Ok(Writer)
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let mut writers = WRITERS.lock().unwrap_or_else(|e| e.into_inner());
+        writers.0 -= 1;
+        if writers.0 == 0 {
+            writers.1.take();
+        }
+    }
+}
+
+/// Writes `value` to `dst`, even if it's in read-only memory.
+///
+/// The write is attempted as is, and if it faults, only the page it faulted on is made writable
+/// from within the exception handler, for the single instruction retrying the write. Other
+/// threads therefore get as little of a window as possible to write to the page themselves, and
+/// never see its protection change otherwise.
+///
+/// # Safety
+/// `dst` must be valid for writes (other than its protection) and aligned for `T`, and nothing
+/// may rely on the value it replaces staying there.
+pub unsafe fn write_through<T>(dst: *mut T, value: T) -> Result<(), MemError> {
+    let start = dst as usize;
+    let end = start + std::mem::size_of::<T>();
+    let first = start & !(PAGE_SIZE - 1);
+    if (first..end.max(start + 1))
+        .step_by(PAGE_SIZE)
+        .any(|page| memory::protection(page).is_none())
+    {
+        return Err(MemError::Unmapped);
+    }
+
+    let _writer = Writer::enter()?;
+    WRITING.with(|writing| writing.set((start, end)));
+    std::ptr::write_volatile(dst, value);
+    WRITING.with(|writing| writing.set((0, 0)));
+    Ok(())
+}
+
+fn faulted(info: &mut ExceptionInfo) -> Handling {
+    let (start, end) = WRITING.with(Cell::get);
+    let page = match info.access_violation() {
+        Some(access) if access.operation == AvOperation::Write => {
+            match (start..end).contains(&access.address) {
+                true => access.address & !(PAGE_SIZE - 1),
+                false => return Handling::ContinueSearch,
+            }
+        }
+        _ => return Handling::ContinueSearch,
+    };
+
+    let old = match memory::protection(page) {
+        Some(old) => old,
+        None => return Handling::ContinueSearch,
+    };
+    if unsafe { memory::set_protection(page, PAGE_SIZE, writable(old)) }.is_err() {
+        return Handling::ContinueSearch;
+    }
+
+    step::single_step(info.context_mut(), move |_| {
+        let _ = unsafe { memory::set_protection(page, PAGE_SIZE, old) };
+    });
+    Handling::ContinueExecution
+}
+
+/// Reads a `T` from `addr`, or returns `None` if any of it can't be read.
+///
+/// # Safety
+/// The bytes at `addr`, if they can be read, must be a valid `T`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub unsafe fn try_read<T: Copy>(addr: *const T) -> Option<T> {
+    let mut value = std::mem::MaybeUninit::<T>::uninit();
+    let len = std::mem::size_of::<T>();
+    match checked_copy(value.as_mut_ptr() as *mut u8, addr as *const u8, len) {
+        Ok(copied) if copied == len => Some(value.assume_init()),
+        _ => None,
+    }
+}
+
+/// Reads as many bytes from `addr` into `buf` as can be read, up to the first one that can't,
+/// returning how many that was.
+///
+/// A guard page ends the read like an unmapped one, and loses its guard in the process.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn try_read_bytes(addr: usize, buf: &mut [u8]) -> Result<usize, MemError> {
+    match unsafe { checked_copy(buf.as_mut_ptr(), addr as *const u8, buf.len()) }? {
+        0 if !buf.is_empty() => Err(MemError::Unmapped),
+        copied => Ok(copied),
+    }
+}
+
+/// Writes `value` to `addr`, returning whether all of it could be written.
+///
+/// A failed write may still have written the part before the first byte that couldn't be.
+///
+/// # Safety
+/// Nothing may rely on the value at `addr` staying there, as with [`std::ptr::write`].
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub unsafe fn try_write<T>(addr: *mut T, value: T) -> bool {
+    let len = std::mem::size_of::<T>();
+    let value = std::mem::ManuallyDrop::new(value);
+    let src = &*value as *const T as *const u8;
+    checked_copy(addr as *mut u8, src, len) == Ok(len)
+}
+
+/// Whether every byte in `range` can be read.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn probe_range(range: Range<usize>) -> bool {
+    if range.is_empty() {
+        return true;
+    }
+
+    // Protection applies to whole pages, so one byte of each says enough
+    let first = range.start & !(PAGE_SIZE - 1);
+    let mut byte = 0u8;
+    std::iter::once(range.start)
+        .chain((first + PAGE_SIZE..range.end).step_by(PAGE_SIZE))
+        .all(|addr| unsafe { checked_copy(&mut byte, addr as *const u8, 1) } == Ok(1))
+}
+
+// Copies `len` bytes, returning how many were copied before one faulted
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn checked_copy(dst: *mut u8, src: *const u8, len: usize) -> Result<usize, MemError> {
+    static HANDLER: OnceLock<Result<(), VehError>> = OnceLock::new();
+    HANDLER
+        .get_or_init(|| {
+            raw::try_add_handler(EXCEPTION_HANDLER_LIST, true, faulted_copy).map(|_| ())
+        })
+        .clone()
+        .map_err(MemError::Handler)?;
+
+    Ok(len - copy(dst, src, len))
+}
+
+// A `rep movsb` with the addresses of it and the instruction after it loaded into `r11` and
+// `r10`, which `faulted_copy` recognizes. It returns how many bytes were left to copy.
+#[cfg(target_arch = "x86_64")]
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let remaining;
+    std::arch::asm!(
+        "lea r11, [rip + 2f]",
+        "lea r10, [rip + 3f]",
+        "2: rep movsb",
+        "3:",
+        inout("rcx") len => remaining,
+        inout("rdi") dst => _,
+        inout("rsi") src => _,
+        out("r10") _,
+        out("r11") _,
+        options(nostack),
+    );
+    remaining
+}
+
+// The same, with the addresses in `eax` and `edx`. `esi` can't be an operand, so it's saved here.
+#[cfg(target_arch = "x86")]
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let remaining;
+    std::arch::asm!(
+        "push esi",
+        "mov esi, {src}",
+        "lea eax, [2f]",
+        "lea edx, [3f]",
+        "2: rep movsb",
+        "3:",
+        "pop esi",
+        src = in(reg) src,
+        inout("ecx") len => remaining,
+        inout("edi") dst => _,
+        out("eax") _,
+        out("edx") _,
+    );
+    remaining
+}
+
+// The length of `rep movsb`
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const COPY_LEN: usize = 2;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe extern "system" fn faulted_copy(ptrs: *mut c_void) -> i32 {
+    let info = ExceptionInfo::from_raw(ptrs);
+    match info.code() {
+        ExceptionCode::AccessViolation
+        | ExceptionCode::InPageError
+        | ExceptionCode::GuardPageViolation => {}
+        _ => return Handling::ContinueSearch.raw(),
+    }
+
+    let context = &mut *(info.context_ptr() as *mut CONTEXT);
+    match copy_resume(context) {
+        // The count register is left with how much wasn't copied
+        Some(resume) => {
+            context.set_ip(resume);
+            Handling::ContinueExecution.raw()
+        }
+        None => Handling::ContinueSearch.raw(),
+    }
+}
+
+// Where to resume a fault raised at `context` by the copy routine, or `None` if it wasn't
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn copy_resume(context: &CONTEXT) -> Option<usize> {
+    #[cfg(target_arch = "x86_64")]
+    let (ip, site, resume) = (context.rip, context.r11, context.r10);
+    #[cfg(target_arch = "x86")]
+    let (ip, site, resume) = (context.eip, context.eax, context.edx);
+
+    match ip == site && resume.wrapping_sub(site) as usize == COPY_LEN {
+        true => Some(resume as usize),
+        false => None,
+    }
+}
+
+// The same protection with write access, keeping modifiers like `PAGE_NOCACHE`
+fn writable(protect: u32) -> u32 {
+    let access = match protect & 0xFF {
+        PAGE_NOACCESS | PAGE_READONLY => PAGE_READWRITE,
+        PAGE_EXECUTE | PAGE_EXECUTE_READ => PAGE_EXECUTE_READWRITE,
+        other => other,
+    };
+    (protect & !0xFF) | access
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    use super::{probe_range, try_read, try_read_bytes, try_write};
+    use super::{write_through, MemError};
+    use crate::dispatch;
+    use crate::memory::{self, PAGE_SIZE};
+    use std::ffi::c_void;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn VirtualAlloc(address: *mut c_void, size: usize, kind: u32, protect: u32) -> *mut c_void;
+        fn VirtualFree(address: *mut c_void, size: usize, kind: u32) -> i32;
+    }
+
+    #[test]
+    fn read_only_page() {
+        let _serial = dispatch::tests::serial();
+
+        let page = unsafe { VirtualAlloc(std::ptr::null_mut(), PAGE_SIZE, 0x3000, 0x04) };
+        assert!(!page.is_null());
+        let target = unsafe { (page as *mut u64).add(1) };
+        unsafe {
+            *target = 1;
+            memory::set_protection(page as usize, PAGE_SIZE, 0x02).unwrap();
+            write_through(target, 0x1122_3344_5566_7788).unwrap();
+        }
+
+        assert_eq!(unsafe { *target }, 0x1122_3344_5566_7788);
+        assert_eq!(memory::protection(page as usize), Some(0x02));
+        assert!(!dispatch::is_installed());
+        unsafe { VirtualFree(page as _, 0, 0x8000) };
+
+        // Reserved, but not committed
+        let reserved = unsafe { VirtualAlloc(std::ptr::null_mut(), PAGE_SIZE, 0x2000, 0x04) };
+        let result = unsafe { write_through(reserved as *mut u64, 0) };
+        assert_eq!(result, Err(MemError::Unmapped));
+        unsafe { VirtualFree(reserved as _, 0, 0x8000) };
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn checked_reads() {
+        let value = 0x1234_5678u32;
+        assert_eq!(unsafe { try_read(&value) }, Some(value));
+
+        let freed = unsafe { VirtualAlloc(std::ptr::null_mut(), PAGE_SIZE, 0x3000, 0x04) };
+        unsafe { VirtualFree(freed, 0, 0x8000) };
+        assert_eq!(unsafe { try_read(freed as *const u32) }, None);
+        assert!(!unsafe { try_write(freed as *mut u32, 1) });
+        assert!(!probe_range(freed as usize..freed as usize + 1));
+
+        // Two pages, with the second one decommitted again
+        let pages = unsafe { VirtualAlloc(std::ptr::null_mut(), 2 * PAGE_SIZE, 0x3000, 0x04) };
+        let pages = pages as usize;
+        unsafe {
+            std::ptr::write_bytes(pages as *mut u8, 0xAB, PAGE_SIZE);
+            VirtualFree((pages + PAGE_SIZE) as _, PAGE_SIZE, 0x4000);
+        }
+
+        let mut buf = [0u8; 32];
+        assert_eq!(try_read_bytes(pages + PAGE_SIZE - 16, &mut buf), Ok(16));
+        assert_eq!(buf[..16], [0xAB; 16]);
+        let unmapped = try_read_bytes(pages + PAGE_SIZE, &mut buf);
+        assert_eq!(unmapped, Err(MemError::Unmapped));
+
+        assert!(unsafe { try_write(pages as *mut u32, 7) });
+        assert_eq!(unsafe { try_read(pages as *const u32) }, Some(7));
+        assert!(probe_range(pages..pages + PAGE_SIZE));
+        assert!(!probe_range(pages..pages + PAGE_SIZE + 1));
+        unsafe { VirtualFree(pages as _, 0, 0x8000) };
+    }
+}
@@ -0,0 +1,176 @@
+// Imports
+use std::ffi::c_void;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::ptr::null_mut;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+struct MEMORY_BASIC_INFORMATION {
+    BaseAddress: *mut c_void,
+    AllocationBase: *mut c_void,
+    AllocationProtect: u32,
+    #[cfg(target_pointer_width = "64")]
+    PartitionId: u16,
+    RegionSize: usize,
+    State: u32,
+    Protect: u32,
+    Type: u32,
+}
+
+/// The page size on every architecture Windows runs on.
+pub(crate) const PAGE_SIZE: usize = 0x1000;
+
+const MEM_COMMIT: u32 = 0x1000;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const MEM_RESERVE: u32 = 0x2000;
+const MEM_RELEASE: u32 = 0x8000;
+pub(crate) const PAGE_READWRITE: u32 = 0x04;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const PAGE_EXECUTE_READ: u32 = 0x20;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+pub(crate) const PAGE_GUARD: u32 = 0x100;
+// PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY and their PAGE_EXECUTE_* counterparts
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const READABLE: u32 = 0x02 | 0x04 | 0x08 | 0x20 | 0x40 | 0x80;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn VirtualQuery(
+        address: *const c_void,
+        buffer: *mut MEMORY_BASIC_INFORMATION,
+        length: usize,
+    ) -> usize;
+    fn VirtualProtect(address: *const c_void, size: usize, protect: u32, old: *mut u32) -> i32;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn VirtualAlloc(address: *mut c_void, size: usize, kind: u32, protect: u32) -> *mut c_void;
+    fn VirtualFree(address: *mut c_void, size: usize, kind: u32) -> i32;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn FlushInstructionCache(process: *mut c_void, address: *const c_void, size: usize) -> i32;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn GetCurrentProcess() -> *mut c_void;
+    fn GetLastError() -> u32;
+}
+
+/// Whether all of `address..address + len` can be read without faulting.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn is_readable(address: usize, len: usize) -> bool {
+    let end = match address.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    // The range might span several regions with different protections
+    let mut current = address;
+    while current < end {
+        let mut info = std::mem::MaybeUninit::<MEMORY_BASIC_INFORMATION>::uninit();
+        let size = std::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+        if unsafe { VirtualQuery(current as _, info.as_mut_ptr(), size) } == 0 {
+            return false;
+        }
+
+        let info = unsafe { info.assume_init() };
+        let readable = info.State == MEM_COMMIT
+            && info.Protect & PAGE_GUARD == 0
+            && info.Protect & READABLE != 0;
+        if !readable {
+            return false;
+        }
+
+        current = info.BaseAddress as usize + info.RegionSize;
+    }
+
+    true
+}
+
+/// The protection of the page containing `address`, or `None` if it isn't committed.
+pub(crate) fn protection(address: usize) -> Option<u32> {
+    let mut info = std::mem::MaybeUninit::<MEMORY_BASIC_INFORMATION>::uninit();
+    let size = std::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+    if unsafe { VirtualQuery(address as _, info.as_mut_ptr(), size) } == 0 {
+        return None;
+    }
+
+    let info = unsafe { info.assume_init() };
+    (info.State == MEM_COMMIT).then_some(info.Protect)
+}
+
+/// Changes the protection of the pages covering `address..address + size`, returning the
+/// previous protection of the first one, or the `GetLastError` code if it couldn't be changed.
+///
+/// # Safety
+/// The pages must be committed, and nothing may rely on their current protection.
+pub(crate) unsafe fn set_protection(address: usize, size: usize, protect: u32) -> Result<u32, u32> {
+    let mut old = 0;
+    match VirtualProtect(address as _, size, protect, &mut old) {
+        0 => Err(GetLastError()),
+        _ => Ok(old),
+    }
+}
+
+/// Overwrites code at `address` with `bytes`, leaving its protection as it was, and returns the
+/// `GetLastError` code if it can't be made writable.
+///
+/// # Safety
+/// `address..address + bytes.len()` must be mapped, and no thread may be executing the bytes
+/// being replaced in a way that breaks if they change.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) unsafe fn write_code(address: usize, bytes: &[u8]) -> Result<(), u32> {
+    let mut old = 0;
+    let target = address as *const c_void;
+    if VirtualProtect(target, bytes.len(), PAGE_EXECUTE_READWRITE, &mut old) == 0 {
+        return Err(GetLastError());
+    }
+
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, bytes.len());
+    VirtualProtect(target, bytes.len(), old, &mut old);
+    FlushInstructionCache(GetCurrentProcess(), target, bytes.len());
+    Ok(())
+}
+
+/// Copies `code` into a new executable (but not writable) page, returning its address or the
+/// `GetLastError` code. The page is freed again with [`free_code`].
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn alloc_code(code: &[u8]) -> Result<usize, u32> {
+    let size = code.len().max(1);
+    let page = unsafe { VirtualAlloc(null_mut(), size, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE) };
+    if page.is_null() {
+        return Err(unsafe { GetLastError() });
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len());
+        let mut old = 0;
+        if VirtualProtect(page, size, PAGE_EXECUTE_READ, &mut old) == 0 {
+            let error = GetLastError();
+            VirtualFree(page, 0, MEM_RELEASE);
+            return Err(error);
+        }
+        FlushInstructionCache(GetCurrentProcess(), page, size);
+    }
+    Ok(page as usize)
+}
+
+/// Frees a page allocated by [`alloc_code`].
+///
+/// # Safety
+/// No thread may be executing the code anymore.
+pub(crate) unsafe fn free_code(address: usize) {
+    VirtualFree(address as _, 0, MEM_RELEASE);
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    use super::is_readable;
+
+    #[test]
+    fn readable() {
+        let value = 0usize;
+        assert!(is_readable(
+            &value as *const _ as usize,
+            std::mem::size_of::<usize>()
+        ));
+        assert!(!is_readable(0, 1));
+        assert!(!is_readable(usize::MAX, 2));
+    }
+}
@@ -0,0 +1,251 @@
+//! Removing the crate's handlers before the DLL it's linked into unloads.
+//!
+//! A DLL injected into another process has to unregister its handlers first thing on
+//! `DLL_PROCESS_DETACH`, before anything they depend on is torn down, or the next exception
+//! calls into freed or unmapped code. Guards leaked on purpose, or owned by state that's never
+//! dropped, make that easy to get wrong.
+//!
+//! Call [`init`] from `DLL_PROCESS_ATTACH` with the DLL's module handle. From then on, every
+//! [`Veh`](crate::Veh) and [`Vch`](crate::Vch) whose registered function is inside the DLL is
+//! tracked, and calling [`detach`] from `DLL_PROCESS_DETACH` removes whichever of them are still
+//! registered, along with the [dispatcher](crate::dispatch)'s native handler. Guards dropped
+//! afterwards leave ntdll alone, so their memory is never needed for the removal, and a guard
+//! that's already been freed is never touched.
+//!
+//! ```ignore
+//! match reason {
+//!     DLL_PROCESS_ATTACH => manual_veh::module_guard::init(hinstance),
+//!     DLL_PROCESS_DETACH => {
+//!         manual_veh::module_guard::detach();
+//!     }
+//!     _ => {}
+//! }
+//! ```
+
+// Imports
+use crate::{dispatch, raw};
+use std::ffi::c_void;
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{Pe, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{Pe, PeView};
+
+struct Tracked {
+    id: u64,
+    handle: usize,
+    handler_list: i32,
+}
+
+struct State {
+    module: Option<Range<usize>>,
+    detached: bool,
+    next_id: u64,
+    // In registration order
+    tracked: Vec<Tracked>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    module: None,
+    detached: false,
+    next_id: 0,
+    tracked: Vec::new(),
+});
+
+fn state() -> MutexGuard<'static, State> {
+    // A panic while holding the lock can't leave the list half-updated
+    STATE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Starts tracking the handlers registered from inside `module`, which is usually the
+/// `hinstance` passed to `DllMain`.
+///
+/// Handlers registered before this are never tracked. Calling it again switches to another
+/// module, keeping what's tracked so far.
+///
+/// # Safety
+/// `module` must be the base of a module loaded in this process.
+pub unsafe fn init(module: *mut c_void) {
+    let base = module as usize;
+    let size = PeView::module(base as *const u8)
+        .optional_header()
+        .SizeOfImage as usize;
+
+    let mut state = state();
+    state.module = Some(base..base + size);
+    state.detached = false;
+}
+
+/// Unregisters every tracked handler that's still registered, newest first, and then the
+/// dispatcher's native handler if it's inside the module, returning how many handlers were
+/// removed.
+///
+/// Nothing is tracked afterwards, and the dispatcher won't register its native handler again
+/// until [`init`] is called, however many callbacks are left or registered later.
+pub fn detach() -> usize {
+    let (module, mut removed) = {
+        let mut state = state();
+        let module = match state.module.clone() {
+            Some(module) if !state.detached => module,
+            _ => return 0,
+        };
+        state.detached = true;
+
+        // Still under the lock, so a guard dropped meanwhile can't free its state before its
+        // handler is unregistered
+        let mut removed = 0;
+        while let Some(tracked) = state.tracked.pop() {
+            if unsafe { raw::remove_handler(tracked.handler_list, tracked.handle as _) } != 0 {
+                removed += 1;
+            }
+        }
+        (module, removed)
+    };
+
+    if dispatch::detach_native(&module) {
+        removed += 1;
+    }
+    removed
+}
+
+// Whether `detach` has run for the module containing `address`
+pub(crate) fn is_detached(address: usize) -> bool {
+    let state = state();
+    let inside = state.module.as_ref().is_some_and(|m| m.contains(&address));
+    state.detached && inside
+}
+
+// Tracks a registration if `handler` is inside the module, returning the id to untrack it with
+pub(crate) fn track(handler: usize, handle: *const c_void, handler_list: i32) -> Option<u64> {
+    let mut state = state();
+    match &state.module {
+        Some(module) if !state.detached && !handle.is_null() && module.contains(&handler) => {}
+        _ => return None,
+    }
+
+    let id = state.next_id;
+    state.next_id += 1;
+    state.tracked.push(Tracked {
+        id,
+        handle: handle as usize,
+        handler_list,
+    });
+    Some(id)
+}
+
+// Stops tracking a registration that's being removed, returning `false` if `detach` already
+// removed it, in which case the handle must not be passed to ntdll again
+pub(crate) fn untrack(id: u64) -> bool {
+    let mut state = state();
+    match state.tracked.iter().position(|tracked| tracked.id == id) {
+        Some(index) => {
+            state.tracked.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatch::{self, CallbackGuard};
+    use crate::testing::run_isolated;
+    use crate::{module_guard, modules, ExceptionCode, ExceptionInfo, Filter, Handling, Order};
+    use crate::{Veh, VehBuilder};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_6001;
+
+    static RAW_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "system" fn counting(ptrs: *mut c_void) -> i32 {
+        if ExceptionInfo::from_raw(ptrs).code().raw() == CODE {
+            RAW_CALLS.fetch_add(1, Ordering::SeqCst);
+        }
+        Handling::ContinueSearch.raw()
+    }
+
+    unsafe extern "system" fn backstop(ptrs: *mut c_void) -> i32 {
+        match ExceptionInfo::from_raw(ptrs).code().raw() {
+            CODE => Handling::ContinueExecution.raw(),
+            _ => Handling::ContinueSearch.raw(),
+        }
+    }
+
+    fn raise() {
+        unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+    }
+
+    fn in_child() {
+        // The test executable stands in for the DLL, found as the loader's first entry
+        let own = modules::containing(counting as *const () as usize).unwrap();
+        assert_eq!(own.base(), modules::iter().next().unwrap().base());
+        unsafe { module_guard::init(own.base() as *mut c_void) };
+
+        let closure_calls = Arc::new(AtomicUsize::new(0));
+        let dispatched = Arc::new(AtomicUsize::new(0));
+        unsafe {
+            std::mem::forget(Veh::<c_void>::add(Order::First, counting));
+            let seen = closure_calls.clone();
+            let closure = VehBuilder::new()
+                .filter_code(ExceptionCode::from_raw(CODE))
+                .install_closure(move |_| {
+                    seen.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueSearch
+                })
+                .unwrap();
+            std::mem::forget(closure);
+            // Removed normally, so detach has nothing left to do for it
+            Veh::<c_void>::add(Order::First, counting).remove();
+        }
+        let seen = dispatched.clone();
+        let callback =
+            dispatch::register_closure(Filter::code(ExceptionCode::from_raw(CODE)), move |_| {
+                seen.fetch_add(1, Ordering::SeqCst);
+                Handling::ContinueSearch
+            })
+            .unwrap();
+        let _backstop = unsafe { Veh::<c_void>::add(Order::Last, backstop) };
+
+        raise();
+        let counts = || {
+            (
+                RAW_CALLS.load(Ordering::SeqCst),
+                closure_calls.load(Ordering::SeqCst),
+                dispatched.load(Ordering::SeqCst),
+            )
+        };
+        assert_eq!(counts(), (1, 1, 1));
+
+        // The two leaked handlers and the backstop, then the dispatcher
+        assert_eq!(module_guard::detach(), 4);
+        assert!(!dispatch::is_installed());
+        assert_eq!(module_guard::detach(), 0);
+
+        // Registered after detaching, so untracked, and catching what nothing else does now
+        let _backstop = unsafe { Veh::<c_void>::add(Order::Last, backstop) };
+        raise();
+        assert_eq!(counts(), (1, 1, 1));
+
+        // Changing the callbacks after detaching doesn't put the native handler back
+        let _other: CallbackGuard =
+            dispatch::register_closure(Filter::code(ExceptionCode::from_raw(CODE)), |_| {
+                Handling::ContinueSearch
+            })
+            .unwrap();
+        drop(callback);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn leaked_handlers_removed() {
+        run_isolated("module_guard::tests::leaked_handlers_removed", in_child).expect_exit_ok();
+    }
+}
@@ -0,0 +1,448 @@
+// Imports
+use crate::VehError;
+use std::cell::Cell;
+use std::ffi::{c_void, OsString};
+use std::ops::Range;
+use std::os::windows::ffi::OsStringExt;
+
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::GetProcAddress, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::GetProcAddress, PeView};
+
+mod cache;
+
+pub use cache::{cache_modules, lookup_cached, refresh_cache, CachedModule, ModuleCache};
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn LoadLibraryW(name: *const u16) -> *mut c_void;
+}
+
+const GET_MODULE_HANDLE_EX_FLAG_PIN: u32 = 0x1;
+const GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS: u32 = 0x4;
+
+type FnGetModuleHandleExW =
+    unsafe extern "system" fn(flags: u32, name: *const u16, module: *mut usize) -> i32;
+
+const LDR_DLL_NOTIFICATION_REASON_LOADED: u32 = 1;
+const LDR_DLL_NOTIFICATION_REASON_UNLOADED: u32 = 2;
+
+type FnLdrDllNotification = unsafe extern "system" fn(
+    reason: u32,
+    data: *const LDR_DLL_NOTIFICATION_DATA,
+    context: *mut c_void,
+);
+type FnLdrRegisterDllNotification = unsafe extern "system" fn(
+    flags: u32,
+    callback: FnLdrDllNotification,
+    context: *mut c_void,
+    cookie: *mut *mut c_void,
+) -> i32;
+type FnLdrUnregisterDllNotification = unsafe extern "system" fn(cookie: *mut c_void) -> i32;
+
+#[cfg(target_pointer_width = "32")]
+const ARCH_PTR_SIZE: usize = 4;
+#[cfg(target_pointer_width = "64")]
+const ARCH_PTR_SIZE: usize = 8;
+
+// Structs
+#[repr(C)]
+struct UNICODE_STRING {
+    bytes_length: u16,
+    bytes_max_length: u16,
+    buffer: *const u16,
+}
+
+// The same for loads and unloads
+#[repr(C)]
+struct LDR_DLL_NOTIFICATION_DATA {
+    flags: u32,
+    full_dll_name: *const UNICODE_STRING,
+    base_dll_name: *const UNICODE_STRING,
+    dll_base: usize,
+    size_of_image: u32,
+}
+
+/// A module found in the PEB's loader data.
+///
+/// The loader lock isn't taken while walking the list, so the values are only meaningful for as
+/// long as the module stays loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    base: usize,
+    size: usize,
+    name: *const u16,
+    name_len: usize,
+}
+
+impl Module {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.base..self.base + self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.range().contains(&address)
+    }
+
+    /// The module's base name, e.g. `ntdll.dll`.
+    pub fn name(&self) -> String {
+        OsString::from_wide(self.name_wide())
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Compares the base name against `name`, ignoring ASCII case, without allocating.
+    pub fn name_eq(&self, name: &str) -> bool {
+        let mut theirs = name.encode_utf16();
+        self.name_wide()
+            .iter()
+            .map(|&c| Some(c))
+            .chain(std::iter::once(None))
+            .all(|ours| match (ours, theirs.next()) {
+                (Some(a), Some(b)) => ascii_upper(a) == ascii_upper(b),
+                (None, None) => true,
+                _ => false,
+            })
+    }
+
+    /// Keeps the module loaded until the process exits, however many times it's freed, with
+    /// `GetModuleHandleExW`'s `GET_MODULE_HANDLE_EX_FLAG_PIN`. Returns whether it was pinned.
+    ///
+    /// A pin can't be undone, so this is for modules whose code must outlive anything that can
+    /// still call into it, such as a registered handler.
+    pub fn pin(&self) -> bool {
+        let get_handle = match find_get_module_handle_ex() {
+            Some(get_handle) => get_handle,
+            None => return false,
+        };
+        let flags = GET_MODULE_HANDLE_EX_FLAG_PIN | GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS;
+        let mut handle = 0;
+        unsafe { get_handle(flags, self.base as *const u16, &mut handle) != 0 }
+    }
+
+    pub(crate) fn name_wide(&self) -> &[u16] {
+        unsafe { std::slice::from_raw_parts(self.name, self.name_len) }
+    }
+}
+
+fn find_get_module_handle_ex() -> Option<FnGetModuleHandleExW> {
+    let address = kernel_export("GetModuleHandleExW")?;
+    Some(unsafe { std::mem::transmute::<usize, FnGetModuleHandleExW>(address) })
+}
+
+// Resolves a kernel32 function from the export tables without calling `GetProcAddress`, taking
+// kernelbase's export first, as kernel32's forwards to it where there is one
+pub(crate) fn kernel_export(name: &str) -> Option<usize> {
+    ["kernelbase.dll", "kernel32.dll"]
+        .iter()
+        .find_map(|module| {
+            let module = find(module)?;
+            let view = unsafe { PeView::module(module.base() as *const u8) };
+            view.get_proc_address(name)
+                .ok()
+                .map(|address| address as usize)
+        })
+}
+
+fn ascii_upper(c: u16) -> u16 {
+    match c {
+        0x61..=0x7A => c - 0x20,
+        _ => c,
+    }
+}
+
+/// Iterator over the modules in the PEB's `InLoadOrderModuleList`, starting with the executable.
+pub struct Modules {
+    list_base: *const u8,
+    current: *const u8,
+}
+
+impl Iterator for Modules {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Module> {
+        unsafe {
+            loop {
+                // Follow the link
+                self.current = *(self.current as *const *const u8);
+
+                // If we're back at the start, we're done
+                if std::ptr::eq(self.current, self.list_base) {
+                    return None;
+                }
+
+                // Get pointers to base addr + size + name, the link being the entry's first field
+                // TODO: Create a struct of this - _LDR_MODULE
+                let base_ptr = self.current.add(ARCH_PTR_SIZE * 6);
+                let size_ptr = self.current.add(ARCH_PTR_SIZE * 8);
+                let name_ptr = self
+                    .current
+                    .add((ARCH_PTR_SIZE * 9) + std::mem::size_of::<UNICODE_STRING>());
+
+                // Entries that are still being initialized might not have a name yet
+                if let Some(name) = (name_ptr as *const UNICODE_STRING).as_ref() {
+                    if name.buffer.is_null() {
+                        continue;
+                    }
+
+                    return Some(Module {
+                        base: *(base_ptr as *const usize),
+                        size: *(size_ptr as *const u32) as usize,
+                        name: name.buffer,
+                        name_len: (name.bytes_length / 2) as usize,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Walks the loaded modules straight from the PEB, without calling any APIs.
+pub fn iter() -> Modules {
+    // Get PEB
+    let peb: *const u8;
+
+    #[cfg(target_pointer_width = "32")]
+    let list_base = unsafe {
+        std::arch::asm!("mov {}, fs:[30h]", out(reg) peb);
+
+        // PEB
+        let x = peb;
+        // Ldr
+        let x = *(x.add(0x0C) as *const *const u8);
+        // InLoadOrderModuleList, as the initialization order one leaves out the executable
+        x.add(0x0C)
+    };
+
+    #[cfg(target_pointer_width = "64")]
+    let list_base = unsafe {
+        std::arch::asm!("mov {}, gs:[60h]", out(reg) peb);
+
+        // PEB
+        let x = peb;
+        // Ldr
+        let x = *(x.add(0x18) as *const *const u8);
+        // InLoadOrderModuleList, as the initialization order one leaves out the executable
+        x.add(0x10)
+    };
+
+    Modules {
+        list_base,
+        current: list_base,
+    }
+}
+
+/// Finds a loaded module by its base name, ignoring ASCII case.
+pub fn find(name: &str) -> Option<Module> {
+    iter().find(|module| module.name_eq(name))
+}
+
+/// Finds the loaded module whose image contains `address`.
+pub fn containing(address: usize) -> Option<Module> {
+    iter().find(|module| module.contains(address))
+}
+
+// Finds a loaded module by name, loading it with `LoadLibraryW` if it isn't loaded yet
+pub(crate) fn load(name: &str) -> Option<Module> {
+    find(name).or_else(|| {
+        let wide: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+        unsafe { LoadLibraryW(wide.as_ptr()) };
+        find(name)
+    })
+}
+
+/// Whether a [`ModuleEvent`] is for a module being loaded or unloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleEventKind {
+    /// The module was mapped, before its entry point ran.
+    Loaded,
+    /// The module is about to be unmapped, after its entry point ran for the detach.
+    Unloaded,
+}
+
+/// A module load or unload, as passed to a [`Notification`] callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleEvent {
+    pub kind: ModuleEventKind,
+    pub base: usize,
+    pub size: usize,
+    /// The module's full path, e.g. `C:\Windows\System32\ntdll.dll`.
+    pub full_name: String,
+    /// The module's base name, e.g. `ntdll.dll`.
+    pub name: String,
+}
+
+impl ModuleEvent {
+    pub fn range(&self) -> Range<usize> {
+        self.base..self.base + self.size
+    }
+}
+
+/// Module load and unload notifications from the loader, with `LdrRegisterDllNotification`.
+pub struct Notification;
+
+impl Notification {
+    /// Calls `callback` every time a module is loaded or unloaded, on the thread doing it, until
+    /// the returned guard is dropped.
+    ///
+    /// The callback runs while the thread holds the loader lock, so it must be quick and must not
+    /// load or free modules, wait on other threads, or register or remove handlers or
+    /// notifications; debug builds assert on the last two.
+    pub fn register(callback: fn(ModuleEvent)) -> Result<NotificationGuard, VehError> {
+        debug_assert_outside_notification();
+        let (register, unregister) = find_dll_notification().ok_or(VehError::Resolution)?;
+
+        let mut cookie = std::ptr::null_mut();
+        let context = callback as *mut c_void;
+        let status = unsafe { register(0, notified, context, &mut cookie) };
+        match status >= 0 {
+            true => Ok(NotificationGuard { cookie, unregister }),
+            false => Err(VehError::Registration),
+        }
+    }
+}
+
+/// Keeps a [`Notification`] callback registered until dropped.
+pub struct NotificationGuard {
+    cookie: *mut c_void,
+    unregister: FnLdrUnregisterDllNotification,
+}
+
+// The cookie is only ever passed back to ntdll
+unsafe impl Send for NotificationGuard {}
+unsafe impl Sync for NotificationGuard {}
+
+impl Drop for NotificationGuard {
+    fn drop(&mut self) {
+        debug_assert_outside_notification();
+        unsafe { (self.unregister)(self.cookie) };
+    }
+}
+
+thread_local! {
+    static IN_NOTIFICATION: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe extern "system" fn notified(
+    reason: u32,
+    data: *const LDR_DLL_NOTIFICATION_DATA,
+    context: *mut c_void,
+) {
+    let kind = match reason {
+        LDR_DLL_NOTIFICATION_REASON_LOADED => ModuleEventKind::Loaded,
+        LDR_DLL_NOTIFICATION_REASON_UNLOADED => ModuleEventKind::Unloaded,
+        _ => return,
+    };
+    let data = match data.as_ref() {
+        Some(data) => data,
+        None => return,
+    };
+
+    let event = ModuleEvent {
+        kind,
+        base: data.dll_base,
+        size: data.size_of_image as usize,
+        full_name: unicode_string(data.full_dll_name),
+        name: unicode_string(data.base_dll_name),
+    };
+    let callback = std::mem::transmute::<*mut c_void, fn(ModuleEvent)>(context);
+
+    let outer = IN_NOTIFICATION.with(|inside| inside.replace(true));
+    callback(event);
+    IN_NOTIFICATION.with(|inside| inside.set(outer));
+}
+
+unsafe fn unicode_string(string: *const UNICODE_STRING) -> String {
+    match string.as_ref() {
+        Some(string) if !string.buffer.is_null() => {
+            let len = (string.bytes_length / 2) as usize;
+            let wide = std::slice::from_raw_parts(string.buffer, len);
+            OsString::from_wide(wide).to_string_lossy().into_owned()
+        }
+        _ => String::new(),
+    }
+}
+
+// Registering from a notification callback deadlocks or re-enters the loader
+pub(crate) fn debug_assert_outside_notification() {
+    debug_assert!(
+        !IN_NOTIFICATION.with(Cell::get),
+        "registration APIs must not be called from a module notification callback"
+    );
+}
+
+fn find_dll_notification() -> Option<(FnLdrRegisterDllNotification, FnLdrUnregisterDllNotification)>
+{
+    let module = find("ntdll.dll")?;
+    let view = unsafe { PeView::module(module.base() as *const u8) };
+    let register = view.get_proc_address("LdrRegisterDllNotification").ok()?;
+    let unregister = view.get_proc_address("LdrUnregisterDllNotification").ok()?;
+    unsafe {
+        Some((
+            std::mem::transmute::<usize, FnLdrRegisterDllNotification>(register as usize),
+            std::mem::transmute::<usize, FnLdrUnregisterDllNotification>(unregister as usize),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{containing, find, iter, ModuleEvent, ModuleEventKind, Notification};
+    use std::ffi::c_void;
+    use std::sync::Mutex;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LoadLibraryW(name: *const u16) -> *mut c_void;
+        fn FreeLibrary(module: *mut c_void) -> i32;
+    }
+
+    static EVENTS: Mutex<Vec<(ModuleEventKind, usize)>> = Mutex::new(Vec::new());
+
+    // Other tests load modules too
+    fn record(event: ModuleEvent) {
+        if event.name.eq_ignore_ascii_case("msimg32.dll") {
+            EVENTS.lock().unwrap().push((event.kind, event.base));
+        }
+    }
+
+    #[test]
+    fn executable_found() {
+        let path = std::env::current_exe().unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let module = find(name).unwrap();
+        assert_eq!(iter().next().unwrap().base(), module.base());
+
+        let own = executable_found as *const () as usize;
+        assert_eq!(containing(own).unwrap().base(), module.base());
+    }
+
+    #[test]
+    fn load_and_unload_notified() {
+        let guard = Notification::register(record).unwrap();
+        let name: Vec<u16> = "msimg32.dll".encode_utf16().chain(Some(0)).collect();
+        let module = unsafe { LoadLibraryW(name.as_ptr()) };
+        assert!(!module.is_null());
+        unsafe { FreeLibrary(module) };
+        drop(guard);
+
+        let base = module as usize;
+        let events = EVENTS.lock().unwrap();
+        assert_eq!(
+            *events,
+            [
+                (ModuleEventKind::Loaded, base),
+                (ModuleEventKind::Unloaded, base)
+            ]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+// Imports
+use super::{iter, ModuleEvent, ModuleEventKind, Notification, NotificationGuard};
+use crate::snapshot::MAX_MODULE_NAME;
+use crate::sync::InFlight;
+use crate::VehError;
+use std::ffi::OsString;
+use std::ops::Range;
+use std::os::windows::ffi::OsStringExt;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// A module from the cache enabled with [`cache_modules`], copied out of it by
+/// [`lookup_cached`].
+#[derive(Clone, Copy)]
+pub struct CachedModule {
+    base: usize,
+    size: usize,
+    name: [u16; MAX_MODULE_NAME],
+    name_len: usize,
+    possibly_unloaded: bool,
+}
+
+impl CachedModule {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.base..self.base + self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.range().contains(&address)
+    }
+
+    /// The module's base name, e.g. `ntdll.dll`.
+    pub fn name(&self) -> String {
+        OsString::from_wide(&self.name[..self.name_len])
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Whether the module might have been unloaded since, because its unload was the last
+    /// change the cache saw, or the cache changed while it was being looked up.
+    pub fn possibly_unloaded(&self) -> bool {
+        self.possibly_unloaded
+    }
+
+    fn new(base: usize, size: usize, wide: &[u16]) -> Self {
+        let name_len = wide.len().min(MAX_MODULE_NAME);
+        let mut name = [0; MAX_MODULE_NAME];
+        name[..name_len].copy_from_slice(&wide[..name_len]);
+        CachedModule {
+            base,
+            size,
+            name,
+            name_len,
+            possibly_unloaded: false,
+        }
+    }
+}
+
+impl std::fmt::Debug for CachedModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedModule")
+            .field("base", &self.base)
+            .field("size", &self.size)
+            .field("name", &self.name())
+            .field("possibly_unloaded", &self.possibly_unloaded)
+            .finish()
+    }
+}
+
+// Sorted by base
+struct Snapshot {
+    modules: Vec<CachedModule>,
+}
+
+// Replaced snapshots are freed by whichever rebuild next finds no lookup running, as the
+// dispatcher does with its callback lists
+static SNAPSHOT: AtomicPtr<Snapshot> = AtomicPtr::new(null_mut());
+static READERS: InFlight = InFlight::new();
+
+struct Writer {
+    enabled: bool,
+    // Boxed where lookups may still be reading them
+    #[allow(clippy::vec_box)]
+    retired: Vec<Box<Snapshot>>,
+}
+
+// Only taken to rebuild, which the loader does from its notification while holding the loader
+// lock, so it's never held while registering or removing the notification itself
+static WRITER: Mutex<Writer> = Mutex::new(Writer {
+    enabled: false,
+    retired: Vec::new(),
+});
+
+struct Users {
+    count: usize,
+    notification: Option<NotificationGuard>,
+}
+
+static USERS: Mutex<Users> = Mutex::new(Users {
+    count: 0,
+    notification: None,
+});
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Keeps the module cache enabled until dropped, and every other one is.
+pub struct ModuleCache {
+    _private: (),
+}
+
+/// Enables the cache [`lookup_cached`] reads, built from the PEB now and rebuilt every time a
+/// module is loaded or unloaded, until the returned guard is dropped.
+///
+/// Rebuilding is done by the thread loading or unloading the module, while it holds the loader
+/// lock, as for any [`Notification`].
+pub fn cache_modules() -> Result<ModuleCache, VehError> {
+    let mut users = lock(&USERS);
+    if users.count == 0 {
+        // Registered first, so no load between building and registering goes unnoticed
+        let notification = Notification::register(rebuild_for)?;
+        lock(&WRITER).enabled = true;
+        rebuild(None);
+        users.notification = Some(notification);
+    }
+    users.count += 1;
+    Ok(ModuleCache { _private: () })
+}
+
+impl Drop for ModuleCache {
+    fn drop(&mut self) {
+        let mut users = lock(&USERS);
+        users.count -= 1;
+        if users.count > 0 {
+            return;
+        }
+
+        users.notification.take();
+        let mut writer = lock(&WRITER);
+        writer.enabled = false;
+        publish(&mut writer, null_mut());
+    }
+}
+
+/// Rebuilds the cache from the PEB now, if it's enabled, forgetting the modules it still kept
+/// after they were unloaded.
+pub fn refresh_cache() {
+    rebuild(None)
+}
+
+/// Finds the module containing `address` in the cache, with a binary search that doesn't
+/// allocate, take locks or walk the loader's lists, so it's cheap enough for any handler.
+///
+/// `None` if the cache isn't enabled. A module that was just unloaded is still found until the
+/// next module is loaded, or the cache is refreshed, flagged as
+/// [`possibly_unloaded`](CachedModule::possibly_unloaded).
+pub fn lookup_cached(address: usize) -> Option<CachedModule> {
+    let _reading = READERS.enter();
+    let current = SNAPSHOT.load(Ordering::SeqCst);
+    let modules = unsafe { &current.as_ref()?.modules };
+
+    let index = modules
+        .partition_point(|module| module.base <= address)
+        .checked_sub(1)?;
+    let mut module = modules[index];
+    if !module.contains(address) {
+        return None;
+    }
+
+    // Rebuilt meanwhile, maybe for this module's unload
+    module.possibly_unloaded |= SNAPSHOT.load(Ordering::SeqCst) != current;
+    Some(module)
+}
+
+fn rebuild_for(event: ModuleEvent) {
+    rebuild(Some(&event))
+}
+
+fn rebuild(event: Option<&ModuleEvent>) {
+    let mut writer = lock(&WRITER);
+    if !writer.enabled {
+        return;
+    }
+
+    let mut modules: Vec<_> = iter()
+        .map(|module| CachedModule::new(module.base(), module.size(), module.name_wide()))
+        .collect();
+
+    match event {
+        Some(event) if event.kind == ModuleEventKind::Loaded => {
+            // The loader may not have linked it into its lists yet
+            let listed = modules.iter().any(|module| module.base == event.base);
+            if !listed {
+                let name: Vec<u16> = event.name.encode_utf16().collect();
+                modules.push(CachedModule::new(event.base, event.size, &name));
+            }
+        }
+        Some(event) => {
+            // Kept around, flagged, as are the ones unloaded since the last load
+            let previous = unsafe { SNAPSHOT.load(Ordering::SeqCst).as_ref() };
+            let unloaded = previous.into_iter().flat_map(|snapshot| &snapshot.modules);
+            let unloaded =
+                unloaded.filter(|module| module.base == event.base || module.possibly_unloaded);
+            let mut unloaded: Vec<_> = unloaded.copied().collect();
+
+            modules.retain(|module| module.base != event.base);
+            unloaded.retain(|old| !modules.iter().any(|live| live.base == old.base));
+            modules.extend(unloaded.into_iter().map(|mut module| {
+                module.possibly_unloaded = true;
+                module
+            }));
+        }
+        None => {}
+    }
+
+    modules.sort_by_key(|module| module.base);
+    let snapshot = Box::into_raw(Box::new(Snapshot { modules }));
+    publish(&mut writer, snapshot);
+}
+
+fn publish(writer: &mut Writer, snapshot: *mut Snapshot) {
+    let old = SNAPSHOT.swap(snapshot, Ordering::SeqCst);
+    if !old.is_null() {
+        writer.retired.push(unsafe { Box::from_raw(old) });
+    }
+    if READERS.is_idle() {
+        writer.retired.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_modules, lookup_cached, refresh_cache};
+    use crate::modules;
+    use std::ffi::c_void;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LoadLibraryW(name: *const u16) -> *mut c_void;
+        fn FreeLibrary(module: *mut c_void) -> i32;
+    }
+
+    #[test]
+    fn rebuilt_on_load() {
+        let own = lookup_cached as *const () as usize;
+        assert!(lookup_cached(own).is_none());
+
+        let cache = cache_modules().unwrap();
+        // In the test executable, the loader's first entry
+        let found = lookup_cached(own).unwrap();
+        assert_eq!(found.base(), modules::containing(own).unwrap().base());
+        assert_eq!(found.base(), modules::iter().next().unwrap().base());
+        assert!(!found.possibly_unloaded());
+
+        // Not loaded by anything else in the tests
+        let name: Vec<u16> = "dciman32.dll".encode_utf16().chain(Some(0)).collect();
+        let module = unsafe { LoadLibraryW(name.as_ptr()) } as usize;
+        assert_ne!(module, 0);
+        for offset in (0..0x1000).step_by(0x10) {
+            let found = lookup_cached(module + offset).unwrap();
+            assert_eq!(found.base(), module);
+            assert!(found.name().eq_ignore_ascii_case("dciman32.dll"));
+        }
+
+        unsafe { FreeLibrary(module as *mut c_void) };
+        if modules::find("dciman32.dll").is_none() {
+            assert!(lookup_cached(module).unwrap().possibly_unloaded());
+            refresh_cache();
+            assert!(lookup_cached(module).is_none());
+        }
+
+        drop(cache);
+        assert!(lookup_cached(own).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+// Imports
+use crate::{ExceptionInfo, Handling};
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Mutex;
+
+// Unwinding out of a handler would cross back into ntdll, so every Rust callback the crate calls
+// from one of its own handlers goes through `guarded`, which stops the panic right there.
+
+static DISPOSITION: AtomicI32 = AtomicI32::new(Handling::ContinueSearch.raw());
+static ABORT: AtomicBool = AtomicBool::new(false);
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+/// Sets what a callback that panicked is treated as having returned. Defaults to
+/// [`Handling::ContinueSearch`], passing the exception on to the next handler.
+pub fn set_handler_panic_disposition(handling: Handling) {
+    DISPOSITION.store(handling.raw(), Ordering::Relaxed);
+}
+
+/// Aborts the process when a callback panics, rather than recovering from it.
+pub fn abort_on_handler_panic() {
+    ABORT.store(true, Ordering::Relaxed);
+}
+
+/// The message of the most recent panic caught in a callback, if there was one since the last
+/// call.
+pub fn take_last_handler_panic() -> Option<String> {
+    LAST_PANIC
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+}
+
+/// Calls `callback`, turning a panic into the configured disposition.
+pub(crate) fn guarded(
+    callback: impl FnOnce(&mut ExceptionInfo) -> Handling,
+    info: &mut ExceptionInfo,
+) -> Handling {
+    match catch_unwind(AssertUnwindSafe(|| callback(info))) {
+        Ok(handling) => handling,
+        Err(payload) => {
+            if ABORT.load(Ordering::Relaxed) {
+                std::process::abort();
+            }
+
+            *LAST_PANIC
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(message(payload));
+            Handling::from_raw(DISPOSITION.load(Ordering::Relaxed))
+        }
+    }
+}
+
+fn message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => String::from("Box<dyn Any>"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::take_last_handler_panic;
+    use crate::{dispatch, ExceptionCode, ExceptionInfo, Filter, Handling, Order, Veh};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_5001;
+
+    #[test]
+    fn panic_passed_on() {
+        static FIXED_UP: AtomicUsize = AtomicUsize::new(0);
+
+        fn panicking(_: &mut ExceptionInfo) -> Handling {
+            panic!("callback panicked");
+        }
+
+        unsafe extern "system" fn fix_up(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                CODE => {
+                    FIXED_UP.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueExecution.raw()
+                }
+                _ => Handling::ContinueSearch.raw(),
+            }
+        }
+
+        let _serial = dispatch::tests::serial();
+
+        unsafe {
+            let _fix_up = Veh::<c_void>::add(Order::Last, fix_up);
+            let filter = Filter::code(ExceptionCode::from_raw(CODE));
+            let _panicking = dispatch::register(filter, panicking).unwrap();
+            RaiseException(CODE, 0, 0, std::ptr::null());
+        }
+
+        assert_eq!(FIXED_UP.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            take_last_handler_panic().as_deref(),
+            Some("callback panicked")
+        );
+        assert_eq!(take_last_handler_panic(), None);
+    }
+}
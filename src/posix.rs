@@ -0,0 +1,374 @@
+//! POSIX-style signals on top of the dispatcher, for code ported from `sigaction` handlers.
+//!
+//! [`signal`] registers a handler for the Windows statuses a POSIX system would have delivered
+//! as that signal, and passes it a [`SigInfo`] with an `si_code`-like reason and the fault
+//! address:
+//!
+//! | Signal    | Status                                     | Code                           |
+//! |-----------|--------------------------------------------|--------------------------------|
+//! | `SIGSEGV` | `STATUS_ACCESS_VIOLATION`                  | `SEGV_ACCERR` if the page is committed, `SEGV_MAPERR` otherwise |
+//! |           | `STATUS_GUARD_PAGE_VIOLATION`              | `SEGV_ACCERR`                  |
+//! |           | `STATUS_STACK_OVERFLOW`                    | `SEGV_MAPERR`                  |
+//! | `SIGFPE`  | `STATUS_INTEGER_DIVIDE_BY_ZERO`            | `FPE_INTDIV`                   |
+//! |           | `STATUS_INTEGER_OVERFLOW`                  | `FPE_INTOVF`                   |
+//! |           | `STATUS_FLOAT_DIVIDE_BY_ZERO`              | `FPE_FLTDIV`                   |
+//! |           | `STATUS_FLOAT_OVERFLOW`                    | `FPE_FLTOVF`                   |
+//! |           | `STATUS_FLOAT_UNDERFLOW`                   | `FPE_FLTUND`                   |
+//! |           | `STATUS_FLOAT_INEXACT_RESULT`              | `FPE_FLTRES`                   |
+//! |           | `STATUS_FLOAT_INVALID_OPERATION`           | `FPE_FLTINV`                   |
+//! |           | `STATUS_FLOAT_DENORMAL_OPERAND`            | `FPE_FLTINV`                   |
+//! |           | `STATUS_FLOAT_STACK_CHECK`                 | `FPE_FLTINV`                   |
+//! |           | `STATUS_FLOAT_MULTIPLE_FAULTS` and `_TRAPS` | from the unmasked `MXCSR` flags, `FPE_FLTINV` if none |
+//! |           | `STATUS_ARRAY_BOUNDS_EXCEEDED`             | `FPE_FLTSUB`                   |
+//! | `SIGILL`  | `STATUS_ILLEGAL_INSTRUCTION`               | `ILL_ILLOPC`                   |
+//! |           | `STATUS_PRIVILEGED_INSTRUCTION`            | `ILL_PRVOPC`                   |
+//! | `SIGTRAP` | `STATUS_BREAKPOINT`                        | `TRAP_BRKPT`                   |
+//! |           | `STATUS_SINGLE_STEP`                       | `TRAP_TRACE`                   |
+//! | `SIGBUS`  | `STATUS_DATATYPE_MISALIGNMENT`             | `BUS_ADRALN`                   |
+//! |           | `STATUS_IN_PAGE_ERROR`                     | `BUS_OBJERR`                   |
+//!
+//! Unlike a signal handler, returning from a handler doesn't retry the faulting instruction: the
+//! exception is passed on to the callbacks and handlers after it, as if the handler weren't
+//! there. Handlers that recover do so by not returning, ending the process or unwinding.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{memory, ExceptionCode, ExceptionInfo, Filter, Handling, VehError};
+
+// Architecture-specific imports
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fp::{self, FpFault};
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn RaiseException(code: u32, flags: u32, count: u32, arguments: *const usize);
+}
+
+/// The signals Windows exceptions are translated to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Signal {
+    /// `SIGSEGV`, an invalid memory access.
+    Segv,
+    /// `SIGFPE`, an arithmetic error.
+    Fpe,
+    /// `SIGILL`, an illegal instruction.
+    Ill,
+    /// `SIGTRAP`, a breakpoint or trace trap.
+    Trap,
+    /// `SIGBUS`, a bus error.
+    Bus,
+}
+
+impl Signal {
+    /// The signal's number on Linux.
+    pub const fn number(self) -> i32 {
+        match self {
+            Signal::Ill => 4,
+            Signal::Trap => 5,
+            Signal::Bus => 7,
+            Signal::Fpe => 8,
+            Signal::Segv => 11,
+        }
+    }
+
+    /// The statuses delivered as this signal, see the [module documentation](self). The first
+    /// one is what [`raise`] raises.
+    pub const fn statuses(self) -> &'static [ExceptionCode] {
+        match self {
+            Signal::Segv => &[
+                ExceptionCode::AccessViolation,
+                ExceptionCode::GuardPageViolation,
+                ExceptionCode::StackOverflow,
+            ],
+            Signal::Fpe => &[
+                ExceptionCode::IntegerDivideByZero,
+                ExceptionCode::IntegerOverflow,
+                ExceptionCode::FloatDivideByZero,
+                ExceptionCode::FloatOverflow,
+                ExceptionCode::FloatUnderflow,
+                ExceptionCode::FloatInexactResult,
+                ExceptionCode::FloatInvalidOperation,
+                ExceptionCode::FloatDenormalOperand,
+                ExceptionCode::FloatStackCheck,
+                ExceptionCode::FloatMultipleFaults,
+                ExceptionCode::FloatMultipleTraps,
+                ExceptionCode::ArrayBoundsExceeded,
+            ],
+            Signal::Ill => &[
+                ExceptionCode::IllegalInstruction,
+                ExceptionCode::PrivilegedInstruction,
+            ],
+            Signal::Trap => &[ExceptionCode::Breakpoint, ExceptionCode::SingleStep],
+            Signal::Bus => &[
+                ExceptionCode::DatatypeMisalignment,
+                ExceptionCode::InPageError,
+            ],
+        }
+    }
+}
+
+/// Why a signal was delivered, after the `si_code` values of the same names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SigCode {
+    /// `SEGV_MAPERR`, the address isn't mapped.
+    SegvMapErr,
+    /// `SEGV_ACCERR`, the page doesn't allow the access.
+    SegvAccErr,
+    /// `FPE_INTDIV`, integer division by zero.
+    FpeIntDiv,
+    /// `FPE_INTOVF`, integer overflow.
+    FpeIntOvf,
+    /// `FPE_FLTDIV`, floating-point division by zero.
+    FpeFltDiv,
+    /// `FPE_FLTOVF`, floating-point overflow.
+    FpeFltOvf,
+    /// `FPE_FLTUND`, floating-point underflow.
+    FpeFltUnd,
+    /// `FPE_FLTRES`, an inexact floating-point result.
+    FpeFltRes,
+    /// `FPE_FLTINV`, an invalid floating-point operation.
+    FpeFltInv,
+    /// `FPE_FLTSUB`, a subscript out of range.
+    FpeFltSub,
+    /// `ILL_ILLOPC`, an illegal opcode.
+    IllIllOpc,
+    /// `ILL_PRVOPC`, a privileged opcode.
+    IllPrvOpc,
+    /// `TRAP_BRKPT`, a breakpoint.
+    TrapBrkpt,
+    /// `TRAP_TRACE`, a trace trap.
+    TrapTrace,
+    /// `BUS_ADRALN`, a misaligned address.
+    BusAdrAln,
+    /// `BUS_OBJERR`, an error backing the page, such as a read error of a mapped file.
+    BusObjErr,
+}
+
+/// A translated exception, passed to the handlers registered with [`signal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigInfo {
+    pub signal: Signal,
+    pub code: SigCode,
+    /// Like `si_addr`, the address that was accessed for `SIGSEGV` and `SIGBUS` when the
+    /// exception says which, and the faulting instruction otherwise.
+    pub address: usize,
+    /// The status the signal was translated from.
+    pub status: ExceptionCode,
+}
+
+impl SigInfo {
+    /// Translates an exception into the signal a POSIX system would have delivered for it, or
+    /// `None` if it has none.
+    pub fn translate(info: &mut ExceptionInfo) -> Option<SigInfo> {
+        let status = info.code();
+        let accessed = info.parameter(1).unwrap_or(info.address());
+        let (signal, code, address) = match status {
+            ExceptionCode::AccessViolation => {
+                let code = match info.accessed_address().and_then(memory::protection) {
+                    Some(_) => SigCode::SegvAccErr,
+                    None => SigCode::SegvMapErr,
+                };
+                (Signal::Segv, code, accessed)
+            }
+            ExceptionCode::GuardPageViolation => (Signal::Segv, SigCode::SegvAccErr, accessed),
+            ExceptionCode::StackOverflow => (Signal::Segv, SigCode::SegvMapErr, accessed),
+            ExceptionCode::InPageError => (Signal::Bus, SigCode::BusObjErr, accessed),
+            ExceptionCode::DatatypeMisalignment => {
+                (Signal::Bus, SigCode::BusAdrAln, info.address())
+            }
+            status => {
+                let (signal, code) = match status {
+                    ExceptionCode::IntegerDivideByZero => (Signal::Fpe, SigCode::FpeIntDiv),
+                    ExceptionCode::IntegerOverflow => (Signal::Fpe, SigCode::FpeIntOvf),
+                    ExceptionCode::FloatDivideByZero => (Signal::Fpe, SigCode::FpeFltDiv),
+                    ExceptionCode::FloatOverflow => (Signal::Fpe, SigCode::FpeFltOvf),
+                    ExceptionCode::FloatUnderflow => (Signal::Fpe, SigCode::FpeFltUnd),
+                    ExceptionCode::FloatInexactResult => (Signal::Fpe, SigCode::FpeFltRes),
+                    ExceptionCode::FloatInvalidOperation
+                    | ExceptionCode::FloatDenormalOperand
+                    | ExceptionCode::FloatStackCheck => (Signal::Fpe, SigCode::FpeFltInv),
+                    ExceptionCode::FloatMultipleFaults | ExceptionCode::FloatMultipleTraps => {
+                        (Signal::Fpe, multiple(info))
+                    }
+                    ExceptionCode::ArrayBoundsExceeded => (Signal::Fpe, SigCode::FpeFltSub),
+                    ExceptionCode::IllegalInstruction => (Signal::Ill, SigCode::IllIllOpc),
+                    ExceptionCode::PrivilegedInstruction => (Signal::Ill, SigCode::IllPrvOpc),
+                    ExceptionCode::Breakpoint => (Signal::Trap, SigCode::TrapBrkpt),
+                    ExceptionCode::SingleStep => (Signal::Trap, SigCode::TrapTrace),
+                    _ => return None,
+                };
+                (signal, code, info.address())
+            }
+        };
+
+        Some(SigInfo {
+            signal,
+            code,
+            address,
+            status,
+        })
+    }
+}
+
+// SSE exceptions reported together, where the flags in `MXCSR` that aren't masked say which
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn multiple(info: &mut ExceptionInfo) -> SigCode {
+    let mxcsr = fp::mxcsr(info.context_mut());
+    match FpFault::from_status(ExceptionCode::FloatMultipleTraps, mxcsr) {
+        Some(FpFault::DivideByZero) => SigCode::FpeFltDiv,
+        Some(FpFault::Overflow) => SigCode::FpeFltOvf,
+        Some(FpFault::Underflow) => SigCode::FpeFltUnd,
+        Some(FpFault::Inexact) => SigCode::FpeFltRes,
+        _ => SigCode::FpeFltInv,
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn multiple(_info: &mut ExceptionInfo) -> SigCode {
+    SigCode::FpeFltInv
+}
+
+/// Keeps a handler registered with [`signal`] until dropped.
+pub struct SignalGuard {
+    _callback: CallbackGuard,
+}
+
+/// Calls `handler` for every exception delivered as `signal`, until the returned guard is
+/// dropped.
+///
+/// The exception is passed on once the handler returns, see the
+/// [module documentation](self). Handlers registered for the same signal all run, in the order
+/// they were registered.
+pub fn signal(signal: Signal, handler: fn(&SigInfo)) -> Result<SignalGuard, VehError> {
+    let filter = Filter::codes(signal.statuses().iter().copied());
+    let callback = Registration::new()
+        .filter(filter)
+        .register_closure(move |info| {
+            if let Some(translated) = SigInfo::translate(info) {
+                handler(&translated);
+            }
+            Handling::ContinueSearch
+        })?;
+    Ok(SignalGuard {
+        _callback: callback,
+    })
+}
+
+/// Raises the first of `signal`'s [statuses](Signal::statuses) with `RaiseException`, without
+/// any parameters.
+pub fn raise(signal: Signal) {
+    let status = signal.statuses()[0];
+    unsafe { RaiseException(status.raw(), 0, 0, std::ptr::null()) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{raise, signal, SigCode, SigInfo, Signal};
+    use crate::dispatch::{self, Registration};
+    use crate::{teb, ExceptionCode, Filter, Handling};
+    use std::sync::Mutex;
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const SIGNALS: [Signal; 5] = [
+        Signal::Segv,
+        Signal::Fpe,
+        Signal::Ill,
+        Signal::Trap,
+        Signal::Bus,
+    ];
+
+    static DELIVERED: Mutex<Vec<(u32, SigInfo)>> = Mutex::new(Vec::new());
+
+    fn record(info: &SigInfo) {
+        let tid = teb::current_thread_id();
+        DELIVERED.lock().unwrap().push((tid, *info));
+    }
+
+    // Runs `f` with every signal handled, returning what was delivered on this thread
+    fn delivered(f: impl FnOnce()) -> Vec<SigInfo> {
+        let handlers = SIGNALS.map(|sig| signal(sig, record).unwrap());
+        let statuses = SIGNALS
+            .iter()
+            .flat_map(|sig| sig.statuses().iter().copied());
+        let filter = Filter::codes(statuses).and(Filter::current_thread());
+        let fixup = Registration::new()
+            .priority(1)
+            .filter(filter)
+            .register_closure(|_| Handling::ContinueExecution)
+            .unwrap();
+        f();
+        drop(fixup);
+        drop(handlers);
+
+        // Other tests' exceptions are delivered too
+        let tid = teb::current_thread_id();
+        let mut delivered = DELIVERED.lock().unwrap();
+        let delivered = delivered.drain(..).filter(|(thread, _)| *thread == tid);
+        delivered.map(|(_, info)| info).collect()
+    }
+
+    #[test]
+    fn every_status_translated() {
+        let _serial = dispatch::tests::serial();
+        // In the order the statuses are raised in
+        let expected = [
+            (Signal::Segv, SigCode::SegvMapErr),
+            (Signal::Segv, SigCode::SegvAccErr),
+            (Signal::Segv, SigCode::SegvAccErr),
+            (Signal::Segv, SigCode::SegvMapErr),
+            (Signal::Fpe, SigCode::FpeIntDiv),
+            (Signal::Fpe, SigCode::FpeIntOvf),
+            (Signal::Fpe, SigCode::FpeFltDiv),
+            (Signal::Fpe, SigCode::FpeFltOvf),
+            (Signal::Fpe, SigCode::FpeFltUnd),
+            (Signal::Fpe, SigCode::FpeFltRes),
+            (Signal::Fpe, SigCode::FpeFltInv),
+            (Signal::Fpe, SigCode::FpeFltInv),
+            (Signal::Fpe, SigCode::FpeFltInv),
+            // No flags are unmasked in the context `RaiseException` captures
+            (Signal::Fpe, SigCode::FpeFltInv),
+            (Signal::Fpe, SigCode::FpeFltInv),
+            (Signal::Fpe, SigCode::FpeFltSub),
+            (Signal::Ill, SigCode::IllIllOpc),
+            (Signal::Ill, SigCode::IllPrvOpc),
+            (Signal::Trap, SigCode::TrapBrkpt),
+            (Signal::Trap, SigCode::TrapTrace),
+            (Signal::Bus, SigCode::BusAdrAln),
+            (Signal::Bus, SigCode::BusObjErr),
+        ];
+
+        // Accessing the unmapped first page, and then the committed page holding `local`
+        let local = 0u8;
+        let unmapped = [1, 0x10];
+        let committed = [0, &local as *const u8 as usize];
+        let raised = delivered(|| unsafe {
+            let code = ExceptionCode::AccessViolation.raw();
+            RaiseException(code, 0, 2, unmapped.as_ptr());
+            RaiseException(code, 0, 2, committed.as_ptr());
+
+            let statuses = SIGNALS.iter().flat_map(|sig| sig.statuses());
+            for status in statuses.filter(|s| **s != ExceptionCode::AccessViolation) {
+                RaiseException(status.raw(), 0, 2, unmapped.as_ptr());
+            }
+        });
+
+        let signals: Vec<_> = raised.iter().map(|info| (info.signal, info.code)).collect();
+        assert_eq!(signals, expected);
+        assert_eq!(raised[0].address, 0x10);
+        assert_eq!(raised[1].address, committed[1]);
+        // Only accesses report the accessed address
+        assert_ne!(raised[4].address, 0x10);
+        assert_eq!(raised[21].address, 0x10);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn raised_signals() {
+        let _serial = dispatch::tests::serial();
+        let raised = delivered(|| SIGNALS.iter().for_each(|sig| raise(*sig)));
+        let signals: Vec<_> = raised.iter().map(|info| info.signal).collect();
+        assert_eq!(signals, SIGNALS);
+        assert_eq!(raised[1].status, ExceptionCode::IntegerDivideByZero);
+        assert!(!dispatch::is_installed());
+    }
+}
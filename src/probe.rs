@@ -0,0 +1,155 @@
+//! Probing whether the processor supports an instruction by executing it.
+//!
+//! `CPUID` can be masked or wrong under a hypervisor, so the reliable answer for some instructions
+//! is to try them. [`instruction`] runs the bytes it's given from a page of their own, with a
+//! handler catching only the exceptions raised on that page.
+
+// Imports
+use crate::{memory, with_closure_handler, ContextExt, ExceptionCode, Handling, Order, VehError};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+const RET: u8 = 0xC3;
+
+// The most instruction bytes that are probed at once
+const MAX_LEN: usize = 64;
+
+/// What happened running a probed instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    /// It executed without raising an exception.
+    Supported,
+    /// It raised `STATUS_ILLEGAL_INSTRUCTION`, so the processor (or the OS) doesn't support it.
+    IllegalInstruction,
+    /// It raised `STATUS_PRIVILEGED_INSTRUCTION`, so it's supported but not in user mode.
+    Privileged,
+    /// It raised some other exception, such as an access violation from a memory operand.
+    Faulted(ExceptionCode),
+}
+
+/// Why an instruction couldn't be probed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProbeError {
+    /// No bytes, or more than the 64 bytes probed at once, were given.
+    InvalidLength,
+    /// The executable page couldn't be set up, with this `GetLastError` code.
+    Alloc(u32),
+    /// The handler couldn't be registered.
+    Handler(VehError),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::InvalidLength => write!(f, "probes must be 1 to {MAX_LEN} bytes long"),
+            ProbeError::Alloc(code) => write!(f, "failed to allocate the probe (error {code})"),
+            ProbeError::Handler(error) => write!(f, "failed to register the handler: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl From<VehError> for ProbeError {
+    fn from(error: VehError) -> Self {
+        ProbeError::Handler(error)
+    }
+}
+
+/// Runs `bytes` on the current thread, reporting whether they raised an exception.
+///
+/// The bytes are followed by a `ret` and called as an `extern "C" fn()`. If they raise an
+/// exception, execution resumes at that `ret`.
+///
+/// # Safety
+/// `bytes` must be a sequence of whole instructions that falls through to their end, and only
+/// changes registers that are volatile in the C calling convention.
+pub unsafe fn instruction(bytes: &[u8]) -> Result<ProbeResult, ProbeError> {
+    if bytes.is_empty() || bytes.len() > MAX_LEN {
+        return Err(ProbeError::InvalidLength);
+    }
+
+    let mut code = [RET; MAX_LEN + 1];
+    code[..bytes.len()].copy_from_slice(bytes);
+    let page = memory::alloc_code(&code[..=bytes.len()]).map_err(ProbeError::Alloc)?;
+    let ret = page + bytes.len();
+
+    // Records the code of an exception raised on the page, 0 if there was none
+    let raised = Arc::new(AtomicU32::new(0));
+    let seen = raised.clone();
+    let handler = move |info: &mut crate::ExceptionInfo| {
+        let ip = info.context().ip();
+        if !(page..=ret).contains(&ip) {
+            return Handling::ContinueSearch;
+        }
+
+        seen.store(info.code().raw(), Ordering::SeqCst);
+        info.context_mut().set_ip(ret);
+        Handling::ContinueExecution
+    };
+    let probe = std::mem::transmute::<usize, extern "C" fn()>(page);
+    let result = with_closure_handler(Order::First, handler, || probe());
+
+    memory::free_code(page);
+    result?;
+    Ok(match raised.load(Ordering::SeqCst) {
+        0 => ProbeResult::Supported,
+        code => match ExceptionCode::from_raw(code) {
+            ExceptionCode::IllegalInstruction => ProbeResult::IllegalInstruction,
+            ExceptionCode::PrivilegedInstruction => ProbeResult::Privileged,
+            other => ProbeResult::Faulted(other),
+        },
+    })
+}
+
+fn supported(bytes: &[u8]) -> bool {
+    let result = unsafe { instruction(bytes) };
+    result == Ok(ProbeResult::Supported)
+}
+
+/// Whether `rdtscp` can be executed.
+pub fn has_rdtscp() -> bool {
+    supported(&[0x0F, 0x01, 0xF9])
+}
+
+/// Whether `rdrand` can be executed.
+pub fn has_rdrand() -> bool {
+    // rdrand eax
+    supported(&[0x0F, 0xC7, 0xF0])
+}
+
+/// Whether `rdseed` can be executed.
+pub fn has_rdseed() -> bool {
+    // rdseed eax
+    supported(&[0x0F, 0xC7, 0xF8])
+}
+
+/// Whether AVX instructions can be executed, which also needs the OS to save their state.
+pub fn has_avx() -> bool {
+    // vzeroupper
+    supported(&[0xC5, 0xF8, 0x77])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instruction, ProbeError, ProbeResult};
+
+    #[test]
+    fn results() {
+        unsafe {
+            assert_eq!(instruction(&[0x90]), Ok(ProbeResult::Supported));
+            assert_eq!(
+                instruction(&[0x0F, 0x0B]),
+                Ok(ProbeResult::IllegalInstruction)
+            );
+            // hlt
+            assert_eq!(instruction(&[0xF4]), Ok(ProbeResult::Privileged));
+            assert_eq!(instruction(&[]), Err(ProbeError::InvalidLength));
+        }
+
+        // Whatever the answer, asking doesn't crash
+        let _ = super::has_rdtscp();
+    }
+}
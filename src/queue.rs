@@ -0,0 +1,448 @@
+//! Handing exceptions from handlers to an ordinary thread.
+//!
+//! Handlers can't safely log to files, send over sockets or allocate, so an [`ExceptionQueue`]
+//! lets them push a snapshot into storage allocated up front, for a consumer thread to drain and
+//! do the work. Pushing takes no locks and gives up after a bounded number of attempts, so it
+//! never blocks a handler, whatever the consumer is doing. [`dispatch::observe_into`] wires a
+//! queue up to the dispatcher.
+//!
+//! The queue is the bounded MPMC queue with per-slot sequence numbers described by Dmitry
+//! Vyukov: a slot's sequence tells which lap of the ring it's free to be written, or ready to be
+//! read, in, so producers and consumers only contend on the counter of their own side.
+//!
+//! [`dispatch::observe_into`]: crate::dispatch::observe_into
+
+// Imports
+use crate::{ExceptionInfo, ExceptionSnapshot};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// How often a push retries after losing a race with another producer, or making room
+const MAX_PUSH_ATTEMPTS: usize = 16;
+
+// Producers wake consumers without taking the lock, so a wakeup can slip in between a consumer
+// finding the queue empty and it waiting; this bounds how long one can go unnoticed
+pub(crate) const MAX_WAIT_SLICE: Duration = Duration::from_millis(10);
+
+/// Which snapshot an [`ExceptionQueue`] gives up when it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Make room by dropping the oldest snapshot, keeping the most recent ones.
+    #[default]
+    DropOldest,
+    /// Drop the snapshot being pushed, keeping the earliest ones.
+    DropNewest,
+}
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    item: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The ring itself, for any item type, shared with `defer`'s work items
+pub(crate) struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    // The positions of the next slot to read and to write, counting up forever
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+    waiters: AtomicUsize,
+    lock: Mutex<()>,
+    pushed: Condvar,
+}
+
+// Each slot is only accessed by whoever claimed it through `head` or `tail`
+unsafe impl<T: Send> Sync for Ring<T> {}
+unsafe impl<T: Send> Send for Ring<T> {}
+
+impl<T> Ring<T> {
+    // Panics if `capacity` is 0
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "a ring needs room for an item");
+        let slots = (0..capacity)
+            .map(|i| Slot {
+                sequence: AtomicUsize::new(i),
+                item: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+
+        Ring {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            waiters: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            pushed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        tail.saturating_sub(head).min(self.capacity())
+    }
+
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Writes an item into a free slot with `write`, making room by dropping the oldest item when
+    // full if `drop_oldest` is set, and returns whether it was queued
+    pub(crate) fn push_with(
+        &self,
+        drop_oldest: bool,
+        write: impl FnOnce(&mut MaybeUninit<T>),
+    ) -> bool {
+        let capacity = self.capacity();
+        let mut position = self.tail.load(Ordering::Relaxed);
+        for _ in 0..MAX_PUSH_ATTEMPTS {
+            let slot = &self.slots[position % capacity];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lap = sequence.wrapping_sub(position) as isize;
+
+            if lap == 0 {
+                let claimed = self.tail.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                match claimed {
+                    Ok(_) => {
+                        write(unsafe { &mut *slot.item.get() });
+                        slot.sequence.store(position + 1, Ordering::Release);
+                        self.wake();
+                        return true;
+                    }
+                    Err(current) => position = current,
+                }
+            } else if lap < 0 {
+                // Full, a lap behind the readers
+                if !drop_oldest || !self.discard_oldest() {
+                    break;
+                }
+                position = self.tail.load(Ordering::Relaxed);
+            } else {
+                position = self.tail.load(Ordering::Relaxed);
+            }
+        }
+
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    // Frees the oldest slot, dropping its item, and returns whether there was one ready
+    fn discard_oldest(&self) -> bool {
+        let claimed = self.claim_read(MAX_PUSH_ATTEMPTS);
+        if let Some((slot, position)) = claimed {
+            unsafe { (*slot.item.get()).assume_init_drop() };
+            self.release_read(slot, position);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        claimed.is_some()
+    }
+
+    // Claims the oldest slot that's ready to be read, with its position, giving up after losing
+    // `attempts` races with other readers
+    fn claim_read(&self, attempts: usize) -> Option<(&Slot<T>, usize)> {
+        let capacity = self.capacity();
+        let mut position = self.head.load(Ordering::Relaxed);
+        for _ in 0..attempts {
+            let slot = &self.slots[position % capacity];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lap = sequence.wrapping_sub(position + 1) as isize;
+
+            if lap == 0 {
+                let claimed = self.head.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                match claimed {
+                    Ok(_) => return Some((slot, position)),
+                    Err(current) => position = current,
+                }
+            } else if lap < 0 {
+                // Empty, or the oldest slot is still being written
+                return None;
+            } else {
+                position = self.head.load(Ordering::Relaxed);
+            }
+        }
+        None
+    }
+
+    // Makes a claimed slot writable in the next lap
+    fn release_read(&self, slot: &Slot<T>, position: usize) {
+        slot.sequence
+            .store(position + self.capacity(), Ordering::Release);
+    }
+
+    // Wakes whoever is waiting in `recv_timeout`
+    pub(crate) fn wake(&self) {
+        // Notifying doesn't lock or allocate, but is skipped when nobody's waiting anyway
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.pushed.notify_all();
+        }
+    }
+
+    pub(crate) fn try_recv(&self) -> Option<T> {
+        let (slot, position) = self.claim_read(usize::MAX)?;
+        let item = unsafe { (*slot.item.get()).assume_init_read() };
+        self.release_read(slot, position);
+        Some(item)
+    }
+
+    // Blocks, so never called from inside a handler
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let mut lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let item = loop {
+            if let Some(item) = self.try_recv() {
+                break Some(item);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break None;
+            }
+            let slice = remaining.min(MAX_WAIT_SLICE);
+            lock = match self.pushed.wait_timeout(lock, slice) {
+                Ok((lock, _)) => lock,
+                Err(e) => e.into_inner().0,
+            };
+        };
+        drop(lock);
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        item
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        while self.try_recv().is_some() {}
+    }
+}
+
+/// A bounded queue of exception snapshots, pushed to from handlers and drained on a normal
+/// thread. See the [module documentation](self).
+pub struct ExceptionQueue {
+    ring: Ring<ExceptionSnapshot>,
+    policy: OverflowPolicy,
+}
+
+impl ExceptionQueue {
+    /// Allocates a queue holding up to `capacity` snapshots, dropping the oldest when full.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ExceptionQueue::with_policy(capacity, OverflowPolicy::DropOldest)
+    }
+
+    /// Allocates a queue holding up to `capacity` snapshots, dropping them per `policy` when
+    /// full.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0.
+    pub fn with_policy(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "an exception queue needs room for a snapshot");
+        ExceptionQueue {
+            ring: Ring::with_capacity(capacity),
+            policy,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Roughly how many snapshots are waiting, as pushes and reads may be in progress.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many snapshots have been dropped because the queue was full, or too contended.
+    pub fn dropped(&self) -> usize {
+        self.ring.dropped()
+    }
+
+    /// Snapshots `info` straight into a free slot, returning whether it was queued.
+    ///
+    /// This takes no locks and doesn't allocate, and gives up after a bounded number of
+    /// attempts, so it's safe to call from inside a handler. When the queue is full the snapshot
+    /// is queued or dropped per the [`OverflowPolicy`]; either way, a dropped snapshot is counted
+    /// in [`dropped`](Self::dropped).
+    pub fn push_snapshot(&self, info: &ExceptionInfo) -> bool {
+        self.push_snapshot_with(info, true)
+    }
+
+    // Like `push_snapshot`, leaving out the backtrace unless `with_backtrace` is set
+    pub(crate) fn push_snapshot_with(&self, info: &ExceptionInfo, with_backtrace: bool) -> bool {
+        self.push_with(|slot| {
+            info.snapshot_into_with(slot, with_backtrace);
+        })
+    }
+
+    /// Queues an existing snapshot, the same way as [`push_snapshot`](Self::push_snapshot).
+    pub fn push(&self, snapshot: &ExceptionSnapshot) -> bool {
+        self.push_with(|slot| {
+            slot.write(snapshot.clone());
+        })
+    }
+
+    fn push_with(&self, write: impl FnOnce(&mut MaybeUninit<ExceptionSnapshot>)) -> bool {
+        let drop_oldest = self.policy == OverflowPolicy::DropOldest;
+        self.ring.push_with(drop_oldest, write)
+    }
+
+    /// Takes the oldest snapshot, if there is one.
+    pub fn try_recv(&self) -> Option<ExceptionSnapshot> {
+        self.ring.try_recv()
+    }
+
+    /// Takes the oldest snapshot, waiting up to `timeout` for one to be pushed.
+    ///
+    /// This blocks, so it must not be called from inside a handler.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ExceptionSnapshot> {
+        self.ring.recv_timeout(timeout)
+    }
+
+    /// Takes every snapshot queued so far, oldest first, stopping once the queue is empty.
+    pub fn drain(&self) -> Drain<'_> {
+        Drain(self)
+    }
+}
+
+impl std::fmt::Debug for ExceptionQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExceptionQueue")
+            .field("capacity", &self.capacity())
+            .field("policy", &self.policy)
+            .field("len", &self.len())
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The snapshots taken by [`ExceptionQueue::drain`].
+pub struct Drain<'a>(&'a ExceptionQueue);
+
+impl Iterator for Drain<'_> {
+    type Item = ExceptionSnapshot;
+
+    fn next(&mut self) -> Option<ExceptionSnapshot> {
+        self.0.try_recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExceptionQueue, OverflowPolicy};
+    use crate::dispatch::{self, Registration};
+    use crate::{ExceptionCode, Filter, Handling};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_5101;
+
+    fn raise(count: usize) {
+        for i in 0..count {
+            let parameters = [i];
+            unsafe { RaiseException(CODE, 0, 1, parameters.as_ptr()) };
+        }
+    }
+
+    // Raises `count` exceptions whose first parameter counts up, pushed into `queue` by a handler
+    fn raise_into(queue: &Arc<ExceptionQueue>, count: usize) {
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let pushing = queue.clone();
+        let callback = Registration::new()
+            .filter(filter)
+            .register_closure(move |info| {
+                pushing.push_snapshot(info);
+                Handling::ContinueExecution
+            })
+            .unwrap();
+        raise(count);
+        drop(callback);
+    }
+
+    fn counters(queue: &ExceptionQueue) -> Vec<usize> {
+        queue
+            .drain()
+            .filter(|snapshot| snapshot.code().raw() == CODE)
+            .map(|snapshot| snapshot.parameters()[0])
+            .collect()
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let _serial = dispatch::tests::serial();
+
+        let oldest = Arc::new(ExceptionQueue::with_capacity(4));
+        raise_into(&oldest, 10);
+        assert_eq!(oldest.len(), 4);
+        assert_eq!(oldest.dropped(), 6);
+        assert_eq!(counters(&oldest), [6, 7, 8, 9]);
+        assert!(oldest.is_empty());
+
+        let newest = Arc::new(ExceptionQueue::with_policy(4, OverflowPolicy::DropNewest));
+        raise_into(&newest, 10);
+        assert_eq!(newest.dropped(), 6);
+        assert_eq!(counters(&newest), [0, 1, 2, 3]);
+
+        // Slots are reused once drained
+        raise_into(&newest, 2);
+        assert_eq!(counters(&newest), [0, 1]);
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn observed_on_another_thread() {
+        let _serial = dispatch::tests::serial();
+
+        let queue = Arc::new(ExceptionQueue::with_capacity(8));
+        let consumer = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                // Other tests' exceptions are observed too
+                while let Some(snapshot) = queue.recv_timeout(Duration::from_secs(10)) {
+                    if snapshot.code().raw() == CODE {
+                        return Some(snapshot);
+                    }
+                }
+                None
+            })
+        };
+
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let fixup = dispatch::register(filter, |_| Handling::ContinueExecution).unwrap();
+        let observer = dispatch::observe_into(&queue).unwrap();
+        raise(1);
+        drop(observer);
+        drop(fixup);
+
+        let snapshot = consumer.join().unwrap().unwrap();
+        assert_eq!(snapshot.parameters(), [0]);
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -1,187 +1,652 @@
-use crate::RawExceptionHandler;
-// Imports
-use crate::raw_offset::RawOffset;
-use once_cell::race::OnceBox;
-use std::ffi::{c_void, OsString};
-use std::os::windows::ffi::OsStringExt;
-
-// Architecture-specific imports
-#[cfg(target_pointer_width = "32")]
-use pelite::pe32::{exports::GetProcAddress, PeView};
-#[cfg(target_pointer_width = "64")]
-use pelite::pe64::{exports::GetProcAddress, PeView};
-
-type Handle = *const c_void;
-
-// Type aliases
-type FnRtlpAddVectoredHandler = unsafe extern "fastcall" fn(
-    FirstHandler: i32,
-    VectoredHandler: RawExceptionHandler,
-    handler_type: i32,
-) -> Handle;
-
-type FnRtlpRemoveVectoredHandler =
-    unsafe extern "fastcall" fn(VectoredHandlerHandle: Handle, handler_type: i32) -> u8;
-
-// Structs
-#[repr(C)]
-struct UNICODE_STRING {
-    bytes_length: u16,
-    bytes_max_length: u16,
-    buffer: *const u16,
-}
-
-struct VectoredHandlers {
-    add: FnRtlpAddVectoredHandler,
-    remove: FnRtlpRemoveVectoredHandler,
-}
-
-unsafe fn get_module_handle<T: AsRef<str>>(name: T) -> Option<*const u8> {
-    #[cfg(target_pointer_width = "32")]
-    static ARCH_PTR_SIZE: usize = 4;
-    #[cfg(target_pointer_width = "64")]
-    static ARCH_PTR_SIZE: usize = 8;
-
-    // Make the name uppercase to make this function case insensitive
-    let target_name = name.as_ref().to_uppercase();
-
-    // Get PEB
-    let peb: *const u8;
-
-    #[cfg(target_pointer_width = "32")]
-    let list_base = {
-        std::arch::asm!("mov {}, fs:[30h]", out(reg) peb);
-
-        // PEB
-        let x = peb;
-        // Ldr
-        let x = *(x.add(0x0C) as *const *const u8);
-        // InInitializationOrderModuleList
-        x.add(0x1C) as *const u8
-    };
-
-    #[cfg(target_pointer_width = "64")]
-    let list_base = {
-        std::arch::asm!("mov {}, gs:[60h]", out(reg) peb);
-
-        // PEB
-        let x = peb;
-        // Ldr
-        let x = *(x.add(0x18) as *const *const u8);
-        // InInitializationOrderModuleList
-        x.add(0x30) as *const u8
-    };
-
-    let mut current = list_base;
-    loop {
-        // Follow the link
-        current = *(current as *const *const u8);
-
-        // If we're back at the start, break out
-        if std::ptr::eq(current, list_base) {
-            break None;
-        }
-
-        // Get pointers to base addr + name
-        // TODO: Create a struct of this - _LDR_MODULE
-        let base_ptr = current.add(ARCH_PTR_SIZE * 2);
-        let name_ptr = current.add((ARCH_PTR_SIZE * 5) + std::mem::size_of::<UNICODE_STRING>());
-
-        // Try match the name against our target name
-        if let Some(name) = (name_ptr as *const UNICODE_STRING).as_ref() {
-            let name = std::slice::from_raw_parts(name.buffer, (name.bytes_length / 2) as _);
-            let name = OsString::from_wide(name);
-            let name = name.to_string_lossy().to_uppercase();
-
-            if name == target_name {
-                break Some(*(base_ptr as *const *const u8));
-            }
-        }
-    }
-}
-
-static VECTORED_HANDLER: OnceBox<VectoredHandlers> = OnceBox::new();
-
-#[inline(never)]
-fn find_handlers() -> Box<VectoredHandlers> {
-    unsafe {
-        // We're using continue handlers as they're less likely to be hooked/modified
-        // They're calling the same function as the exception handler funcs do
-        const RAVCH: &str = "RtlAddVectoredContinueHandler";
-        const RRVCH: &str = "RtlRemoveVectoredContinueHandler";
-
-        get_module_handle("ntdll.dll")
-            .map(|base| PeView::module(base))
-            .and_then(|module| {
-                unsafe fn get_wrapped_function<T>(wrapper: *const u8, size: usize) -> Option<T> {
-                    // Create a window with the size of the pattern
-                    // Enumerate so we can get where the pattern is found
-                    // Filter on if current location matches the pattern
-                    //   If it does
-                    //     Read the `call` instruction's argument
-                    //     Offset the current location by the argument (as calls are relative)
-                    //     Skip until the end of the call instruction
-                    // Map offset-into-function to a real pointer
-                    // Transmute the pointer to a function
-                    // Take the first match out of the iterator
-
-                    #[cfg(target_pointer_width = "32")]
-                    return std::slice::from_raw_parts(wrapper, size)
-                        .windows(9)
-                        .enumerate()
-                        .filter_map(|(index, bytes)| match bytes {
-                            &[0xE8, b1, b2, b3, b4, 0x5D, 0xC2, _, 0x00] => {
-                                Some((i32::from_le_bytes([b1, b2, b3, b4])) + index as i32 + 5)
-                            }
-                            _ => None,
-                        })
-                        .map(|ravh| wrapper.raw_add(ravh as _))
-                        .map(|ravh| std::mem::transmute_copy(&ravh))
-                        .next();
-
-                    #[cfg(target_pointer_width = "64")]
-                    return std::slice::from_raw_parts(wrapper, size)
-                        .windows(5)
-                        .enumerate()
-                        .filter_map(|(index, bytes)| match bytes {
-                            &[0xE9, b1, b2, b3, b4] => {
-                                Some((i32::from_le_bytes([b1, b2, b3, b4])) + index as i32 + 5)
-                            }
-                            _ => None,
-                        })
-                        .map(|ravh| wrapper.raw_add(ravh as _))
-                        .map(|ravh| std::mem::transmute_copy(&ravh))
-                        .next();
-                }
-
-                // Get the addresses of the exported functions we'll be reading from
-                let ravch = module.get_proc_address(RAVCH).unwrap() as *const u8;
-                let rrvch = module.get_proc_address(RRVCH).unwrap() as *const u8;
-
-                // Get the wrapped function
-                let ravch = get_wrapped_function(ravch, 0x50);
-                let rrvch = get_wrapped_function(rrvch, 0x50);
-
-                match (ravch, rrvch) {
-                    (Some(ra), Some(rr)) => Some(VectoredHandlers {
-                        add: ra,
-                        remove: rr,
-                    }),
-                    _ => None,
-                }
-            })
-            .unwrap()
-            .into()
-    }
-}
-
-pub unsafe fn add_vectored_exception_handler(
-    first_handler: bool,
-    vectored_handler: RawExceptionHandler,
-) -> *const c_void {
-    (VECTORED_HANDLER.get_or_init(find_handlers).add)(first_handler as _, vectored_handler, 0)
-}
-
-pub unsafe fn remove_vectored_exception_handler(vectored_handler: *const c_void) -> u8 {
-    (VECTORED_HANDLER.get_or_init(find_handlers).remove)(vectored_handler, 0)
-}
+// Imports
+#[cfg(feature = "tracing")]
+use crate::events;
+use crate::raw_offset::RawOffset;
+use crate::scan::{self, Pattern};
+use crate::{modules, VectoredHandler, VehError, CONTEXT};
+use once_cell::race::OnceBox;
+use std::ffi::c_void;
+use std::fmt;
+
+// Architecture-specific imports
+#[cfg(all(feature = "log", target_pointer_width = "32"))]
+use pelite::pe32::Pe;
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::GetProcAddress, PeView};
+#[cfg(all(feature = "log", target_pointer_width = "64"))]
+use pelite::pe64::Pe;
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::GetProcAddress, PeView};
+
+type Handle = *const c_void;
+
+// Type aliases
+// ntdll's internal functions are fastcall on x86, a convention only x86 has
+#[cfg(target_arch = "x86")]
+type FnRtlpAddVectoredHandler = unsafe extern "fastcall" fn(
+    first_handler: i32,
+    vectored_handler: VectoredHandler,
+    handler_type: i32,
+) -> Handle;
+#[cfg(not(target_arch = "x86"))]
+type FnRtlpAddVectoredHandler = unsafe extern "system" fn(
+    first_handler: i32,
+    vectored_handler: VectoredHandler,
+    handler_type: i32,
+) -> Handle;
+
+#[cfg(target_arch = "x86")]
+type FnRtlpRemoveVectoredHandler =
+    unsafe extern "fastcall" fn(vectored_handler_handle: Handle, handler_type: i32) -> u8;
+#[cfg(not(target_arch = "x86"))]
+type FnRtlpRemoveVectoredHandler =
+    unsafe extern "system" fn(vectored_handler_handle: Handle, handler_type: i32) -> u8;
+
+type FnNtContextThread =
+    unsafe extern "system" fn(thread: *mut c_void, context: *mut CONTEXT) -> i32;
+
+type FnNtContinue = unsafe extern "system" fn(context: *const CONTEXT, test_alert: u8) -> i32;
+
+type FnRtlCaptureContext = unsafe extern "system" fn(context: *mut CONTEXT);
+
+// Structs
+struct VectoredHandlers {
+    add: FnRtlpAddVectoredHandler,
+    remove: FnRtlpRemoveVectoredHandler,
+}
+
+struct ContextFunctions {
+    get: FnNtContextThread,
+    set: FnNtContextThread,
+}
+
+struct ResumeFunctions {
+    nt_continue: FnNtContinue,
+    capture: FnRtlCaptureContext,
+}
+
+// We're using continue handlers as they're less likely to be hooked/modified
+// They're calling the same function as the exception handler funcs do
+pub(crate) const RAVCH: &str = "RtlAddVectoredContinueHandler";
+pub(crate) const RRVCH: &str = "RtlRemoveVectoredContinueHandler";
+
+// How far into the wrappers the call to the internal function is looked for
+pub(crate) const WRAPPER_SCAN_LEN: usize = 0x50;
+
+// The `handler_type` values understood by the internal functions
+pub(crate) const EXCEPTION_HANDLER_LIST: i32 = 0;
+pub(crate) const CONTINUE_HANDLER_LIST: i32 = 1;
+
+static VECTORED_HANDLER: OnceBox<Option<VectoredHandlers>> = OnceBox::new();
+static CONTEXT_FUNCTIONS: OnceBox<Option<ContextFunctions>> = OnceBox::new();
+static RESUME_FUNCTIONS: OnceBox<Option<ResumeFunctions>> = OnceBox::new();
+
+// The architecture's `CONTEXT_*` flag, and the parts of the context that can be selected along
+// with it. `CONTEXT_XSTATE` isn't one of them, as it needs a larger buffer than `CONTEXT`.
+#[cfg(target_arch = "x86")]
+const CONTEXT_ARCH: u32 = 0x0001_0000;
+#[cfg(target_arch = "x86")]
+const CONTEXT_PARTS: u32 = 0x3F;
+#[cfg(target_arch = "x86_64")]
+const CONTEXT_ARCH: u32 = 0x0010_0000;
+#[cfg(target_arch = "x86_64")]
+const CONTEXT_PARTS: u32 = 0x1F;
+#[cfg(target_arch = "aarch64")]
+const CONTEXT_ARCH: u32 = 0x0040_0000;
+#[cfg(target_arch = "aarch64")]
+const CONTEXT_PARTS: u32 = 0x1F;
+
+/// Why [`nt_get_context_thread`] or [`nt_set_context_thread`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ThreadContextError {
+    /// The functions couldn't be located in ntdll.
+    Resolution,
+    /// `ContextFlags` doesn't select this architecture's context, or selects parts that don't
+    /// fit in a `CONTEXT`.
+    InvalidFlags(u32),
+    /// The call failed with this `NTSTATUS`.
+    Status(i32),
+}
+
+impl fmt::Display for ThreadContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadContextError::Resolution => {
+                f.write_str("failed to locate the thread context functions in ntdll")
+            }
+            ThreadContextError::InvalidFlags(flags) => {
+                write!(f, "invalid context flags {flags:#x}")
+            }
+            ThreadContextError::Status(status) => {
+                write!(f, "the call failed with status {:#010x}", *status as u32)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThreadContextError {}
+
+#[inline(never)]
+fn find_handlers() -> Box<Option<VectoredHandlers>> {
+    unsafe {
+        #[cfg(feature = "tracing")]
+        events::resolving("vectored handler functions", events::WRAPPER_SCAN);
+
+        let handlers = modules::find("ntdll.dll")
+            .map(|ntdll| PeView::module(ntdll.base() as *const u8))
+            .and_then(|module| {
+                unsafe fn get_wrapped_function<T>(wrapper: *const u8, size: usize) -> Option<T> {
+                    let bytes = std::slice::from_raw_parts(wrapper, size);
+                    let wrapped = wrapper.raw_offset(wrapped_offset(bytes)?);
+                    Some(std::mem::transmute_copy(&wrapped))
+                }
+
+                // Get the addresses of the exported functions we'll be reading from
+                let ravch = module.get_proc_address(RAVCH).ok()? as *const u8;
+                let rrvch = module.get_proc_address(RRVCH).ok()? as *const u8;
+
+                // Get the wrapped function
+                let ravch = get_wrapped_function(ravch, WRAPPER_SCAN_LEN);
+                let rrvch = get_wrapped_function(rrvch, WRAPPER_SCAN_LEN);
+
+                match (ravch, rrvch) {
+                    (Some(ra), Some(rr)) => Some(VectoredHandlers {
+                        add: ra,
+                        remove: rr,
+                    }),
+                    _ => None,
+                }
+            });
+
+        #[cfg(feature = "tracing")]
+        events::resolved(
+            "vectored handler functions",
+            events::WRAPPER_SCAN,
+            handlers.is_some(),
+        );
+        #[cfg(feature = "log")]
+        if let Some(handlers) = &handlers {
+            log_handlers(handlers);
+        }
+        handlers.into()
+    }
+}
+
+// Where the function called by the wrapper in `bytes` starts, relative to the wrapper
+pub(crate) fn wrapped_offset(bytes: &[u8]) -> Option<isize> {
+    // The wrapper calls the internal function and returns, or tail-calls it where it can
+    #[cfg(target_pointer_width = "32")]
+    let pattern = Pattern::parse("E8 ?? ?? ?? ?? 5D C2 ?? 00");
+    #[cfg(target_pointer_width = "64")]
+    let pattern = Pattern::parse("E9 ?? ?? ?? ??");
+
+    let pattern = pattern.ok()?;
+    let call = scan::scan(bytes, &pattern)?;
+    scan::resolve_rel32(bytes, call, 5)
+}
+
+// ntdll's file version, such as `10.0.19041.3636`
+#[cfg(feature = "log")]
+fn ntdll_version() -> Option<pelite::image::VS_VERSION> {
+    let ntdll = modules::find("ntdll.dll")?;
+    let view = unsafe { PeView::module(ntdll.base() as *const u8) };
+    let version_info = view.resources().ok()?.version_info().ok()?;
+    Some(version_info.fixed()?.dwFileVersion)
+}
+
+#[cfg(feature = "log")]
+fn log_resolved(method: &str, functions: &[(&str, usize)]) {
+    let version = ntdll_version().map_or_else(|| "of an unknown version".into(), |v| v.to_string());
+    let functions: Vec<_> = functions
+        .iter()
+        .map(|(name, address)| format!("{name} at {address:#x}"))
+        .collect();
+    log::info!(
+        "resolved {} through the {method} of ntdll {version}",
+        functions.join(" and "),
+    );
+}
+
+#[cfg(feature = "log")]
+fn log_handlers(handlers: &VectoredHandlers) {
+    let add = ("RtlpAddVectoredHandler", handlers.add as usize);
+    let remove = ("RtlpRemoveVectoredHandler", handlers.remove as usize);
+    log_resolved("continue handler wrappers", &[add, remove]);
+}
+
+#[inline(never)]
+fn find_context_functions() -> Box<Option<ContextFunctions>> {
+    #[cfg(feature = "tracing")]
+    events::resolving("thread context functions", events::EXPORTS);
+
+    let functions = modules::find("ntdll.dll")
+        .map(|ntdll| unsafe { PeView::module(ntdll.base() as *const u8) })
+        .and_then(|module| {
+            let get = module.get_proc_address("NtGetContextThread").ok()?;
+            let set = module.get_proc_address("NtSetContextThread").ok()?;
+            unsafe {
+                Some(ContextFunctions {
+                    get: std::mem::transmute::<usize, FnNtContextThread>(get as usize),
+                    set: std::mem::transmute::<usize, FnNtContextThread>(set as usize),
+                })
+            }
+        });
+
+    #[cfg(feature = "tracing")]
+    events::resolved(
+        "thread context functions",
+        events::EXPORTS,
+        functions.is_some(),
+    );
+    functions.into()
+}
+
+fn context_functions() -> Result<&'static ContextFunctions, ThreadContextError> {
+    CONTEXT_FUNCTIONS
+        .get_or_init(find_context_functions)
+        .as_ref()
+        .ok_or(ThreadContextError::Resolution)
+}
+
+#[inline(never)]
+fn find_resume_functions() -> Box<Option<ResumeFunctions>> {
+    #[cfg(feature = "tracing")]
+    events::resolving("resume functions", events::EXPORTS);
+
+    let functions = modules::find("ntdll.dll")
+        .map(|ntdll| unsafe { PeView::module(ntdll.base() as *const u8) })
+        .and_then(|module| {
+            let nt_continue = module.get_proc_address("NtContinue").ok()?;
+            let capture = module.get_proc_address("RtlCaptureContext").ok()?;
+            unsafe {
+                Some(ResumeFunctions {
+                    nt_continue: std::mem::transmute::<usize, FnNtContinue>(nt_continue as usize),
+                    capture: std::mem::transmute::<usize, FnRtlCaptureContext>(capture as usize),
+                })
+            }
+        });
+
+    #[cfg(feature = "tracing")]
+    events::resolved("resume functions", events::EXPORTS, functions.is_some());
+    #[cfg(feature = "log")]
+    if let Some(functions) = &functions {
+        let nt_continue = ("NtContinue", functions.nt_continue as usize);
+        log_resolved(
+            "exports",
+            &[
+                nt_continue,
+                ("RtlCaptureContext", functions.capture as usize),
+            ],
+        );
+    }
+    functions.into()
+}
+
+fn resume_functions() -> Result<&'static ResumeFunctions, ThreadContextError> {
+    RESUME_FUNCTIONS
+        .get_or_init(find_resume_functions)
+        .as_ref()
+        .ok_or(ThreadContextError::Resolution)
+}
+
+fn check_flags(context: &CONTEXT) -> Result<(), ThreadContextError> {
+    let flags = context.context_flags;
+    match flags & CONTEXT_ARCH != 0 && flags & !(CONTEXT_ARCH | CONTEXT_PARTS) == 0 {
+        true => Ok(()),
+        false => Err(ThreadContextError::InvalidFlags(flags)),
+    }
+}
+
+fn vectored_handlers() -> Result<&'static VectoredHandlers, VehError> {
+    VECTORED_HANDLER
+        .get_or_init(find_handlers)
+        .as_ref()
+        .ok_or(VehError::Resolution)
+}
+
+// The addresses of the internal add and remove functions, for reporting where they were found
+#[cfg(feature = "capi")]
+pub(crate) fn handler_functions() -> Result<(usize, usize), VehError> {
+    let handlers = vectored_handlers()?;
+    Ok((handlers.add as usize, handlers.remove as usize))
+}
+
+// Adds a handler, returning the possibly null handle
+unsafe fn add_handler(
+    handler_type: i32,
+    first_handler: bool,
+    vectored_handler: VectoredHandler,
+) -> Result<*const c_void, VehError> {
+    modules::debug_assert_outside_notification();
+    let handle = (vectored_handlers()?.add)(first_handler as _, vectored_handler, handler_type);
+    #[cfg(feature = "tracing")]
+    events::handler_added(
+        handler_type,
+        first_handler,
+        vectored_handler as usize,
+        handle,
+    );
+    Ok(handle)
+}
+
+pub(crate) unsafe fn try_add_handler(
+    handler_type: i32,
+    first_handler: bool,
+    vectored_handler: VectoredHandler,
+) -> Result<*const c_void, VehError> {
+    let handle = add_handler(handler_type, first_handler, vectored_handler);
+    #[cfg(feature = "log")]
+    if let Err(error) = &handle {
+        log::error!("couldn't register a vectored handler: {error}");
+    }
+    let handle = handle?;
+    match handle.is_null() {
+        true => Err(VehError::Registration),
+        false => Ok(handle),
+    }
+}
+
+pub(crate) unsafe fn remove_handler(handler_type: i32, handle: *const c_void) -> u8 {
+    modules::debug_assert_outside_notification();
+    let removed = match vectored_handlers() {
+        Ok(handlers) => (handlers.remove)(handle, handler_type),
+        Err(_) => 0,
+    };
+    #[cfg(feature = "tracing")]
+    events::handler_removed(handler_type, handle, removed != 0);
+    removed
+}
+
+/// Locates ntdll's internal registration functions now rather than on first use, so a failure
+/// shows up at startup instead of at the first registration.
+///
+/// With the `log` feature, the functions found are logged, again if they already had been.
+pub fn init() -> Result<(), VehError> {
+    #[cfg(feature = "log")]
+    if let Some(Some(handlers)) = VECTORED_HANDLER.get() {
+        log_handlers(handlers);
+        return Ok(());
+    }
+
+    let handlers = vectored_handlers();
+    #[cfg(feature = "log")]
+    if let Err(error) = &handlers {
+        log::error!("couldn't initialize: {error}");
+    }
+    handlers.map(|_| ())
+}
+
+/// Registers `vectored_handler` into the exception handler list, returning its handle.
+///
+/// # Safety
+/// This calls straight into ntdll's internal registration function, which is located on first use.
+/// The handler has the same requirements as one passed to [`Veh::add`](crate::Veh::add).
+///
+/// # Panics
+/// Panics if the internal function couldn't be located.
+pub unsafe fn add_vectored_exception_handler(
+    first_handler: bool,
+    vectored_handler: VectoredHandler,
+) -> *const c_void {
+    add_handler(EXCEPTION_HANDLER_LIST, first_handler, vectored_handler).unwrap()
+}
+
+/// Removes a handler registered with [`add_vectored_exception_handler`], returning non-zero on success.
+///
+/// # Safety
+/// `vectored_handler` must be a handle returned by [`add_vectored_exception_handler`] that hasn't
+/// been removed yet.
+pub unsafe fn remove_vectored_exception_handler(vectored_handler: *const c_void) -> u8 {
+    remove_handler(EXCEPTION_HANDLER_LIST, vectored_handler)
+}
+
+/// Registers `vectored_handler` into the continue handler list, returning its handle.
+///
+/// # Safety
+/// See [`add_vectored_exception_handler`].
+///
+/// # Panics
+/// Panics if the internal function couldn't be located.
+pub unsafe fn add_vectored_continue_handler(
+    first_handler: bool,
+    vectored_handler: VectoredHandler,
+) -> *const c_void {
+    add_handler(CONTINUE_HANDLER_LIST, first_handler, vectored_handler).unwrap()
+}
+
+/// Removes a handler registered with [`add_vectored_continue_handler`], returning non-zero on success.
+///
+/// # Safety
+/// `vectored_handler` must be a handle returned by [`add_vectored_continue_handler`] that hasn't
+/// been removed yet.
+pub unsafe fn remove_vectored_continue_handler(vectored_handler: *const c_void) -> u8 {
+    remove_handler(CONTINUE_HANDLER_LIST, vectored_handler)
+}
+
+/// Reads the parts of `thread`'s context selected by `context.context_flags`, calling ntdll's
+/// `NtGetContextThread` directly rather than going through kernel32.
+///
+/// # Safety
+/// `thread` must be a thread handle with `THREAD_GET_CONTEXT` access. Unless it's the current
+/// thread, it should be suspended, or the context is stale as soon as it's read.
+pub unsafe fn nt_get_context_thread(
+    thread: *mut c_void,
+    context: &mut CONTEXT,
+) -> Result<(), ThreadContextError> {
+    check_flags(context)?;
+    match (context_functions()?.get)(thread, context) {
+        0 => Ok(()),
+        status => Err(ThreadContextError::Status(status)),
+    }
+}
+
+/// Writes the parts of `context` selected by its `context_flags` to `thread`, calling ntdll's
+/// `NtSetContextThread` directly rather than going through kernel32.
+///
+/// # Safety
+/// `thread` must be a thread handle with `THREAD_SET_CONTEXT` access, and the context must be one
+/// the thread can resume with, such as one read with [`nt_get_context_thread`] and then modified.
+pub unsafe fn nt_set_context_thread(
+    thread: *mut c_void,
+    context: &CONTEXT,
+) -> Result<(), ThreadContextError> {
+    check_flags(context)?;
+    let context = context as *const CONTEXT as *mut CONTEXT;
+    match (context_functions()?.set)(thread, context) {
+        0 => Ok(()),
+        status => Err(ThreadContextError::Status(status)),
+    }
+}
+
+/// Fills in `context` with the caller's registers, as they are when this returns, calling ntdll's
+/// `RtlCaptureContext`.
+///
+/// Every part of the context a `CONTEXT` holds is captured, and `context_flags` set to match.
+///
+/// # Safety
+/// Resuming the captured context makes this return a second time, as `setjmp` does, which the
+/// compiler knows nothing about. It's meant for taking a complete context to modify and resume
+/// somewhere else instead.
+pub unsafe fn rtl_capture_context(context: &mut CONTEXT) -> Result<(), ThreadContextError> {
+    (resume_functions()?.capture)(context);
+    Ok(())
+}
+
+/// Resumes the current thread with `context`, calling ntdll's `NtContinue`.
+///
+/// Unlike returning `EXCEPTION_CONTINUE_EXECUTION`, which leaves restoring the context to the
+/// exception dispatcher, this restores every part of it selected by its `context_flags` straight
+/// away, and never returns. If `test_alert` is set, queued user APCs are run first.
+///
+/// # Safety
+/// Everything on the stack between here and the context's stack pointer is abandoned as if the
+/// thread had jumped, without running destructors or releasing locks. When called from an
+/// exception handler, this includes the dispatch frame the OS would have returned through.
+///
+/// # Panics
+/// Panics if `NtContinue` couldn't be located, or if it fails.
+pub unsafe fn nt_continue(context: *const CONTEXT, test_alert: bool) -> ! {
+    let status = (resume_functions().unwrap().nt_continue)(context, test_alert as u8);
+    panic!("NtContinue failed with status {:#010x}", status as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nt_get_context_thread, ThreadContextError, CONTEXT_ARCH};
+    use crate::{teb, ContextExt, CONTEXT};
+
+    // `CONTEXT_CONTROL`, and the pseudo-handle `GetCurrentThread` returns
+    const CONTEXT_CONTROL: u32 = CONTEXT_ARCH | 0x1;
+    const CURRENT_THREAD: *mut std::ffi::c_void = -2isize as _;
+
+    #[test]
+    fn current_thread_context() {
+        let mut context: CONTEXT = unsafe { std::mem::zeroed() };
+        context.context_flags = CONTEXT_CONTROL;
+        unsafe { nt_get_context_thread(CURRENT_THREAD, &mut context) }.unwrap();
+        assert!(teb::stack_bounds().contains(&context.sp()));
+
+        context.context_flags = 0x1;
+        assert_eq!(
+            unsafe { nt_get_context_thread(CURRENT_THREAD, &mut context) },
+            Err(ThreadContextError::InvalidFlags(0x1))
+        );
+    }
+
+    // Resumes at `ip` with the stack pointer `sp`, returning 42 in the return register
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    extern "C" fn continue_to(ip: usize, sp: usize) -> ! {
+        use super::{nt_continue, rtl_capture_context};
+
+        let mut context: CONTEXT = unsafe { std::mem::zeroed() };
+        unsafe { rtl_capture_context(&mut context) }.unwrap();
+        context.set_ip(ip);
+        context.set_sp(sp);
+        #[cfg(target_arch = "x86")]
+        {
+            context.eax = 42;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            context.rax = 42;
+        }
+        unsafe { nt_continue(&context, false) }
+    }
+
+    // The non-volatile registers are resumed with whatever `continue_to` had in them, so they're
+    // saved around it
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn continue_to_label() {
+        let value: usize;
+        unsafe {
+            std::arch::asm!(
+                "push rbx",
+                "push rbp",
+                "push rsi",
+                "push rdi",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "lea rcx, [rip + 2f]",
+                "mov rdx, rsp",
+                "sub rsp, 0x20",
+                "call {continue_to}",
+                "2:",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop rdi",
+                "pop rsi",
+                "pop rbp",
+                "pop rbx",
+                continue_to = sym continue_to,
+                out("rax") value,
+                out("xmm6") _,
+                out("xmm7") _,
+                out("xmm8") _,
+                out("xmm9") _,
+                out("xmm10") _,
+                out("xmm11") _,
+                out("xmm12") _,
+                out("xmm13") _,
+                out("xmm14") _,
+                out("xmm15") _,
+                clobber_abi("C"),
+            )
+        };
+        assert_eq!(value, 42);
+    }
+
+    #[cfg(target_arch = "x86")]
+    #[test]
+    fn continue_to_label() {
+        let value: usize;
+        unsafe {
+            std::arch::asm!(
+                "push ebx",
+                "push ebp",
+                "push esi",
+                "push edi",
+                "mov eax, esp",
+                "lea ecx, [2f]",
+                "push eax",
+                "push ecx",
+                "call {continue_to}",
+                "2:",
+                "pop edi",
+                "pop esi",
+                "pop ebp",
+                "pop ebx",
+                continue_to = sym continue_to,
+                out("eax") value,
+                clobber_abi("C"),
+            )
+        };
+        assert_eq!(value, 42);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn init_logs_resolution() {
+        use std::sync::Mutex;
+
+        static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        struct Capture;
+
+        impl log::Log for Capture {
+            fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record<'_>) {
+                let line = format!("{} {}", record.level(), record.args());
+                LINES.lock().unwrap().push(line);
+            }
+
+            fn flush(&self) {}
+        }
+
+        // Only the first logger set in the process is used
+        let _ = log::set_logger(&Capture);
+        log::set_max_level(log::LevelFilter::Info);
+
+        super::init().unwrap();
+        let handlers = super::vectored_handlers().unwrap();
+        let add = format!("RtlpAddVectoredHandler at {:#x}", handlers.add as usize);
+        let remove = format!(
+            "RtlpRemoveVectoredHandler at {:#x}",
+            handlers.remove as usize
+        );
+
+        let lines = LINES.lock().unwrap();
+        let line = lines.iter().find(|line| line.contains(&add)).unwrap();
+        assert!(line.starts_with("INFO "));
+        assert!(line.contains(&remove));
+        assert!(line.contains("continue handler wrappers of ntdll 10."));
+    }
+}
@@ -1,6 +1,5 @@
 pub trait RawOffset {
     unsafe fn raw_offset(self, count: isize) -> Self;
-    unsafe fn raw_add(self, count: usize) -> Self;
 }
 
 impl<T> RawOffset for *mut T {
@@ -8,11 +7,6 @@ impl<T> RawOffset for *mut T {
     unsafe fn raw_offset(self, count: isize) -> Self {
         (self as *mut u8).offset(count) as Self
     }
-
-    #[inline]
-    unsafe fn raw_add(self, count: usize) -> Self {
-        (self as *mut u8).add(count) as Self
-    }
 }
 
 impl<T> RawOffset for *const T {
@@ -20,9 +14,4 @@ impl<T> RawOffset for *const T {
     unsafe fn raw_offset(self, count: isize) -> Self {
         (self as *const u8).offset(count) as Self
     }
-
-    #[inline]
-    unsafe fn raw_add(self, count: usize) -> Self {
-        (self as *const u8).add(count) as Self
-    }
 }
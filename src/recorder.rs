@@ -0,0 +1,206 @@
+//! Remembering the most recent hardware exception, for reporting it when the process dies.
+//!
+//! The snapshot lives in a single static slot guarded by a sequence lock: the observer writing it
+//! makes the sequence odd while it does, and readers retry until they've copied the slot without
+//! the sequence changing, so they never see a torn snapshot. Observers that find another one
+//! writing skip their exception rather than wait in a handler.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{ExceptionInfo, ExceptionSnapshot, Handling};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::panic;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// `NTSTATUS` severity error, and the bit set on codes defined by applications, like C++ exceptions
+const SEVERITY_ERROR: u32 = 0xC000_0000;
+const CUSTOMER: u32 = 0x2000_0000;
+
+// How often a reader retries while the slot is being written
+const MAX_READ_ATTEMPTS: usize = 1000;
+
+struct Slot {
+    // Even while the snapshot is stable, zero until one has been written
+    sequence: AtomicUsize,
+    snapshot: UnsafeCell<MaybeUninit<ExceptionSnapshot>>,
+}
+
+unsafe impl Sync for Slot {}
+
+static LAST: Slot = Slot {
+    sequence: AtomicUsize::new(0),
+    snapshot: UnsafeCell::new(MaybeUninit::uninit()),
+};
+
+// Live recorder guards, and the observer they share
+static RECORDERS: Mutex<(usize, Option<CallbackGuard>)> = Mutex::new((0, None));
+
+/// Keeps [`record_last_exception`] recording until dropped.
+pub struct RecorderGuard(());
+
+impl Drop for RecorderGuard {
+    fn drop(&mut self) {
+        let mut recorders = RECORDERS.lock().unwrap_or_else(|e| e.into_inner());
+        recorders.0 -= 1;
+        if recorders.0 == 0 {
+            recorders.1.take();
+        }
+    }
+}
+
+/// Starts recording the most recent hardware exception raised on any thread, for
+/// [`last_exception`].
+///
+/// Only exceptions with an error severity that aren't application-defined are recorded, such as
+/// access violations and illegal instructions, but not breakpoints or C++ exceptions. They're
+/// recorded by an observer running after every other dispatcher callback, whether or not one of
+/// them handled the exception. The last snapshot is kept after the guard is dropped.
+///
+/// # Panics
+/// Panics if the observer couldn't be registered with the dispatcher.
+pub fn record_last_exception() -> RecorderGuard {
+    let mut recorders = RECORDERS.lock().unwrap_or_else(|e| e.into_inner());
+    if recorders.1.is_none() {
+        let registration = Registration::new().priority(i32::MAX).observe();
+        let observer = registration
+            .register(record)
+            .expect("failed to register the exception recorder");
+        recorders.1 = Some(observer);
+    }
+    recorders.0 += 1;
+    RecorderGuard(())
+}
+
+fn record(info: &mut ExceptionInfo) -> Handling {
+    let code = info.code().raw();
+    if code & SEVERITY_ERROR != SEVERITY_ERROR || code & CUSTOMER != 0 {
+        return Handling::ContinueSearch;
+    }
+
+    let sequence = LAST.sequence.load(Ordering::Relaxed);
+    let writing = sequence | 1;
+    if sequence == writing {
+        return Handling::ContinueSearch;
+    }
+    let claimed =
+        LAST.sequence
+            .compare_exchange(sequence, writing, Ordering::Acquire, Ordering::Relaxed);
+    if claimed.is_err() {
+        return Handling::ContinueSearch;
+    }
+
+    fence(Ordering::Release);
+    info.snapshot_into(unsafe { &mut *LAST.snapshot.get() });
+    LAST.sequence.store(writing + 1, Ordering::Release);
+    Handling::ContinueSearch
+}
+
+/// The most recent exception recorded since [`record_last_exception`] was first called, if any.
+///
+/// Returns `None` as well if the slot was being written for the whole time spent retrying.
+pub fn last_exception() -> Option<ExceptionSnapshot> {
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let before = LAST.sequence.load(Ordering::Acquire);
+        if before == 0 {
+            return None;
+        }
+        if before & 1 != 0 {
+            std::hint::spin_loop();
+            continue;
+        }
+
+        let copy = unsafe { std::ptr::read_volatile(LAST.snapshot.get()) };
+        fence(Ordering::Acquire);
+        if LAST.sequence.load(Ordering::Relaxed) == before {
+            return Some(unsafe { copy.assume_init() });
+        }
+    }
+    None
+}
+
+// A one-line description of an exception: its code, address, and the module it was raised in
+struct Summary<'a>(&'a ExceptionSnapshot);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let snapshot = self.0;
+        write!(
+            f,
+            "last hardware exception: {:?} ({:#010x}) at {:#x}",
+            snapshot.code(),
+            snapshot.code().raw(),
+            snapshot.address()
+        )?;
+        if let Some(module) = snapshot.module() {
+            write!(f, " ({}+{:#x})", module.name(), module.offset())?;
+        }
+        Ok(())
+    }
+}
+
+/// Wraps the current panic hook so that, after it has run, a line with the code, address and
+/// module of the [`last_exception`] is printed to stderr if there is one.
+///
+/// Each call wraps the hook again, so this should be called once.
+pub fn install_panic_hook_enrichment() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        if let Some(snapshot) = last_exception() {
+            eprintln!("{}", Summary(&snapshot));
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install_panic_hook_enrichment, last_exception, record_last_exception};
+    use crate::testing::run_isolated;
+    use crate::{dispatch, ExceptionCode, Filter, Handling};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    #[test]
+    fn summary_in_panic_output() {
+        let run = run_isolated("recorder::tests::summary_in_panic_output", || {
+            let recorder = record_last_exception();
+            let filter = Filter::code(ExceptionCode::AccessViolation).and(Filter::current_thread());
+            let fixup = dispatch::register(filter, |_| Handling::ContinueExecution).unwrap();
+            let parameters = [0, 0x10];
+            unsafe {
+                RaiseException(
+                    ExceptionCode::AccessViolation.raw(),
+                    0,
+                    2,
+                    parameters.as_ptr(),
+                )
+            };
+            drop(fixup);
+            drop(recorder);
+            assert!(!dispatch::is_installed());
+
+            let snapshot = last_exception().unwrap();
+            assert_eq!(snapshot.code(), ExceptionCode::AccessViolation);
+            assert_eq!(snapshot.parameters(), parameters);
+            eprintln!("raised at {:#x}", snapshot.address());
+
+            install_panic_hook_enrichment();
+            panic!("after the access violation");
+        });
+        run.expect_exit_code(101);
+
+        // Printed after the panic message itself
+        let stderr = run.stderr();
+        let raised = stderr
+            .lines()
+            .find_map(|line| line.strip_prefix("raised at "));
+        let summary = format!(
+            "last hardware exception: AccessViolation (0xc0000005) at {}",
+            raised.unwrap()
+        );
+        let panicked = stderr.find("after the access violation").unwrap();
+        assert!(stderr[panicked..].contains(&summary), "stderr:\n{stderr}");
+    }
+}
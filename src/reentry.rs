@@ -0,0 +1,168 @@
+// Imports
+use crate::{teb, ExceptionInfo};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Called instead of the user callbacks when the crate's handlers are re-entered too deeply, with
+/// the nesting depth and the exception that caused it.
+///
+/// This runs while a handler is already faulting, so it should do as little as possible, such as
+/// passing a fixed message to `OutputDebugStringA`.
+pub type ReentryHook = fn(depth: u32, info: &ExceptionInfo);
+
+static MAX_DEPTH: AtomicU32 = AtomicU32::new(1);
+static ON_REENTRY: AtomicUsize = AtomicUsize::new(0);
+
+// The threads running any of the crate's handlers, for `threads::suspend_others` to leave
+// running. Any past this many at once go unrecorded
+const MAX_DISPATCHING: usize = 64;
+static DISPATCHING: [AtomicU32; MAX_DISPATCHING] = [const { AtomicU32::new(0) }; MAX_DISPATCHING];
+
+thread_local! {
+    // How many of the crate's handlers are currently running on this thread
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+    // This thread's entry in `DISPATCHING`, while it has one
+    static SLOT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Sets how deeply the crate's handlers (the dispatcher, and anything registered through
+/// [`VehBuilder`](crate::VehBuilder)) may be nested on a single thread. Defaults to 1.
+///
+/// A callback that faults re-enters the exception handler chain, and with it the same callback,
+/// which would likely fault again until the stack runs out. Past the maximum depth, the crate's
+/// handlers skip their callbacks and let the exception continue the search instead.
+pub fn set_max_handler_depth(depth: u32) {
+    MAX_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// Sets a hook to call whenever [`set_max_handler_depth`]'s limit is hit, or removes it.
+pub fn set_on_reentry(hook: Option<ReentryHook>) {
+    ON_REENTRY.store(hook.map_or(0, |hook| hook as usize), Ordering::Relaxed);
+}
+
+/// Counts a handler as running on this thread until dropped, including when unwinding.
+pub(crate) struct DepthGuard(());
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        let depth = DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+        if depth == 0 {
+            if let Some(slot) = SLOT.with(Cell::take) {
+                DISPATCHING[slot].store(0, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+fn claim_slot() {
+    let thread_id = teb::current_thread_id();
+    let slot = DISPATCHING.iter().position(|slot| {
+        slot.compare_exchange(0, thread_id, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+    });
+    SLOT.with(|own| own.set(slot));
+}
+
+/// Whether the thread `thread_id` is running one of the crate's handlers.
+pub(crate) fn is_dispatching(thread_id: u32) -> bool {
+    DISPATCHING
+        .iter()
+        .any(|slot| slot.load(Ordering::SeqCst) == thread_id)
+}
+
+/// Enters a handler on this thread, or returns `None` if that would nest too deeply.
+pub(crate) fn enter(info: &ExceptionInfo) -> Option<DepthGuard> {
+    let depth = DEPTH.with(|depth| {
+        depth.set(depth.get() + 1);
+        depth.get()
+    });
+    let guard = DepthGuard(());
+    if depth == 1 {
+        claim_slot();
+    }
+
+    if depth <= MAX_DEPTH.load(Ordering::Relaxed) {
+        return Some(guard);
+    }
+
+    let hook = ON_REENTRY.load(Ordering::Relaxed);
+    if hook != 0 {
+        let hook = unsafe { std::mem::transmute::<usize, ReentryHook>(hook) };
+        hook(depth, info);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::set_on_reentry;
+    use crate::{dispatch, ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling, Order, Veh};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+    use winapi::um::minwinbase::EXCEPTION_ACCESS_VIOLATION;
+    use winapi::um::winnt::{EXCEPTION_POINTERS, LONG, PEXCEPTION_POINTERS};
+    use winapi::vc::excpt::{EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH};
+
+    const CODE: u32 = 0xE056_5201;
+
+    #[test]
+    fn faulting_callback_not_reentered() {
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        static REENTERED: AtomicU32 = AtomicU32::new(0);
+
+        fn faulty(info: &mut ExceptionInfo) -> Handling {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            if info.code().raw() != CODE {
+                return Handling::ContinueSearch;
+            }
+
+            // Faults, and only the fix-up handler below gets to see it
+            unsafe { (std::mem::transmute::<usize, fn()>(1))() };
+            Handling::ContinueExecution
+        }
+
+        fn on_reentry(depth: u32, info: &ExceptionInfo) {
+            if info.address() == 1 {
+                REENTERED.store(depth, Ordering::SeqCst);
+            }
+        }
+
+        unsafe extern "system" fn fix_up(ptrs: PEXCEPTION_POINTERS) -> LONG {
+            let cr = &mut *(*ptrs).ContextRecord;
+            let er = &mut *(*ptrs).ExceptionRecord;
+
+            if er.ExceptionCode == EXCEPTION_ACCESS_VIOLATION && er.ExceptionAddress == 1 as _ {
+                // Pretend the bad call returned to let the program continue
+                if cr.emulate_return().is_err() {
+                    return EXCEPTION_CONTINUE_SEARCH;
+                }
+
+                EXCEPTION_CONTINUE_EXECUTION
+            } else {
+                EXCEPTION_CONTINUE_SEARCH
+            }
+        }
+
+        let _serial = dispatch::tests::serial();
+        set_on_reentry(Some(on_reentry));
+
+        unsafe {
+            let _fix_up = Veh::<EXCEPTION_POINTERS>::add(Order::Last, fix_up);
+            // Without the depth limit, the callback would see its own fault as well
+            let codes = [
+                ExceptionCode::from_raw(CODE),
+                ExceptionCode::AccessViolation,
+            ];
+            let filter = Filter::codes(codes).and(Filter::current_thread());
+            let _faulty = dispatch::register(filter, faulty).unwrap();
+            RaiseException(CODE, 0, 0, std::ptr::null());
+        }
+
+        set_on_reentry(None);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(REENTERED.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,418 @@
+// Formatting and comparing a context's registers, for logs and crash reports.
+
+// Imports
+use crate::{ContextExt, CONTEXT};
+use std::fmt;
+
+/// A register's name and value, in the order registers are dumped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Register {
+    pub(crate) name: &'static str,
+    pub(crate) value: u64,
+    /// How many hex digits the value is printed with.
+    pub(crate) digits: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+const fn reg(name: &'static str, value: u64) -> Register {
+    Register {
+        name,
+        value,
+        digits: 16,
+    }
+}
+
+const fn reg32(name: &'static str, value: u32) -> Register {
+    Register {
+        name,
+        value: value as u64,
+        digits: 8,
+    }
+}
+
+#[cfg(target_arch = "x86")]
+const PER_LINE: usize = 6;
+#[cfg(target_arch = "x86_64")]
+const PER_LINE: usize = 3;
+#[cfg(target_arch = "aarch64")]
+const PER_LINE: usize = 4;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const NAME_WIDTH: usize = 3;
+#[cfg(target_arch = "aarch64")]
+const NAME_WIDTH: usize = 4;
+
+/// The general-purpose registers, followed by the instruction pointer, stack pointer and flags.
+#[cfg(target_arch = "x86")]
+pub(crate) fn general(c: &CONTEXT) -> [Register; 10] {
+    [
+        reg32("eax", c.eax),
+        reg32("ebx", c.ebx),
+        reg32("ecx", c.ecx),
+        reg32("edx", c.edx),
+        reg32("esi", c.esi),
+        reg32("edi", c.edi),
+        reg32("eip", c.eip),
+        reg32("esp", c.esp),
+        reg32("ebp", c.ebp),
+        reg32("efl", c.eflags),
+    ]
+}
+
+/// The general-purpose registers, followed by the instruction pointer, stack pointer and flags.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn general(c: &CONTEXT) -> [Register; 18] {
+    [
+        reg("rax", c.rax),
+        reg("rbx", c.rbx),
+        reg("rcx", c.rcx),
+        reg("rdx", c.rdx),
+        reg("rsi", c.rsi),
+        reg("rdi", c.rdi),
+        reg("rip", c.rip),
+        reg("rsp", c.rsp),
+        reg("rbp", c.rbp),
+        reg("r8", c.r8),
+        reg("r9", c.r9),
+        reg("r10", c.r10),
+        reg("r11", c.r11),
+        reg("r12", c.r12),
+        reg("r13", c.r13),
+        reg("r14", c.r14),
+        reg("r15", c.r15),
+        reg32("efl", c.eflags),
+    ]
+}
+
+/// The general-purpose registers, followed by the instruction pointer, stack pointer and flags.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn general(c: &CONTEXT) -> [Register; 34] {
+    const NAMES: [&str; 31] = [
+        "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+        "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+        "x27", "x28", "fp", "lr",
+    ];
+
+    let mut registers = [reg("", 0); 34];
+    for (i, name) in NAMES.iter().enumerate() {
+        registers[i] = reg(name, c.x[i]);
+    }
+    registers[31] = reg("sp", c.sp);
+    registers[32] = reg("pc", c.pc);
+    registers[33] = reg32("cpsr", c.cpsr);
+    registers
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn segments(c: &CONTEXT) -> [Register; 6] {
+    let seg = |name, value| Register {
+        name,
+        value: value as u64,
+        digits: 4,
+    };
+    [
+        seg("cs", c.seg_cs),
+        seg("ss", c.seg_ss),
+        seg("ds", c.seg_ds),
+        seg("es", c.seg_es),
+        seg("fs", c.seg_fs),
+        seg("gs", c.seg_gs),
+    ]
+}
+
+// ARM64 has no segment registers
+#[cfg(target_arch = "aarch64")]
+fn segments(_: &CONTEXT) -> [Register; 0] {
+    []
+}
+
+/// Writes the registers of `ctx` in the style of WinDbg's `r` command: the general-purpose
+/// registers, instruction pointer, stack pointer and flags a few to a line, then the segment
+/// registers on x86 and x64. Every value is in hex, zero-padded to the register's width, and
+/// every line ends in a newline.
+pub fn fmt_registers(ctx: &(impl ContextExt + ?Sized), out: &mut impl fmt::Write) -> fmt::Result {
+    let context = ctx.to_native();
+
+    for line in general(&context).chunks(PER_LINE) {
+        for (i, register) in line.iter().enumerate() {
+            if i > 0 {
+                out.write_char(' ')?;
+            }
+            write!(out, "{:>NAME_WIDTH$}=", register.name)?;
+            write!(out, "{:0width$x}", register.value, width = register.digits)?;
+        }
+        out.write_char('\n')?;
+    }
+
+    let segments = segments(&context);
+    for (i, register) in segments.iter().enumerate() {
+        if i > 0 {
+            out.write_str("  ")?;
+        }
+        write!(out, "{}={:04x}", register.name, register.value)?;
+    }
+    if !segments.is_empty() {
+        out.write_char('\n')?;
+    }
+
+    Ok(())
+}
+
+/// Displays a context's registers, see [`fmt_registers`].
+pub struct RegisterDump<'a>(pub &'a dyn ContextExt);
+
+impl fmt::Display for RegisterDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_registers(self.0, f)
+    }
+}
+
+/// A register whose value differs between two contexts, see [`context_diff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterChange {
+    /// The register's name in `CONTEXT`, such as `"Rip"`.
+    pub register: &'static str,
+    pub old: u64,
+    pub new: u64,
+    digits: usize,
+}
+
+impl fmt::Display for RegisterChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.digits + 2;
+        write!(
+            f,
+            "{}: {:#0width$x} -> {:#0width$x}",
+            self.register, self.old, self.new
+        )
+    }
+}
+
+/// The general-purpose registers, instruction pointer, stack pointer and flags that differ
+/// between `before` and `after`, in the order [`fmt_registers`] prints them.
+pub fn context_diff(before: &CONTEXT, after: &CONTEXT) -> Vec<RegisterChange> {
+    general(before)
+        .iter()
+        .zip(general(after).iter())
+        .filter(|(old, new)| old.value != new.value)
+        .map(|(old, new)| RegisterChange {
+            register: field_name(old.name),
+            old: old.value,
+            new: new.value,
+            digits: old.digits,
+        })
+        .collect()
+}
+
+// Maps the short names of the dump to the names of the `CONTEXT` fields
+fn field_name(name: &'static str) -> &'static str {
+    macro_rules! fields {
+        ($($name:literal => $field:literal,)*) => {
+            match name {
+                $($name => $field,)*
+                _ => name,
+            }
+        };
+    }
+
+    #[cfg(target_arch = "x86")]
+    let field = fields! {
+        "eax" => "Eax", "ebx" => "Ebx", "ecx" => "Ecx", "edx" => "Edx", "esi" => "Esi",
+        "edi" => "Edi", "eip" => "Eip", "esp" => "Esp", "ebp" => "Ebp", "efl" => "EFlags",
+    };
+    #[cfg(target_arch = "x86_64")]
+    let field = fields! {
+        "rax" => "Rax", "rbx" => "Rbx", "rcx" => "Rcx", "rdx" => "Rdx", "rsi" => "Rsi",
+        "rdi" => "Rdi", "rip" => "Rip", "rsp" => "Rsp", "rbp" => "Rbp", "r8" => "R8",
+        "r9" => "R9", "r10" => "R10", "r11" => "R11", "r12" => "R12", "r13" => "R13",
+        "r14" => "R14", "r15" => "R15", "efl" => "EFlags",
+    };
+    #[cfg(target_arch = "aarch64")]
+    let field = fields! {
+        "x0" => "X0", "x1" => "X1", "x2" => "X2", "x3" => "X3", "x4" => "X4", "x5" => "X5",
+        "x6" => "X6", "x7" => "X7", "x8" => "X8", "x9" => "X9", "x10" => "X10", "x11" => "X11",
+        "x12" => "X12", "x13" => "X13", "x14" => "X14", "x15" => "X15", "x16" => "X16",
+        "x17" => "X17", "x18" => "X18", "x19" => "X19", "x20" => "X20", "x21" => "X21",
+        "x22" => "X22", "x23" => "X23", "x24" => "X24", "x25" => "X25", "x26" => "X26",
+        "x27" => "X27", "x28" => "X28", "fp" => "Fp", "lr" => "Lr", "sp" => "Sp", "pc" => "Pc",
+        "cpsr" => "Cpsr",
+    };
+
+    field
+}
+
+/// Sets the register [`general`] calls `name`, returning whether there is one. Values are
+/// truncated to the register's width.
+#[cfg(feature = "serde")]
+pub(crate) fn set_general(c: &mut CONTEXT, name: &str, value: u64) -> bool {
+    #[cfg(target_arch = "x86")]
+    let register = match name {
+        "eax" => &mut c.eax,
+        "ebx" => &mut c.ebx,
+        "ecx" => &mut c.ecx,
+        "edx" => &mut c.edx,
+        "esi" => &mut c.esi,
+        "edi" => &mut c.edi,
+        "eip" => &mut c.eip,
+        "esp" => &mut c.esp,
+        "ebp" => &mut c.ebp,
+        "efl" => &mut c.eflags,
+        _ => return false,
+    };
+    #[cfg(target_arch = "x86_64")]
+    let register = match name {
+        "rax" => &mut c.rax,
+        "rbx" => &mut c.rbx,
+        "rcx" => &mut c.rcx,
+        "rdx" => &mut c.rdx,
+        "rsi" => &mut c.rsi,
+        "rdi" => &mut c.rdi,
+        "rip" => &mut c.rip,
+        "rsp" => &mut c.rsp,
+        "rbp" => &mut c.rbp,
+        "r8" => &mut c.r8,
+        "r9" => &mut c.r9,
+        "r10" => &mut c.r10,
+        "r11" => &mut c.r11,
+        "r12" => &mut c.r12,
+        "r13" => &mut c.r13,
+        "r14" => &mut c.r14,
+        "r15" => &mut c.r15,
+        "efl" => {
+            c.eflags = value as u32;
+            return true;
+        }
+        _ => return false,
+    };
+    #[cfg(target_arch = "aarch64")]
+    let register = match name {
+        "fp" => &mut c.x[29],
+        "lr" => &mut c.x[30],
+        "sp" => &mut c.sp,
+        "pc" => &mut c.pc,
+        "cpsr" => {
+            c.cpsr = value as u32;
+            return true;
+        }
+        _ => match name.strip_prefix('x').and_then(|i| i.parse::<usize>().ok()) {
+            Some(i) if i < 29 => &mut c.x[i],
+            _ => return false,
+        },
+    };
+
+    *register = value as _;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{context_diff, RegisterDump};
+    use crate::{ContextExt, CONTEXT};
+
+    // Every register gets a distinct value, so a field mapped to the wrong name shows up
+    #[cfg(target_arch = "x86")]
+    fn known() -> CONTEXT {
+        let mut c: CONTEXT = unsafe { std::mem::zeroed() };
+        (c.eax, c.ebx, c.ecx, c.edx, c.esi, c.edi) = (1, 2, 3, 4, 5, 6);
+        (c.eip, c.esp, c.ebp, c.eflags) = (0x401000, 0x19ff00, 0x19ff40, 0x246);
+        (c.seg_cs, c.seg_ss, c.seg_ds, c.seg_es, c.seg_fs, c.seg_gs) =
+            (0x23, 0x2b, 0x2b, 0x2b, 0x53, 0);
+        c
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn known() -> CONTEXT {
+        let mut c: CONTEXT = unsafe { std::mem::zeroed() };
+        (c.rax, c.rbx, c.rcx, c.rdx, c.rsi, c.rdi) = (1, 2, 3, 4, 5, 6);
+        (c.rip, c.rsp, c.rbp) = (0x7ff6_0000_1000, 0xff_0000, 0xff_0040);
+        (c.r8, c.r9, c.r10, c.r11) = (8, 9, 10, 11);
+        (c.r12, c.r13, c.r14, c.r15) = (12, 13, 14, 15);
+        c.eflags = 0x246;
+        (c.seg_cs, c.seg_ss, c.seg_ds, c.seg_es, c.seg_fs, c.seg_gs) =
+            (0x33, 0x2b, 0x2b, 0x2b, 0x53, 0x2b);
+        c
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn known() -> CONTEXT {
+        let mut c: CONTEXT = unsafe { std::mem::zeroed() };
+        for (i, x) in c.x.iter_mut().enumerate() {
+            *x = i as u64;
+        }
+        (c.sp, c.pc, c.cpsr) = (0xff_0000, 0x7ff6_0000_1000, 0x6000_0000);
+        c
+    }
+
+    #[cfg(target_arch = "x86")]
+    const GOLDEN: &str = "\
+eax=00000001 ebx=00000002 ecx=00000003 edx=00000004 esi=00000005 edi=00000006
+eip=00401000 esp=0019ff00 ebp=0019ff40 efl=00000246
+cs=0023  ss=002b  ds=002b  es=002b  fs=0053  gs=0000
+";
+
+    #[cfg(target_arch = "x86_64")]
+    const GOLDEN: &str = "\
+rax=0000000000000001 rbx=0000000000000002 rcx=0000000000000003
+rdx=0000000000000004 rsi=0000000000000005 rdi=0000000000000006
+rip=00007ff600001000 rsp=0000000000ff0000 rbp=0000000000ff0040
+ r8=0000000000000008  r9=0000000000000009 r10=000000000000000a
+r11=000000000000000b r12=000000000000000c r13=000000000000000d
+r14=000000000000000e r15=000000000000000f efl=00000246
+cs=0033  ss=002b  ds=002b  es=002b  fs=0053  gs=002b
+";
+
+    // The line continuation drops the padding of the line after it, so it's written before
+    #[cfg(target_arch = "aarch64")]
+    const GOLDEN: &str = "  \
+  x0=0000000000000000   x1=0000000000000001   x2=0000000000000002   x3=0000000000000003
+  x4=0000000000000004   x5=0000000000000005   x6=0000000000000006   x7=0000000000000007
+  x8=0000000000000008   x9=0000000000000009  x10=000000000000000a  x11=000000000000000b
+ x12=000000000000000c  x13=000000000000000d  x14=000000000000000e  x15=000000000000000f
+ x16=0000000000000010  x17=0000000000000011  x18=0000000000000012  x19=0000000000000013
+ x20=0000000000000014  x21=0000000000000015  x22=0000000000000016  x23=0000000000000017
+ x24=0000000000000018  x25=0000000000000019  x26=000000000000001a  x27=000000000000001b
+ x28=000000000000001c   fp=000000000000001d   lr=000000000000001e   sp=0000000000ff0000
+  pc=00007ff600001000 cpsr=60000000
+";
+
+    #[test]
+    fn golden_dump() {
+        let context = known();
+        assert_eq!(RegisterDump(&context).to_string(), GOLDEN);
+    }
+
+    #[cfg(feature = "impl-winapi")]
+    #[test]
+    fn winapi_dump() {
+        let context = known();
+        let theirs: winapi::um::winnt::CONTEXT = unsafe { std::mem::transmute(context) };
+        assert_eq!(RegisterDump(&theirs).to_string(), GOLDEN);
+    }
+
+    #[test]
+    fn diff_two_fields() {
+        let before = known();
+        let mut after = before;
+        after.set_ip(before.ip() + 2);
+        after.set_flags(before.flags() | 1 << 8);
+
+        let changes = context_diff(&before, &after);
+        let names: Vec<_> = changes.iter().map(|change| change.register).collect();
+        #[cfg(target_arch = "x86")]
+        assert_eq!(names, ["Eip", "EFlags"]);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(names, ["Rip", "EFlags"]);
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(names, ["Pc", "Cpsr"]);
+
+        assert_eq!(changes[0].old, before.ip() as u64);
+        assert_eq!(changes[0].new, after.ip() as u64);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            changes[0].to_string(),
+            "Rip: 0x00007ff600001000 -> 0x00007ff600001002"
+        );
+
+        assert!(context_diff(&before, &before).is_empty());
+    }
+}
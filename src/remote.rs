@@ -0,0 +1,598 @@
+//! Registering vectored handlers in another process.
+//!
+//! [`RemoteVeh::add`] does what the rest of the crate does locally, but against another process
+//! of the same architecture: it walks the target's PEB with `ReadProcessMemory` to find its
+//! ntdll, copies the image over to find the internal registration functions the same way
+//! [`raw`](crate::raw) does, and then calls them in the target from a small stub run with
+//! `CreateRemoteThread`.
+//!
+//! The handler itself must already be in the target, such as in a DLL injected beforehand. The
+//! process handle needs `PROCESS_CREATE_THREAD`, `PROCESS_QUERY_INFORMATION`,
+//! `PROCESS_VM_OPERATION`, `PROCESS_VM_READ` and `PROCESS_VM_WRITE` access.
+
+// Imports
+use crate::memory::{PAGE_READWRITE, PAGE_SIZE};
+use crate::raw::{self, EXCEPTION_HANDLER_LIST, RAVCH, RRVCH, WRAPPER_SCAN_LEN};
+use crate::{modules, Order};
+use std::ffi::c_void;
+use std::fmt;
+use std::mem::size_of;
+
+// Architecture-specific imports
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::GetProcAddress, Pe, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::GetProcAddress, Pe, PeView};
+
+type FnNtQueryInformationProcess = unsafe extern "system" fn(
+    process: *mut c_void,
+    class: u32,
+    information: *mut c_void,
+    length: u32,
+    returned: *mut u32,
+) -> i32;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetLastError() -> u32;
+    fn GetCurrentProcess() -> *mut c_void;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+    fn DuplicateHandle(
+        source_process: *mut c_void,
+        source: *mut c_void,
+        target_process: *mut c_void,
+        target: *mut *mut c_void,
+        access: u32,
+        inherit: i32,
+        options: u32,
+    ) -> i32;
+    fn IsWow64Process(process: *mut c_void, wow64: *mut i32) -> i32;
+    fn ReadProcessMemory(
+        process: *mut c_void,
+        address: *const c_void,
+        buffer: *mut c_void,
+        size: usize,
+        read: *mut usize,
+    ) -> i32;
+    fn WriteProcessMemory(
+        process: *mut c_void,
+        address: *mut c_void,
+        buffer: *const c_void,
+        size: usize,
+        written: *mut usize,
+    ) -> i32;
+    fn VirtualAllocEx(
+        process: *mut c_void,
+        address: *mut c_void,
+        size: usize,
+        kind: u32,
+        protect: u32,
+    ) -> *mut c_void;
+    fn VirtualFreeEx(process: *mut c_void, address: *mut c_void, size: usize, kind: u32) -> i32;
+    fn VirtualProtectEx(
+        process: *mut c_void,
+        address: *mut c_void,
+        size: usize,
+        protect: u32,
+        old: *mut u32,
+    ) -> i32;
+    fn FlushInstructionCache(process: *mut c_void, address: *const c_void, size: usize) -> i32;
+    fn CreateRemoteThread(
+        process: *mut c_void,
+        attributes: *mut c_void,
+        stack_size: usize,
+        start: *mut c_void,
+        parameter: *mut c_void,
+        flags: u32,
+        thread_id: *mut u32,
+    ) -> *mut c_void;
+    fn WaitForSingleObject(handle: *mut c_void, milliseconds: u32) -> u32;
+}
+
+const DUPLICATE_SAME_ACCESS: u32 = 0x2;
+const MEM_COMMIT_RESERVE: u32 = 0x3000;
+const MEM_RELEASE: u32 = 0x8000;
+const PAGE_EXECUTE_READ: u32 = 0x20;
+const PROCESS_BASIC_INFORMATION: u32 = 0;
+
+// How long the stub may take to run in the target
+const CALL_TIMEOUT_MS: u32 = 5000;
+
+// Loader entries looked at before giving up on finding ntdll, in case the list is being changed
+const MAX_MODULES: usize = 1024;
+
+const PTR: usize = size_of::<usize>();
+
+// The same offsets `modules::iter` uses: `Ldr`, `InLoadOrderModuleList`, and the
+// `DllBase`, `SizeOfImage` and `BaseDllName` of an entry, from its link
+#[cfg(target_pointer_width = "32")]
+const PEB_LDR: usize = 0x0C;
+#[cfg(target_pointer_width = "32")]
+const LDR_LOAD_ORDER_LIST: usize = 0x0C;
+#[cfg(target_pointer_width = "64")]
+const PEB_LDR: usize = 0x18;
+#[cfg(target_pointer_width = "64")]
+const LDR_LOAD_ORDER_LIST: usize = 0x10;
+const ENTRY_BASE: usize = PTR * 6;
+const ENTRY_SIZE: usize = PTR * 8;
+const ENTRY_NAME: usize = PTR * 11;
+
+// Calls `block[0](block[1], block[2], block[3])` with ntdll's internal fastcall convention, and
+// stores what it returned in `block[4]`. Run as a thread's start routine, taking `block`.
+#[cfg(target_arch = "x86")]
+const STUB: &[u8] = &[
+    0x53, // push ebx
+    0x55, // push ebp
+    0x89, 0xE5, // mov ebp, esp
+    0x8B, 0x5C, 0x24, 0x0C, // mov ebx, [esp + 0xC]
+    0xFF, 0x73, 0x0C, // push dword [ebx + 0xC]
+    0x8B, 0x4B, 0x04, // mov ecx, [ebx + 4]
+    0x8B, 0x53, 0x08, // mov edx, [ebx + 8]
+    0xFF, 0x13, // call [ebx]
+    0x89, 0xEC, // mov esp, ebp, as the remove function takes no stack argument to pop
+    0x89, 0x43, 0x10, // mov [ebx + 0x10], eax
+    0x31, 0xC0, // xor eax, eax
+    0x5D, // pop ebp
+    0x5B, // pop ebx
+    0xC2, 0x04, 0x00, // ret 4
+];
+#[cfg(target_arch = "x86_64")]
+const STUB: &[u8] = &[
+    0x53, // push rbx
+    0x48, 0x89, 0xCB, // mov rbx, rcx
+    0x48, 0x83, 0xEC, 0x20, // sub rsp, 0x20
+    0x48, 0x8B, 0x4B, 0x08, // mov rcx, [rbx + 8]
+    0x48, 0x8B, 0x53, 0x10, // mov rdx, [rbx + 0x10]
+    0x4C, 0x8B, 0x43, 0x18, // mov r8, [rbx + 0x18]
+    0xFF, 0x13, // call [rbx]
+    0x48, 0x89, 0x43, 0x20, // mov [rbx + 0x20], rax
+    0x48, 0x83, 0xC4, 0x20, // add rsp, 0x20
+    0x31, 0xC0, // xor eax, eax
+    0x5B, // pop rbx
+    0xC3, // ret
+];
+
+/// Errors returned by [`RemoteVeh::add`] and [`RemoteVeh::remove`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RemoteError {
+    /// The process handle couldn't be duplicated or queried, with this error code.
+    Handle(u32),
+    /// The target isn't of the same architecture as this process.
+    ArchitectureMismatch,
+    /// Querying the target failed with this `NTSTATUS`.
+    Query(i32),
+    /// Reading, writing or allocating the target's memory failed with this error code.
+    Memory(u32),
+    /// ntdll wasn't found in the target's loader data.
+    ModuleNotFound,
+    /// The internal registration functions couldn't be located in the target's ntdll.
+    Resolution,
+    /// Starting the stub in the target failed with this error code.
+    Thread(u32),
+    /// The stub didn't finish in time, so its memory was left in the target.
+    Timeout,
+    /// ntdll refused to register the handler.
+    Registration,
+    /// ntdll didn't find the handler to remove.
+    NotRemoved,
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteError::Handle(code) => {
+                write!(f, "the process handle is unusable, error {code}")
+            }
+            RemoteError::ArchitectureMismatch => {
+                f.write_str("the target process has a different architecture")
+            }
+            RemoteError::Query(status) => {
+                write!(
+                    f,
+                    "querying the target failed with status {:#010x}",
+                    *status as u32
+                )
+            }
+            RemoteError::Memory(code) => {
+                write!(f, "accessing the target's memory failed with error {code}")
+            }
+            RemoteError::ModuleNotFound => f.write_str("ntdll is not loaded in the target"),
+            RemoteError::Resolution => {
+                f.write_str("failed to locate the vectored handler functions in the target")
+            }
+            RemoteError::Thread(code) => {
+                write!(
+                    f,
+                    "starting a thread in the target failed with error {code}"
+                )
+            }
+            RemoteError::Timeout => f.write_str("the target didn't run the stub in time"),
+            RemoteError::Registration => f.write_str("ntdll failed to register the handler"),
+            RemoteError::NotRemoved => f.write_str("ntdll didn't remove the handler"),
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+// A handle to the target of our own, closed on drop
+struct Process(*mut c_void);
+
+impl Process {
+    fn duplicate(process: *mut c_void) -> Result<Process, RemoteError> {
+        let mut handle = std::ptr::null_mut();
+        let current = unsafe { GetCurrentProcess() };
+        let duplicated = unsafe {
+            DuplicateHandle(
+                current,
+                process,
+                current,
+                &mut handle,
+                0,
+                0,
+                DUPLICATE_SAME_ACCESS,
+            )
+        };
+        match duplicated {
+            0 => Err(RemoteError::Handle(unsafe { GetLastError() })),
+            _ => Ok(Process(handle)),
+        }
+    }
+
+    fn is_wow64(&self) -> Result<bool, RemoteError> {
+        wow64(self.0)
+    }
+
+    fn read(&self, address: usize, buffer: &mut [u8]) -> Result<(), RemoteError> {
+        let (size, mut read) = (buffer.len(), 0);
+        let target = buffer.as_mut_ptr() as *mut c_void;
+        match unsafe { ReadProcessMemory(self.0, address as _, target, size, &mut read) } {
+            0 => Err(RemoteError::Memory(unsafe { GetLastError() })),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_usize(&self, address: usize) -> Result<usize, RemoteError> {
+        let mut bytes = [0; PTR];
+        self.read(address, &mut bytes)?;
+        Ok(usize::from_le_bytes(bytes))
+    }
+
+    fn write(&self, address: usize, bytes: &[u8]) -> Result<(), RemoteError> {
+        let (size, mut written) = (bytes.len(), 0);
+        let source = bytes.as_ptr() as *const c_void;
+        match unsafe { WriteProcessMemory(self.0, address as _, source, size, &mut written) } {
+            0 => Err(RemoteError::Memory(unsafe { GetLastError() })),
+            _ => Ok(()),
+        }
+    }
+
+    fn alloc(&self, size: usize) -> Result<Allocation<'_>, RemoteError> {
+        let null = std::ptr::null_mut();
+        let address =
+            unsafe { VirtualAllocEx(self.0, null, size, MEM_COMMIT_RESERVE, PAGE_READWRITE) };
+        match address.is_null() {
+            true => Err(RemoteError::Memory(unsafe { GetLastError() })),
+            false => Ok(Allocation {
+                process: self,
+                address: address as usize,
+            }),
+        }
+    }
+
+    // Runs `STUB` in the target to call `function` with `arguments`, returning what it returned
+    fn call(&self, function: usize, arguments: [usize; 3]) -> Result<usize, RemoteError> {
+        let code = self.alloc(STUB.len())?;
+        self.write(code.address, STUB)?;
+        let mut old = 0;
+        let (address, size) = (code.address as *mut c_void, STUB.len());
+        if unsafe { VirtualProtectEx(self.0, address, size, PAGE_EXECUTE_READ, &mut old) } == 0 {
+            return Err(RemoteError::Memory(unsafe { GetLastError() }));
+        }
+        unsafe { FlushInstructionCache(self.0, address, size) };
+
+        let block = self.alloc(PTR * 5)?;
+        let [first, second, third] = arguments;
+        let words = [function, first, second, third, 0];
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        self.write(block.address, &bytes)?;
+
+        let thread = unsafe {
+            CreateRemoteThread(
+                self.0,
+                std::ptr::null_mut(),
+                0,
+                code.address as *mut c_void,
+                block.address as *mut c_void,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if thread.is_null() {
+            return Err(RemoteError::Thread(unsafe { GetLastError() }));
+        }
+        let waited = unsafe { WaitForSingleObject(thread, CALL_TIMEOUT_MS) };
+        unsafe { CloseHandle(thread) };
+        if waited != 0 {
+            // Still running, or about to, so neither can be freed
+            std::mem::forget(code);
+            std::mem::forget(block);
+            return Err(RemoteError::Timeout);
+        }
+
+        self.read_usize(block.address + PTR * 4)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+// Memory allocated in the target, freed on drop
+struct Allocation<'a> {
+    process: &'a Process,
+    address: usize,
+}
+
+impl Drop for Allocation<'_> {
+    fn drop(&mut self) {
+        unsafe { VirtualFreeEx(self.process.0, self.address as _, 0, MEM_RELEASE) };
+    }
+}
+
+fn wow64(process: *mut c_void) -> Result<bool, RemoteError> {
+    let mut wow64 = 0;
+    match unsafe { IsWow64Process(process, &mut wow64) } {
+        0 => Err(RemoteError::Handle(unsafe { GetLastError() })),
+        _ => Ok(wow64 != 0),
+    }
+}
+
+fn find_query_information() -> Option<FnNtQueryInformationProcess> {
+    let ntdll = modules::find("ntdll.dll")?;
+    let view = unsafe { PeView::module(ntdll.base() as *const u8) };
+    let query = view.get_proc_address("NtQueryInformationProcess").ok()?;
+    Some(unsafe { std::mem::transmute::<usize, FnNtQueryInformationProcess>(query as usize) })
+}
+
+#[repr(C)]
+struct ProcessBasicInformation {
+    exit_status: i32,
+    peb: usize,
+    affinity_mask: usize,
+    base_priority: i32,
+    process_id: usize,
+    parent_process_id: usize,
+}
+
+// The target's ntdll, as its base and size, found like `modules::find` does
+fn remote_ntdll(process: &Process) -> Result<(usize, usize), RemoteError> {
+    let query = find_query_information().ok_or(RemoteError::Resolution)?;
+    let mut information = std::mem::MaybeUninit::<ProcessBasicInformation>::zeroed();
+    let length = size_of::<ProcessBasicInformation>() as u32;
+    let status = unsafe {
+        query(
+            process.0,
+            PROCESS_BASIC_INFORMATION,
+            information.as_mut_ptr() as *mut c_void,
+            length,
+            std::ptr::null_mut(),
+        )
+    };
+    if status < 0 {
+        return Err(RemoteError::Query(status));
+    }
+    let peb = unsafe { information.assume_init() }.peb;
+
+    let list_base = process.read_usize(peb + PEB_LDR)? + LDR_LOAD_ORDER_LIST;
+    let mut current = list_base;
+    for _ in 0..MAX_MODULES {
+        current = process.read_usize(current)?;
+        if current == list_base {
+            break;
+        }
+
+        // `UNICODE_STRING`'s length, and buffer after the padding
+        let length = process.read_usize(current + ENTRY_NAME)? & 0xFFFF;
+        let buffer = process.read_usize(current + ENTRY_NAME + PTR)?;
+        if buffer == 0 || length != "ntdll.dll".len() * 2 {
+            continue;
+        }
+        let mut name = [0; 18];
+        process.read(buffer, &mut name)?;
+        let name: Vec<u16> = name
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        if String::from_utf16_lossy(&name).eq_ignore_ascii_case("ntdll.dll") {
+            let base = process.read_usize(current + ENTRY_BASE)?;
+            let mut size = [0; 4];
+            process.read(current + ENTRY_SIZE, &mut size)?;
+            return Ok((base, u32::from_le_bytes(size) as usize));
+        }
+    }
+    Err(RemoteError::ModuleNotFound)
+}
+
+// The target's ntdll with its headers and sections copied to where they're mapped, in `u64`s
+// so the headers are aligned for pelite
+fn copy_image(process: &Process, base: usize, size: usize) -> Result<Vec<u64>, RemoteError> {
+    let mut image = vec![0u64; size.div_ceil(8)];
+    let bytes = |image: &mut Vec<u64>| unsafe {
+        std::slice::from_raw_parts_mut(image.as_mut_ptr() as *mut u8, size)
+    };
+    process.read(base, &mut bytes(&mut image)[..PAGE_SIZE.min(size)])?;
+
+    let sections: Vec<_> = {
+        let view = PeView::from_bytes(bytes(&mut image)).map_err(|_| RemoteError::Resolution)?;
+        let headers = view.section_headers().image();
+        let ranges = headers.iter().map(|section| {
+            let start = section.VirtualAddress as usize;
+            start..(start + section.VirtualSize as usize).min(size)
+        });
+        ranges.filter(|range| range.start < range.end).collect()
+    };
+    // Sections that can't be read aren't needed to find the functions, or show up as missing
+    for range in sections {
+        let _ = process.read(base + range.start, &mut bytes(&mut image)[range]);
+    }
+    Ok(image)
+}
+
+// The addresses of the target's internal add and remove functions
+fn resolve(process: &Process) -> Result<(usize, usize), RemoteError> {
+    let (base, size) = remote_ntdll(process)?;
+    let image = copy_image(process, base, size)?;
+    let bytes = unsafe { std::slice::from_raw_parts(image.as_ptr() as *const u8, size) };
+    let view = PeView::from_bytes(bytes).map_err(|_| RemoteError::Resolution)?;
+
+    let wrapped = |name: &str| {
+        let rva = view.get_export(name).ok()?.symbol()? as usize;
+        let wrapper = bytes.get(rva..rva + WRAPPER_SCAN_LEN)?;
+        let offset = raw::wrapped_offset(wrapper)?;
+        Some(base.wrapping_add(rva).wrapping_add_signed(offset))
+    };
+    match (wrapped(RAVCH), wrapped(RRVCH)) {
+        (Some(add), Some(remove)) => Ok((add, remove)),
+        _ => Err(RemoteError::Resolution),
+    }
+}
+
+/// A vectored exception handler registered in another process, removed on drop.
+pub struct RemoteVeh {
+    process: Process,
+    remove: usize,
+    handle: usize,
+}
+
+impl RemoteVeh {
+    /// Registers the handler at `remote_handler_addr` in `process`'s exception handler list.
+    ///
+    /// The handle is duplicated, so it only has to stay open for this call.
+    ///
+    /// # Safety
+    /// `remote_handler_addr` must be a vectored exception handler in the target that stays there
+    /// until it's removed.
+    pub unsafe fn add(
+        process: *mut c_void,
+        order: Order,
+        remote_handler_addr: usize,
+    ) -> Result<RemoteVeh, RemoteError> {
+        let process = Process::duplicate(process)?;
+        if process.is_wow64()? != wow64(GetCurrentProcess())? {
+            return Err(RemoteError::ArchitectureMismatch);
+        }
+
+        let (add, remove) = resolve(&process)?;
+        let arguments = [
+            order as usize,
+            remote_handler_addr,
+            EXCEPTION_HANDLER_LIST as usize,
+        ];
+        match process.call(add, arguments)? {
+            0 => Err(RemoteError::Registration),
+            handle => Ok(RemoteVeh {
+                process,
+                remove,
+                handle,
+            }),
+        }
+    }
+
+    /// The handle ntdll returned in the target.
+    pub fn handle(&self) -> usize {
+        self.handle
+    }
+
+    /// Removes the handler, unlike dropping it, reporting whether that worked.
+    pub fn remove(mut self) -> Result<(), RemoteError> {
+        let handle = std::mem::take(&mut self.handle);
+        self.remove_handle(handle)
+    }
+
+    fn remove_handle(&self, handle: usize) -> Result<(), RemoteError> {
+        let arguments = [handle, EXCEPTION_HANDLER_LIST as usize, 0];
+        // The remove function returns a `BOOLEAN`, in the low byte
+        match self.process.call(self.remove, arguments)? & 0xFF {
+            0 => Err(RemoteError::NotRemoved),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Removes the handler in the target, ignoring any failure.
+impl Drop for RemoteVeh {
+    fn drop(&mut self) {
+        if self.handle != 0 {
+            let _ = self.remove_handle(self.handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemoteVeh;
+    use crate::{Order, EXCEPTION_CONTINUE_SEARCH};
+    use std::ffi::c_void;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::windows::io::AsRawHandle;
+    use std::process::{Command, Stdio};
+
+    // Set in the child process
+    const CHILD: &str = "MANUAL_VEH_REMOTE";
+    // Printed by the child before the handler's address
+    const MARKER: &str = "remote handler at ";
+
+    unsafe extern "system" fn noop(_pointers: *mut c_void) -> i32 {
+        EXCEPTION_CONTINUE_SEARCH
+    }
+
+    #[test]
+    fn add_and_remove_in_child() {
+        if std::env::var_os(CHILD).is_some() {
+            // Straight to stdout, past the test harness's capturing, and then wait to be closed
+            let line = format!("{MARKER}{:#x}\n", noop as *const () as usize);
+            std::io::stdout().write_all(line.as_bytes()).unwrap();
+            std::io::stdout().flush().unwrap();
+            let _ = std::io::stdin().read_to_end(&mut Vec::new());
+            return;
+        }
+
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "remote::tests::add_and_remove_in_child",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env(CHILD, "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // The harness prints the test's name on the same line first
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let handler = lines
+            .by_ref()
+            .map(Result::unwrap)
+            .find_map(|line| {
+                let (_, address) = line.split_once(MARKER)?;
+                usize::from_str_radix(address.trim().trim_start_matches("0x"), 16).ok()
+            })
+            .unwrap();
+
+        let process = child.as_raw_handle();
+        let veh = unsafe { RemoteVeh::add(process, Order::Last, handler) }.unwrap();
+        assert_ne!(veh.handle(), 0);
+        veh.remove().unwrap();
+
+        // Closing stdin lets the child finish, and reading the rest of its output keeps the
+        // harness from failing to print its result
+        drop(child.stdin.take());
+        lines.for_each(drop);
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,184 @@
+//! Finding code by byte patterns, for locating functions that aren't exported.
+//!
+//! This is how the crate finds ntdll's internal registration functions: the exported wrappers
+//! are scanned for the `call` or `jmp` to the function they wrap, and its relative displacement
+//! is followed with [`resolve_rel32`].
+//!
+//! ```ignore
+//! let pattern = Pattern::parse("48 8B ?? E8 ?? ?? ?? ??")?;
+//! let found = scan(code, &pattern)?;
+//! let callee = resolve_rel32(code, found + 3, 5)?;
+//! ```
+
+// Imports
+use std::fmt;
+use std::str::FromStr;
+
+/// A byte pattern in which any byte can be a wildcard, matching whatever byte is there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+/// Why [`Pattern::parse`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PatternError {
+    /// The pattern has no bytes.
+    Empty,
+    /// A token is neither two hex digits nor a wildcard.
+    InvalidToken(String),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::Empty => f.write_str("the pattern is empty"),
+            PatternError::InvalidToken(token) => write!(f, "invalid pattern byte {token:?}"),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl Pattern {
+    /// Parses an IDA-style pattern of whitespace-separated bytes, each either two hex digits or
+    /// `??` (or `?`) for a wildcard, such as `"48 8B ?? E8 ?? ?? ?? ??"`.
+    pub fn parse(pattern: &str) -> Result<Pattern, PatternError> {
+        let bytes = pattern
+            .split_whitespace()
+            .map(|token| match token {
+                "?" | "??" => Ok(None),
+                _ if token.len() == 2 => u8::from_str_radix(token, 16)
+                    .map(Some)
+                    .map_err(|_| PatternError::InvalidToken(token.into())),
+                _ => Err(PatternError::InvalidToken(token.into())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match bytes.is_empty() {
+            true => Err(PatternError::Empty),
+            false => Ok(Pattern { bytes }),
+        }
+    }
+
+    /// How many bytes the pattern matches, wildcards included.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Always `false`, as parsing rejects empty patterns.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether `bytes` starts with a match.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.len() >= self.len()
+            && self
+                .bytes
+                .iter()
+                .zip(bytes)
+                .all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte))
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Pattern::parse(pattern)
+    }
+}
+
+/// The offset of the first match of `pattern` in `haystack`.
+pub fn scan(haystack: &[u8], pattern: &Pattern) -> Option<usize> {
+    scan_all(haystack, pattern).next()
+}
+
+/// The offsets of every match of `pattern` in `haystack`, overlapping ones included, in order.
+pub fn scan_all<'a>(haystack: &'a [u8], pattern: &'a Pattern) -> impl Iterator<Item = usize> + 'a {
+    haystack
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, window)| pattern.matches(window))
+        .map(|(offset, _)| offset)
+}
+
+/// Where the instruction of `instr_len` bytes at `match_offset` in `haystack` jumps to or calls,
+/// relative to the start of `haystack`, if it ends with a 32-bit relative displacement as `call`
+/// and `jmp` do.
+///
+/// The displacement is counted from the end of the instruction, so the result can be anywhere,
+/// including before the start of `haystack`. `None` if the instruction doesn't fit in `haystack`,
+/// or is too short to hold a displacement.
+pub fn resolve_rel32(haystack: &[u8], match_offset: usize, instr_len: usize) -> Option<isize> {
+    if instr_len < 4 {
+        return None;
+    }
+    let end = match_offset.checked_add(instr_len)?;
+    let displacement = haystack.get(end - 4..end)?;
+
+    let displacement = i32::from_le_bytes(displacement.try_into().ok()?);
+    Some(end as isize + displacement as isize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_rel32, scan, scan_all, Pattern, PatternError};
+
+    #[test]
+    fn parsed() {
+        let pattern = Pattern::parse(" 48 8b ?? E8 ? ").unwrap();
+        assert_eq!(pattern.len(), 5);
+        assert_eq!("E9 ?? ??".parse::<Pattern>().unwrap().len(), 3);
+
+        assert_eq!(Pattern::parse(" "), Err(PatternError::Empty));
+        let invalid = PatternError::InvalidToken("4".into());
+        assert_eq!(Pattern::parse("48 4 E8"), Err(invalid));
+        let invalid = PatternError::InvalidToken("GG".into());
+        assert_eq!(Pattern::parse("GG"), Err(invalid));
+    }
+
+    #[test]
+    fn wildcards_at_boundaries() {
+        let haystack = [0xE8, 0x11, 0x22, 0x90, 0xC3];
+        let pattern = |text| Pattern::parse(text).unwrap();
+
+        assert_eq!(scan(&haystack, &pattern("E8 ?? 22")), Some(0));
+        assert_eq!(scan(&haystack, &pattern("?? C3")), Some(3));
+        assert_eq!(scan(&haystack, &pattern("90 ??")), Some(3));
+        assert_eq!(scan(&haystack, &pattern("?? ?? ?? ?? ??")), Some(0));
+
+        // Running off the end doesn't match, even on wildcards
+        assert_eq!(scan(&haystack, &pattern("C3 ??")), None);
+        assert_eq!(scan(&haystack, &pattern("?? ?? ?? ?? ?? ??")), None);
+        assert_eq!(scan(&[], &pattern("??")), None);
+    }
+
+    #[test]
+    fn every_match_found() {
+        let haystack = [0xCC, 0xCC, 0xCC, 0x90, 0xCC, 0xCC];
+        let pattern = Pattern::parse("CC CC").unwrap();
+        assert_eq!(scan_all(&haystack, &pattern).collect::<Vec<_>>(), [0, 1, 4]);
+
+        let pattern = Pattern::parse("CC ?? CC").unwrap();
+        assert_eq!(scan_all(&haystack, &pattern).collect::<Vec<_>>(), [0, 2]);
+    }
+
+    #[test]
+    fn rel32_resolved() {
+        // A call forwards over a nop, and a jmp back to the start
+        let call = [0x90, 0xE8, 0x01, 0x00, 0x00, 0x00, 0x90, 0xC3];
+        assert_eq!(resolve_rel32(&call, 1, 5), Some(7));
+        let jmp = [0x90, 0x90, 0xE9, 0xF9, 0xFF, 0xFF, 0xFF];
+        assert_eq!(resolve_rel32(&jmp, 2, 5), Some(0));
+        // Before the start of the haystack, as for `mov rax, [rip - 0x20]`
+        let mov = [0x48, 0x8B, 0x05, 0xE0, 0xFF, 0xFF, 0xFF];
+        assert_eq!(resolve_rel32(&mov, 0, 7), Some(-25));
+
+        assert_eq!(resolve_rel32(&jmp, 3, 5), None);
+        assert_eq!(resolve_rel32(&jmp, 2, 3), None);
+        assert_eq!(resolve_rel32(&jmp, usize::MAX, 5), None);
+    }
+}
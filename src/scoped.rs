@@ -0,0 +1,120 @@
+// Imports
+use crate::{ExceptionInfo, Handling, Order, VectoredHandler, VehBuilder, VehError};
+
+/// Registers `handler` for the duration of `body`, passing its return value through.
+///
+/// The handler is removed when `body` returns, and also if it panics: the registration is owned by
+/// a guard living on this function's stack, so unwinding out of `body` drops it before the panic
+/// propagates any further. The handler is therefore never left behind, no matter how `body` exits.
+///
+/// # Safety
+/// This function will never directly cause undefined behaviour, but the handlers it registers
+/// might very well cause UB if they're not written correctly. Calling this function is
+/// therefore unsafe, as it might affect the program in unexpected ways if the caller doesn't
+/// properly handle exceptions it catches.
+pub unsafe fn with_handler<R>(
+    order: Order,
+    handler: VectoredHandler,
+    body: impl FnOnce() -> R,
+) -> Result<R, VehError> {
+    let _guard = VehBuilder::new().order(order).install_fn(handler)?;
+    Ok(body())
+}
+
+/// Like [`with_handler`], but registers a closure.
+///
+/// # Safety
+/// See [`with_handler`].
+pub unsafe fn with_closure_handler<F, R>(
+    order: Order,
+    handler: F,
+    body: impl FnOnce() -> R,
+) -> Result<R, VehError>
+where
+    F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+{
+    let _guard = VehBuilder::new().order(order).install_closure(handler)?;
+    Ok(body())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{with_closure_handler, with_handler, ExceptionInfo, Handling, Order, Veh};
+    use std::ffi::c_void;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    #[test]
+    fn body_result_passed_through() {
+        const CODE: u32 = 0xE056_5301;
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+
+        let result = unsafe {
+            with_closure_handler(
+                Order::First,
+                |info| match info.code().raw() {
+                    CODE => {
+                        SEEN.fetch_add(1, Ordering::SeqCst);
+                        Handling::ContinueExecution
+                    }
+                    _ => Handling::ContinueSearch,
+                },
+                || {
+                    RaiseException(CODE, 0, 0, std::ptr::null());
+                    42
+                },
+            )
+        };
+
+        assert_eq!(result, Ok(42));
+        assert_eq!(SEEN.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn removed_after_panic() {
+        const CODE: u32 = 0xE056_5302;
+        static SCOPED: AtomicUsize = AtomicUsize::new(0);
+        static BACKSTOP: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "system" fn scoped(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                CODE => {
+                    SCOPED.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueExecution.raw()
+                }
+                _ => Handling::ContinueSearch.raw(),
+            }
+        }
+
+        unsafe extern "system" fn backstop(ptrs: *mut c_void) -> i32 {
+            match ExceptionInfo::from_raw(ptrs).code().raw() {
+                CODE => {
+                    BACKSTOP.fetch_add(1, Ordering::SeqCst);
+                    Handling::ContinueExecution.raw()
+                }
+                _ => Handling::ContinueSearch.raw(),
+            }
+        }
+
+        unsafe {
+            let _backstop = Veh::<c_void>::add(Order::Last, backstop);
+
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                with_handler(Order::First, scoped, || {
+                    RaiseException(CODE, 0, 0, std::ptr::null());
+                    panic!("body panicked");
+                })
+            }));
+            assert!(result.is_err());
+            assert_eq!(SCOPED.load(Ordering::SeqCst), 1);
+            assert_eq!(BACKSTOP.load(Ordering::SeqCst), 0);
+
+            // The scoped handler is gone, so this one falls through to the backstop
+            RaiseException(CODE, 0, 0, std::ptr::null());
+        }
+
+        assert_eq!(SCOPED.load(Ordering::SeqCst), 1);
+        assert_eq!(BACKSTOP.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,179 @@
+// Handling access violations with a single callback.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{AvOperation, ContextExt, ExceptionCode, Filter, Handling, SnapshotModule, VehError};
+
+/// An access violation, as passed to [`on_segfault`]'s callback.
+#[derive(Debug, Clone, Copy)]
+pub struct SegfaultInfo {
+    /// The address that was being accessed.
+    pub address: usize,
+    pub operation: AvOperation,
+    /// The faulting instruction.
+    pub ip: usize,
+    /// The module the faulting instruction is in, if any.
+    pub module: Option<SnapshotModule>,
+}
+
+/// How to carry on after a [`SegfaultInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegfaultAction {
+    /// Skip the faulting instruction, decoding it to find its length. The access violation is
+    /// passed on if it can't be decoded.
+    #[cfg(feature = "iced")]
+    Skip,
+    /// Skip the faulting instruction, which is this many bytes long.
+    SkipBytes(usize),
+    /// Run the faulting instruction again, such as after making the memory accessible.
+    Retry,
+    /// Leave the access violation to the callbacks and handlers after this one.
+    Pass,
+}
+
+/// Keeps [`on_segfault`]'s callback registered until dropped.
+pub struct SegfaultGuard {
+    _callback: CallbackGuard,
+}
+
+/// Calls `callback` for every access violation, on any thread, until the returned guard is
+/// dropped.
+///
+/// Each call registers a dispatcher callback of its own, so several guards can be alive at once;
+/// their callbacks run in the order they were registered, until one of them doesn't return
+/// [`SegfaultAction::Pass`].
+pub fn on_segfault<F>(callback: F) -> Result<SegfaultGuard, VehError>
+where
+    F: Fn(SegfaultInfo) -> SegfaultAction + Send + Sync + 'static,
+{
+    let callback = Registration::new()
+        .filter(Filter::code(ExceptionCode::AccessViolation))
+        .register_closure(move |info| {
+            let violation = match info.access_violation() {
+                Some(violation) => violation,
+                None => return Handling::ContinueSearch,
+            };
+            let ip = info.context().ip();
+            let segfault = SegfaultInfo {
+                address: violation.address,
+                operation: violation.operation,
+                ip,
+                module: SnapshotModule::containing(ip),
+            };
+
+            match callback(segfault) {
+                #[cfg(feature = "iced")]
+                SegfaultAction::Skip => {
+                    if info.context_mut().skip_instruction().is_err() {
+                        return Handling::ContinueSearch;
+                    }
+                }
+                SegfaultAction::SkipBytes(len) => info.context_mut().skip_bytes(len),
+                SegfaultAction::Retry => {}
+                SegfaultAction::Pass => return Handling::ContinueSearch,
+            }
+            Handling::ContinueExecution
+        })?;
+    Ok(SegfaultGuard {
+        _callback: callback,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{on_segfault, SegfaultAction};
+    use crate::dispatch;
+    use crate::memory::{self, PAGE_READWRITE, PAGE_SIZE};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn VirtualAlloc(address: *mut c_void, size: usize, kind: u32, protect: u32) -> *mut c_void;
+        fn VirtualFree(address: *mut c_void, size: usize, kind: u32) -> i32;
+    }
+
+    const PAGE_READONLY: u32 = 0x02;
+
+    // A committed page holding 1 in its first `u64`, made read-only
+    fn read_only_page() -> *mut u64 {
+        let page = unsafe { VirtualAlloc(std::ptr::null_mut(), PAGE_SIZE, 0x3000, PAGE_READWRITE) };
+        assert!(!page.is_null());
+        unsafe {
+            *(page as *mut u64) = 1;
+            memory::set_protection(page as usize, PAGE_SIZE, PAGE_READONLY).unwrap();
+        }
+        page as *mut u64
+    }
+
+    #[cfg(all(feature = "iced", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[test]
+    fn write_skipped() {
+        use crate::AvOperation;
+        use std::sync::Mutex;
+
+        let _serial = dispatch::tests::serial();
+        let page = read_only_page();
+        let target = page as usize;
+
+        let seen = Arc::new(Mutex::new(None));
+        let guard = {
+            let seen = seen.clone();
+            on_segfault(move |info| match info.address == target {
+                true => {
+                    *seen.lock().unwrap() = Some((info.operation, info.module.is_some()));
+                    SegfaultAction::Skip
+                }
+                // Other tests' access violations
+                false => SegfaultAction::Pass,
+            })
+            .unwrap()
+        };
+        unsafe { std::ptr::write_volatile(page, 2) };
+        let after = unsafe { std::ptr::read_volatile(page) };
+        drop(guard);
+
+        assert_eq!(*seen.lock().unwrap(), Some((AvOperation::Write, true)));
+        assert_eq!(after, 1);
+        assert!(!dispatch::is_installed());
+        unsafe { VirtualFree(page as _, 0, 0x8000) };
+    }
+
+    #[test]
+    fn retried_after_second_guard() {
+        let _serial = dispatch::tests::serial();
+        let page = read_only_page();
+        let target = page as usize;
+
+        let passed = Arc::new(AtomicUsize::new(0));
+        let first = {
+            let passed = passed.clone();
+            on_segfault(move |info| {
+                if info.address == target {
+                    passed.fetch_add(1, Ordering::SeqCst);
+                }
+                SegfaultAction::Pass
+            })
+            .unwrap()
+        };
+        let second = on_segfault(move |info| match info.address == target {
+            true => {
+                unsafe { memory::set_protection(target, PAGE_SIZE, PAGE_READWRITE).unwrap() };
+                SegfaultAction::Retry
+            }
+            false => SegfaultAction::Pass,
+        })
+        .unwrap();
+
+        unsafe { std::ptr::write_volatile(page, 2) };
+        drop(first);
+        assert!(dispatch::is_installed());
+        drop(second);
+
+        assert_eq!(unsafe { std::ptr::read_volatile(page) }, 2);
+        assert_eq!(passed.load(Ordering::SeqCst), 1);
+        assert!(!dispatch::is_installed());
+        unsafe { VirtualFree(page as _, 0, 0x8000) };
+    }
+}
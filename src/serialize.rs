@@ -0,0 +1,668 @@
+// Serde support for snapshots and exception details, behind the `serde` feature.
+//
+// Addresses are written as `0x`-prefixed hex strings, as JSON readers commonly parse numbers into
+// doubles, which can't hold every 64-bit pointer. Contexts are written as a map of the registers
+// `fmt_registers` prints, by their short names, and read back into a zeroed context with only
+// the control and integer registers flagged as present.
+
+// Imports
+use crate::registers::{self, general};
+use crate::snapshot::{ExceptionSnapshot, SnapshotModule, MAX_MODULE_NAME};
+use crate::{symbols, ExceptionCode, CONTEXT, MAX_SNAPSHOT_FRAMES};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[cfg(target_arch = "x86")]
+const CONTEXT_CONTROL_INTEGER: u32 = 0x0001_0003;
+#[cfg(target_arch = "x86_64")]
+const CONTEXT_CONTROL_INTEGER: u32 = 0x0010_0003;
+#[cfg(target_arch = "aarch64")]
+const CONTEXT_CONTROL_INTEGER: u32 = 0x0040_0003;
+
+/// Serializes a `usize` field as a hex string, for `#[serde(with = "crate::serialize::hex")]`.
+pub(crate) mod hex {
+    use super::Hex;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &usize,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Hex(*value as u64).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<usize, D::Error> {
+        let Hex(value) = Hex::deserialize(deserializer)?;
+        usize::try_from(value).map_err(|_| serde::de::Error::custom("address out of range"))
+    }
+}
+
+// A value written as a hex string
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Hex(u64);
+
+impl Serialize for Hex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:#x}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HexVisitor;
+
+        impl Visitor<'_> for HexVisitor {
+            type Value = Hex;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a 0x-prefixed hex string")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Hex, E> {
+                let digits = value
+                    .strip_prefix("0x")
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))?;
+                u64::from_str_radix(digits, 16)
+                    .map(Hex)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_str(HexVisitor)
+    }
+}
+
+fn hex_usize<E: de::Error>(Hex(value): Hex) -> Result<usize, E> {
+    usize::try_from(value).map_err(|_| E::custom("address out of range"))
+}
+
+// A context as a map of register names to hex strings
+struct Registers<'a>(&'a CONTEXT);
+
+impl Serialize for Registers<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let registers = general(self.0);
+        let mut map = serializer.serialize_map(Some(registers.len()))?;
+        for register in registers.iter() {
+            map.serialize_entry(register.name, &Hex(register.value))?;
+        }
+        map.end()
+    }
+}
+
+struct OwnedRegisters(CONTEXT);
+
+impl<'de> Deserialize<'de> for OwnedRegisters {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RegistersVisitor;
+
+        impl<'de> Visitor<'de> for RegistersVisitor {
+            type Value = OwnedRegisters;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a map of register names to hex strings")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<OwnedRegisters, A::Error> {
+                let mut context: CONTEXT = unsafe { std::mem::zeroed() };
+                context.context_flags = CONTEXT_CONTROL_INTEGER;
+                while let Some((name, Hex(value))) = map.next_entry::<String, Hex>()? {
+                    if !registers::set_general(&mut context, &name, value) {
+                        return Err(de::Error::custom(format_args!("unknown register {name}")));
+                    }
+                }
+                Ok(OwnedRegisters(context))
+            }
+        }
+
+        deserializer.deserialize_map(RegistersVisitor)
+    }
+}
+
+// How a snapshot's module is written, with its module+offset string for readers
+#[derive(Serialize, Deserialize)]
+struct ModuleRepr {
+    name: String,
+    base: Hex,
+    offset: Hex,
+    // Only written, as it's derived from the others
+    #[serde(default, skip_deserializing)]
+    location: String,
+}
+
+// How a backtrace frame is written, formatted with `symbols::format_address` for readers
+#[derive(Serialize, Deserialize)]
+struct FrameRepr {
+    address: Hex,
+    #[serde(default, skip_deserializing)]
+    symbol: String,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    code: ExceptionCode,
+    flags: u32,
+    address: Hex,
+    parameters: Vec<Hex>,
+    context: Option<Registers<'a>>,
+    thread_id: u32,
+    stack_base: Hex,
+    stack_limit: Hex,
+    stack_reservation: Hex,
+    timestamp: i64,
+    module: Option<ModuleRepr>,
+    backtrace: Vec<FrameRepr>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotRepr {
+    code: ExceptionCode,
+    flags: u32,
+    address: Hex,
+    #[serde(default)]
+    parameters: Vec<Hex>,
+    #[serde(default)]
+    context: Option<OwnedRegisters>,
+    thread_id: u32,
+    #[serde(default)]
+    stack_base: Hex,
+    #[serde(default)]
+    stack_limit: Hex,
+    #[serde(default)]
+    stack_reservation: Hex,
+    timestamp: i64,
+    #[serde(default)]
+    module: Option<ModuleRepr>,
+    #[serde(default)]
+    backtrace: Vec<FrameRepr>,
+}
+
+impl Serialize for ExceptionSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let module = self.module().map(|module| ModuleRepr {
+            name: module.name(),
+            base: Hex(module.base() as u64),
+            offset: Hex(module.offset() as u64),
+            location: format!("{}+{:#x}", module.name(), module.offset()),
+        });
+        let backtrace = self.backtrace().iter().map(|&address| FrameRepr {
+            address: Hex(address as u64),
+            symbol: symbols::format_address(address).to_string(),
+        });
+
+        SnapshotRef {
+            code: self.code(),
+            flags: self.flags(),
+            address: Hex(self.address() as u64),
+            parameters: self.parameters().iter().map(|&p| Hex(p as u64)).collect(),
+            context: self.context().map(Registers),
+            thread_id: self.thread_id(),
+            stack_base: Hex(self.stack_base as u64),
+            stack_limit: Hex(self.stack_limit as u64),
+            stack_reservation: Hex(self.stack_reservation as u64),
+            timestamp: self.timestamp(),
+            module,
+            backtrace: backtrace.collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExceptionSnapshot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = SnapshotRepr::deserialize(deserializer)?;
+
+        let mut parameters = [0; 15];
+        if repr.parameters.len() > parameters.len() {
+            let expected = "at most 15 parameters";
+            return Err(de::Error::invalid_length(repr.parameters.len(), &expected));
+        }
+        for (slot, &parameter) in parameters.iter_mut().zip(&repr.parameters) {
+            *slot = hex_usize(parameter)?;
+        }
+
+        let mut frames = [0; MAX_SNAPSHOT_FRAMES];
+        if repr.backtrace.len() > frames.len() {
+            let expected = "at most MAX_SNAPSHOT_FRAMES frames";
+            return Err(de::Error::invalid_length(repr.backtrace.len(), &expected));
+        }
+        for (slot, frame) in frames.iter_mut().zip(&repr.backtrace) {
+            *slot = hex_usize(frame.address)?;
+        }
+
+        let module = match repr.module {
+            Some(module) => {
+                let mut name = [0; MAX_MODULE_NAME];
+                let mut name_len = 0;
+                for (slot, c) in name.iter_mut().zip(module.name.encode_utf16()) {
+                    *slot = c;
+                    name_len += 1;
+                }
+                Some(SnapshotModule {
+                    base: hex_usize(module.base)?,
+                    offset: hex_usize(module.offset)?,
+                    name,
+                    name_len,
+                })
+            }
+            None => None,
+        };
+
+        Ok(ExceptionSnapshot {
+            code: repr.code,
+            flags: repr.flags,
+            address: hex_usize(repr.address)?,
+            parameters,
+            parameter_count: repr.parameters.len(),
+            context: repr.context.map(|registers| registers.0),
+            thread_id: repr.thread_id,
+            stack_base: hex_usize(repr.stack_base)?,
+            stack_limit: hex_usize(repr.stack_limit)?,
+            stack_reservation: hex_usize(repr.stack_reservation)?,
+            timestamp: repr.timestamp,
+            module,
+            frames,
+            frame_count: repr.backtrace.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{dispatch, AccessViolationInfo, AvOperation, ContextExt, ExceptionCode};
+    use crate::{ExceptionSnapshot, Filter, Handling};
+    use serde::de::value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer};
+    use serde::de::{self, IntoDeserializer, Visitor};
+    use serde::ser::{self, Impossible};
+    use serde::{forward_to_deserialize_any, Deserialize, Serialize};
+    use std::fmt;
+    use std::sync::Mutex;
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_5301;
+
+    // Just enough of a JSON-like data model to round-trip through, without a format crate
+    #[derive(Debug, Clone, PartialEq)]
+    enum Value {
+        Null,
+        Bool(bool),
+        Unsigned(u64),
+        Signed(i64),
+        Str(String),
+        Seq(Vec<Value>),
+        Map(Vec<(String, Value)>),
+    }
+
+    impl Value {
+        fn get(&self, key: &str) -> Option<&Value> {
+            match self {
+                Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    impl ser::Error for Error {
+        fn custom<T: fmt::Display>(message: T) -> Self {
+            Error(message.to_string())
+        }
+    }
+
+    impl de::Error for Error {
+        fn custom<T: fmt::Display>(message: T) -> Self {
+            Error(message.to_string())
+        }
+    }
+
+    fn to_value(value: &impl Serialize) -> Value {
+        value.serialize(ValueSerializer).unwrap()
+    }
+
+    struct ValueSerializer;
+
+    struct SeqBuilder(Vec<Value>);
+
+    struct MapBuilder {
+        entries: Vec<(String, Value)>,
+        key: Option<String>,
+    }
+
+    impl ser::Serializer for ValueSerializer {
+        type Ok = Value;
+        type Error = Error;
+        type SerializeSeq = SeqBuilder;
+        type SerializeTuple = SeqBuilder;
+        type SerializeTupleStruct = Impossible<Value, Error>;
+        type SerializeTupleVariant = Impossible<Value, Error>;
+        type SerializeMap = MapBuilder;
+        type SerializeStruct = MapBuilder;
+        type SerializeStructVariant = Impossible<Value, Error>;
+
+        fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+            Ok(Value::Bool(v))
+        }
+        fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+            Ok(Value::Signed(v.into()))
+        }
+        fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+            Ok(Value::Signed(v.into()))
+        }
+        fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+            Ok(Value::Signed(v.into()))
+        }
+        fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+            Ok(Value::Signed(v))
+        }
+        fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+            Ok(Value::Unsigned(v.into()))
+        }
+        fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+            Ok(Value::Unsigned(v.into()))
+        }
+        fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+            Ok(Value::Unsigned(v.into()))
+        }
+        fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+            Ok(Value::Unsigned(v))
+        }
+        fn serialize_f32(self, _: f32) -> Result<Value, Error> {
+            Err(Error("floats aren't supported".into()))
+        }
+        fn serialize_f64(self, _: f64) -> Result<Value, Error> {
+            Err(Error("floats aren't supported".into()))
+        }
+        fn serialize_char(self, v: char) -> Result<Value, Error> {
+            Ok(Value::Str(v.to_string()))
+        }
+        fn serialize_str(self, v: &str) -> Result<Value, Error> {
+            Ok(Value::Str(v.to_owned()))
+        }
+        fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+            Ok(Value::Seq(
+                v.iter().map(|&b| Value::Unsigned(b.into())).collect(),
+            ))
+        }
+        fn serialize_none(self) -> Result<Value, Error> {
+            Ok(Value::Null)
+        }
+        fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+            value.serialize(self)
+        }
+        fn serialize_unit(self) -> Result<Value, Error> {
+            Ok(Value::Null)
+        }
+        fn serialize_unit_struct(self, _: &'static str) -> Result<Value, Error> {
+            Ok(Value::Null)
+        }
+        fn serialize_unit_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            variant: &'static str,
+        ) -> Result<Value, Error> {
+            Ok(Value::Str(variant.to_owned()))
+        }
+        fn serialize_newtype_struct<T: Serialize + ?Sized>(
+            self,
+            _: &'static str,
+            value: &T,
+        ) -> Result<Value, Error> {
+            value.serialize(self)
+        }
+        fn serialize_newtype_variant<T: Serialize + ?Sized>(
+            self,
+            _: &'static str,
+            _: u32,
+            variant: &'static str,
+            value: &T,
+        ) -> Result<Value, Error> {
+            Ok(Value::Map(vec![(variant.to_owned(), to_value(&value))]))
+        }
+        fn serialize_seq(self, _: Option<usize>) -> Result<SeqBuilder, Error> {
+            Ok(SeqBuilder(Vec::new()))
+        }
+        fn serialize_tuple(self, _: usize) -> Result<SeqBuilder, Error> {
+            Ok(SeqBuilder(Vec::new()))
+        }
+        fn serialize_tuple_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleStruct, Error> {
+            Err(Error("tuple structs aren't supported".into()))
+        }
+        fn serialize_tuple_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleVariant, Error> {
+            Err(Error("tuple variants aren't supported".into()))
+        }
+        fn serialize_map(self, _: Option<usize>) -> Result<MapBuilder, Error> {
+            Ok(MapBuilder {
+                entries: Vec::new(),
+                key: None,
+            })
+        }
+        fn serialize_struct(self, _: &'static str, _: usize) -> Result<MapBuilder, Error> {
+            self.serialize_map(None)
+        }
+        fn serialize_struct_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeStructVariant, Error> {
+            Err(Error("struct variants aren't supported".into()))
+        }
+    }
+
+    impl ser::SerializeSeq for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+
+        fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+            self.0.push(value.serialize(ValueSerializer)?);
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(Value::Seq(self.0))
+        }
+    }
+
+    impl ser::SerializeTuple for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+
+        fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeMap for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+
+        fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+            match key.serialize(ValueSerializer)? {
+                Value::Str(key) => self.key = Some(key),
+                _ => return Err(Error("keys must be strings".into())),
+            }
+            Ok(())
+        }
+        fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+            let key = self.key.take().unwrap();
+            self.entries.push((key, value.serialize(ValueSerializer)?));
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(Value::Map(self.entries))
+        }
+    }
+
+    impl ser::SerializeStruct for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+
+        fn serialize_field<T: Serialize + ?Sized>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Error> {
+            self.entries
+                .push((key.to_owned(), value.serialize(ValueSerializer)?));
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(Value::Map(self.entries))
+        }
+    }
+
+    impl<'de> IntoDeserializer<'de, Error> for Value {
+        type Deserializer = Value;
+
+        fn into_deserializer(self) -> Value {
+            self
+        }
+    }
+
+    impl<'de> de::Deserializer<'de> for Value {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self {
+                Value::Null => visitor.visit_unit(),
+                Value::Bool(v) => visitor.visit_bool(v),
+                Value::Unsigned(v) => visitor.visit_u64(v),
+                Value::Signed(v) => visitor.visit_i64(v),
+                Value::Str(v) => visitor.visit_string(v),
+                Value::Seq(v) => visitor.visit_seq(SeqDeserializer::new(v.into_iter())),
+                Value::Map(v) => visitor.visit_map(MapDeserializer::new(v.into_iter())),
+            }
+        }
+
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self {
+                Value::Null => visitor.visit_none(),
+                value => visitor.visit_some(value),
+            }
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            _: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error> {
+            match self {
+                Value::Str(variant) => visitor.visit_enum(variant.into_deserializer()),
+                Value::Map(entries) => {
+                    let map = MapDeserializer::new(entries.into_iter());
+                    visitor.visit_enum(MapAccessDeserializer::new(map))
+                }
+                _ => Err(de::Error::custom("expected an enum")),
+            }
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+            unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+            ignored_any
+        }
+    }
+
+    #[test]
+    fn populated_round_trip() {
+        static SNAPSHOT: Mutex<Option<ExceptionSnapshot>> = Mutex::new(None);
+        let _serial = dispatch::tests::serial();
+
+        let filter = Filter::code(ExceptionCode::Other(CODE)).and(Filter::current_thread());
+        let callback = dispatch::register(filter, |info| {
+            *SNAPSHOT.lock().unwrap() = Some(info.snapshot());
+            Handling::ContinueExecution
+        })
+        .unwrap();
+        let parameters = [usize::MAX, 0x1234];
+        unsafe { RaiseException(CODE, 0, 2, parameters.as_ptr()) };
+        drop(callback);
+
+        let snapshot = SNAPSHOT.lock().unwrap().take().unwrap();
+        assert!(!snapshot.backtrace().is_empty());
+        let value = to_value(&snapshot);
+        let expected = Value::Seq(vec![
+            Value::Str(format!("{:#x}", usize::MAX)),
+            Value::Str("0x1234".to_owned()),
+        ]);
+        assert_eq!(value.get("parameters"), Some(&expected));
+        let context = value.get("context").unwrap();
+        assert!(matches!(context, Value::Map(registers) if !registers.is_empty()));
+        assert!(matches!(value.get("backtrace"), Some(Value::Seq(frames)) if !frames.is_empty()));
+
+        let back = ExceptionSnapshot::deserialize(value.clone()).unwrap();
+        assert_eq!(to_value(&back), value);
+        assert_eq!(back.code().raw(), CODE);
+        assert_eq!(back.parameters(), parameters);
+        assert_eq!(back.backtrace(), snapshot.backtrace());
+        let (ours, theirs) = (back.context().unwrap(), snapshot.context().unwrap());
+        assert_eq!((ours.ip(), ours.sp()), (theirs.ip(), theirs.sp()));
+        assert_eq!(
+            back.module().unwrap().name(),
+            snapshot.module().unwrap().name()
+        );
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    fn minimal_round_trip() {
+        let entry = |key: &str, value| (key.to_owned(), value);
+        let minimal = Value::Map(vec![
+            entry("code", Value::Str("AccessViolation".to_owned())),
+            entry("flags", Value::Unsigned(0)),
+            entry("address", Value::Str("0x10".to_owned())),
+            entry("thread_id", Value::Unsigned(1)),
+            entry("timestamp", Value::Signed(0)),
+        ]);
+
+        let snapshot = ExceptionSnapshot::deserialize(minimal).unwrap();
+        assert_eq!(snapshot.code(), ExceptionCode::AccessViolation);
+        assert_eq!(snapshot.address(), 0x10);
+        assert!(snapshot.parameters().is_empty());
+        assert!(snapshot.context().is_none());
+        assert!(snapshot.module().is_none());
+        assert!(snapshot.backtrace().is_empty());
+
+        let value = to_value(&snapshot);
+        let back = ExceptionSnapshot::deserialize(value.clone()).unwrap();
+        assert_eq!(to_value(&back), value);
+        assert_eq!(value.get("context"), Some(&Value::Null));
+
+        let info = AccessViolationInfo {
+            operation: AvOperation::Write,
+            address: 0xDEAD,
+        };
+        let value = to_value(&info);
+        assert_eq!(value.get("address"), Some(&Value::Str("0xdead".to_owned())));
+        assert_eq!(AccessViolationInfo::deserialize(value).unwrap(), info);
+    }
+}
@@ -0,0 +1,205 @@
+// Imports
+use crate::exception::EXCEPTION_CONTINUE_SEARCH;
+use crate::filter::Filter;
+use crate::sync::InFlight;
+use crate::{
+    continuable, panics, reentry, teb, ExceptionInfo, Handling, VectoredHandler, VehError,
+};
+use std::ffi::c_void;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+// Vectored handlers don't get a user data pointer, so every registration needing state of its
+// own (closures, filters, ...) is given one of a fixed pool of trampolines, each of which knows
+// which slot to look its state up in.
+const SLOT_COUNT: usize = 64;
+
+pub(crate) type Closure = Box<dyn Fn(&mut ExceptionInfo) -> Handling + Send + Sync>;
+
+pub(crate) enum Callback {
+    Raw(VectoredHandler),
+    Closure(Closure),
+}
+
+pub(crate) struct Entry {
+    pub(crate) callback: Callback,
+    pub(crate) filter: Filter,
+    pub(crate) toggleable: bool,
+    pub(crate) enabled: AtomicBool,
+}
+
+impl Entry {
+    pub(crate) fn new(callback: Callback, filter: Filter, toggleable: bool) -> Self {
+        Entry {
+            callback,
+            filter,
+            toggleable,
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    unsafe fn invoke(&self, ptrs: *mut c_void) -> i32 {
+        let info = ExceptionInfo::from_raw(ptrs);
+        let _depth = match reentry::enter(info) {
+            Some(depth) => depth,
+            None => return EXCEPTION_CONTINUE_SEARCH,
+        };
+        let _errors = teb::SavedErrors::save();
+
+        if !self.enabled.load(Ordering::Relaxed) || !self.filter.matches(info) {
+            return EXCEPTION_CONTINUE_SEARCH;
+        }
+
+        match &self.callback {
+            Callback::Raw(handler) => handler(ptrs),
+            Callback::Closure(closure) => {
+                let handling = panics::guarded(closure, info);
+                continuable::enforce(info, handling).raw()
+            }
+        }
+    }
+}
+
+struct Slot {
+    // Owned by a `SlotGuard` from claim until its entry has been freed
+    claimed: AtomicBool,
+    entry: AtomicPtr<Entry>,
+    // Trampoline calls currently looking at `entry`
+    in_flight: InFlight,
+}
+
+static SLOTS: [Slot; SLOT_COUNT] = [const {
+    Slot {
+        claimed: AtomicBool::new(false),
+        entry: AtomicPtr::new(null_mut()),
+        in_flight: InFlight::new(),
+    }
+}; SLOT_COUNT];
+
+unsafe extern "system" fn trampoline<const INDEX: usize>(ptrs: *mut c_void) -> i32 {
+    let slot = &SLOTS[INDEX];
+
+    // Enter before loading the entry, so a concurrent drop either sees us or we see null
+    let _in_flight = slot.in_flight.enter();
+    match slot.entry.load(Ordering::SeqCst).as_ref() {
+        Some(entry) => entry.invoke(ptrs),
+        None => EXCEPTION_CONTINUE_SEARCH,
+    }
+}
+
+macro_rules! trampolines {
+    ($($index:literal)*) => {
+        static TRAMPOLINES: [VectoredHandler; SLOT_COUNT] = [$(trampoline::<$index>,)*];
+    };
+}
+
+trampolines! {
+     0  1  2  3  4  5  6  7  8  9 10 11 12 13 14 15
+    16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+    48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+}
+
+/// Ownership of a claimed slot, releasing it (and freeing its entry) on drop.
+///
+/// Dropping waits for trampoline calls still running the entry's callback on other threads, so
+/// the callback's storage is never freed out from under them. The handler should be unregistered
+/// from ntdll first, so no new calls can come in while waiting.
+pub(crate) struct SlotGuard {
+    index: usize,
+    entry: *mut Entry,
+}
+
+impl SlotGuard {
+    pub(crate) fn claim(entry: Entry) -> Result<Self, VehError> {
+        let index = SLOTS
+            .iter()
+            .position(|slot| {
+                slot.claimed
+                    .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .ok_or(VehError::SlotsExhausted)?;
+
+        let entry = Box::into_raw(Box::new(entry));
+        SLOTS[index].entry.store(entry, Ordering::SeqCst);
+        Ok(SlotGuard { index, entry })
+    }
+
+    /// The trampoline to register with ntdll for this slot.
+    pub(crate) fn handler(&self) -> VectoredHandler {
+        TRAMPOLINES[self.index]
+    }
+
+    pub(crate) fn entry(&self) -> &Entry {
+        unsafe { &*self.entry }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let slot = &SLOTS[self.index];
+
+        // Unpublish the entry, then wait for everyone that might still be using it
+        slot.entry.store(null_mut(), Ordering::SeqCst);
+        slot.in_flight.wait_idle();
+
+        drop(unsafe { Box::from_raw(self.entry) });
+        slot.claimed.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ExceptionCode, ExceptionInfo, Handling, Order, Veh, VehBuilder};
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    const CODE: u32 = 0xE056_4501;
+
+    unsafe extern "system" fn backstop(ptrs: *mut c_void) -> i32 {
+        match ExceptionInfo::from_raw(ptrs).code().raw() {
+            CODE => Handling::ContinueExecution.raw(),
+            _ => Handling::ContinueSearch.raw(),
+        }
+    }
+
+    #[test]
+    fn drop_waits_for_in_flight_callbacks() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        static CORRUPTED: AtomicUsize = AtomicUsize::new(0);
+
+        let _backstop = unsafe { Veh::<c_void>::add(Order::Last, backstop) };
+
+        let raiser = std::thread::spawn(|| {
+            while !STOP.load(Ordering::SeqCst) {
+                unsafe { RaiseException(CODE, 0, 0, std::ptr::null()) };
+            }
+        });
+
+        for _ in 0..1000 {
+            // Reading freed storage would (very likely) not sum up to the expected value
+            let data = Box::new([1u8; 64]);
+            let veh = unsafe {
+                VehBuilder::new()
+                    .filter_code(ExceptionCode::from_raw(CODE))
+                    .install_closure(move |_| {
+                        if data.iter().map(|&b| b as usize).sum::<usize>() != 64 {
+                            CORRUPTED.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Handling::ContinueExecution
+                    })
+                    .unwrap()
+            };
+
+            std::thread::yield_now();
+            drop(veh);
+        }
+
+        STOP.store(true, Ordering::SeqCst);
+        raiser.join().unwrap();
+
+        assert_eq!(CORRUPTED.load(Ordering::SeqCst), 0);
+    }
+}
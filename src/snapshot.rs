@@ -0,0 +1,345 @@
+// Imports
+use crate::{backtrace, modules, teb, ContextExt, ExceptionCode, ExceptionInfo, CONTEXT};
+use once_cell::race::OnceBox;
+use std::ffi::{c_void, OsString};
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::os::windows::ffi::OsStringExt;
+use std::ptr::addr_of_mut;
+
+type FnGetThreadDescription =
+    unsafe extern "system" fn(thread: *mut c_void, description: *mut *mut u16) -> i32;
+
+// Longer module names are cut off, which only happens for names nobody would give a DLL
+pub(crate) const MAX_MODULE_NAME: usize = 64;
+
+/// The most frames [`ExceptionSnapshot::backtrace`] holds.
+pub const MAX_SNAPSHOT_FRAMES: usize = 32;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn QueryPerformanceCounter(count: *mut i64) -> i32;
+    fn OpenThread(access: u32, inherit: i32, thread_id: u32) -> *mut c_void;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+    fn LocalFree(memory: *mut c_void) -> *mut c_void;
+}
+
+const THREAD_QUERY_LIMITED_INFORMATION: u32 = 0x0800;
+
+// Only exported from Windows 10 1607 on
+static GET_THREAD_DESCRIPTION: OnceBox<Option<FnGetThreadDescription>> = OnceBox::new();
+
+/// An owned copy of an exception, taken inside a handler to be looked at once it has returned.
+///
+/// Taking one never allocates, and can't fail, so it's safe to do from any handler.
+#[derive(Clone)]
+pub struct ExceptionSnapshot {
+    pub(crate) code: ExceptionCode,
+    pub(crate) flags: u32,
+    pub(crate) address: usize,
+    pub(crate) parameters: [usize; 15],
+    pub(crate) parameter_count: usize,
+    pub(crate) context: Option<CONTEXT>,
+    pub(crate) thread_id: u32,
+    pub(crate) stack_base: usize,
+    pub(crate) stack_limit: usize,
+    pub(crate) stack_reservation: usize,
+    pub(crate) timestamp: i64,
+    pub(crate) module: Option<SnapshotModule>,
+    pub(crate) frames: [usize; MAX_SNAPSHOT_FRAMES],
+    pub(crate) frame_count: usize,
+}
+
+/// The module an [`ExceptionSnapshot`] was raised in.
+#[derive(Clone, Copy)]
+pub struct SnapshotModule {
+    pub(crate) base: usize,
+    pub(crate) offset: usize,
+    pub(crate) name: [u16; MAX_MODULE_NAME],
+    pub(crate) name_len: usize,
+}
+
+impl ExceptionSnapshot {
+    pub fn code(&self) -> ExceptionCode {
+        self.code
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// The address the exception was raised at.
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// The exception's parameters, see [`ExceptionInfo::parameters`].
+    pub fn parameters(&self) -> &[usize] {
+        &self.parameters[..self.parameter_count]
+    }
+
+    /// The register state of the thread when the exception was raised, if the handler was given
+    /// one.
+    pub fn context(&self) -> Option<&CONTEXT> {
+        self.context.as_ref()
+    }
+
+    /// The ID of the thread that raised the exception.
+    pub fn thread_id(&self) -> u32 {
+        self.thread_id
+    }
+
+    /// The name the thread that raised the exception was given with `SetThreadDescription`, such
+    /// as by [`std::thread::Builder::name`], if it has one.
+    ///
+    /// The name is looked up when this is called rather than when the snapshot is taken, which
+    /// can't be done safely inside a handler. It's `None` once the thread has exited, or if
+    /// Windows is too old to name threads, and might belong to another thread that's since been
+    /// given the same ID.
+    pub fn thread_name(&self) -> Option<String> {
+        thread_name(self.thread_id)
+    }
+
+    /// The top of the thread's stack, from `NT_TIB.StackBase`. Zero if the snapshot was
+    /// deserialized from one without it.
+    pub fn stack_base(&self) -> usize {
+        self.stack_base
+    }
+
+    /// The lowest committed address of the thread's stack, from `NT_TIB.StackLimit`.
+    pub fn stack_limit(&self) -> usize {
+        self.stack_limit
+    }
+
+    /// How far the stack pointer was above the bottom of the stack's reservation, which is how
+    /// much further it could have grown before overflowing. `None` without a context.
+    pub fn stack_bytes_remaining(&self) -> Option<usize> {
+        let sp = self.context.as_ref()?.sp();
+        Some(sp.saturating_sub(self.stack_reservation))
+    }
+
+    /// When the snapshot was taken, in `QueryPerformanceCounter` ticks.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// The loaded module the exception was raised in, if any.
+    pub fn module(&self) -> Option<&SnapshotModule> {
+        self.module.as_ref()
+    }
+
+    /// The call stack when the exception was raised, starting with the faulting instruction and
+    /// followed by return addresses, see [`backtrace::capture`]. Empty without a context.
+    ///
+    /// [`backtrace::capture`]: crate::backtrace::capture
+    pub fn backtrace(&self) -> &[usize] {
+        &self.frames[..self.frame_count]
+    }
+}
+
+impl SnapshotModule {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// How far into the module the exception was raised.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The module's base name, e.g. `ntdll.dll`.
+    pub fn name(&self) -> String {
+        OsString::from_wide(&self.name[..self.name_len])
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // The module `address` is in, copied without allocating
+    pub(crate) fn containing(address: usize) -> Option<SnapshotModule> {
+        modules::containing(address).map(|module| {
+            let wide = module.name_wide();
+            let name_len = wide.len().min(MAX_MODULE_NAME);
+            let mut name = [0; MAX_MODULE_NAME];
+            name[..name_len].copy_from_slice(&wide[..name_len]);
+
+            SnapshotModule {
+                base: module.base(),
+                offset: address - module.base(),
+                name,
+                name_len,
+            }
+        })
+    }
+}
+
+impl std::fmt::Debug for ExceptionSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExceptionSnapshot")
+            .field("code", &self.code)
+            .field("flags", &self.flags)
+            .field("address", &self.address)
+            .field("parameters", &self.parameters())
+            .field("thread_id", &self.thread_id)
+            .field("stack", &(self.stack_limit..self.stack_base))
+            .field("timestamp", &self.timestamp)
+            .field("module", &self.module)
+            .field("backtrace", &self.backtrace())
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for SnapshotModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnapshotModule")
+            .field("base", &self.base)
+            .field("offset", &self.offset)
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+impl ExceptionInfo {
+    /// Copies everything about the exception into an owned [`ExceptionSnapshot`].
+    pub fn snapshot(&self) -> ExceptionSnapshot {
+        let mut snapshot = MaybeUninit::uninit();
+        self.snapshot_into(&mut snapshot);
+        unsafe { snapshot.assume_init() }
+    }
+
+    /// Like [`ExceptionInfo::snapshot`], but writes the snapshot straight into `out`, so the
+    /// context is copied once and no room for it is needed on the handler's stack.
+    pub fn snapshot_into<'a>(
+        &self,
+        out: &'a mut MaybeUninit<ExceptionSnapshot>,
+    ) -> &'a mut ExceptionSnapshot {
+        self.snapshot_into_with(out, true)
+    }
+
+    // Leaving out the backtrace saves walking the stack, which is most of a snapshot's cost
+    pub(crate) fn snapshot_into_with<'a>(
+        &self,
+        out: &'a mut MaybeUninit<ExceptionSnapshot>,
+        with_backtrace: bool,
+    ) -> &'a mut ExceptionSnapshot {
+        let snapshot = out.as_mut_ptr();
+        let parameters = self.parameters();
+
+        // Every field is written exactly once before the value is handed out
+        unsafe {
+            addr_of_mut!((*snapshot).code).write(self.code());
+            addr_of_mut!((*snapshot).flags).write(self.flags());
+            addr_of_mut!((*snapshot).address).write(self.address());
+
+            let mut copied = [0; 15];
+            copied[..parameters.len()].copy_from_slice(parameters);
+            addr_of_mut!((*snapshot).parameters).write(copied);
+            addr_of_mut!((*snapshot).parameter_count).write(parameters.len());
+
+            let context = self.context_ptr() as *const CONTEXT;
+            let target = addr_of_mut!((*snapshot).context);
+            match context.as_ref() {
+                Some(context) => target.write(Some(*context)),
+                None => target.write(None),
+            }
+
+            let frames = addr_of_mut!((*snapshot).frames);
+            frames.write([0; MAX_SNAPSHOT_FRAMES]);
+            let frame_count = match context.as_ref() {
+                Some(context) if with_backtrace => backtrace::capture(context, &mut *frames),
+                _ => 0,
+            };
+            addr_of_mut!((*snapshot).frame_count).write(frame_count);
+
+            addr_of_mut!((*snapshot).thread_id).write(teb::current_thread_id());
+            let Range { start, end } = teb::stack_bounds();
+            addr_of_mut!((*snapshot).stack_base).write(end);
+            addr_of_mut!((*snapshot).stack_limit).write(start);
+            addr_of_mut!((*snapshot).stack_reservation).write(teb::deallocation_stack());
+
+            let mut timestamp = 0;
+            QueryPerformanceCounter(&mut timestamp);
+            addr_of_mut!((*snapshot).timestamp).write(timestamp);
+
+            let module = SnapshotModule::containing(self.address());
+            addr_of_mut!((*snapshot).module).write(module);
+
+            out.assume_init_mut()
+        }
+    }
+}
+
+fn thread_name(thread_id: u32) -> Option<String> {
+    let get_description = GET_THREAD_DESCRIPTION
+        .get_or_init(|| Box::new(find_get_thread_description()))
+        .as_ref()?;
+
+    unsafe {
+        let thread = OpenThread(THREAD_QUERY_LIMITED_INFORMATION, 0, thread_id);
+        if thread.is_null() {
+            return None;
+        }
+        let mut description = std::ptr::null_mut();
+        let result = get_description(thread, &mut description);
+        CloseHandle(thread);
+        if result < 0 || description.is_null() {
+            return None;
+        }
+
+        let len = (0..).take_while(|&i| *description.add(i) != 0).count();
+        let name = OsString::from_wide(std::slice::from_raw_parts(description, len));
+        LocalFree(description as *mut c_void);
+        (len > 0).then(|| name.to_string_lossy().into_owned())
+    }
+}
+
+fn find_get_thread_description() -> Option<FnGetThreadDescription> {
+    let address = modules::kernel_export("GetThreadDescription")?;
+    Some(unsafe { std::mem::transmute::<usize, FnGetThreadDescription>(address) })
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    use crate::dispatch::{self, Registration};
+    use crate::{testing, ExceptionCode, ExceptionSnapshot, Filter, Handling};
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn thread_identity_captured() {
+        static SNAPSHOT: Mutex<Option<ExceptionSnapshot>> = Mutex::new(None);
+        let _serial = dispatch::tests::serial();
+
+        let (snapshot, name, sp) = thread::Builder::new()
+            .name("snapshot-identity".into())
+            .spawn(|| {
+                let filter =
+                    Filter::code(ExceptionCode::AccessViolation).and(Filter::current_thread());
+                let callback = Registration::new()
+                    .filter(filter)
+                    .observe()
+                    .register_closure(|info| {
+                        *SNAPSHOT.lock().unwrap() = Some(info.snapshot());
+                        Handling::ContinueSearch
+                    })
+                    .unwrap();
+                testing::exceptions_in(|| testing::fault::read_av(None));
+                drop(callback);
+
+                // Looked up while the thread is still around
+                let snapshot = SNAPSHOT.lock().unwrap().take().unwrap();
+                let local = 0u8;
+                let sp = &local as *const u8 as usize;
+                (snapshot.clone(), snapshot.thread_name(), sp)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(name.as_deref(), Some("snapshot-identity"));
+        assert!((snapshot.stack_limit()..snapshot.stack_base()).contains(&sp));
+        let remaining = snapshot.stack_bytes_remaining().unwrap();
+        let reserved = snapshot.stack_base() - snapshot.stack_reservation;
+        assert!(remaining > 0 && remaining < reserved);
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,241 @@
+//! Surviving stack overflows.
+//!
+//! A stack overflow is raised once the stack has grown into its guard page, and the handlers run
+//! on whatever little stack is left below it. The guard page isn't put back either, so a second
+//! overflow on the same thread finds no guard page and kills the process outright.
+//!
+//! [`Protection::install`] sets aside a reserve with `SetThreadStackGuarantee`, which the OS
+//! keeps free below the guard page for the handlers to run on. [`on_overflow`] then calls a
+//! callback for overflows on protected threads, and when the callback resumes execution
+//! somewhere higher up the stack, puts the guard page back below it, which is what the CRT's
+//! `_resetstkoflw` does. Overflows on threads without a reserve are passed on untouched, as there
+//! wouldn't be enough stack left to run the callback on.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::memory::{self, PAGE_GUARD, PAGE_READWRITE, PAGE_SIZE};
+use crate::{modules, teb, ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling, VehError};
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::thread::{self, JoinHandle};
+
+type FnSetThreadStackGuarantee = unsafe extern "system" fn(size: *mut u32) -> i32;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetLastError() -> u32;
+}
+
+thread_local! {
+    // The reserve set aside on this thread by the innermost `Protection`, or 0
+    static RESERVE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Errors returned by [`Protection::install`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StackError {
+    /// `SetThreadStackGuarantee` isn't exported by kernel32.
+    Unsupported,
+    /// The reserve doesn't fit in a `u32`.
+    TooLarge(usize),
+    /// `SetThreadStackGuarantee` failed with this error code, such as when the reserve doesn't
+    /// fit in the thread's stack.
+    Guarantee(u32),
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::Unsupported => f.write_str("SetThreadStackGuarantee is not available"),
+            StackError::TooLarge(size) => write!(f, "a {size} byte stack reserve is too large"),
+            StackError::Guarantee(code) => {
+                write!(f, "SetThreadStackGuarantee failed with error {code}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
+/// A stack reserve for handling overflows on the current thread, see [`Protection::install`].
+///
+/// Dropping it only stops [`on_overflow`]'s callback from running on this thread, as a thread's
+/// stack guarantee can't be lowered again.
+pub struct Protection {
+    reserve: usize,
+    outer: usize,
+    // Tied to the thread it was installed on
+    _thread: PhantomData<*const ()>,
+}
+
+impl Protection {
+    /// Guarantees at least `reserve_bytes` of stack to the handlers of a stack overflow on the
+    /// current thread, and has [`on_overflow`]'s callback run for them.
+    ///
+    /// The callback itself, the dispatcher and the OS's exception dispatch all run within the
+    /// reserve, so it should be generous, at least some tens of kilobytes.
+    pub fn install(reserve_bytes: usize) -> Result<Protection, StackError> {
+        let guarantee = find_set_thread_stack_guarantee().ok_or(StackError::Unsupported)?;
+        let mut size =
+            u32::try_from(reserve_bytes).map_err(|_| StackError::TooLarge(reserve_bytes))?;
+        // Returns the previous guarantee in `size`, and leaves a larger one as it was
+        if unsafe { guarantee(&mut size) } == 0 {
+            return Err(StackError::Guarantee(unsafe { GetLastError() }));
+        }
+
+        let reserve = reserve_bytes.max(size as usize);
+        Ok(Protection {
+            reserve,
+            outer: RESERVE.with(|current| current.replace(reserve)),
+            _thread: PhantomData,
+        })
+    }
+
+    /// Spawns a thread with `builder` that runs `f` with a [`Protection`] of `reserve_bytes`
+    /// installed, returning the installation's error from the thread instead if it failed.
+    pub fn spawn<F, T>(
+        builder: thread::Builder,
+        reserve_bytes: usize,
+        f: F,
+    ) -> io::Result<JoinHandle<Result<T, StackError>>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        builder.spawn(move || {
+            let _protection = Protection::install(reserve_bytes)?;
+            Ok(f())
+        })
+    }
+
+    /// The stack the OS guarantees to the handlers, which may be more than was asked for.
+    pub fn reserve(&self) -> usize {
+        self.reserve
+    }
+}
+
+impl Drop for Protection {
+    fn drop(&mut self) {
+        RESERVE.with(|current| current.set(self.outer));
+    }
+}
+
+fn find_set_thread_stack_guarantee() -> Option<FnSetThreadStackGuarantee> {
+    let address = modules::kernel_export("SetThreadStackGuarantee")?;
+    Some(unsafe { std::mem::transmute::<usize, FnSetThreadStackGuarantee>(address) })
+}
+
+/// Keeps [`on_overflow`]'s callback registered until dropped.
+pub struct OverflowGuard {
+    _callback: CallbackGuard,
+}
+
+/// Calls `callback` for stack overflows on threads with a [`Protection`] installed, before any
+/// other dispatcher callback.
+///
+/// The callback runs on the reserve, so it must not allocate much, or anything at all if the
+/// overflow may have happened inside the allocator. To recover, it changes the context to resume
+/// somewhere higher up the stack and returns [`Handling::ContinueExecution`]. The guard page is
+/// then put back below the new stack pointer, so the thread can overflow, and be caught, again.
+/// If there isn't room for it there, the thread resumes without one, and another overflow ends
+/// the process.
+pub fn on_overflow<F>(callback: F) -> Result<OverflowGuard, VehError>
+where
+    F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+{
+    let callback = Registration::new()
+        .priority(i32::MIN)
+        .filter(Filter::code(ExceptionCode::StackOverflow))
+        .register_closure(move |info| {
+            let reserve = RESERVE.with(Cell::get);
+            if reserve == 0 {
+                return Handling::ContinueSearch;
+            }
+
+            let handling = callback(info);
+            if handling == Handling::ContinueExecution {
+                rearm(info.context().sp(), reserve);
+            }
+            handling
+        })?;
+    Ok(OverflowGuard {
+        _callback: callback,
+    })
+}
+
+// Puts the guard page, and the reserve the guarantee keeps with it, back right below `sp`,
+// returning whether there was room for them
+fn rearm(sp: usize, reserve: usize) -> bool {
+    let region = PAGE_SIZE + reserve.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let guard = match (sp & !(PAGE_SIZE - 1)).checked_sub(region) {
+        Some(guard) => guard,
+        None => return false,
+    };
+
+    // The lowest page of the reservation is never committed
+    if guard < teb::deallocation_stack() + PAGE_SIZE {
+        return false;
+    }
+    // The pages were committed when the stack grew past them
+    if !(guard..guard + region)
+        .step_by(PAGE_SIZE)
+        .all(|page| memory::protection(page).is_some())
+    {
+        return false;
+    }
+
+    let protect = PAGE_READWRITE | PAGE_GUARD;
+    if unsafe { memory::set_protection(guard, region, protect) }.is_err() {
+        return false;
+    }
+    // As if the stack had only ever grown down to the guard page
+    unsafe { teb::set_stack_limit(guard + region) };
+    true
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    use super::{on_overflow, Protection};
+    use crate::{catch, dispatch, try_execute, ExceptionCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn recurse(depth: usize) -> usize {
+        let frame = std::hint::black_box([depth as u8; 512]);
+        if depth == usize::MAX {
+            return 0;
+        }
+        recurse(depth + 1) + frame[depth % 512] as usize
+    }
+
+    #[test]
+    fn overflow_twice() {
+        static OVERFLOWS: AtomicUsize = AtomicUsize::new(0);
+
+        let _serial = dispatch::tests::serial();
+        let handler = on_overflow(|info| {
+            OVERFLOWS.fetch_add(1, Ordering::SeqCst);
+            catch::caught(info)
+        })
+        .unwrap();
+
+        let builder = thread::Builder::new().stack_size(512 * 1024);
+        let overflowed = Protection::spawn(builder, 64 * 1024, || {
+            (0..2)
+                .map(|_| try_execute(|| recurse(0)).unwrap_err().code())
+                .collect::<Vec<_>>()
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(overflowed, [ExceptionCode::StackOverflow; 2]);
+        assert_eq!(OVERFLOWS.load(Ordering::SeqCst), 2);
+        drop(handler);
+        assert!(!dispatch::is_installed());
+    }
+}
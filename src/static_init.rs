@@ -0,0 +1,115 @@
+//! Registering a handler before `main`, and before any other static initializer that might
+//! fault.
+//!
+//! [`install_static!`](crate::install_static) places a pointer to a registration function in the
+//! `.CRT$XCU` section, which the C runtime calls through along with every other C++-style static
+//! initializer, before `main` or, in a DLL, during `DLL_PROCESS_ATTACH`. The entry is written by
+//! hand rather than with the `ctor` crate. Resolving ntdll's functions only walks the loader's
+//! module list and ntdll's exports, so it works that early, and under the loader lock.
+//!
+//! ```ignore
+//! manual_veh::install_static!(static CRASH_HANDLER = Order::First, crash_handler);
+//!
+//! fn main() {
+//!     assert!(CRASH_HANDLER.is_installed());
+//! }
+//! ```
+
+// Imports
+use crate::raw::{self, EXCEPTION_HANDLER_LIST};
+use crate::{Order, VectoredHandler};
+use std::ffi::c_void;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// The registration made by [`install_static!`](crate::install_static).
+pub struct StaticVeh {
+    handle: AtomicPtr<c_void>,
+}
+
+impl StaticVeh {
+    #[doc(hidden)]
+    pub const fn new() -> Self {
+        StaticVeh {
+            handle: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    #[doc(hidden)]
+    pub unsafe fn install(&self, order: Order, handler: VectoredHandler) {
+        let first = order == Order::First;
+        // Nothing can report a failure this early, so it just leaves the handler uninstalled
+        if let Ok(handle) = raw::try_add_handler(EXCEPTION_HANDLER_LIST, first, handler) {
+            self.handle.store(handle as *mut c_void, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether the handler was registered, and hasn't been removed since.
+    pub fn is_installed(&self) -> bool {
+        !self.handle.load(Ordering::SeqCst).is_null()
+    }
+
+    /// Unregisters the handler, returning whether it was still registered.
+    pub fn remove(&self) -> bool {
+        let handle = self.handle.swap(null_mut(), Ordering::SeqCst);
+        !handle.is_null() && unsafe { raw::remove_handler(EXCEPTION_HANDLER_LIST, handle) } != 0
+    }
+}
+
+impl Default for StaticVeh {
+    fn default() -> Self {
+        StaticVeh::new()
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use std::ffi::c_int;
+
+    extern "C" {
+        // The C runtime's, run as the process exits normally
+        pub fn atexit(function: extern "C" fn()) -> c_int;
+    }
+}
+
+/// Registers a vectored exception handler from a static initializer, before `main` runs.
+///
+/// `install_static!(Order::First, handler)` registers `handler`, an
+/// `unsafe extern "system" fn(*mut c_void) -> i32`, for the rest of the process.
+/// `install_static!(static NAME = Order::First, handler)` also declares a
+/// [`StaticVeh`](crate::static_init::StaticVeh) called `NAME` to check or remove it with. Adding
+/// `, teardown` at the end removes the handler again with `atexit`, once the program exits
+/// normally.
+///
+/// # Safety
+/// As [`Veh::add`](crate::Veh::add), the handler runs for every exception from then on, and
+/// also for those raised by the remaining static initializers, so it must not rely on anything
+/// initialized in `main`.
+#[macro_export]
+macro_rules! install_static {
+    (@teardown $name:ident) => {{
+        extern "C" fn teardown() {
+            $name.remove();
+        }
+        $crate::static_init::__private::atexit(teardown);
+    }};
+    ($vis:vis static $name:ident = $order:expr, $handler:expr $(, $teardown:ident)? $(,)?) => {
+        $vis static $name: $crate::static_init::StaticVeh = $crate::static_init::StaticVeh::new();
+
+        const _: () = {
+            unsafe extern "C" fn install() {
+                $name.install($order, $handler);
+                $( $crate::install_static!(@$teardown $name); )?
+            }
+
+            #[used]
+            #[link_section = ".CRT$XCU"]
+            static INSTALL: unsafe extern "C" fn() = install;
+        };
+    };
+    ($order:expr, $handler:expr $(, $teardown:ident)? $(,)?) => {
+        const _: () = {
+            $crate::install_static!(static __MANUAL_VEH_STATIC = $order, $handler $(, $teardown)?);
+        };
+    };
+}
@@ -0,0 +1,161 @@
+//! Counting the exceptions the dispatcher sees, for monitoring.
+//!
+//! Every exception reaching the dispatcher's native handler is counted once, in a coarse bucket
+//! by its code, and as handled if one of the callbacks handled it, or as passed on otherwise.
+//! Counting is a couple of relaxed increments, so it's cheap enough to leave on. Handlers
+//! installed without the dispatcher, such as with [`VehBuilder`](crate::VehBuilder), aren't
+//! counted.
+
+// Imports
+use crate::ExceptionCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The code MSVC raises C++ exceptions with
+const CPP_EXCEPTION: u32 = 0xE06D_7363;
+
+/// The coarse kinds of exception counted separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatsBucket {
+    AccessViolation,
+    GuardPage,
+    Breakpoint,
+    SingleStep,
+    IllegalInstruction,
+    DivideByZero,
+    /// Floating-point exceptions of any kind.
+    Float,
+    StackOverflow,
+    /// Exceptions thrown by MSVC's C++ `throw`.
+    Cpp,
+    Other,
+}
+
+impl StatsBucket {
+    /// Every bucket, in the order of [`StatsSnapshot::buckets`].
+    pub const ALL: [StatsBucket; BUCKET_COUNT] = [
+        StatsBucket::AccessViolation,
+        StatsBucket::GuardPage,
+        StatsBucket::Breakpoint,
+        StatsBucket::SingleStep,
+        StatsBucket::IllegalInstruction,
+        StatsBucket::DivideByZero,
+        StatsBucket::Float,
+        StatsBucket::StackOverflow,
+        StatsBucket::Cpp,
+        StatsBucket::Other,
+    ];
+
+    /// The bucket exceptions with `code` are counted in.
+    pub const fn of(code: ExceptionCode) -> Self {
+        match code.raw() {
+            0xC000_0005 => StatsBucket::AccessViolation,
+            0x8000_0001 => StatsBucket::GuardPage,
+            0x8000_0003 => StatsBucket::Breakpoint,
+            0x8000_0004 => StatsBucket::SingleStep,
+            0xC000_001D | 0xC000_0096 => StatsBucket::IllegalInstruction,
+            0xC000_0094 | 0xC000_0095 => StatsBucket::DivideByZero,
+            0xC000_008D..=0xC000_0093 | 0xC000_02B4 | 0xC000_02B5 => StatsBucket::Float,
+            0xC000_00FD => StatsBucket::StackOverflow,
+            CPP_EXCEPTION => StatsBucket::Cpp,
+            _ => StatsBucket::Other,
+        }
+    }
+}
+
+const BUCKET_COUNT: usize = 10;
+
+static BUCKETS: [AtomicU64; BUCKET_COUNT] = [const { AtomicU64::new(0) }; BUCKET_COUNT];
+static HANDLED: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// Counts an exception the dispatcher has decided on.
+pub(crate) fn record(code: ExceptionCode, handled: bool) {
+    BUCKETS[StatsBucket::of(code) as usize].fetch_add(1, Ordering::Relaxed);
+    [&UNHANDLED, &HANDLED][handled as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The counters at the time of a [`snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatsSnapshot {
+    buckets: [u64; BUCKET_COUNT],
+    handled: u64,
+    unhandled: u64,
+}
+
+impl StatsSnapshot {
+    /// How many exceptions were counted in `bucket`.
+    pub fn count(&self, bucket: StatsBucket) -> u64 {
+        self.buckets[bucket as usize]
+    }
+
+    /// The count of every bucket, in the order of [`StatsBucket::ALL`].
+    pub fn buckets(&self) -> [(StatsBucket, u64); BUCKET_COUNT] {
+        StatsBucket::ALL.map(|bucket| (bucket, self.count(bucket)))
+    }
+
+    /// How many exceptions a dispatcher callback handled.
+    pub fn handled(&self) -> u64 {
+        self.handled
+    }
+
+    /// How many exceptions the dispatcher passed on to the next handler.
+    pub fn unhandled(&self) -> u64 {
+        self.unhandled
+    }
+
+    pub fn total(&self) -> u64 {
+        self.handled + self.unhandled
+    }
+}
+
+/// Reads the counters. They're read one by one, so exceptions counted meanwhile may show up in
+/// some of them and not others.
+pub fn snapshot() -> StatsSnapshot {
+    StatsSnapshot {
+        buckets: BUCKETS
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed)),
+        handled: HANDLED.load(Ordering::Relaxed),
+        unhandled: UNHANDLED.load(Ordering::Relaxed),
+    }
+}
+
+/// Sets every counter back to 0.
+pub fn reset() {
+    for count in &BUCKETS {
+        count.store(0, Ordering::Relaxed);
+    }
+    HANDLED.store(0, Ordering::Relaxed);
+    UNHANDLED.store(0, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reset, snapshot, StatsBucket};
+    use crate::{dispatch, ExceptionCode, Filter, Handling};
+    use winapi::um::errhandlingapi::RaiseException;
+
+    #[test]
+    fn fixed_up_access_violations() {
+        let _serial = dispatch::tests::serial();
+
+        let filter = Filter::code(ExceptionCode::AccessViolation).and(Filter::current_thread());
+        let fixup = dispatch::register(filter, |_| Handling::ContinueExecution).unwrap();
+        reset();
+        let parameters = [0, 0x10];
+        for _ in 0..3 {
+            let code = ExceptionCode::AccessViolation.raw();
+            unsafe { RaiseException(code, 0, 2, parameters.as_ptr()) };
+        }
+        let stats = snapshot();
+        drop(fixup);
+
+        assert_eq!(stats.count(StatsBucket::AccessViolation), 3);
+        assert_eq!(stats.handled(), 3);
+        assert_eq!(
+            StatsBucket::of(ExceptionCode::FloatOverflow),
+            StatsBucket::Float
+        );
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,182 @@
+//! Single-stepping threads from inside a handler.
+//!
+//! [`single_step`] sets the trap flag in a context, so the thread raises `STATUS_SINGLE_STEP`
+//! after executing one more instruction, and stores a continuation for that thread. The crate
+//! consumes the single-step exception and runs the continuation, which can request another step
+//! to keep going.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling};
+use std::cell::Cell;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The most bytes a continuation passed to [`single_step`] may capture.
+pub const MAX_CONTINUATION_SIZE: usize = 64;
+
+// Threads with a continuation pending, and the callback consuming their steps while there are any
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static HOOK: Mutex<Option<CallbackGuard>> = Mutex::new(None);
+
+thread_local! {
+    static CONTINUATION: Cell<Option<Continuation>> = const { Cell::new(None) };
+}
+
+#[repr(C, align(16))]
+struct Storage([MaybeUninit<u8>; MAX_CONTINUATION_SIZE]);
+
+// A type-erased `FnOnce`, stored inline so neither storing nor running it allocates
+struct Continuation {
+    storage: Storage,
+    call: unsafe fn(*mut Storage, &mut ExceptionInfo),
+    drop: unsafe fn(*mut Storage),
+}
+
+impl Continuation {
+    fn new<F: FnOnce(&mut ExceptionInfo) + Send + 'static>(f: F) -> Self {
+        const {
+            assert!(std::mem::size_of::<F>() <= MAX_CONTINUATION_SIZE);
+            assert!(std::mem::align_of::<F>() <= std::mem::align_of::<Storage>());
+        }
+
+        unsafe fn call<F: FnOnce(&mut ExceptionInfo)>(
+            storage: *mut Storage,
+            info: &mut ExceptionInfo,
+        ) {
+            (storage as *mut F).read()(info)
+        }
+
+        unsafe fn drop<F>(storage: *mut Storage) {
+            std::ptr::drop_in_place(storage as *mut F)
+        }
+
+        let mut storage = Storage([MaybeUninit::uninit(); MAX_CONTINUATION_SIZE]);
+        unsafe { (storage.0.as_mut_ptr() as *mut F).write(f) };
+        Continuation {
+            storage,
+            call: call::<F>,
+            drop: drop::<F>,
+        }
+    }
+
+    fn run(self, info: &mut ExceptionInfo) {
+        // The closure is moved out by the call, so it mustn't be dropped again
+        let mut this = ManuallyDrop::new(self);
+        unsafe { (this.call)(&mut this.storage, info) }
+    }
+}
+
+impl Drop for Continuation {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(&mut self.storage) }
+    }
+}
+
+/// Makes the current thread single-step once `ctx` is resumed, calling `on_step` with the
+/// single-step exception after the next instruction has executed.
+///
+/// `ctx` must be the context of an exception raised on the current thread, which the caller then
+/// continues execution with. Calling this again from `on_step` keeps stepping; otherwise the trap
+/// flag is cleared again afterwards. Calling it again before the step happened replaces the
+/// pending continuation.
+///
+/// Continuations are stored in a fixed-size slot per thread, so `on_step` may capture at most
+/// [`MAX_CONTINUATION_SIZE`] bytes, which is checked at compile time.
+pub fn single_step<F>(ctx: &mut (impl ContextExt + ?Sized), on_step: F)
+where
+    F: FnOnce(&mut ExceptionInfo) + Send + 'static,
+{
+    let replaced = CONTINUATION.with(|pending| pending.replace(Some(Continuation::new(on_step))));
+    if replaced.is_none() {
+        acquire();
+    }
+    ctx.set_trap_flag(true);
+}
+
+// Counts a newly pending continuation, installing the hook for the first one
+fn acquire() {
+    let mut hook = HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    if PENDING.fetch_add(1, Ordering::SeqCst) == 0 && hook.is_none() {
+        let filter = Filter::code(ExceptionCode::SingleStep);
+        *hook = Registration::new()
+            .priority(i32::MIN)
+            .filter(filter)
+            .register(stepped)
+            .ok();
+    }
+}
+
+// Counts a continuation as done, removing the hook after the last one
+fn release() {
+    let mut hook = HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    if PENDING.fetch_sub(1, Ordering::SeqCst) == 1 {
+        hook.take();
+    }
+}
+
+fn stepped(info: &mut ExceptionInfo) -> Handling {
+    // Hardware breakpoints raise the same exception, with the slot that was hit in Dr6 instead
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if !info.context_ptr().is_null() {
+        let dr6 = info.context().dr6 as usize;
+        if dr6 & 0b1111 != 0 && dr6 & 1 << 14 == 0 {
+            return Handling::ContinueSearch;
+        }
+    }
+
+    let continuation = match CONTINUATION.with(|pending| pending.take()) {
+        Some(continuation) => continuation,
+        None => return Handling::ContinueSearch,
+    };
+
+    info.context_mut().set_trap_flag(false);
+    continuation.run(info);
+    release();
+    Handling::ContinueExecution
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    use super::single_step;
+    use crate::dispatch::{self, Registration};
+    use crate::{ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling};
+    use std::sync::Mutex;
+
+    static IPS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn record(remaining: usize) -> impl FnOnce(&mut ExceptionInfo) + Send + 'static {
+        move |info| {
+            IPS.lock().unwrap().push(info.context().ip());
+            if remaining > 1 {
+                single_step(info.context_mut(), record(remaining - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn three_instructions() {
+        let _serial = dispatch::tests::serial();
+
+        let filter = Filter::code(ExceptionCode::IllegalInstruction).and(Filter::current_thread());
+        let _start = Registration::new()
+            .filter(filter)
+            .register(|info| {
+                let context = info.context_mut();
+                context.skip_bytes(2);
+                IPS.lock().unwrap().push(context.ip());
+                single_step(context, record(3));
+                Handling::ContinueExecution
+            })
+            .unwrap();
+
+        unsafe { std::arch::asm!("ud2", "nop", "nop", "nop") };
+
+        // Where the steps started, then after each of the one-byte instructions
+        let ips = IPS.lock().unwrap();
+        assert_eq!(ips.len(), 4);
+        assert_eq!(ips[1..], [ips[0] + 1, ips[0] + 2, ips[0] + 3]);
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,253 @@
+//! Software breakpoints, patching an `int3` over the first byte of an instruction.
+//!
+//! Unlike [hardware breakpoints](crate::hwbp) there's no limit on how many can be set, and they
+//! apply to every thread, but they can only watch for execution. When one is hit, the original
+//! byte is put back so the instruction can run, and the thread single-steps over it with the
+//! trap flag before the `int3` is written again.
+//!
+//! While the original byte is back, other threads run the instruction without hitting the
+//! breakpoint. A thread that hit it just before is still reported, but the breakpoint is only
+//! re-armed by whichever thread restored the byte.
+
+// Imports
+use crate::dispatch::{self, CallbackGuard};
+use crate::threads::{self, ThreadError};
+use crate::{memory, teb, ContextExt, ExceptionCode, ExceptionInfo, Filter, Handling, VehError};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+const INT3: u8 = 0xCC;
+
+/// Why a [`SwBreakpoint`] couldn't be set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BpError {
+    /// The address isn't readable, so it can't be code.
+    Unreadable,
+    /// There's an `int3` at the address already, such as from another breakpoint.
+    AlreadyPatched,
+    /// The code couldn't be made writable, with this `GetLastError` code.
+    Protect(u32),
+    /// The breakpoint's callback couldn't be registered with the dispatcher.
+    Dispatch(VehError),
+    /// The other threads couldn't be suspended around patching the code.
+    Suspend(ThreadError),
+}
+
+impl fmt::Display for BpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BpError::Unreadable => f.write_str("the address is not readable"),
+            BpError::AlreadyPatched => f.write_str("the address already holds a breakpoint"),
+            BpError::Protect(code) => write!(f, "failed to make the code writable (error {code})"),
+            BpError::Dispatch(error) => write!(f, "failed to register the callback: {error}"),
+            BpError::Suspend(error) => write!(f, "failed to suspend other threads: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for BpError {}
+
+impl From<VehError> for BpError {
+    fn from(error: VehError) -> Self {
+        BpError::Dispatch(error)
+    }
+}
+
+impl From<ThreadError> for BpError {
+    fn from(error: ThreadError) -> Self {
+        BpError::Suspend(error)
+    }
+}
+
+struct State {
+    address: usize,
+    original: u8,
+    // The thread single-stepping over the restored instruction, or 0
+    stepping: AtomicU32,
+    removed: AtomicBool,
+}
+
+/// A software breakpoint, removed again when dropped.
+pub struct SwBreakpoint {
+    state: Arc<State>,
+    // Taken on drop, once no thread is stepping anymore
+    callback: Option<CallbackGuard>,
+}
+
+impl SwBreakpoint {
+    /// Patches an `int3` over the instruction at `address`, calling `callback` every time any
+    /// thread is about to execute it.
+    ///
+    /// Unless the callback moves the instruction pointer elsewhere, the instruction is then
+    /// executed as usual.
+    ///
+    /// # Safety
+    /// `address` must be the first byte of an instruction, in code that stays mapped for as long
+    /// as the breakpoint exists.
+    pub unsafe fn set<F>(address: usize, callback: F) -> Result<Self, BpError>
+    where
+        F: Fn(&mut ExceptionInfo) + Send + Sync + 'static,
+    {
+        Self::set_with(address, callback, false)
+    }
+
+    /// As [`set`](Self::set), but with every other thread
+    /// [suspended](threads::suspend_others) while the `int3` is written, so none of them runs the
+    /// instruction part way through being patched.
+    ///
+    /// # Safety
+    /// As [`set`](Self::set).
+    pub unsafe fn set_suspended<F>(address: usize, callback: F) -> Result<Self, BpError>
+    where
+        F: Fn(&mut ExceptionInfo) + Send + Sync + 'static,
+    {
+        Self::set_with(address, callback, true)
+    }
+
+    unsafe fn set_with<F>(address: usize, callback: F, suspend: bool) -> Result<Self, BpError>
+    where
+        F: Fn(&mut ExceptionInfo) + Send + Sync + 'static,
+    {
+        if !memory::is_readable(address, 1) {
+            return Err(BpError::Unreadable);
+        }
+
+        let original = *(address as *const u8);
+        if original == INT3 {
+            return Err(BpError::AlreadyPatched);
+        }
+
+        let state = Arc::new(State {
+            address,
+            original,
+            stepping: AtomicU32::new(0),
+            removed: AtomicBool::new(false),
+        });
+
+        let filter = Filter::codes([ExceptionCode::Breakpoint, ExceptionCode::SingleStep]);
+        let shared = state.clone();
+        let guard = dispatch::register_closure(filter, move |info| match info.code() {
+            ExceptionCode::Breakpoint => hit(info, &shared, &callback),
+            _ => stepped(info, &shared),
+        })?;
+
+        // Declared after `guard`, so a failure resumes the others before unregistering
+        let _others = suspend.then(threads::suspend_others).transpose()?;
+        memory::write_code(address, &[INT3]).map_err(BpError::Protect)?;
+        Ok(SwBreakpoint {
+            state,
+            callback: Some(guard),
+        })
+    }
+
+    pub fn address(&self) -> usize {
+        self.state.address
+    }
+}
+
+impl Drop for SwBreakpoint {
+    fn drop(&mut self) {
+        // Stop threads from re-arming, and wait for the one stepping to finish its single-step,
+        // which would otherwise go unhandled
+        let state = &self.state;
+        state.removed.store(true, Ordering::SeqCst);
+        while state.stepping.load(Ordering::SeqCst) != 0 {
+            std::thread::yield_now();
+        }
+
+        let _ = unsafe { memory::write_code(state.address, &[state.original]) };
+        self.callback.take();
+    }
+}
+
+fn hit(
+    info: &mut ExceptionInfo,
+    state: &State,
+    callback: &impl Fn(&mut ExceptionInfo),
+) -> Handling {
+    if info.address() != state.address {
+        return Handling::ContinueSearch;
+    }
+
+    // Resume at the instruction itself rather than after the `int3`, unless the callback says
+    // otherwise
+    info.context_mut().set_ip(state.address);
+    callback(info);
+
+    // Resuming elsewhere leaves the instruction and its breakpoint alone
+    let context = info.context_mut();
+    if context.ip() != state.address {
+        return Handling::ContinueExecution;
+    }
+
+    // If another thread is already stepping over it, the original byte is back already
+    let thread_id = teb::current_thread_id();
+    let claimed = state
+        .stepping
+        .compare_exchange(0, thread_id, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok();
+    if claimed {
+        if state.removed.load(Ordering::SeqCst) {
+            state.stepping.store(0, Ordering::SeqCst);
+            return Handling::ContinueExecution;
+        }
+
+        let _ = unsafe { memory::write_code(state.address, &[state.original]) };
+        context.set_trap_flag(true);
+    }
+
+    Handling::ContinueExecution
+}
+
+fn stepped(info: &mut ExceptionInfo, state: &State) -> Handling {
+    if state.stepping.load(Ordering::SeqCst) != teb::current_thread_id() {
+        return Handling::ContinueSearch;
+    }
+
+    if !state.removed.load(Ordering::SeqCst) {
+        let _ = unsafe { memory::write_code(state.address, &[INT3]) };
+    }
+    info.context_mut().set_trap_flag(false);
+    state.stepping.store(0, Ordering::SeqCst);
+    Handling::ContinueExecution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BpError, SwBreakpoint};
+    use crate::dispatch;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[inline(never)]
+    fn doubled(x: usize) -> usize {
+        std::hint::black_box(x) * 2
+    }
+
+    #[test]
+    fn persists_across_hits() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        let _serial = dispatch::tests::serial();
+
+        let call = std::hint::black_box(doubled as fn(usize) -> usize);
+        let breakpoint = unsafe {
+            SwBreakpoint::set(call as usize, |_| {
+                HITS.fetch_add(1, Ordering::SeqCst);
+            })
+        }
+        .unwrap();
+        assert_eq!(
+            unsafe { SwBreakpoint::set(call as usize, |_| {}) }.err(),
+            Some(BpError::AlreadyPatched)
+        );
+
+        assert_eq!(call(1), 2);
+        assert_eq!(call(2), 4);
+        assert_eq!(HITS.load(Ordering::SeqCst), 2);
+
+        drop(breakpoint);
+        assert_eq!(call(3), 6);
+        assert_eq!(HITS.load(Ordering::SeqCst), 2);
+    }
+}
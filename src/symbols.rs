@@ -0,0 +1,152 @@
+//! Naming code addresses, for reporting captured frames.
+//!
+//! [`format_address`] works in any build, naming an address after the nearest export of the
+//! module containing it, which needs nothing but the loaded images. With the `dbghelp` feature,
+//! a [`Symbolizer`] resolves addresses with the PDBs of developer builds instead.
+
+// Imports
+use crate::modules::{self, Module};
+use std::fmt;
+
+// Architecture-specific imports
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::Export, Pe, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::Export, Pe, PeView};
+
+#[cfg(feature = "dbghelp")]
+pub use crate::dbghelp::{SymbolError, SymbolInfo, Symbolizer};
+
+/// An address named after its module and the nearest export before it, see [`format_address`].
+///
+/// Like a [`Module`], the names are read from the loaded image, so they're only meaningful for
+/// as long as the module stays loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct FormattedAddress {
+    address: usize,
+    module: Option<Module>,
+    export: Option<NearestExport>,
+}
+
+// An export's RVA, and its name in the module's export table
+#[derive(Debug, Clone, Copy)]
+struct NearestExport {
+    rva: usize,
+    name: *const u8,
+    name_len: usize,
+}
+
+impl FormattedAddress {
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// The module containing the address, if any.
+    pub fn module(&self) -> Option<&Module> {
+        self.module.as_ref()
+    }
+
+    /// The name of the export the address is in, or after, if any.
+    pub fn export(&self) -> Option<&str> {
+        let export = self.export?;
+        let name = unsafe { std::slice::from_raw_parts(export.name, export.name_len) };
+        std::str::from_utf8(name).ok()
+    }
+
+    /// How far the address is past the export, or the module's base if there's no export.
+    pub fn offset(&self) -> usize {
+        let module = match self.module {
+            Some(module) => module,
+            None => return 0,
+        };
+        let rva = self.export.map_or(0, |export| export.rva);
+        self.address - module.base() - rva
+    }
+}
+
+/// Shows the address as `module!export+offset`, `module+offset` when there's no export before
+/// it, or as a bare address when it isn't in a module.
+impl fmt::Display for FormattedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let module = match &self.module {
+            Some(module) => module,
+            None => return write!(f, "{:#x}", self.address),
+        };
+        for c in char::decode_utf16(module.name_wide().iter().copied()) {
+            fmt::Write::write_char(f, c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+        }
+        if let Some(export) = self.export() {
+            write!(f, "!{export}")?;
+        }
+        write!(f, "+{:#x}", self.offset())
+    }
+}
+
+/// Names `address` after the module containing it and the exported function with the greatest
+/// address not past it, such as `ntdll.dll!RtlAllocateHeap+0x42`.
+///
+/// Exports are a poor stand-in for symbols, as functions that aren't exported are named after
+/// whichever export comes before them, but they're available without PDBs. Nothing is allocated,
+/// and the module list and export table are read without calling any APIs, so this is safe to
+/// call while post-processing snapshots.
+pub fn format_address(address: usize) -> FormattedAddress {
+    let module = modules::containing(address);
+    let export = module.and_then(|module| nearest_export(&module, address));
+    FormattedAddress {
+        address,
+        module,
+        export,
+    }
+}
+
+// The RVA and name of the named export closest before `address`, which is in `module`
+fn nearest_export(module: &Module, address: usize) -> Option<NearestExport> {
+    let target = address - module.base();
+    let view = unsafe { PeView::module(module.base() as *const u8) };
+    let exports = view.exports().ok()?.by().ok()?;
+
+    let mut nearest: Option<NearestExport> = None;
+    for (name, export) in exports.iter_names() {
+        let (name, rva) = match (name, export) {
+            (Ok(name), Ok(Export::Symbol(&rva))) => (name.c_str(), rva as usize),
+            _ => continue,
+        };
+        if rva <= target && nearest.is_none_or(|best| rva > best.rva) {
+            nearest = Some(NearestExport {
+                rva,
+                name: name.as_ptr(),
+                name_len: name.len(),
+            });
+        }
+    }
+    nearest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_address;
+    use crate::modules;
+
+    // Architecture-specific imports
+    #[cfg(target_pointer_width = "32")]
+    use pelite::pe32::{exports::GetProcAddress, PeView};
+    #[cfg(target_pointer_width = "64")]
+    use pelite::pe64::{exports::GetProcAddress, PeView};
+
+    #[test]
+    fn inside_ntdll_export() {
+        let ntdll = modules::find("ntdll.dll").unwrap();
+        let view = unsafe { PeView::module(ntdll.base() as *const u8) };
+        let function = view.get_proc_address("RtlAllocateHeap").unwrap() as usize;
+
+        let formatted = format_address(function + 4);
+        assert!(formatted.module().unwrap().name_eq("ntdll.dll"));
+        assert_eq!(formatted.export(), Some("RtlAllocateHeap"));
+        assert_eq!(formatted.offset(), 4);
+        assert!(formatted.to_string().ends_with("!RtlAllocateHeap+0x4"));
+
+        let unmapped = format_address(0x10);
+        assert!(unmapped.module().is_none());
+        assert_eq!(unmapped.to_string(), "0x10");
+    }
+}
@@ -0,0 +1,50 @@
+// Imports
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts the threads currently running through some piece of shared state, so whoever tears
+/// that state down can wait for them to leave before freeing it.
+///
+/// The protocol is: readers `enter()` *before* loading the pointer to the shared state, and the
+/// owner unpublishes the pointer *before* calling `wait_idle()`. Any reader that entered late will
+/// then see the unpublished pointer, and any reader that entered early is waited for.
+pub(crate) struct InFlight(AtomicUsize);
+
+impl InFlight {
+    pub(crate) const fn new() -> Self {
+        InFlight(AtomicUsize::new(0))
+    }
+
+    pub(crate) fn enter(&self) -> InFlightGuard<'_> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self)
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+
+    /// Spins until no reader is inside, yielding the time slice after a short while.
+    ///
+    /// Calling this from inside the section being waited on (e.g. dropping a handler from within
+    /// itself) never returns.
+    pub(crate) fn wait_idle(&self) {
+        let mut spins = 0u32;
+        while !self.is_idle() {
+            if spins < 64 {
+                std::hint::spin_loop();
+                spins += 1;
+            } else {
+                std::thread::yield_now();
+            }
+        }
+    }
+}
+
+/// Leaves the section on drop, so the count is decremented even if the reader unwinds.
+pub(crate) struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,172 @@
+// Accesses the current thread's TEB, which is always mapped and safe to use from inside a
+// handler, unlike most APIs.
+
+/// The current thread's ID, from `TEB.ClientId.UniqueThread`.
+pub(crate) fn current_thread_id() -> u32 {
+    let tid: usize;
+
+    #[cfg(target_pointer_width = "32")]
+    unsafe {
+        std::arch::asm!("mov {}, fs:[24h]", out(reg) tid, options(nostack, readonly, preserves_flags));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    unsafe {
+        std::arch::asm!("mov {}, gs:[48h]", out(reg) tid, options(nostack, readonly, preserves_flags));
+    }
+
+    tid as u32
+}
+
+// Offsets of `TEB.LastErrorValue` and `TEB.LastStatusValue`
+#[cfg(target_pointer_width = "32")]
+const LAST_ERROR: usize = 0x34;
+#[cfg(target_pointer_width = "32")]
+const LAST_STATUS: usize = 0xBF4;
+#[cfg(target_pointer_width = "64")]
+const LAST_ERROR: usize = 0x68;
+#[cfg(target_pointer_width = "64")]
+const LAST_STATUS: usize = 0x1250;
+
+fn teb() -> *mut u8 {
+    let teb: *mut u8;
+
+    // `NT_TIB.Self`
+    #[cfg(target_pointer_width = "32")]
+    unsafe {
+        std::arch::asm!("mov {}, fs:[18h]", out(reg) teb, options(nostack, readonly, preserves_flags));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    unsafe {
+        std::arch::asm!("mov {}, gs:[30h]", out(reg) teb, options(nostack, readonly, preserves_flags));
+    }
+
+    teb
+}
+
+/// The current thread's stack, from `NT_TIB.StackLimit` up to `NT_TIB.StackBase`.
+pub(crate) fn stack_bounds() -> std::ops::Range<usize> {
+    let teb = teb() as *const usize;
+    unsafe { *teb.add(2)..*teb.add(1) }
+}
+
+// Offset of `TEB.DeallocationStack`
+#[cfg(target_pointer_width = "32")]
+const DEALLOCATION_STACK: usize = 0xE0C;
+#[cfg(target_pointer_width = "64")]
+const DEALLOCATION_STACK: usize = 0x1478;
+
+/// The lowest address of the current thread's stack reservation, from `TEB.DeallocationStack`.
+pub(crate) fn deallocation_stack() -> usize {
+    unsafe { *(teb().add(DEALLOCATION_STACK) as *const usize) }
+}
+
+// Offset of `TEB.ProcessEnvironmentBlock`
+#[cfg(target_arch = "x86")]
+const PEB: usize = 0x30;
+#[cfg(target_arch = "x86_64")]
+const PEB: usize = 0x60;
+
+/// Whether a debugger is attached to the process, from `PEB.BeingDebugged`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn being_debugged() -> bool {
+    unsafe {
+        let peb = *(teb().add(PEB) as *const *const u8);
+        std::ptr::read_volatile(peb.add(2)) != 0
+    }
+}
+
+/// Moves `NT_TIB.StackLimit`, which should always be the lowest committed page above the guard
+/// page.
+///
+/// # Safety
+/// The pages from `limit` up to the stack base must be committed.
+pub(crate) unsafe fn set_stack_limit(limit: usize) {
+    *(teb() as *mut usize).add(2) = limit;
+}
+
+/// The thread's last-error and last-status values, put back when dropped.
+///
+/// Handlers interrupt arbitrary code, which might be about to call `GetLastError`, so the crate's
+/// handlers keep callbacks from clobbering either value.
+pub(crate) struct SavedErrors {
+    last_error: u32,
+    last_status: u32,
+}
+
+impl SavedErrors {
+    pub(crate) fn save() -> Self {
+        let teb = teb();
+        unsafe {
+            SavedErrors {
+                last_error: *(teb.add(LAST_ERROR) as *const u32),
+                last_status: *(teb.add(LAST_STATUS) as *const u32),
+            }
+        }
+    }
+}
+
+impl Drop for SavedErrors {
+    fn drop(&mut self) {
+        let teb = teb();
+        unsafe {
+            *(teb.add(LAST_ERROR) as *mut u32) = self.last_error;
+            *(teb.add(LAST_STATUS) as *mut u32) = self.last_status;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::current_thread_id;
+    use crate::{dispatch, ExceptionCode, Filter, Handling, Order, VehBuilder};
+    use winapi::um::errhandlingapi::{GetLastError, RaiseException, SetLastError};
+    use winapi::um::processthreadsapi::GetCurrentThreadId;
+
+    #[test]
+    fn matches_api() {
+        assert_eq!(current_thread_id(), unsafe { GetCurrentThreadId() });
+
+        let (from_teb, from_api) =
+            std::thread::spawn(|| (current_thread_id(), unsafe { GetCurrentThreadId() }))
+                .join()
+                .unwrap();
+        assert_eq!(from_teb, from_api);
+    }
+
+    #[test]
+    fn last_error_preserved() {
+        const DISPATCHED: u32 = 0xE056_5401;
+        const INSTALLED: u32 = 0xE056_5402;
+
+        let clobber = |code: u32| {
+            move |info: &mut crate::ExceptionInfo| match info.code().raw() == code {
+                true => {
+                    unsafe { SetLastError(5) };
+                    Handling::ContinueExecution
+                }
+                false => Handling::ContinueSearch,
+            }
+        };
+
+        let _serial = dispatch::tests::serial();
+
+        unsafe {
+            let filter = Filter::code(ExceptionCode::from_raw(DISPATCHED));
+            let _dispatched = dispatch::register_closure(filter, clobber(DISPATCHED)).unwrap();
+            let _installed = VehBuilder::new()
+                .order(Order::First)
+                .install_closure(clobber(INSTALLED))
+                .unwrap();
+
+            SetLastError(1234);
+            RaiseException(DISPATCHED, 0, 0, std::ptr::null());
+            assert_eq!(GetLastError(), 1234);
+
+            SetLastError(4321);
+            RaiseException(INSTALLED, 0, 0, std::ptr::null());
+            assert_eq!(GetLastError(), 4321);
+        }
+    }
+}
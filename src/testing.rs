@@ -0,0 +1,399 @@
+//! Running tests that may crash in a process of their own, and raising exceptions for them.
+//!
+//! An exception handler that gets something wrong usually takes the whole test binary down with
+//! it, and every test still running with it. [`run_isolated`] runs a test's body in a fresh copy
+//! of the test binary instead, so that the test only reports how that process ended:
+//!
+//! ```ignore
+//! use manual_veh::testing::run_isolated;
+//!
+//! #[test]
+//! fn null_read_crashes() {
+//!     run_isolated("tests::null_read_crashes", || unsafe {
+//!         std::ptr::read_volatile(std::ptr::null::<u8>());
+//!     })
+//!     .expect_crash_with_code(0xC000_0005);
+//! }
+//! ```
+
+// Imports
+use crate::dispatch::Registration;
+use crate::{modules, ExceptionCode, ExceptionInfo, Filter, Handling, EXCEPTION_RECORD};
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_pointer_width = "32")]
+use pelite::pe32::{exports::GetProcAddress, PeView};
+#[cfg(target_pointer_width = "64")]
+use pelite::pe64::{exports::GetProcAddress, PeView};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod fault;
+
+type FnRtlRaiseException = unsafe extern "system" fn(record: *mut EXCEPTION_RECORD);
+
+// Set in the child process, to the name of the test it runs
+const CHILD: &str = "MANUAL_VEH_ISOLATED";
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn SetErrorMode(mode: u32) -> u32;
+}
+
+/// How a child process started by [`run_isolated`] ended.
+#[derive(Debug)]
+pub struct IsolatedRun {
+    status: ExitStatus,
+    stdout: String,
+    stderr: String,
+}
+
+impl IsolatedRun {
+    /// The process's exit code, which for a crash is the exception code.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.status.code().map(|code| code as u32)
+    }
+
+    pub fn stdout(&self) -> &str {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    /// Panics unless the test ran to completion.
+    #[track_caller]
+    pub fn expect_exit_ok(&self) -> &Self {
+        self.expect_exit_code(0)
+    }
+
+    /// Panics unless the process exited with `code`, such as one passed to `TerminateProcess`.
+    #[track_caller]
+    pub fn expect_exit_code(&self, code: u32) -> &Self {
+        if self.exit_code() != Some(code) {
+            self.fail(&format!("exit code {code:#X}"));
+        }
+        self
+    }
+
+    /// Panics unless the process was ended by an unhandled exception with `code`, such as
+    /// `0xC0000005` for an access violation.
+    #[track_caller]
+    pub fn expect_crash_with_code(&self, code: u32) -> &Self {
+        // Error severity, which ordinary exit codes don't have
+        if code >> 30 != 0b11 || self.exit_code() != Some(code) {
+            self.fail(&format!("a crash with code {code:#X}"));
+        }
+        self
+    }
+
+    #[track_caller]
+    fn fail(&self, expected: &str) -> ! {
+        let actual = match self.exit_code() {
+            Some(code) => format!("{code:#X}"),
+            None => "no exit code".into(),
+        };
+        panic!(
+            "expected {expected}, but the child process ended with {actual}\nstderr:\n{}",
+            self.stderr
+        );
+    }
+}
+
+/// Runs `f` in a child process, and returns how it ended there.
+///
+/// `name` is the full path of the calling test, e.g. `module::tests::crashes`, which the child
+/// process is told to run by itself. There, this function calls `f` and exits as soon as it
+/// returns; if `f` panics, the test fails in the child, which then exits with code 101. The
+/// child doesn't capture its output, so anything written before a crash still shows up in
+/// [`IsolatedRun::stderr`].
+///
+/// Crashes in the child don't show Windows Error Reporting's dialog.
+pub fn run_isolated(name: &str, f: fn()) -> IsolatedRun {
+    if std::env::var_os(CHILD).is_some_and(|child| child == name) {
+        unsafe { SetErrorMode(0x2) };
+        f();
+        std::process::exit(0);
+    }
+
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([name, "--exact", "--test-threads=1", "--nocapture"])
+        .env(CHILD, name)
+        .output()
+        .unwrap();
+    IsolatedRun {
+        status: output.status,
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+/// Raises a continuable exception with `code` and `params` with ntdll's `RtlRaiseException`.
+///
+/// Unlike a hardware exception, a handler returning
+/// [`Handling::ContinueExecution`](crate::Handling::ContinueExecution) for it just makes this
+/// return. This is always inlined, so the exception's address is in the caller.
+///
+/// # Panics
+/// If there are more than 15 parameters, or `RtlRaiseException` couldn't be located.
+#[inline(always)]
+pub fn raise(code: u32, params: &[usize]) {
+    let mut record = record(code, params);
+    unsafe { rtl_raise_exception()(&mut record) };
+}
+
+fn record(code: u32, params: &[usize]) -> EXCEPTION_RECORD {
+    let mut record: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+    assert!(
+        params.len() <= record.exception_information.len(),
+        "at most 15 parameters"
+    );
+    record.exception_code = code;
+    record.number_parameters = params.len() as u32;
+    record.exception_information[..params.len()].copy_from_slice(params);
+    record
+}
+
+fn rtl_raise_exception() -> FnRtlRaiseException {
+    let ntdll = modules::find("ntdll.dll").expect("ntdll isn't loaded");
+    let view = unsafe { PeView::module(ntdll.base() as *const u8) };
+    let raise = view
+        .get_proc_address("RtlRaiseException")
+        .expect("no RtlRaiseException");
+    unsafe { std::mem::transmute::<usize, FnRtlRaiseException>(raise as usize) }
+}
+
+// Raised by the CPU, with the context at the faulting instruction rather than past it
+const HARDWARE_CODES: [ExceptionCode; 6] = [
+    ExceptionCode::AccessViolation,
+    ExceptionCode::IllegalInstruction,
+    ExceptionCode::PrivilegedInstruction,
+    ExceptionCode::IntegerDivideByZero,
+    ExceptionCode::IntegerOverflow,
+    ExceptionCode::DatatypeMisalignment,
+];
+
+/// Runs `f`, resuming after every exception raised on this thread meanwhile, and returns their
+/// codes in the order they were raised.
+///
+/// Faults from the [`fault`] generators are resumed after as their sites say, and exceptions
+/// raised with [`raise`] or `RaiseException` just return. Other hardware faults are skipped
+/// with [`skip_instruction`](crate::ContextExt::skip_instruction) with the `iced` feature, and
+/// crash the process without it, as do non-continuable exceptions.
+///
+/// This registers a dispatcher callback ahead of every other one for as long as `f` runs.
+pub fn exceptions_in(f: impl FnOnce()) -> Vec<ExceptionCode> {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let callback = {
+        let seen = seen.clone();
+        Registration::new()
+            .priority(i32::MIN)
+            .filter(Filter::current_thread())
+            .register_closure(move |info| match resume(info) {
+                true => {
+                    seen.lock().unwrap().push(info.code());
+                    Handling::ContinueExecution
+                }
+                false => Handling::ContinueSearch,
+            })
+            .expect("couldn't register the fix-up callback")
+    };
+    f();
+    drop(callback);
+
+    let seen = std::mem::take(&mut *seen.lock().unwrap());
+    seen
+}
+
+// Moves the context past the exception if it knows how, returning whether it did
+fn resume(info: &mut ExceptionInfo) -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64", feature = "iced"))]
+    use crate::ContextExt;
+
+    if !info.is_continuable() {
+        return false;
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if let Some(site) = fault::last_fault_site() {
+        if site.address == info.context().ip() {
+            match site.len {
+                0 => return info.context_mut().emulate_return().is_ok(),
+                len => {
+                    info.context_mut().skip_bytes(len);
+                    return true;
+                }
+            }
+        }
+    }
+
+    if !HARDWARE_CODES.contains(&info.code()) {
+        return true;
+    }
+    #[cfg(feature = "iced")]
+    return info.context_mut().skip_instruction().is_ok();
+    #[cfg(not(feature = "iced"))]
+    false
+}
+
+// The codes as hex, which is how they're usually looked up
+fn describe(codes: &[ExceptionCode]) -> String {
+    let codes: Vec<_> = codes
+        .iter()
+        .map(|code| format!("{:#X}", code.raw()))
+        .collect();
+    format!("[{}]", codes.join(", "))
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_exception(expected: ExceptionCode, seen: &[ExceptionCode]) {
+    let name = format!("{expected:?} ({:#X})", expected.raw());
+    match seen {
+        [code] if *code == expected => {}
+        [] => panic!("expected a {name} exception, but none was raised"),
+        _ => panic!(
+            "expected one {name} exception, but the block raised {}",
+            describe(seen)
+        ),
+    }
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_no_exception(seen: &[ExceptionCode]) {
+    if !seen.is_empty() {
+        panic!(
+            "expected no exception, but the block raised {}",
+            describe(seen)
+        );
+    }
+}
+
+/// Asserts that evaluating an expression raises exactly one exception, with the given
+/// [`ExceptionCode`], resuming after it as [`exceptions_in`] does.
+///
+/// ```ignore
+/// use manual_veh::testing::raise;
+/// use manual_veh::{assert_exception, ExceptionCode};
+///
+/// assert_exception!(ExceptionCode::Other(0xE000_0001), raise(0xE000_0001, &[]));
+/// // With the `iced` feature
+/// assert_exception!(ExceptionCode::AccessViolation, {
+///     unsafe { std::ptr::read_volatile(std::ptr::null::<u8>()) }
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_exception {
+    ($code:expr, $body:expr $(,)?) => {{
+        let seen = $crate::testing::exceptions_in(|| {
+            let _ = $body;
+        });
+        $crate::testing::__assert_exception($code, &seen);
+    }};
+}
+
+/// Asserts that evaluating an expression doesn't raise any exception, resuming after those it
+/// does raise as [`exceptions_in`] does.
+#[macro_export]
+macro_rules! assert_no_exception {
+    ($body:expr $(,)?) => {{
+        let seen = $crate::testing::exceptions_in(|| {
+            let _ = $body;
+        });
+        $crate::testing::__assert_no_exception(&seen);
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{raise, run_isolated};
+    use crate::{dispatch, modules, ExceptionCode, ExceptionInfo, Handling, Order, Veh};
+    use std::ffi::c_void;
+    use std::sync::Mutex;
+
+    #[test]
+    fn raised_with_parameters() {
+        const CODE: u32 = 0xE056_6101;
+        static SEEN: Mutex<Option<(Vec<usize>, bool, usize)>> = Mutex::new(None);
+
+        unsafe extern "system" fn handler(ptrs: *mut c_void) -> i32 {
+            let info = ExceptionInfo::from_raw(ptrs);
+            if info.code().raw() != CODE {
+                return Handling::ContinueSearch.raw();
+            }
+            let seen = (
+                info.parameters().to_vec(),
+                info.is_continuable(),
+                info.address(),
+            );
+            *SEEN.lock().unwrap() = Some(seen);
+            Handling::ContinueExecution.raw()
+        }
+
+        let veh = unsafe { Veh::<c_void>::add(Order::First, handler) };
+        raise(CODE, &[1, 0x2000, usize::MAX]);
+        drop(veh);
+
+        let (params, continuable, address) = SEEN.lock().unwrap().take().unwrap();
+        assert_eq!(params, [1, 0x2000, usize::MAX]);
+        assert!(continuable);
+        // Raised from the test executable, which the loader lists first
+        let own = modules::containing(raised_with_parameters as *const () as usize).unwrap();
+        assert_eq!(own.base(), modules::iter().next().unwrap().base());
+        assert!(own.contains(address));
+    }
+
+    #[test]
+    fn crash_contained() {
+        run_isolated("testing::tests::crash_contained", || unsafe {
+            std::ptr::read_volatile(std::ptr::null::<u8>());
+        })
+        .expect_crash_with_code(0xC000_0005);
+    }
+
+    #[test]
+    fn output_and_panics_captured() {
+        let run = run_isolated("testing::tests::output_and_panics_captured", || {
+            eprintln!("on the way out");
+            panic!("deliberately");
+        });
+        run.expect_exit_code(101);
+        assert!(run.stderr().contains("on the way out"));
+        assert!(run.stderr().contains("deliberately"));
+    }
+
+    const MACRO_CODE: u32 = 0xE056_6103;
+
+    #[test]
+    fn exception_asserted() {
+        let _serial = dispatch::tests::serial();
+        assert_exception!(ExceptionCode::Other(MACRO_CODE), raise(MACRO_CODE, &[7]));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert_exception!(ExceptionCode::AccessViolation, super::fault::write_av());
+        assert_no_exception!({ 1 + 1 });
+        assert!(!dispatch::is_installed());
+    }
+
+    #[test]
+    #[should_panic(expected = "none was raised")]
+    fn missing_exception() {
+        let _serial = dispatch::tests::serial();
+        assert_exception!(ExceptionCode::Other(MACRO_CODE), {});
+    }
+
+    #[test]
+    #[should_panic(expected = "but the block raised [0xE0566103]")]
+    fn wrong_exception() {
+        let _serial = dispatch::tests::serial();
+        assert_exception!(ExceptionCode::Breakpoint, raise(MACRO_CODE, &[]));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected no exception")]
+    fn unexpected_exception() {
+        let _serial = dispatch::tests::serial();
+        assert_no_exception!(raise(MACRO_CODE, &[]));
+    }
+}
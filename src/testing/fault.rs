@@ -0,0 +1,272 @@
+//! Hardware faults at known addresses, for testing handlers that need a real one.
+//!
+//! Each generator runs a small stub whose faulting instruction is recorded, per thread, before
+//! it runs, so that a handler can find it with [`last_fault_site`] and resume after it:
+//! with [`skip_bytes`](crate::ContextExt::skip_bytes) of the site's length, or with
+//! [`emulate_return`](crate::ContextExt::emulate_return) for [`exec_av`]. Without a handler
+//! resuming it, the fault crashes the process.
+
+// Imports
+use crate::memory::{self, PAGE_SIZE};
+use once_cell::race::OnceBox;
+use std::cell::Cell;
+
+const PAGE_NOACCESS: u32 = 0x01;
+
+/// An address in the first 64 KiB of the address space, which Windows never maps.
+pub const UNMAPPED: usize = 0x100;
+
+/// Where a generator's faulting instruction is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultSite {
+    pub address: usize,
+    /// The faulting instruction's length, or 0 for [`exec_av`], which faults on the call's
+    /// target.
+    pub len: usize,
+}
+
+// Offsets into `CODE` of a stub's entry point and its faulting instruction
+struct Stub {
+    entry: usize,
+    site: usize,
+    len: usize,
+}
+
+#[cfg(target_arch = "x86_64")]
+const CODE: &[u8] = &[
+    0x8A, 0x01, // mov al, [rcx]
+    0xC3, // ret
+    0xC6, 0x01, 0x00, // mov byte ptr [rcx], 0
+    0xC3, // ret
+    0x31, 0xD2, // xor edx, edx
+    0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0x31, 0xC9, // xor ecx, ecx
+    0xF7, 0xF1, // div ecx
+    0xC3, // ret
+    0x0F, 0x0B, // ud2
+    0xC3, // ret
+];
+#[cfg(target_arch = "x86_64")]
+const READ: Stub = Stub {
+    entry: 0,
+    site: 0,
+    len: 2,
+};
+#[cfg(target_arch = "x86_64")]
+const WRITE: Stub = Stub {
+    entry: 3,
+    site: 3,
+    len: 3,
+};
+#[cfg(target_arch = "x86_64")]
+const DIVIDE: Stub = Stub {
+    entry: 7,
+    site: 16,
+    len: 2,
+};
+#[cfg(target_arch = "x86_64")]
+const UD2: Stub = Stub {
+    entry: 19,
+    site: 19,
+    len: 2,
+};
+
+#[cfg(target_arch = "x86")]
+const CODE: &[u8] = &[
+    0x8B, 0x44, 0x24, 0x04, // mov eax, [esp + 4]
+    0x8A, 0x00, // mov al, [eax]
+    0xC3, // ret
+    0x8B, 0x44, 0x24, 0x04, // mov eax, [esp + 4]
+    0xC6, 0x00, 0x00, // mov byte ptr [eax], 0
+    0xC3, // ret
+    0x31, 0xD2, // xor edx, edx
+    0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0x31, 0xC9, // xor ecx, ecx
+    0xF7, 0xF1, // div ecx
+    0xC3, // ret
+    0x0F, 0x0B, // ud2
+    0xC3, // ret
+];
+#[cfg(target_arch = "x86")]
+const READ: Stub = Stub {
+    entry: 0,
+    site: 4,
+    len: 2,
+};
+#[cfg(target_arch = "x86")]
+const WRITE: Stub = Stub {
+    entry: 7,
+    site: 11,
+    len: 3,
+};
+#[cfg(target_arch = "x86")]
+const DIVIDE: Stub = Stub {
+    entry: 15,
+    site: 24,
+    len: 2,
+};
+#[cfg(target_arch = "x86")]
+const UD2: Stub = Stub {
+    entry: 27,
+    site: 27,
+    len: 2,
+};
+
+struct Pages {
+    code: usize,
+    no_access: usize,
+}
+
+// Allocated on first use, and never freed
+static PAGES: OnceBox<Pages> = OnceBox::new();
+
+thread_local! {
+    static LAST_SITE: Cell<Option<FaultSite>> = const { Cell::new(None) };
+}
+
+fn pages() -> &'static Pages {
+    PAGES.get_or_init(|| {
+        let code = memory::alloc_code(CODE).expect("couldn't allocate the fault stubs");
+        let no_access = memory::alloc_code(&[0xC3]).expect("couldn't allocate the exec page");
+        unsafe { memory::set_protection(no_access, PAGE_SIZE, PAGE_NOACCESS) }
+            .expect("couldn't make the exec page inaccessible");
+        Box::new(Pages { code, no_access })
+    })
+}
+
+// Records where the stub faults, and returns its entry point
+fn prepare(stub: &Stub) -> usize {
+    let code = pages().code;
+    set_site(code + stub.site, stub.len);
+    code + stub.entry
+}
+
+fn set_site(address: usize, len: usize) {
+    LAST_SITE.with(|site| site.set(Some(FaultSite { address, len })));
+}
+
+/// The faulting instruction of the generator that last ran on this thread.
+pub fn last_fault_site() -> Option<FaultSite> {
+    LAST_SITE.with(Cell::get)
+}
+
+/// Reads a byte from `address`, or [`UNMAPPED`] if it's `None`.
+pub fn read_av(address: Option<usize>) {
+    let entry = prepare(&READ);
+    let read = unsafe { std::mem::transmute::<usize, extern "C" fn(usize)>(entry) };
+    read(address.unwrap_or(UNMAPPED));
+}
+
+/// Writes a byte to [`UNMAPPED`].
+pub fn write_av() {
+    let entry = prepare(&WRITE);
+    let write = unsafe { std::mem::transmute::<usize, extern "C" fn(usize)>(entry) };
+    write(UNMAPPED);
+}
+
+/// Calls into a `PAGE_NOACCESS` page, which faults on the page's first byte, before anything
+/// there runs. Its site has no length; resume at the caller with `emulate_return` instead.
+pub fn exec_av() {
+    let target = pages().no_access;
+    set_site(target, 0);
+    let call = unsafe { std::mem::transmute::<usize, extern "C" fn()>(target) };
+    call();
+}
+
+/// Divides by zero with `div`, raising `STATUS_INTEGER_DIVIDE_BY_ZERO`.
+pub fn div_by_zero() {
+    let entry = prepare(&DIVIDE);
+    let divide = unsafe { std::mem::transmute::<usize, extern "C" fn()>(entry) };
+    divide();
+}
+
+/// Runs `ud2` from an executable page, raising `STATUS_ILLEGAL_INSTRUCTION`.
+pub fn illegal_instruction() {
+    let entry = prepare(&UD2);
+    let ud2 = unsafe { std::mem::transmute::<usize, extern "C" fn()>(entry) };
+    ud2();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        div_by_zero, exec_av, illegal_instruction, last_fault_site, read_av, write_av, UNMAPPED,
+    };
+    use crate::Handling;
+    use crate::{dispatch, AccessViolationInfo, AvOperation, ContextExt, ExceptionCode, Filter};
+    use std::sync::{Arc, Mutex};
+
+    // Runs `generate` under a fix-up handler resuming after its fault, returning what the handler
+    // saw and the faulting instruction it resumed after
+    fn caught(generate: fn()) -> (ExceptionCode, Option<AccessViolationInfo>, usize) {
+        let _serial = dispatch::tests::serial();
+        let seen = Arc::new(Mutex::new(None));
+        let guard = {
+            let seen = seen.clone();
+            dispatch::register_closure(Filter::current_thread(), move |info| {
+                let ip = info.context().ip();
+                let site = match last_fault_site() {
+                    Some(site) if site.address == ip => site,
+                    _ => return Handling::ContinueSearch,
+                };
+                *seen.lock().unwrap() = Some((info.code(), info.access_violation(), ip));
+                match site.len {
+                    0 if info.context_mut().emulate_return().is_err() => {
+                        return Handling::ContinueSearch
+                    }
+                    0 => {}
+                    len => info.context_mut().skip_bytes(len),
+                }
+                Handling::ContinueExecution
+            })
+            .unwrap()
+        };
+        generate();
+        drop(guard);
+
+        assert!(!dispatch::is_installed());
+        let seen = seen.lock().unwrap().take();
+        seen.unwrap()
+    }
+
+    fn violation(operation: AvOperation, address: usize) -> Option<AccessViolationInfo> {
+        Some(AccessViolationInfo { operation, address })
+    }
+
+    #[test]
+    fn read_and_write_decoded() {
+        let (code, av, ip) = caught(|| read_av(Some(0x1234)));
+        assert_eq!(code, ExceptionCode::AccessViolation);
+        assert_eq!(av, violation(AvOperation::Read, 0x1234));
+        assert_eq!(ip, last_fault_site().unwrap().address);
+
+        let (_, av, _) = caught(|| read_av(None));
+        assert_eq!(av, violation(AvOperation::Read, UNMAPPED));
+
+        let (code, av, ip) = caught(write_av);
+        assert_eq!(code, ExceptionCode::AccessViolation);
+        assert_eq!(av, violation(AvOperation::Write, UNMAPPED));
+        assert_eq!(
+            last_fault_site().map(|site| (site.address, site.len)),
+            Some((ip, 3))
+        );
+    }
+
+    #[test]
+    fn exec_decoded() {
+        let (code, av, ip) = caught(exec_av);
+        assert_eq!(code, ExceptionCode::AccessViolation);
+        assert_eq!(av, violation(AvOperation::Execute, ip));
+        assert_eq!(last_fault_site().unwrap().len, 0);
+    }
+
+    #[test]
+    fn divide_and_ud2() {
+        let (code, av, _) = caught(div_by_zero);
+        assert_eq!(code, ExceptionCode::IntegerDivideByZero);
+        assert_eq!(av, None);
+
+        let (code, _, _) = caught(illegal_instruction);
+        assert_eq!(code, ExceptionCode::IllegalInstruction);
+    }
+}
@@ -0,0 +1,140 @@
+//! Dispatcher callbacks that belong to one thread, and go away with it.
+//!
+//! A [`ThreadVeh`] is registered with a current-thread filter, and also parked in a thread-local
+//! that unregisters it when the thread exits, in case the thread never gets around to it. The
+//! unregistration goes through the [`CallbackGuard`], so it waits out other threads still
+//! dispatching through the callback.
+
+// Imports
+use crate::dispatch::{CallbackGuard, Registration};
+use crate::{ExceptionInfo, Filter, Handling, Order, VehError};
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+// The registration, shared between the guard and the thread's exit hook, and taken out by
+// whichever of them unregisters first
+type Shared = Arc<Mutex<Option<CallbackGuard>>>;
+
+// Dropped when the thread exits, unregistering whatever is still registered
+struct Owned(Vec<Shared>);
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|shared| drop(take(shared)));
+    }
+}
+
+thread_local! {
+    static OWNED: RefCell<Owned> = const { RefCell::new(Owned(Vec::new())) };
+}
+
+fn take(shared: &Shared) -> Option<CallbackGuard> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+}
+
+/// A dispatcher callback belonging to the thread that registered it, only called for that
+/// thread's exceptions and unregistered when the thread exits, if it hasn't been already.
+///
+/// The guard can be sent elsewhere and outlive the thread, and dropping it or calling
+/// [`remove`](Self::remove) unregisters the callback early. Like any [`CallbackGuard`], the
+/// callback's storage is only freed once no other thread is dispatching through it, so the
+/// thread exiting in the middle of another thread's dispatch is harmless.
+pub struct ThreadVeh {
+    shared: Shared,
+}
+
+impl ThreadVeh {
+    /// Registers `callback` with the [dispatcher](crate::dispatch) for the calling thread's
+    /// exceptions. [`Order::First`] runs it before any callback of the default priority, and
+    /// [`Order::Last`] after them.
+    ///
+    /// Fails with [`VehError::Registration`] if the thread is already exiting.
+    pub fn add<F>(order: Order, callback: F) -> Result<ThreadVeh, VehError>
+    where
+        F: Fn(&mut ExceptionInfo) -> Handling + Send + Sync + 'static,
+    {
+        // Leaving the extremes to the crate's own callbacks
+        let priority = match order {
+            Order::First => i32::MIN + 1,
+            Order::Last => i32::MAX - 1,
+        };
+
+        // Register last, so there's nothing to undo if the thread-local is already gone
+        let shared: Shared = Arc::new(Mutex::new(None));
+        OWNED
+            .try_with(|owned| {
+                let mut owned = owned.borrow_mut();
+                // Forget the ones already unregistered, so a long-lived thread doesn't pile them up
+                owned.0.retain(|shared| Arc::strong_count(shared) > 1);
+                owned.0.push(shared.clone());
+            })
+            .map_err(|_| VehError::Registration)?;
+
+        let guard = Registration::new()
+            .priority(priority)
+            .filter(Filter::current_thread())
+            .register_closure(callback)?;
+        *shared
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(guard);
+        Ok(ThreadVeh { shared })
+    }
+
+    /// Whether the callback is still registered, which stops once its thread exits.
+    pub fn is_registered(&self) -> bool {
+        let shared = self.shared.lock();
+        shared
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_some()
+    }
+
+    /// Unregisters the callback now, the same as dropping the guard.
+    pub fn remove(self) {
+        drop(self)
+    }
+}
+
+impl Drop for ThreadVeh {
+    fn drop(&mut self) {
+        drop(take(&self.shared));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{dispatch, testing, Handling, Order, ThreadVeh};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn expires_with_thread() {
+        const CODE: u32 = 0xE056_6401;
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let _serial = dispatch::tests::serial();
+        let guard = thread::spawn(|| {
+            let guard = ThreadVeh::add(Order::First, |_| {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                Handling::ContinueExecution
+            })
+            .unwrap();
+            assert!(guard.is_registered());
+            assert!(dispatch::is_installed());
+            testing::raise(CODE, &[]);
+            assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+            guard
+        })
+        .join()
+        .unwrap();
+
+        // The guard outlived its thread, but the registration didn't
+        assert!(!guard.is_registered());
+        assert!(!dispatch::is_installed());
+
+        guard.remove();
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,232 @@
+//! Stopping the process's other threads while code or protections they may be running through
+//! are changed.
+//!
+//! [`suspend_others`] suspends every thread but the calling one, from a ToolHelp snapshot of the
+//! process's threads, and resumes them when the returned [`SuspendGuard`] is dropped. Threads
+//! started after the snapshot keep running.
+//!
+//! A suspended thread might hold a lock the calling thread needs, such as the heap's, so keep
+//! the work done while they're suspended short, and avoid allocating. Threads that are inside one
+//! of the crate's handlers are left running, because they may hold the locks the crate's own
+//! hooks and breakpoints take.
+
+// Imports
+use crate::{reentry, teb, CONTEXT};
+use std::ffi::c_void;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const TH32CS_SNAPTHREAD: u32 = 0x0000_0004;
+const INVALID_HANDLE_VALUE: *mut c_void = -1isize as _;
+
+const THREAD_SUSPEND_RESUME: u32 = 0x0002;
+const THREAD_GET_CONTEXT: u32 = 0x0008;
+
+#[cfg(target_arch = "x86")]
+const CONTEXT_CONTROL: u32 = 0x0001_0001;
+#[cfg(target_arch = "x86_64")]
+const CONTEXT_CONTROL: u32 = 0x0010_0001;
+#[cfg(target_arch = "aarch64")]
+const CONTEXT_CONTROL: u32 = 0x0040_0001;
+
+// How long other threads may stay suspended before debug builds complain, as whatever runs
+// meanwhile is likely to block on something one of them holds
+const MAX_SUSPENDED: Duration = Duration::from_secs(1);
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn OpenThread(access: u32, inherit: i32, thread_id: u32) -> *mut c_void;
+    fn SuspendThread(thread: *mut c_void) -> u32;
+    fn ResumeThread(thread: *mut c_void) -> u32;
+    fn GetThreadContext(thread: *mut c_void, context: *mut CONTEXT) -> i32;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+    fn GetLastError() -> u32;
+    fn GetCurrentProcessId() -> u32;
+    fn CreateToolhelp32Snapshot(flags: u32, process_id: u32) -> *mut c_void;
+    fn Thread32First(snapshot: *mut c_void, entry: *mut THREADENTRY32) -> i32;
+    fn Thread32Next(snapshot: *mut c_void, entry: *mut THREADENTRY32) -> i32;
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+struct THREADENTRY32 {
+    dwSize: u32,
+    cntUsage: u32,
+    th32ThreadID: u32,
+    th32OwnerProcessID: u32,
+    tpBasePri: i32,
+    tpDeltaPri: i32,
+    dwFlags: u32,
+}
+
+/// Why [`suspend_others`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ThreadError {
+    /// The process's threads couldn't be listed, with this `GetLastError` code.
+    Snapshot(u32),
+}
+
+impl fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadError::Snapshot(code) => {
+                write!(f, "failed to list the process's threads (error {code})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThreadError {}
+
+/// Keeps the threads suspended by [`suspend_others`] suspended until dropped.
+pub struct SuspendGuard {
+    suspended: Vec<*mut c_void>,
+    running: Vec<u32>,
+    since: Instant,
+}
+
+impl SuspendGuard {
+    /// How many threads are suspended.
+    pub fn suspended(&self) -> usize {
+        self.suspended.len()
+    }
+
+    /// The IDs of the threads left running because they were inside one of the crate's handlers.
+    pub fn running(&self) -> &[u32] {
+        &self.running
+    }
+}
+
+impl Drop for SuspendGuard {
+    fn drop(&mut self) {
+        for &thread in &self.suspended {
+            unsafe {
+                ResumeThread(thread);
+                CloseHandle(thread);
+            }
+        }
+        debug_assert!(
+            self.since.elapsed() < MAX_SUSPENDED,
+            "other threads were suspended for {:?}",
+            self.since.elapsed()
+        );
+    }
+}
+
+/// Suspends every other thread in the process until the returned guard is dropped, apart from
+/// the ones running one of the crate's handlers, see the [module documentation](self).
+///
+/// Threads that exit while this runs are skipped.
+pub fn suspend_others() -> Result<SuspendGuard, ThreadError> {
+    let threads = process_threads()?;
+    let own = teb::current_thread_id();
+
+    // Allocated up front, as a suspended thread may hold the heap's lock
+    let mut guard = SuspendGuard {
+        suspended: Vec::with_capacity(threads.len()),
+        running: Vec::with_capacity(threads.len()),
+        since: Instant::now(),
+    };
+    for thread_id in threads.into_iter().filter(|&id| id != own) {
+        unsafe {
+            // Gone already
+            let thread = OpenThread(THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT, 0, thread_id);
+            if thread.is_null() {
+                continue;
+            }
+            if SuspendThread(thread) == u32::MAX {
+                CloseHandle(thread);
+                continue;
+            }
+            wait_suspended(thread);
+
+            // Checked once it's suspended, so it can't enter a handler afterwards
+            if reentry::is_dispatching(thread_id) {
+                ResumeThread(thread);
+                CloseHandle(thread);
+                guard.running.push(thread_id);
+                continue;
+            }
+            guard.suspended.push(thread);
+        }
+    }
+
+    guard.since = Instant::now();
+    Ok(guard)
+}
+
+// `SuspendThread` only asks for another thread to be suspended, and returns before it has
+// stopped; reading its context waits until it has. If that fails, it's left suspended anyway, and
+// stops soon after
+unsafe fn wait_suspended(thread: *mut c_void) {
+    let mut context: CONTEXT = std::mem::zeroed();
+    context.context_flags = CONTEXT_CONTROL;
+    GetThreadContext(thread, &mut context);
+}
+
+// The IDs of the threads currently running in this process
+pub(crate) fn process_threads() -> Result<Vec<u32>, ThreadError> {
+    let mut threads = Vec::new();
+
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Err(ThreadError::Snapshot(GetLastError()));
+        }
+
+        // The snapshot contains every thread in the system
+        let process_id = GetCurrentProcessId();
+        let mut entry: THREADENTRY32 = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+        let mut found = Thread32First(snapshot, &mut entry);
+        while found != 0 {
+            if entry.th32OwnerProcessID == process_id {
+                threads.push(entry.th32ThreadID);
+            }
+            found = Thread32Next(snapshot, &mut entry);
+        }
+
+        CloseHandle(snapshot);
+    }
+
+    Ok(threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::suspend_others;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn others_frozen() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (counter, stop) = (counter.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        };
+        while counter.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+
+        let guard = suspend_others().unwrap();
+        assert!(guard.suspended() > 0);
+        let frozen = counter.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(counter.load(Ordering::SeqCst), frozen);
+        drop(guard);
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(counter.load(Ordering::SeqCst) > frozen);
+        stop.store(true, Ordering::SeqCst);
+        worker.join().unwrap();
+    }
+}
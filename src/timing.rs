@@ -0,0 +1,133 @@
+// Timing dispatcher callbacks with the performance counter, for `dispatch::timing_report`.
+
+// Imports
+use crate::dispatch::CallbackKey;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn QueryPerformanceCounter(count: *mut i64) -> i32;
+    fn QueryPerformanceFrequency(frequency: *mut i64) -> i32;
+}
+
+/// How long a dispatcher callback has taken, as reported by
+/// [`timing_report`](crate::dispatch::timing_report).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackTiming {
+    /// The registration's [`CallbackGuard::id`](crate::dispatch::CallbackGuard::id).
+    pub id: u64,
+    /// The key the closure was registered with, if any.
+    pub key: Option<CallbackKey>,
+    pub priority: i32,
+    pub calls: u64,
+    pub total: Duration,
+    /// The longest single call.
+    pub max: Duration,
+}
+
+// A callback's totals, in performance counter ticks
+#[derive(Default)]
+pub(crate) struct Timing {
+    calls: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Timing {
+    pub(crate) fn time<R>(&self, f: impl FnOnce() -> R) -> R {
+        let start = now();
+        let result = f();
+        let ticks = now().saturating_sub(start);
+
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(ticks, Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+        result
+    }
+
+    pub(crate) fn report(
+        &self,
+        id: u64,
+        key: Option<CallbackKey>,
+        priority: i32,
+    ) -> CallbackTiming {
+        CallbackTiming {
+            id,
+            key,
+            priority,
+            calls: self.calls.load(Ordering::Relaxed),
+            total: to_duration(self.total.load(Ordering::Relaxed)),
+            max: to_duration(self.max.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+fn now() -> u64 {
+    let mut count = 0;
+    unsafe { QueryPerformanceCounter(&mut count) };
+    count as u64
+}
+
+fn to_duration(ticks: u64) -> Duration {
+    let mut frequency = 0;
+    unsafe { QueryPerformanceFrequency(&mut frequency) };
+    match frequency {
+        0 => Duration::ZERO,
+        frequency => {
+            let nanos = ticks as u128 * 1_000_000_000 / frequency as u128;
+            Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatch::{self, Registration};
+    use crate::{testing, ExceptionCode, Filter, Handling};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn busy_callback_timed() {
+        const CODE: u32 = 0xE056_6201;
+        let _serial = dispatch::tests::serial();
+
+        let guard = Registration::new()
+            .key("busy")
+            .priority(7)
+            .filter(Filter::code(ExceptionCode::from_raw(CODE)))
+            .register_closure(|_| {
+                let start = Instant::now();
+                while start.elapsed() < Duration::from_millis(20) {
+                    std::hint::spin_loop();
+                }
+                Handling::ContinueExecution
+            })
+            .unwrap();
+
+        testing::raise(CODE, &[]);
+        testing::raise(CODE, &[]);
+        let report = dispatch::timing_report();
+        dispatch::reset_timing();
+        let after_reset = dispatch::timing_report();
+        let id = guard.id();
+        drop(guard);
+
+        let timing = report.iter().find(|timing| timing.id == id).unwrap();
+        assert_eq!(timing.key, Some("busy".into()));
+        assert_eq!(timing.priority, 7);
+        assert_eq!(timing.calls, 2);
+        assert!(timing.max >= Duration::from_millis(15));
+        assert!(timing.total >= timing.max * 2 - Duration::from_millis(10));
+
+        let reset = after_reset.iter().find(|timing| timing.id == id).unwrap();
+        assert_eq!((reset.calls, reset.total), (0, Duration::ZERO));
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,216 @@
+//! Tracing every instruction executed within an address range.
+//!
+//! A [`Tracer`] sets an execute [hardware breakpoint](crate::hwbp) on the start of the range.
+//! Whenever a thread it's armed on gets there, that thread is [single-stepped](crate::step) for
+//! as long as it stays inside the range, with each step recorded into a buffer allocated up
+//! front. The buffer is drained into the tracer's sink with [`Tracer::drain`], outside of any
+//! exception handler.
+//!
+//! Only entries through the start of the range are noticed, so execution that leaves the range
+//! (including calls out of it) ends the trace until the start is reached again.
+
+// Imports
+use crate::hwbp::{HwBreakpoint, HwbpError, Kind, Size};
+use crate::{step, teb, ContextExt, ExceptionInfo};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Something that happened while tracing a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The instruction at `ip` is about to execute, with the stack pointer at `sp`.
+    Step {
+        thread_id: u32,
+        ip: usize,
+        sp: usize,
+    },
+    /// Execution left the range, and is about to continue at `ip`.
+    Left { thread_id: u32, ip: usize },
+    /// The thread took [`TraceLimits::max_steps`] steps without leaving the range, and isn't
+    /// traced any further until it reaches the start again.
+    LimitReached { thread_id: u32, ip: usize },
+}
+
+/// Bounds on how much a [`Tracer`] records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceLimits {
+    /// The most steps taken each time a thread enters the range.
+    pub max_steps: usize,
+    /// How many events are buffered until drained, after which the oldest are overwritten.
+    pub capacity: usize,
+}
+
+impl Default for TraceLimits {
+    fn default() -> Self {
+        TraceLimits {
+            max_steps: 100_000,
+            capacity: 4096,
+        }
+    }
+}
+
+struct Shared {
+    range: Range<usize>,
+    max_steps: usize,
+    capacity: usize,
+    events: Mutex<VecDeque<TraceEvent>>,
+    overwritten: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    // Never grows the buffer, so it doesn't allocate
+    fn push(&self, event: TraceEvent) {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() == self.capacity {
+            events.pop_front();
+            self.overwritten.fetch_add(1, Ordering::Relaxed);
+        }
+        events.push_back(event);
+    }
+}
+
+/// Traces the instructions executed within a range, stopping once dropped and passing the events
+/// still buffered to the sink.
+pub struct Tracer<S: FnMut(TraceEvent)> {
+    shared: Arc<Shared>,
+    breakpoint: HwBreakpoint,
+    sink: S,
+}
+
+impl<S: FnMut(TraceEvent)> Tracer<S> {
+    /// Traces the instructions executed within `range` on the current thread, with the default
+    /// [`TraceLimits`].
+    ///
+    /// # Panics
+    /// If `range` is empty.
+    pub fn trace_range(range: Range<usize>, sink: S) -> Result<Self, HwbpError> {
+        Tracer::trace_range_with(range, TraceLimits::default(), sink)
+    }
+
+    /// Traces the instructions executed within `range` on the current thread, recording at most
+    /// as much as `limits` allows.
+    ///
+    /// # Panics
+    /// If `range` is empty, or `limits.capacity` is 0.
+    pub fn trace_range_with(
+        range: Range<usize>,
+        limits: TraceLimits,
+        sink: S,
+    ) -> Result<Self, HwbpError> {
+        assert!(!range.is_empty(), "the traced range is empty");
+        assert!(limits.capacity > 0, "the trace buffer has no capacity");
+
+        let start = range.start;
+        let shared = Arc::new(Shared {
+            range,
+            max_steps: limits.max_steps,
+            capacity: limits.capacity,
+            events: Mutex::new(VecDeque::with_capacity(limits.capacity)),
+            overwritten: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+        });
+
+        let entered = shared.clone();
+        let breakpoint = HwBreakpoint::set(start, Kind::Execute, Size::One, move |info| {
+            if !entered.stopped.load(Ordering::SeqCst) {
+                record(info, entered.clone(), 0);
+            }
+        })?;
+
+        Ok(Tracer {
+            shared,
+            breakpoint,
+            sink,
+        })
+    }
+
+    /// Passes the events recorded so far to the sink, oldest first.
+    pub fn drain(&mut self) {
+        // The lock isn't held while the sink runs, in case it executes traced code itself
+        loop {
+            let event = self.shared.events.lock().unwrap().pop_front();
+            match event {
+                Some(event) => (self.sink)(event),
+                None => break,
+            }
+        }
+    }
+
+    /// How many events were overwritten before being drained, because the buffer was full.
+    pub fn overwritten(&self) -> usize {
+        self.shared.overwritten.load(Ordering::Relaxed)
+    }
+
+    /// The breakpoint detecting entries into the range, for applying it to other threads.
+    pub fn breakpoint(&self) -> &HwBreakpoint {
+        &self.breakpoint
+    }
+}
+
+impl<S: FnMut(TraceEvent)> Drop for Tracer<S> {
+    fn drop(&mut self) {
+        // Threads still stepping see this on their next step, and stop there
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        self.drain();
+    }
+}
+
+// Records the instruction about to execute, and steps over it if it's still inside the range
+fn record(info: &mut ExceptionInfo, shared: Arc<Shared>, steps: usize) {
+    let thread_id = teb::current_thread_id();
+    let context = info.context_mut();
+    let ip = context.ip();
+
+    if !shared.range.contains(&ip) {
+        shared.push(TraceEvent::Left { thread_id, ip });
+        return;
+    }
+    if steps == shared.max_steps {
+        shared.push(TraceEvent::LimitReached { thread_id, ip });
+        return;
+    }
+
+    let sp = context.sp();
+    shared.push(TraceEvent::Step { thread_id, ip, sp });
+    if !shared.stopped.load(Ordering::SeqCst) {
+        step::single_step(context, move |info| record(info, shared, steps + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TraceEvent, Tracer};
+    use crate::dispatch;
+
+    #[unsafe(naked)]
+    extern "C" fn traced() {
+        std::arch::naked_asm!("nop", "nop", "nop", "ret")
+    }
+
+    #[test]
+    fn steps_through_function() {
+        let _serial = dispatch::tests::serial();
+
+        let start = traced as extern "C" fn() as usize;
+        let mut events = Vec::new();
+        let mut tracer = Tracer::trace_range(start..start + 3, |event| events.push(event)).unwrap();
+        traced();
+        tracer.drain();
+        drop(tracer);
+
+        let (last, steps) = events.split_last().unwrap();
+        let ips: Vec<_> = steps
+            .iter()
+            .map(|event| match *event {
+                TraceEvent::Step { ip, .. } => ip,
+                other => panic!("unexpected event {other:?}"),
+            })
+            .collect();
+        assert_eq!(ips, [start, start + 1, start + 2]);
+        assert!(matches!(*last, TraceEvent::Left { ip, .. } if ip == start + 3));
+        assert!(!dispatch::is_installed());
+    }
+}
@@ -0,0 +1,128 @@
+//! Installing an unhandled exception filter without going through the import table.
+//!
+//! Vectored handlers see exceptions first, the unhandled exception filter sees the ones nothing
+//! else handled, right before WER does. [`Uef::set`] finds `SetUnhandledExceptionFilter` by
+//! walking kernelbase's or kernel32's exports, like the rest of the crate finds ntdll's functions,
+//! and puts the previous filter back when dropped.
+
+// Imports
+use crate::{modules, VehError, EXCEPTION_CONTINUE_SEARCH, EXCEPTION_POINTERS};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// An unhandled exception filter, returning `EXCEPTION_EXECUTE_HANDLER` (1) to end the process
+/// quietly, `EXCEPTION_CONTINUE_SEARCH` (0) to go on to WER, or `EXCEPTION_CONTINUE_EXECUTION`
+/// (-1) to resume.
+pub type UnhandledFilter = unsafe extern "system" fn(info: *mut EXCEPTION_POINTERS) -> i32;
+
+type FnSetUnhandledExceptionFilter = unsafe extern "system" fn(filter: usize) -> usize;
+
+// The filter the innermost `Uef` replaced, or 0, for `chain_previous`
+static PREVIOUS: AtomicUsize = AtomicUsize::new(0);
+
+/// The unhandled exception filter set with [`Uef::set`], replaced by the previous one on drop.
+///
+/// Filters set one after another should be dropped in the opposite order.
+pub struct Uef {
+    set_filter: FnSetUnhandledExceptionFilter,
+    filter: usize,
+    previous: usize,
+    // What `PREVIOUS` was before this filter was set
+    outer: usize,
+}
+
+impl Uef {
+    /// Makes `filter` the process's unhandled exception filter.
+    pub fn set(filter: UnhandledFilter) -> Result<Uef, VehError> {
+        let set_filter = find_set_filter().ok_or(VehError::Resolution)?;
+
+        let filter = filter as usize;
+        let previous = unsafe { set_filter(filter) };
+        let outer = PREVIOUS.swap(previous, Ordering::SeqCst);
+        Ok(Uef {
+            set_filter,
+            filter,
+            previous,
+            outer,
+        })
+    }
+
+    /// Passes the exception on to the filter the innermost [`Uef`] replaced, for filters that want
+    /// to leave the decision to whoever was there before them. Returns `EXCEPTION_CONTINUE_SEARCH`
+    /// if there was none.
+    ///
+    /// # Safety
+    /// `info` must be the pointer the filter was called with.
+    pub unsafe fn chain_previous(info: *mut EXCEPTION_POINTERS) -> i32 {
+        match PREVIOUS.load(Ordering::SeqCst) {
+            0 => EXCEPTION_CONTINUE_SEARCH,
+            previous => std::mem::transmute::<usize, UnhandledFilter>(previous)(info),
+        }
+    }
+
+    /// Whether this is still the process's filter, or something else has replaced it since.
+    ///
+    /// There's no way of reading the filter, so this briefly sets this one again, and then puts
+    /// back whichever was there. An exception reaching the filter meanwhile sees this one.
+    pub fn is_current(&self) -> bool {
+        let current = unsafe { (self.set_filter)(self.filter) };
+        unsafe { (self.set_filter)(current) };
+        current == self.filter
+    }
+}
+
+/// Puts the previous filter back, unless something else has replaced this one since, in which
+/// case that one is left in place.
+impl Drop for Uef {
+    fn drop(&mut self) {
+        if self.is_current() {
+            unsafe { (self.set_filter)(self.previous) };
+        }
+        PREVIOUS.store(self.outer, Ordering::SeqCst);
+    }
+}
+
+fn find_set_filter() -> Option<FnSetUnhandledExceptionFilter> {
+    let address = modules::kernel_export("SetUnhandledExceptionFilter")?;
+    Some(unsafe { std::mem::transmute::<usize, FnSetUnhandledExceptionFilter>(address) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Uef;
+    use crate::testing::run_isolated;
+    use crate::{EXCEPTION_CONTINUE_SEARCH, EXCEPTION_POINTERS};
+    use winapi::um::processthreadsapi::{GetCurrentProcess, TerminateProcess};
+
+    unsafe extern "system" fn terminate(_info: *mut EXCEPTION_POINTERS) -> i32 {
+        TerminateProcess(GetCurrentProcess(), 0x5B);
+        EXCEPTION_CONTINUE_SEARCH
+    }
+
+    unsafe extern "system" fn chain(info: *mut EXCEPTION_POINTERS) -> i32 {
+        Uef::chain_previous(info)
+    }
+
+    #[test]
+    fn replaced_and_restored() {
+        let outer = Uef::set(terminate).unwrap();
+        assert!(outer.is_current());
+
+        let inner = Uef::set(chain).unwrap();
+        assert!(inner.is_current());
+        assert!(!outer.is_current());
+
+        drop(inner);
+        assert!(outer.is_current());
+        drop(outer);
+    }
+
+    #[test]
+    fn filter_runs_on_crash() {
+        run_isolated("uef::tests::filter_runs_on_crash", || {
+            let _outer = Uef::set(terminate).unwrap();
+            let _inner = Uef::set(chain).unwrap();
+            unsafe { std::ptr::read_volatile(std::ptr::null::<u8>()) };
+        })
+        .expect_exit_code(0x5B);
+    }
+}
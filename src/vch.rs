@@ -0,0 +1,140 @@
+// Imports
+use crate::{raw_add_continue, Order, VectoredHandler, Veh, CONTINUE_HANDLER_LIST};
+use std::ffi::c_void;
+
+// Wrap the handle of a handler registered into the *continue* handler list
+//
+// Continue handlers are called once a vectored handler or SEH filter has decided to continue
+// execution, right before the thread resumes. The `Order` applies within the continue list the
+// same way it does for `Veh`.
+pub struct Vch<T> {
+    // Removes the handler from the continue list on drop
+    _registration: Veh<T>,
+}
+
+pub type VchVoid = Vch<c_void>;
+
+#[cfg(feature = "impl-winapi")]
+pub type VchWinapi = Vch<winapi::um::winnt::EXCEPTION_POINTERS>;
+
+#[cfg(feature = "impl-windows")]
+pub type VchWindows = Vch<windows_sys::Win32::System::Diagnostics::Debug::EXCEPTION_POINTERS>;
+
+impl<T> Vch<T> {
+    /// # Safety
+    /// The function passed as `handler` *must* be `unsafe extern "system" fn(*mut T) -> i32`,
+    /// where T is a type that matches the Windows API's `EXCEPTION_POINTERS` type.
+    ///
+    /// This function will never directly cause undefined behaviour, but the handlers it registers
+    /// might very well cause UB if they're not written correctly. Calling this function is
+    /// therefore unsafe, as it might affect the program in unexpected ways if the caller doesn't
+    /// properly handle exceptions it catches.
+    ///
+    /// This registers a function to be called when execution is about to continue after an
+    /// exception was handled.
+    pub unsafe fn add_raw(order: Order, handler: usize) -> Self {
+        let handle = raw_add_continue(order, handler);
+        Vch {
+            _registration: Veh::from_parts(handle, CONTINUE_HANDLER_LIST, handler, None),
+        }
+    }
+
+    /// Unregisters the handler, the same as dropping it.
+    pub fn remove(self) {
+        drop(self)
+    }
+}
+
+impl Vch<c_void> {
+    /// # Safety
+    /// See [`Vch::add_raw`].
+    pub unsafe fn add(order: Order, handler: VectoredHandler) -> Self {
+        Vch::add_raw(order, handler as _)
+    }
+}
+
+#[cfg(feature = "impl-winapi")]
+impl VchWinapi {
+    /// # Safety
+    /// See [`Vch::add_raw`].
+    pub unsafe fn add(
+        order: Order,
+        handler: crate::VectoredHandlerFor<winapi::um::winnt::EXCEPTION_POINTERS>,
+    ) -> Self {
+        Vch::add_raw(order, handler as _)
+    }
+}
+
+#[cfg(feature = "impl-windows")]
+impl VchWindows {
+    /// # Safety
+    /// See [`Vch::add_raw`].
+    pub unsafe fn add(
+        order: Order,
+        handler: crate::VectoredHandlerFor<
+            windows_sys::Win32::System::Diagnostics::Debug::EXCEPTION_POINTERS,
+        >,
+    ) -> Self {
+        Vch::add_raw(order, handler as _)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ContextExt, Order, Vch, Veh};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use winapi::{
+        um::{
+            minwinbase::EXCEPTION_ACCESS_VIOLATION,
+            winnt::{EXCEPTION_POINTERS, LONG, PEXCEPTION_POINTERS},
+        },
+        vc::excpt::{EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH},
+    };
+
+    #[test]
+    fn continue_handler_sees_fixed_up_exception() {
+        static FIXED: AtomicBool = AtomicBool::new(false);
+        static OBSERVED: AtomicBool = AtomicBool::new(false);
+
+        unsafe extern "system" fn handler(ptrs: PEXCEPTION_POINTERS) -> LONG {
+            let cr = &mut *(*ptrs).ContextRecord;
+            let er = &mut *(*ptrs).ExceptionRecord;
+
+            // Avoid catching exceptions that aren't caused by us
+            if er.ExceptionCode == EXCEPTION_ACCESS_VIOLATION && er.ExceptionAddress == 1 as _ {
+                // Pretend the bad call returned to let the program continue
+                if cr.emulate_return().is_err() {
+                    return EXCEPTION_CONTINUE_SEARCH;
+                }
+
+                FIXED.store(true, Ordering::SeqCst);
+                EXCEPTION_CONTINUE_EXECUTION
+            } else {
+                EXCEPTION_CONTINUE_SEARCH
+            }
+        }
+
+        unsafe extern "system" fn continue_handler(ptrs: PEXCEPTION_POINTERS) -> LONG {
+            let cr = &*(*ptrs).ContextRecord;
+            let er = &*(*ptrs).ExceptionRecord;
+
+            let ip = cr.ip();
+
+            // By now the context must already have been moved away from the faulting address
+            if er.ExceptionCode == EXCEPTION_ACCESS_VIOLATION && er.ExceptionAddress == 1 as _ {
+                OBSERVED.store(FIXED.load(Ordering::SeqCst) && ip != 1, Ordering::SeqCst);
+            }
+
+            EXCEPTION_CONTINUE_SEARCH
+        }
+
+        unsafe {
+            let _veh = Veh::<EXCEPTION_POINTERS>::add(Order::First, handler);
+            let _vch = Vch::<EXCEPTION_POINTERS>::add(Order::First, continue_handler);
+
+            (std::mem::transmute::<usize, fn()>(1))();
+        }
+
+        assert!(OBSERVED.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,262 @@
+// Access to the AVX upper halves of the YMM registers, which aren't part of `CONTEXT` itself.
+//
+// When a context is captured with `CONTEXT_XSTATE`, a `CONTEXT_EX` directly follows it, whose
+// `XState` chunk locates an XSAVE area's 64-byte header followed by its extended components. The
+// lower halves of the YMM registers are the XMM registers from the legacy FXSAVE area already in
+// `CONTEXT`, the upper halves (YMMH) are the AVX component, the first extended one.
+
+// Imports
+use crate::{ExceptionInfo, CONTEXT};
+
+#[cfg(target_arch = "x86")]
+const CONTEXT_XSTATE: u32 = 0x0001_0040;
+#[cfg(target_arch = "x86_64")]
+const CONTEXT_XSTATE: u32 = 0x0010_0040;
+
+#[cfg(target_arch = "x86")]
+const YMM_COUNT: usize = 8;
+#[cfg(target_arch = "x86_64")]
+const YMM_COUNT: usize = 16;
+
+const XSAVE_HEADER_SIZE: usize = 64;
+const XSAVE_ALIGNMENT: usize = 64;
+// Offset of the XMM registers in the FXSAVE area
+const XMM_OFFSET: usize = 160;
+// The AVX state component
+const AVX: u64 = 1 << 2;
+// Set in `CompactionMask` if the area uses the compacted format
+const COMPACTED: u64 = 1 << 63;
+
+#[repr(C)]
+struct ContextChunk {
+    offset: i32,
+    length: u32,
+}
+
+#[repr(C)]
+struct ContextEx {
+    all: ContextChunk,
+    legacy: ContextChunk,
+    xstate: ContextChunk,
+}
+
+#[repr(C)]
+struct XSaveHeader {
+    mask: u64,
+    compaction_mask: u64,
+}
+
+// The XMM registers, in the context's FXSAVE area
+fn xmm(context: &mut CONTEXT) -> *mut [u8; 16] {
+    #[cfg(target_arch = "x86")]
+    let legacy = context.extended_registers.as_mut_ptr();
+    #[cfg(target_arch = "x86_64")]
+    let legacy = context.flt_save.as_mut_ptr();
+
+    unsafe { legacy.add(XMM_OFFSET) as *mut [u8; 16] }
+}
+
+/// Locates the XSAVE header and the upper halves in the AVX component, or `None` if the context
+/// wasn't captured with them.
+///
+/// # Safety
+/// `context` must be followed by a valid `CONTEXT_EX` if its flags include `CONTEXT_XSTATE`.
+unsafe fn avx_area(context: &mut CONTEXT) -> Option<(*mut XSaveHeader, *mut [u8; 16])> {
+    if context.context_flags & CONTEXT_XSTATE != CONTEXT_XSTATE {
+        return None;
+    }
+
+    let context_ex = (context as *mut CONTEXT).add(1) as *mut ContextEx;
+    let chunk = &(*context_ex).xstate;
+    if (chunk.length as usize) < XSAVE_HEADER_SIZE + YMM_COUNT * 16 {
+        return None;
+    }
+
+    let header = (context_ex as *mut u8).offset(chunk.offset as isize);
+    if !(header as usize).is_multiple_of(XSAVE_ALIGNMENT) {
+        return None;
+    }
+
+    // AVX is the first extended component, so it directly follows the header in both formats,
+    // but a compacted area only contains it at all if it's in the compaction mask
+    let header = header as *mut XSaveHeader;
+    let compaction_mask = (*header).compaction_mask;
+    if compaction_mask & COMPACTED != 0 && compaction_mask & AVX == 0 {
+        return None;
+    }
+
+    let ymmh = (header as *mut u8).add(XSAVE_HEADER_SIZE) as *mut [u8; 16];
+    Some((header, ymmh))
+}
+
+impl ExceptionInfo {
+    /// Register `YMMi` of the thread that raised the exception, as little-endian bytes.
+    ///
+    /// Returns `None` if there's no such register, or if the extended state isn't available,
+    /// which is the case when the context wasn't captured with `CONTEXT_XSTATE` (the processor or
+    /// OS doesn't support AVX, or it has been disabled).
+    pub fn ymm(&mut self, i: usize) -> Option<[u8; 32]> {
+        if i >= YMM_COUNT {
+            return None;
+        }
+
+        let context = self.context_mut();
+        let (header, ymmh) = unsafe { avx_area(context)? };
+
+        let mut value = [0; 32];
+        unsafe {
+            value[..16].copy_from_slice(&*xmm(context).add(i));
+
+            // Without the AVX bit, the upper halves are in their initial state: all zeroes
+            if (*header).mask & AVX != 0 {
+                value[16..].copy_from_slice(&*ymmh.add(i));
+            }
+        }
+
+        Some(value)
+    }
+
+    /// Sets register `YMMi` to use once execution resumes, returning `false` without changing
+    /// anything if it's unavailable, see [`ExceptionInfo::ymm`].
+    pub fn set_ymm(&mut self, i: usize, value: [u8; 32]) -> bool {
+        if i >= YMM_COUNT {
+            return false;
+        }
+
+        let context = self.context_mut();
+        let (header, ymmh) = match unsafe { avx_area(context) } {
+            Some(area) => area,
+            None => return false,
+        };
+
+        unsafe {
+            // Leaving the initial state means every other upper half has to be written as zero
+            if (*header).mask & AVX == 0 {
+                for register in 0..YMM_COUNT {
+                    *ymmh.add(register) = [0; 16];
+                }
+                (*header).mask |= AVX;
+            }
+
+            (*xmm(context).add(i)).copy_from_slice(&value[..16]);
+            (*ymmh.add(i)).copy_from_slice(&value[16..]);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ContextEx, XSaveHeader, AVX, COMPACTED, CONTEXT_XSTATE, XMM_OFFSET, XSAVE_HEADER_SIZE,
+        YMM_COUNT,
+    };
+    use crate::{ExceptionInfo, CONTEXT, EXCEPTION_POINTERS, EXCEPTION_RECORD};
+    use std::mem::size_of;
+
+    // A context followed by a `CONTEXT_EX`, and an XSAVE area `header_offset` bytes in
+    #[repr(C, align(64))]
+    struct Captured([u8; 4096]);
+
+    fn captured(header_offset: usize, mask: u64, compaction_mask: u64) -> Box<Captured> {
+        let mut buffer = Box::new(Captured([0; 4096]));
+        let base = buffer.0.as_mut_ptr();
+
+        unsafe {
+            let context = &mut *(base as *mut CONTEXT);
+            context.context_flags = CONTEXT_XSTATE;
+
+            #[cfg(target_arch = "x86")]
+            let legacy = context.extended_registers.as_mut_ptr();
+            #[cfg(target_arch = "x86_64")]
+            let legacy = context.flt_save.as_mut_ptr();
+            for i in 0..YMM_COUNT {
+                *legacy.add(XMM_OFFSET + i * 16) = i as u8;
+            }
+
+            let context_ex = &mut *(base.add(size_of::<CONTEXT>()) as *mut ContextEx);
+            context_ex.xstate.offset = (header_offset - size_of::<CONTEXT>()) as i32;
+            context_ex.xstate.length = (XSAVE_HEADER_SIZE + YMM_COUNT * 16) as u32;
+
+            let header = &mut *(base.add(header_offset) as *mut XSaveHeader);
+            header.mask = mask;
+            header.compaction_mask = compaction_mask;
+            for i in 0..YMM_COUNT {
+                *base.add(header_offset + XSAVE_HEADER_SIZE + i * 16) = 0x80 | i as u8;
+            }
+        }
+
+        buffer
+    }
+
+    fn with_info<R>(buffer: &mut Captured, f: impl FnOnce(&mut ExceptionInfo) -> R) -> R {
+        let mut record: EXCEPTION_RECORD = unsafe { std::mem::zeroed() };
+        let mut ptrs = EXCEPTION_POINTERS {
+            exception_record: &mut record,
+            context_record: buffer.0.as_mut_ptr() as *mut CONTEXT,
+        };
+        f(unsafe { ExceptionInfo::from_raw(&mut ptrs as *mut _ as _) })
+    }
+
+    fn expected(i: usize, upper: bool) -> [u8; 32] {
+        let mut value = [0; 32];
+        value[0] = i as u8;
+        if upper {
+            value[16] = 0x80 | i as u8;
+        }
+        value
+    }
+
+    #[test]
+    fn standard_format() {
+        let mut buffer = captured(2048, AVX, 0);
+        with_info(&mut buffer, |info| {
+            assert_eq!(info.ymm(0), Some(expected(0, true)));
+            assert_eq!(info.ymm(YMM_COUNT - 1), Some(expected(YMM_COUNT - 1, true)));
+            assert_eq!(info.ymm(YMM_COUNT), None);
+        });
+    }
+
+    #[test]
+    fn compacted_format() {
+        let mut buffer = captured(2048, AVX, COMPACTED | AVX | 0b11);
+        with_info(&mut buffer, |info| {
+            assert_eq!(info.ymm(3), Some(expected(3, true)))
+        });
+
+        // Not saved at all
+        let mut buffer = captured(2048, AVX, COMPACTED | 0b11);
+        with_info(&mut buffer, |info| assert_eq!(info.ymm(3), None));
+    }
+
+    #[test]
+    fn initial_state() {
+        let mut buffer = captured(2048, 0, 0);
+        with_info(&mut buffer, |info| {
+            assert_eq!(info.ymm(1), Some(expected(1, false)));
+
+            let value = [7; 32];
+            assert!(info.set_ymm(1, value));
+            assert_eq!(info.ymm(1), Some(value));
+            // The other upper halves are zeroed rather than exposing what was in the buffer
+            assert_eq!(info.ymm(2), Some(expected(2, false)));
+        });
+    }
+
+    #[test]
+    fn unavailable() {
+        // Misaligned XSAVE areas can't come from the OS, so they're rejected
+        let mut buffer = captured(2048 + 16, AVX, 0);
+        with_info(&mut buffer, |info| {
+            assert_eq!(info.ymm(0), None);
+            assert!(!info.set_ymm(0, [1; 32]));
+        });
+
+        let mut buffer = captured(2048, AVX, 0);
+        with_info(&mut buffer, |info| {
+            info.context_mut().context_flags = 0;
+            assert_eq!(info.ymm(0), None);
+        });
+    }
+}
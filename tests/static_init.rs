@@ -0,0 +1,42 @@
+//! Registers a handler with `install_static!` in a binary of its own, as it stays registered in
+//! first position for every test in the binary.
+#![cfg(windows)]
+
+use manual_veh::{ExceptionInfo, Order, EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH};
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use winapi::um::errhandlingapi::RaiseException;
+
+const CODE: u32 = 0xE056_6601;
+static HANDLED: AtomicUsize = AtomicUsize::new(0);
+static RESUMED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "system" fn early(ptrs: *mut c_void) -> i32 {
+    match ExceptionInfo::from_raw(ptrs).code().raw() {
+        CODE => {
+            HANDLED.fetch_add(1, Ordering::SeqCst);
+            EXCEPTION_CONTINUE_EXECUTION
+        }
+        _ => EXCEPTION_CONTINUE_SEARCH,
+    }
+}
+
+manual_veh::install_static!(static EARLY = Order::First, early, teardown);
+
+// Another initializer, sorted after `.CRT$XCU`, that would take the test binary down if the
+// handler weren't registered yet
+unsafe extern "C" fn raise_early() {
+    RaiseException(CODE, 0, 0, std::ptr::null());
+    RESUMED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[used]
+#[link_section = ".CRT$XCV"]
+static RAISE_EARLY: unsafe extern "C" fn() = raise_early;
+
+#[test]
+fn handled_before_main() {
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    assert_eq!(RESUMED.load(Ordering::SeqCst), 1);
+    assert!(EARLY.is_installed());
+}